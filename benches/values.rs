@@ -0,0 +1,142 @@
+//! Run with `cargo bench`, times scripts which copy many values or branch on them
+//! and the compilation of a large file, with the number of allocations they make.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use rlox::{compiler::compile, vm::{InterpretResult, VM}};
+
+/// Counts the allocations, so the compile benchmark shows the churn.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+const CALLS: &str = "
+fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+var result = fib(24);";
+
+const STRINGS: &str = "
+var text = \"\";
+for (var i = 0; i < 2000; i = i + 1) { text = text + \"ab\"; }
+var count = 0;
+for (var c in text) { if (c == \"a\") count = count + 1; }
+var copies = 0;
+for (var i = 0; i < 100000; i = i + 1) { var copy = text; if (copy == text) copies = copies + 1; }";
+
+const ARITHMETIC: &str = "
+var sum = 0;
+for (var i = 0; i < 300000; i = i + 1) { sum = sum + i * 2 - i / 2; if (!(sum > 0) == nil) sum = -sum; }";
+
+/// Conditions on strings and lists, the jumps and `==` only look at the values.
+const BRANCHES: &str = "
+var word = \"word\"; var list = [1, 2];
+var hits = 0;
+for (var i = 0; i < 200000; i = i + 1) {
+    if (word == \"word\" and list) hits = hits + 1;
+    if (!list or word == nil) hits = hits - 1; else if (list != word) hits = hits + 1;
+    while (word and !word) {}
+}";
+
+/// Branches on globals, their instructions have names as operands.
+const GLOBALS: &str = "
+var flag = true; var limit = 100; var misses = 0;
+{
+    var hits = 0;
+    for (var i = 0; i < 200000; i = i + 1) {
+        if (flag and i > limit) hits = hits + 1; else if (!flag) hits = hits - 1; else misses = i;
+    }
+}";
+
+fn measure(name: &str, source: &str) {
+    let runs = 5;
+    let mut best = Duration::MAX;
+    for _ in 0..runs {
+        let mut vm = VM::new();
+        let start = Instant::now();
+        let result = vm.interpret(source.to_string(), false);
+        best = best.min(start.elapsed());
+        assert!(matches!(result, InterpretResult::Ok), "{name} failed");
+    }
+
+    println!("{name:<8} best of {runs}: {best:?}");
+}
+
+/// Functions with a few locals each, the loop reads and writes them often.
+fn large_source() -> String {
+    (0..500)
+        .map(|i| format!("
+fun function{i}(first, second) {{
+    var total = first;
+    for (var index = 0; index < second; index = index + 1) {{
+        var product = index * first;
+        total = total + product - second;
+    }}
+    return total;
+}}
+print function{i}(1, 2);"))
+        .collect()
+}
+
+/// Times running the compiled script and counts the allocations of the run, which
+/// don't grow with the instructions executed when the run loop doesn't copy them.
+fn measure_run(name: &str, source: &str) {
+    let runs = 5;
+    let mut best = Duration::MAX;
+    let mut allocations = 0;
+    for _ in 0..runs {
+        let function = compile(source.to_string(), false).expect("compile failed").main;
+        let mut vm = VM::new();
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let result = vm.run_function(function, false);
+        best = best.min(start.elapsed());
+        allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert!(matches!(result, InterpretResult::Ok), "{name} failed");
+    }
+
+    println!("{name:<8} best of {runs}: {best:?}, {allocations} allocations");
+}
+
+fn measure_compile() {
+    let source = large_source();
+    let runs = 5;
+    let mut best = Duration::MAX;
+    let mut allocations = 0;
+    for _ in 0..runs {
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        let start = Instant::now();
+        let result = compile(source.clone(), false);
+        best = best.min(start.elapsed());
+        allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        assert!(result.is_ok(), "compile failed");
+    }
+
+    println!("{:<8} best of {runs}: {best:?}, {allocations} allocations", "compile");
+}
+
+fn main() {
+    println!("size of Value: {} bytes", std::mem::size_of::<rlox::value::Value>());
+    measure("calls", CALLS);
+    measure("strings", STRINGS);
+    measure("arith", ARITHMETIC);
+    measure("branches", BRANCHES);
+    measure_run("globals", GLOBALS);
+    measure_compile();
+}
@@ -0,0 +1,19 @@
+//! Loads arbitrary `.loxer` files and runs the ones which pass the verifier,
+//! run with `cargo +nightly fuzz run bytecode`. Errors are fine, panics and hangs are not.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{bytecode, vm::VM};
+
+/// Enough for the loops of small programs, a file looping forever stops with an error.
+const INSTRUCTION_LIMIT: u64 = 100_000;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(function) = bytecode::decode(data) else {
+        return;
+    };
+
+    let mut vm = VM::new();
+    vm.set_instruction_limit(Some(INSTRUCTION_LIMIT));
+    let _ = vm.run_function(function, false);
+});
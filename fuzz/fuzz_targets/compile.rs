@@ -0,0 +1,20 @@
+//! Scans and compiles arbitrary input, run with `cargo +nightly fuzz run compile`.
+//! Errors are fine, panics are not.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    compiler::compile,
+    scanner::{Scanner, Token, TokenType},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+
+    let mut scanner = Scanner::new(source);
+    while !matches!(scanner.scan_token(), Ok(Token { token_type: TokenType::Eof, .. })) {}
+
+    let _ = compile(source.to_string(), false);
+});
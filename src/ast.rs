@@ -0,0 +1,273 @@
+use std::rc::Rc;
+
+use crate::scanner::Token;
+
+/// An identifier, the parser shares one allocation between all uses of a name,
+/// so the resolver compares names by pointer.
+pub type Name = Rc<str>;
+
+/// Where a variable lives, the parser leaves it global and the resolver sets the locals.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Slot {
+    #[default]
+    Global,
+    Local(usize),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Literal {
+    Number(f64),
+    String(String),
+    Bool(bool),
+    Nil,
+}
+
+/// The token of an expression is where its instructions point to,
+/// the operator for unary and binary expressions.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Literal {
+        value: Literal,
+        token: Token,
+    },
+    Variable {
+        name: Name,
+        token: Token,
+        slot: Slot,
+    },
+    Assign {
+        name: Name,
+        token: Token,
+        value: Box<Expr>,
+        slot: Slot,
+    },
+    Unary {
+        operator: Token,
+        right: Box<Expr>,
+    },
+    Binary {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    /// `and` and `or`, the right side is only evaluated if needed.
+    Logical {
+        left: Box<Expr>,
+        operator: Token,
+        right: Box<Expr>,
+    },
+    List {
+        elements: Vec<Expr>,
+        /// The opening bracket.
+        token: Token,
+    },
+    /// `object.name`, a call of it invokes the method.
+    Get {
+        object: Box<Expr>,
+        name: Name,
+        token: Token,
+    },
+    Call {
+        callee: Box<Expr>,
+        /// The closing parenthesis.
+        paren: Token,
+        arguments: Vec<Expr>,
+    },
+    Yield {
+        keyword: Token,
+        value: Option<Box<Expr>>,
+    },
+}
+
+impl Expr {
+    pub fn token(&self) -> &Token {
+        match self {
+            Expr::Literal { token, .. }
+            | Expr::Variable { token, .. }
+            | Expr::Assign { token, .. }
+            | Expr::List { token, .. }
+            | Expr::Get { token, .. } => token,
+            Expr::Unary { operator, .. }
+            | Expr::Binary { operator, .. }
+            | Expr::Logical { operator, .. } => operator,
+            Expr::Call { paren, .. } => paren,
+            Expr::Yield { keyword, .. } => keyword,
+        }
+    }
+}
+
+/// What a `match` arm compares the value with, names bind the part they match.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Pattern {
+    Literal {
+        value: Literal,
+        token: Token,
+    },
+    /// `_`, matches anything without binding it.
+    Wildcard(Token),
+    Binding {
+        name: Name,
+        token: Token,
+    },
+    /// Matches a list of the same length whose elements match.
+    List {
+        elements: Vec<Pattern>,
+        /// The opening bracket.
+        token: Token,
+    },
+}
+
+impl Pattern {
+    /// The names the pattern binds in the order of their slots, with the
+    /// list indices leading to the bound part of the value.
+    pub fn bindings(&self) -> Vec<(&Name, &Token, Vec<usize>)> {
+        let mut bindings = vec![];
+        self.collect_bindings(&mut vec![], &mut bindings);
+        bindings
+    }
+
+    fn collect_bindings<'a>(&'a self, path: &mut Vec<usize>, bindings: &mut Vec<(&'a Name, &'a Token, Vec<usize>)>) {
+        match self {
+            Pattern::Literal { .. } | Pattern::Wildcard(_) => (),
+            Pattern::Binding { name, token } => bindings.push((name, token, path.clone())),
+            Pattern::List { elements, .. } => {
+                for (index, element) in elements.iter().enumerate() {
+                    path.push(index);
+                    element.collect_bindings(path, bindings);
+                    path.pop();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MatchArm {
+    pub pattern: Pattern,
+    pub body: Stmt,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FunctionDecl {
+    pub name: Name,
+    pub token: Token,
+    /// Names and tokens, the last parameter is a rest parameter if the
+    /// function is variadic, collecting the extra arguments in a list.
+    pub params: Vec<(Name, Token)>,
+    pub variadic: bool,
+    pub body: Vec<Stmt>,
+    /// Set by the resolver if the body contains `yield`.
+    pub generator: bool,
+}
+
+/// Where a `break` or `continue` goes and what it leaves behind.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LoopTarget {
+    /// The number of loops between, 0 for the innermost one.
+    pub depth: usize,
+    /// The locals declared in the loop, which are popped.
+    pub locals: usize,
+    /// The `try` blocks in the loop, whose handlers are removed.
+    pub handlers: usize,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stmt {
+    Expression(Expr),
+    /// Also the trailing expression of a REPL input.
+    Print {
+        keyword: Token,
+        /// Printed on one line, separated by spaces.
+        expressions: Vec<Expr>,
+    },
+    Var {
+        name: Name,
+        token: Token,
+        initializer: Option<Expr>,
+        constant: bool,
+    },
+    Function(FunctionDecl),
+    Block {
+        statements: Vec<Stmt>,
+        /// The closing brace.
+        end: Token,
+    },
+    If {
+        keyword: Token,
+        condition: Expr,
+        then_branch: Box<Stmt>,
+        else_branch: Option<Box<Stmt>>,
+    },
+    /// The loops can have a label, which `break` and `continue` can name.
+    While {
+        keyword: Token,
+        condition: Expr,
+        body: Box<Stmt>,
+        label: Option<Name>,
+    },
+    For {
+        keyword: Token,
+        initializer: Option<Box<Stmt>>,
+        condition: Option<Expr>,
+        increment: Option<Expr>,
+        body: Box<Stmt>,
+        label: Option<Name>,
+    },
+    /// `for (var x in collection)`, the collection and the index are hidden locals.
+    ForIn {
+        keyword: Token,
+        name: Name,
+        token: Token,
+        collection: Expr,
+        body: Box<Stmt>,
+        /// Set by the resolver, the index follows in the next slot.
+        collection_slot: usize,
+        label: Option<Name>,
+    },
+    /// Leaves the innermost loop or the one with the label.
+    Break {
+        keyword: Token,
+        label: Option<(Name, Token)>,
+        /// Set by the resolver.
+        target: LoopTarget,
+    },
+    /// Goes on with the next iteration of the innermost loop or the one with the label.
+    Continue {
+        keyword: Token,
+        label: Option<(Name, Token)>,
+        /// Set by the resolver.
+        target: LoopTarget,
+    },
+    Return {
+        keyword: Token,
+        value: Option<Expr>,
+    },
+    Throw {
+        keyword: Token,
+        value: Expr,
+    },
+    /// The first arm whose pattern matches runs, the value is a hidden local.
+    Match {
+        keyword: Token,
+        value: Expr,
+        arms: Vec<MatchArm>,
+        /// Set by the resolver.
+        value_slot: usize,
+    },
+    /// The catch variable is in the same scope as the declarations of the handler.
+    Try {
+        keyword: Token,
+        body: Box<Stmt>,
+        name: Name,
+        token: Token,
+        handler: Vec<Stmt>,
+        end: Token,
+    },
+}
+
+impl Stmt {
+    /// Declares a variable in the enclosing scope.
+    pub fn is_declaration(&self) -> bool {
+        matches!(self, Stmt::Var { .. } | Stmt::Function(_))
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use crate::{bytecode, value::Function};
+
+/// Ends a bundled executable, after the payload and its length.
+const MARKER: &[u8; 8] = b"RLOXBNDL";
+
+/// Length of the payload as little endian `u64` and the marker.
+const TRAILER_LEN: u64 = 16;
+
+/// Writes a copy of the executable with the bytecode of the function appended, the copy runs it on start.
+pub fn write_bundle(executable: &Path, function: &Function, output: &Path) -> io::Result<()> {
+    let mut data = fs::read(executable)?;
+    // an executable which is a bundle itself only contributes its own code
+    if let Some(length) = payload_length(&data) {
+        data.truncate(data.len() - (length + TRAILER_LEN) as usize);
+    }
+
+    let payload = bytecode::encode(function);
+    data.extend_from_slice(&payload);
+    data.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    data.extend_from_slice(MARKER);
+    fs::write(output, data)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// The function bundled into the executable, `None` for a plain executable.
+/// Only the trailer and the payload are read.
+pub fn read_bundle(executable: &Path) -> io::Result<Option<Function>> {
+    let mut file = File::open(executable)?;
+    let size = file.metadata()?.len();
+    if size < TRAILER_LEN {
+        return Ok(None);
+    }
+
+    let mut trailer = [0; TRAILER_LEN as usize];
+    file.seek(SeekFrom::Start(size - TRAILER_LEN))?;
+    file.read_exact(&mut trailer)?;
+    let Some(length) = payload_length(&trailer) else {
+        return Ok(None);
+    };
+    if length > size - TRAILER_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bundle payload is longer than the file"));
+    }
+
+    let mut payload = vec![0; length as usize];
+    file.seek(SeekFrom::Start(size - TRAILER_LEN - length))?;
+    file.read_exact(&mut payload)?;
+    bytecode::decode(&payload)
+        .map(Some)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+fn payload_length(data: &[u8]) -> Option<u64> {
+    let trailer = data.len().checked_sub(TRAILER_LEN as usize).map(|start| &data[start..])?;
+    let (length, marker) = trailer.split_at(8);
+    (marker == MARKER).then(|| u64::from_le_bytes(length.try_into().expect("8 bytes")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use crate::compiler::compile;
+
+    use super::*;
+
+    #[test]
+    fn test_bundle_round_trip() {
+        let directory = env::temp_dir().join(format!("rlox-bundle-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let executable = directory.join("rlox");
+        fs::write(&executable, b"not really an executable").unwrap();
+        assert!(read_bundle(&executable).unwrap().is_none());
+
+        let first = compile("print 1;".to_string(), false).unwrap().main;
+        let second = compile("print 2;".to_string(), false).unwrap().main;
+        let app = directory.join("app");
+        let rebundled = directory.join("app2");
+        write_bundle(&executable, &first, &app).unwrap();
+        write_bundle(&app, &second, &rebundled).unwrap();
+
+        assert_eq!(read_bundle(&app).unwrap(), Some(first));
+        assert_eq!(read_bundle(&rebundled).unwrap(), Some(second));
+        assert_eq!(fs::metadata(&app).unwrap().len(), fs::metadata(&rebundled).unwrap().len());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use bson::{doc, Bson, Document};
+use serde::Serialize;
+
+use crate::{op_code::{OpCode, Span}, value::Function};
+
+/// Version of the bytecode in `.loxer` files, raised when the format changes, e.g. with a
+/// new instruction, so an older rlox rejects the files instead of misreading them.
+/// Files without a header are version 0, their functions were inlined in `OpCode::Function`.
+/// Up to version 1 the spans counted chars instead of bytes.
+/// Version 3 added `GreaterEqual`, `LessEqual` and `StrictEqual`, version 4 `GetNative`,
+/// version 5 `DefineConstant` and version 6 `PrintLine`.
+pub const BYTECODE_VERSION: i32 = 6;
+
+/// Files from this version on are read as they are, older ones need `upgrade`.
+const OLDEST_READABLE_VERSION: i32 = 2;
+
+#[derive(Serialize)]
+struct Header<'a> {
+    version: i32,
+    function: &'a Function,
+}
+
+/// The function as bson with the version header.
+pub fn encode(function: &Function) -> Vec<u8> {
+    bson::to_vec(&Header { version: BYTECODE_VERSION, function }).expect("Serialize to bson failed.")
+}
+
+/// Reads a file of the current version or one that only lacks newer instructions,
+/// older ones need `upgrade`.
+pub fn decode(data: &[u8]) -> Result<Function, String> {
+    let document = read_document(data)?;
+    match version(&document) {
+        OLDEST_READABLE_VERSION..=BYTECODE_VERSION => verify_script(function_from(document)?),
+        version if version > BYTECODE_VERSION => Err(format!(
+            "Bytecode version {version} is newer than this rlox ({BYTECODE_VERSION}), recompile from source."
+        )),
+        version => Err(format!(
+            "Bytecode version {version} is outdated, run `rlox upgrade` on the file or recompile from source."
+        )),
+    }
+}
+
+/// Reads a file of this or an older version, converting it to the current one.
+pub fn upgrade(data: &[u8]) -> Result<Function, String> {
+    let document = read_document(data)?;
+    match version(&document) {
+        0 => {
+            let function = inline_functions_to_table(document)
+                .map_err(|err| format!("Can't upgrade the bytecode, {err}, recompile from source."))?;
+            let function = bson::from_document(function)
+                .map_err(|err| format!("Can't upgrade the bytecode, {err}, recompile from source."))?;
+            verify_script(char_spans_to_bytes(function))
+        }
+        1 => verify_script(char_spans_to_bytes(function_from(document)?)),
+        _ => decode(data),
+    }
+}
+
+fn read_document(data: &[u8]) -> Result<Document, String> {
+    Document::from_reader(data).map_err(|err| format!("Not a compiled lox program: {err}."))
+}
+
+fn version(document: &Document) -> i32 {
+    document.get_i32("version").unwrap_or(0)
+}
+
+fn function_from(mut document: Document) -> Result<Function, String> {
+    let function = document.remove("function")
+        .ok_or_else(|| "The bytecode has no function.".to_string())?;
+    bson::from_bson(function).map_err(|err| format!("Invalid bytecode: {err}, recompile from source."))
+}
+
+/// Converts the spans of version 1 and older with the source, without it they are dropped.
+fn char_spans_to_bytes(mut function: Function) -> Function {
+    let Some(source) = function.source() else {
+        function.strip_source_map();
+        return function;
+    };
+    // the byte offset of every char and of the end
+    let offsets: Vec<usize> = source.char_indices().map(|(offset, _)| offset).chain([source.len()]).collect();
+    function.map_spans(&|span| {
+        let start = *offsets.get(span.start)?;
+        let end = *offsets.get(span.start.checked_add(span.length)?)?;
+        Some(Span { start, length: end - start })
+    });
+    function
+}
+
+/// The script is called without arguments, it can't take parameters or yield.
+fn verify_script(function: Function) -> Result<Function, String> {
+    if function.parameter_count() > 0 || function.is_generator() {
+        return Err("Invalid bytecode: the script has parameters or yields, recompile from source.".to_string());
+    }
+    verify(&function).map_err(|err| format!("Invalid bytecode: {err}, recompile from source."))?;
+    Ok(function)
+}
+
+/// Checks what the VM relies on without checking it at runtime: every path through a chunk
+/// ends in a `Return` or `Throw`, jumps and function indices are in range, the stack
+/// holds the values an instruction pops and the locals it addresses, and the stack
+/// reserved for a call is not larger than the deepest path needs.
+pub fn verify(function: &Function) -> Result<(), String> {
+    let count = function.instruction_count();
+    let mut depths: Vec<Option<usize>> = vec![None; count];
+    let mut pending = vec![(0, function.parameter_count())];
+    let mut max_depth = function.parameter_count();
+
+    while let Some((index, depth)) = pending.pop() {
+        if index >= count {
+            return Err(format!("'{}' runs past its last instruction", function.name()));
+        }
+        match depths[index] {
+            Some(known) if known == depth => continue,
+            Some(known) => {
+                return Err(format!("instruction {index} of '{}' is reached with {known} and {depth} values", function.name()));
+            }
+            None => depths[index] = Some(depth),
+        }
+
+        let code = &function.read_instruction(index).code;
+        let needed = match code {
+            OpCode::GetLocal(slot)
+            | OpCode::GetLocalWide(slot)
+            | OpCode::GetLocalAddConstant(slot, _)
+            | OpCode::SetLocal(slot)
+            | OpCode::SetLocalWide(slot) => slot.saturating_add(1),
+            // the collection and the index
+            OpCode::IterNext(slot) => slot.saturating_add(2),
+            OpCode::Function(function_index) if *function_index >= function.functions().len() => {
+                return Err(format!("instruction {index} of '{}' refers to a missing function", function.name()));
+            }
+            code => pops(code),
+        };
+        if depth < needed {
+            return Err(format!("instruction {index} of '{}' needs {needed} values on the stack", function.name()));
+        }
+
+        let after = depth.saturating_add_signed(code.stack_effect());
+        // a fused call pushes the constant, `PrintLine` the native, a handler the thrown value
+        let peak = match code {
+            OpCode::ConstantCall(..) | OpCode::PrintLine(_) | OpCode::PushHandler(_) => depth + 1,
+            _ => after.max(depth),
+        };
+        max_depth = max_depth.max(peak);
+        let next = index + 1;
+        let target = |offset: usize| next.checked_add(offset).ok_or_else(|| format!("jump out of '{}'", function.name()));
+        match code {
+            OpCode::Return | OpCode::Throw => (),
+            OpCode::Jump(offset) => pending.push((target(*offset)?, after)),
+            OpCode::Loop(offset) => {
+                let start = next.checked_sub(*offset).ok_or_else(|| format!("loop out of '{}'", function.name()))?;
+                pending.push((start, after));
+            }
+            // the catch block starts with the thrown value
+            OpCode::PushHandler(offset) => {
+                pending.push((target(*offset)?, depth + 1));
+                pending.push((next, after));
+            }
+            code => {
+                if let Some(offset) = code.jump_offset() {
+                    pending.push((target(offset)?, after));
+                }
+                pending.push((next, after));
+            }
+        }
+    }
+
+    if function.max_stack() > max_depth {
+        return Err(format!("'{}' reserves more stack than it uses", function.name()));
+    }
+    function.functions().iter().try_for_each(|nested| verify(nested))
+}
+
+/// The values an instruction pops, the locals it reads are checked separately.
+fn pops(code: &OpCode) -> usize {
+    match code {
+        OpCode::Equal
+        | OpCode::StrictEqual
+        | OpCode::Greater
+        | OpCode::Less
+        | OpCode::GreaterEqual
+        | OpCode::LessEqual
+        | OpCode::Add
+        | OpCode::Subtract
+        | OpCode::Multiply
+        | OpCode::Divide
+        | OpCode::Range
+        | OpCode::CompareLessJumpIfFalse(_) => 2,
+        OpCode::Pop
+        | OpCode::DefineGlobal(_)
+        | OpCode::DefineConstant(_)
+        | OpCode::SetGlobal(_)
+        | OpCode::Not
+        | OpCode::Negate
+        | OpCode::Print
+        | OpCode::JumpIfFalse(_)
+        | OpCode::JumpIfTrue(_)
+        | OpCode::JumpIfFalsePop(_)
+        | OpCode::JumpIfTruePop(_)
+        | OpCode::GetProperty(_)
+        | OpCode::IsList(_)
+        | OpCode::Index(_)
+        | OpCode::Throw
+        | OpCode::Yield
+        | OpCode::Return => 1,
+        OpCode::PopN(count) | OpCode::List(count) => *count,
+        // the callee and the arguments, a fused call pushes its last argument
+        OpCode::Call(arg_count) | OpCode::Invoke(_, arg_count) => arg_count.saturating_add(1),
+        OpCode::ConstantCall(_, arg_count) | OpCode::PrintLine(arg_count) => *arg_count,
+        _ => 0,
+    }
+}
+
+/// Moves the functions of version 0 from `Function` instructions into the
+/// function table of their chunk, the instruction keeps the index.
+fn inline_functions_to_table(mut function: Document) -> Result<Document, String> {
+    let chunk = function.get_document_mut("chunk").map_err(|err| err.to_string())?;
+    let mut table = vec![];
+    let instructions = chunk.get_array_mut("instructions").map_err(|err| err.to_string())?;
+    for instruction in instructions {
+        let Bson::Document(instruction) = instruction else {
+            return Err("an instruction is not a document".to_string());
+        };
+        if let Ok(code) = instruction.get_document_mut("code")
+            && let Some(Bson::Document(nested)) = code.get("Function")
+        {
+            table.push(Bson::Document(inline_functions_to_table(nested.clone())?));
+            *code = doc! { "Function": (table.len() - 1) as i64 };
+        }
+    }
+    chunk.insert("functions", table);
+    Ok(function)
+}
+
+/// Where the program compiled from `source` is written: `output` or the source with the
+/// extension `loxer`, inside `out_dir` if there is one. Never the source itself.
+pub fn output_path(source: &Path, output: Option<&Path>, out_dir: Option<&Path>) -> Result<PathBuf, String> {
+    let path = match output {
+        Some(output) => output.to_path_buf(),
+        None if out_dir.is_some() => source.with_extension("loxer").file_name()
+            .map(PathBuf::from)
+            .ok_or_else(|| format!("{} is not a file.", source.display()))?,
+        None => source.with_extension("loxer"),
+    };
+    let path = match out_dir {
+        Some(out_dir) => out_dir.join(path),
+        None => path,
+    };
+
+    let same_file = match (fs::canonicalize(&path), fs::canonicalize(source)) {
+        (Ok(path), Ok(source)) => path == source,
+        _ => path == source,
+    };
+    if same_file {
+        return Err(format!("Refusing to overwrite the source {} with the compiled program.", source.display()));
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::{chunk::Chunk, compiler::compile, value::Value, vm::{InterpretResult, VM}};
+
+    use super::*;
+
+    /// The reverse of the upgrade, how version 0 wrote the function.
+    fn version_0(function: &Function) -> Document {
+        let mut document = bson::to_document(function).unwrap();
+        let chunk = document.get_document_mut("chunk").unwrap();
+        let functions = chunk.remove("functions").unwrap();
+        let Bson::Array(functions) = functions else { panic!() };
+        for instruction in chunk.get_array_mut("instructions").unwrap() {
+            let Bson::Document(instruction) = instruction else { panic!() };
+            let code = instruction.get_document_mut("code");
+            if let Ok(code) = code
+                && let Some(index) = code.get("Function")
+            {
+                let index = index.as_i64().or(index.as_i32().map(i64::from)).unwrap() as usize;
+                let nested: Function = bson::from_bson(functions[index].clone()).unwrap();
+                *code = doc! { "Function": version_0(&nested) };
+            }
+        }
+        document
+    }
+
+    #[test]
+    fn test_encode_and_decode() {
+        let function = compile("fun f() { return 1; } print f() === 1;".to_string(), false).unwrap().main;
+        assert_eq!(decode(&encode(&function)), Ok(function.clone()));
+
+        let newer = bson::to_vec(&doc! { "version": BYTECODE_VERSION + 1 }).unwrap();
+        assert!(decode(&newer).unwrap_err().contains("recompile from source"));
+        assert!(decode(b"nonsense").is_err());
+    }
+
+    #[test]
+    fn test_upgrade_version_0() {
+        let function = compile("fun f() { fun g() { return 2; } return g; } print f()();".to_string(), false).unwrap().main;
+        let mut old = vec![];
+        version_0(&function).to_writer(&mut old).unwrap();
+
+        assert!(decode(&old).unwrap_err().contains("rlox upgrade"));
+        assert_eq!(upgrade(&old), Ok(function.clone()));
+        assert_eq!(upgrade(&encode(&function)), Ok(function));
+
+        let mut broken = vec![];
+        doc! { "chunk": { "instructions": [{ "code": "Unknown", "line": 1 }] } }.to_writer(&mut broken).unwrap();
+        assert!(upgrade(&broken).unwrap_err().contains("recompile from source"));
+    }
+
+    #[test]
+    fn test_upgrade_version_1() {
+        let function = compile("var s = \"ünïcödé\"; fun f() { return -s; } f();".to_string(), false).unwrap().main;
+        // how version 1 wrote the spans, in chars
+        let mut old = function.clone();
+        let source = function.source().unwrap();
+        old.map_spans(&|span| Some(Span {
+            start: source[..span.start].chars().count(),
+            length: source[span.start..span.start + span.length].chars().count(),
+        }));
+        let old_file = |old: &Function| bson::to_vec(&doc! { "version": 1, "function": bson::to_bson(old).unwrap() }).unwrap();
+
+        assert!(decode(&old_file(&old)).unwrap_err().contains("rlox upgrade"));
+        assert_eq!(upgrade(&old_file(&old)), Ok(function));
+    }
+
+    #[test]
+    fn test_read_version_2() {
+        let function = compile("var a = 1 < 2; print a;".to_string(), false).unwrap().main;
+        let old = bson::to_vec(&doc! { "version": 2, "function": bson::to_bson(&function).unwrap() }).unwrap();
+        assert_eq!(decode(&old), Ok(function.clone()));
+        assert_eq!(upgrade(&old), Ok(function));
+    }
+
+    #[test]
+    fn test_verify() {
+        let chunk = |codes: Vec<OpCode>| {
+            let mut chunk = Chunk::new();
+            for code in codes {
+                chunk.write(code, 1);
+            }
+            Function::new_from_chunk("script".to_string(), chunk)
+        };
+
+        assert_eq!(verify(&chunk(vec![OpCode::Nil, OpCode::Return])), Ok(()));
+        assert!(verify(&chunk(vec![])).unwrap_err().contains("past its last instruction"));
+        assert!(verify(&chunk(vec![OpCode::Nil, OpCode::Pop])).unwrap_err().contains("past its last instruction"));
+        assert!(verify(&chunk(vec![OpCode::Nil, OpCode::Add, OpCode::Return])).unwrap_err().contains("needs 2 values"));
+        assert!(verify(&chunk(vec![OpCode::GetLocal(3), OpCode::Return])).is_err());
+        assert!(verify(&chunk(vec![OpCode::Jump(usize::MAX), OpCode::Return])).is_err());
+        assert!(verify(&chunk(vec![OpCode::Loop(5), OpCode::Return])).is_err());
+        assert!(verify(&chunk(vec![OpCode::Function(0), OpCode::Return])).unwrap_err().contains("missing function"));
+        let merge = vec![OpCode::Bool(true), OpCode::JumpIfFalse(1), OpCode::Nil, OpCode::Return];
+        assert!(verify(&chunk(merge)).unwrap_err().contains("reached with"));
+
+        let script = encode(&chunk(vec![OpCode::Pop, OpCode::Nil, OpCode::Return]));
+        assert!(decode(&script).unwrap_err().contains("Invalid bytecode"));
+    }
+
+    #[test]
+    fn test_output_path() {
+        let path = |source: &str, output: Option<&str>, out_dir: Option<&str>| {
+            output_path(Path::new(source), output.map(Path::new), out_dir.map(Path::new))
+        };
+        assert_eq!(path("src/a.lox", None, None), Ok(PathBuf::from("src/a.loxer")));
+        assert_eq!(path("src/lox/a", None, None), Ok(PathBuf::from("src/lox/a.loxer")));
+        assert_eq!(path("src/a.lox", None, Some("build")), Ok(PathBuf::from("build/a.loxer")));
+        assert_eq!(path("src/a.lox", Some("b.out"), None), Ok(PathBuf::from("b.out")));
+        assert_eq!(path("src/a.lox", Some("b.out"), Some("build")), Ok(PathBuf::from("build/b.out")));
+        assert!(path("src/a.lox", Some("src/a.lox"), None).is_err());
+        assert!(path("a.loxer", None, None).is_err());
+    }
+
+    /// Small programs which assign numbers and bools to the globals, the loops are bounded.
+    mod programs {
+        use proptest::{prelude::*, sample::select};
+
+        fn expression() -> impl Strategy<Value = String> {
+            let leaf = prop_oneof![
+                (0..100u32).prop_map(|number| number.to_string()),
+                select(vec!["a", "b", "c", "true", "false", "nil"]).prop_map(str::to_string),
+            ];
+            leaf.prop_recursive(3, 16, 2, |inner| {
+                prop_oneof![
+                    (inner.clone(), select(vec!["+", "-", "*", "<", ">", "==", "!=", "and", "or"]), inner.clone())
+                        .prop_map(|(left, operator, right)| format!("({left} {operator} {right})")),
+                    inner.clone().prop_map(|operand| format!("(-{operand})")),
+                    inner.clone().prop_map(|operand| format!("(!{operand})")),
+                    inner.prop_map(|argument| format!("f({argument})")),
+                ]
+            })
+        }
+
+        fn statement() -> impl Strategy<Value = String> {
+            let leaf = (select(vec!["a", "b", "c"]), expression())
+                .prop_map(|(name, value)| format!("{name} = {value};"));
+            leaf.prop_recursive(3, 24, 4, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..4)
+                        .prop_map(|statements| format!("{{ {} }}", statements.join(" "))),
+                    (expression(), inner.clone(), inner.clone())
+                        .prop_map(|(condition, then, other)| format!("if ({condition}) {then} else {other}")),
+                    (0..4u32, inner.clone())
+                        .prop_map(|(count, body)| format!("for (var i = 0; i < {count}; i = i + 1) {body}")),
+                    (expression(), inner.clone())
+                        .prop_map(|(value, body)| format!("{{ var local = {value}; {body} a = local; }}")),
+                    (inner.clone(), inner)
+                        .prop_map(|(body, handler)| format!("try {{ {body} }} catch (error) {{ {handler} }}")),
+                ]
+            })
+        }
+
+        pub(super) fn program() -> impl Strategy<Value = String> {
+            prop::collection::vec(statement(), 1..6).prop_map(|statements| {
+                format!("var a = 1; var b = 2; var c = 3; fun f(x) {{ return x; }} {}", statements.join(" "))
+            })
+        }
+    }
+
+    fn run(function: Function) -> (Result<(), String>, Vec<(String, String)>) {
+        let mut vm = VM::new();
+        let result = match vm.run_function(function, false) {
+            InterpretResult::Ok => Ok(()),
+            other => Err(format!("{other:?}")),
+        };
+        // functions are equal by identity, their code is compared
+        let globals = vm.globals().into_iter().map(|(name, value)| match value {
+            Value::Function(function) => (name.to_string(), format!("{function:?}")),
+            value => (name.to_string(), format!("{value:?}")),
+        }).collect();
+        (result, globals)
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(source in programs::program()) {
+            let function = compile(source, false).unwrap().main;
+            prop_assert_eq!(verify(&function), Ok(()));
+            let decoded = decode(&encode(&function)).unwrap();
+            prop_assert_eq!(run(decoded), run(function));
+        }
+    }
+}
@@ -1,67 +1,346 @@
+use std::{fmt::Write, sync::Arc};
+
 use serde::{Serialize, Deserialize};
 
-use crate::op_code::{Instruction, OpCode};
+use crate::{op_code::{Instruction, OpCode, Span}, peephole, value::Function};
 
 pub trait OpCodeVisitor {
     fn operate(&mut self, code: &OpCode, line: i32);
 }
 
-#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+/// Offset of a jump at index `from` to the later index `to`.
+pub fn forward_offset(from: usize, to: usize) -> usize {
+    to - from - 1
+}
+
+/// Offset of a `Loop` at index `from` back to the index `to`.
+pub fn backward_offset(from: usize, to: usize) -> usize {
+    from + 1 - to
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct Chunk {
     instructions: Vec<Instruction>,
+    /// The functions declared in this chunk, `OpCode::Function` refers to them by index.
+    #[serde(default)]
+    functions: Vec<Arc<Function>>,
 }
 
 impl Chunk {
     pub fn new() -> Self {
-        Self {
-            instructions: vec![],
-        }
+        Self::default()
     }
 
     pub fn write(&mut self, code: OpCode, line: i32) {
-        self.instructions.push(Instruction { code, line });
+        self.instructions.push(Instruction { code, line, span: None });
+    }
+
+    pub fn write_span(&mut self, code: OpCode, line: i32, span: Span) {
+        self.instructions.push(Instruction { code, line, span: Some(span) });
     }
 
     pub fn operate_on_codes(&self, op: &mut dyn OpCodeVisitor) {
-        for Instruction { code, line } in &self.instructions {
+        for Instruction { code, line, .. } in &self.instructions {
             op.operate(code, *line);
         }
     }
 
+    /// Remove the spans, also from the functions defined in this chunk.
+    pub fn strip_spans(&mut self) {
+        for instruction in &mut self.instructions {
+            instruction.span = None;
+        }
+        for function in &mut self.functions {
+            Arc::make_mut(function).strip_source_map();
+        }
+    }
+
+    /// Replaces the spans, also in the functions defined in this chunk, `None` drops a span.
+    pub fn map_spans(&mut self, map: &dyn Fn(Span) -> Option<Span>) {
+        for instruction in &mut self.instructions {
+            instruction.span = instruction.span.and_then(map);
+        }
+        for function in &mut self.functions {
+            Arc::make_mut(function).map_spans(map);
+        }
+    }
+
+    /// Adds the function to the table, returns the index for `OpCode::Function`.
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.functions.push(Arc::new(function));
+        self.functions.len() - 1
+    }
+
+    pub fn functions(&self) -> &[Arc<Function>] {
+        &self.functions
+    }
+
     pub fn read_instruction(&self, ip: usize) -> &Instruction {
         &self.instructions[ip]
     }
 
-    pub fn emit_jump(&mut self, code: OpCode, line: i32) -> usize {
-        self.write(code, line);
-        self.current_offset()
+    /// Peephole pass over the jumps, see `peephole::optimize`.
+    pub fn optimize(&mut self) {
+        peephole::optimize(&mut self.instructions);
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.instructions.len()
     }
 
-    pub fn emit_loop(&mut self, offset: usize, line: i32) {
-        self.write(OpCode::Loop(self.current_offset() - offset + 1), line);
+    /// The highest stack depth any path through the chunk reaches, starting with
+    /// `base` values. A handler starts with the depth at its `PushHandler` and the thrown value.
+    pub fn max_stack_depth(&self, base: usize) -> usize {
+        let mut depths: Vec<Option<usize>> = vec![None; self.instructions.len()];
+        let mut pending = vec![(0, base)];
+        let mut max = base;
+
+        while let Some((index, depth)) = pending.pop() {
+            if index >= self.instructions.len() || depths[index].is_some() {
+                continue;
+            }
+            depths[index] = Some(depth);
+
+            let code = &self.instructions[index].code;
+            let after = depth.saturating_add_signed(code.stack_effect());
+            // a fused call pushes the constant before the call pops the arguments,
+            // `PrintLine` the native
+            let peak = match code {
+                OpCode::ConstantCall(..) | OpCode::PrintLine(_) => depth + 1,
+                _ => after.max(depth),
+            };
+            max = max.max(peak);
+
+            match code {
+                OpCode::Return | OpCode::Throw => (),
+                OpCode::Jump(offset) => pending.push((index + 1 + offset, after)),
+                OpCode::Loop(offset) => pending.push((index + 1 - offset, after)),
+                OpCode::PushHandler(offset) => {
+                    pending.push((index + 1 + offset, depth + 1));
+                    max = max.max(depth + 1);
+                    pending.push((index + 1, after));
+                }
+                code => {
+                    if let Some(offset) = code.jump_offset() {
+                        pending.push((index + 1 + offset, after));
+                    }
+                    pending.push((index + 1, after));
+                }
+            }
+        }
+
+        max
+    }
+
+    /// One instruction per line with its index, then the declared functions, each
+    /// under its signature. Lines and spans are left out, so only changes of the code show.
+    pub fn to_test_string(&self) -> String {
+        let mut text = String::new();
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            writeln!(text, "{index:>4} {:?}", instruction.code).unwrap();
+        }
+        for function in &self.functions {
+            write!(text, "\n== {} ==\n{}", function.signature(), function.to_test_string()).unwrap();
+        }
+        text
+    }
+
+    pub fn codes_from(&self, start: usize) -> Vec<OpCode> {
+        self.instructions[start..]
+            .iter()
+            .map(|instruction| instruction.code.clone())
+            .collect()
+    }
+
+    pub fn last_code(&self) -> Option<&OpCode> {
+        self.instructions.last().map(|instruction| &instruction.code)
+    }
+}
+
+/// A position in a chunk that jumps can target before it is known.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Label(usize);
+
+/// Builds a chunk with jumps to labels instead of offsets.
+/// Forward jumps are patched when their label is bound, a jump to a
+/// label bound before becomes a `Loop`.
+#[derive(Debug, Default)]
+pub struct ChunkBuilder {
+    chunk: Chunk,
+    /// Instruction index of each label once bound.
+    labels: Vec<Option<usize>>,
+    /// Jumps waiting for their label, as (instruction index, label).
+    unresolved: Vec<(usize, Label)>,
+}
+
+impl ChunkBuilder {
+    pub fn new() -> Self {
+        Self::default()
     }
 
-    pub fn patch_jump(&mut self, offset: usize) {
-        let pos = self.instructions.len() - 1 - offset;
-        let new_instruction = match self.instructions.get(offset) {
-            Some(Instruction { code, line }) => match code {
-                OpCode::JumpIfFalse(_) => Instruction {
-                    code: OpCode::JumpIfFalse(pos),
-                    line: *line,
-                },
-                OpCode::Jump(_) => Instruction {
-                    code: OpCode::Jump(pos),
-                    line: *line,
-                },
-                other => panic!("Wrong jump patch {:?}", other),
-            },
-            None => panic!("Invalid jump offset"),
-        };
+    pub fn write(&mut self, code: OpCode, line: i32) {
+        self.chunk.write(code, line);
+    }
+
+    pub fn write_span(&mut self, code: OpCode, line: i32, span: Span) {
+        self.chunk.write_span(code, line, span);
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.chunk.instruction_count()
+    }
+
+    pub fn codes_from(&self, start: usize) -> Vec<OpCode> {
+        self.chunk.codes_from(start)
+    }
+
+    pub fn last_code(&self) -> Option<&OpCode> {
+        self.chunk.last_code()
+    }
+
+    pub fn add_function(&mut self, function: Function) -> usize {
+        self.chunk.add_function(function)
+    }
+
+    pub fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    /// Binds the label to the next instruction written and patches the jumps to it.
+    pub fn bind(&mut self, label: Label) {
+        assert!(self.labels[label.0].is_none(), "label {} is bound twice", label.0);
+        let target = self.chunk.instruction_count();
+        self.labels[label.0] = Some(target);
+
+        let instructions = &mut self.chunk.instructions;
+        self.unresolved.retain(|&(jump, pending)| {
+            if pending != label {
+                return true;
+            }
+            instructions[jump].code.set_jump_offset(forward_offset(jump, target));
+            false
+        });
+    }
+
+    /// Unconditional jump, backwards it is a `Loop`.
+    pub fn jump(&mut self, label: Label, line: i32) {
+        match self.labels[label.0] {
+            Some(target) => {
+                let offset = backward_offset(self.chunk.instruction_count(), target);
+                self.chunk.write(OpCode::Loop(offset), line);
+            }
+            None => self.forward_jump(OpCode::Jump(0), label, line),
+        }
+    }
+
+    pub fn jump_if_false(&mut self, label: Label, line: i32) {
+        self.forward_jump(OpCode::JumpIfFalse(0), label, line);
+    }
+
+    pub fn jump_if_false_pop(&mut self, label: Label, line: i32) {
+        self.forward_jump(OpCode::JumpIfFalsePop(0), label, line);
+    }
+
+    /// The handler of a `try` starts at the label.
+    pub fn push_handler(&mut self, label: Label, line: i32) {
+        self.forward_jump(OpCode::PushHandler(0), label, line);
+    }
+
+    fn forward_jump(&mut self, code: OpCode, label: Label, line: i32) {
+        assert!(
+            self.labels[label.0].is_none(),
+            "{:?} can only jump forward, label {} is already bound", code, label.0
+        );
+        self.unresolved.push((self.chunk.instruction_count(), label));
+        self.chunk.write(code, line);
+    }
+
+    pub fn finish(self) -> Chunk {
+        if let Some((jump, label)) = self.unresolved.first() {
+            panic!("jump at {} to label {} which is never bound", jump, label.0);
+        }
+        self.chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jumps() {
+        let mut builder = ChunkBuilder::new();
+        let start = builder.new_label();
+        let exit = builder.new_label();
+        builder.bind(start);
+        builder.jump_if_false_pop(exit, 1);
+        builder.jump_if_false(exit, 1);
+        builder.write(OpCode::Nil, 1);
+        builder.jump(start, 1);
+        builder.bind(exit);
+        builder.write(OpCode::Return, 1);
+
+        assert_eq!(
+            builder.finish().codes_from(0),
+            vec![
+                OpCode::JumpIfFalsePop(3),
+                OpCode::JumpIfFalse(2),
+                OpCode::Nil,
+                OpCode::Loop(4),
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_max_stack_depth() {
+        let mut builder = ChunkBuilder::new();
+        let handler = builder.new_label();
+        let end = builder.new_label();
+        builder.push_handler(handler, 1);
+        builder.write(OpCode::Nil, 1);
+        builder.write(OpCode::Throw, 1);
+        builder.bind(handler);
+        builder.write(OpCode::Constant(1.0), 1);
+        builder.write(OpCode::Constant(2.0), 1);
+        builder.write(OpCode::Add, 1);
+        builder.jump_if_false_pop(end, 1);
+        builder.write(OpCode::GetLocal(0), 1);
+        builder.write(OpCode::Print, 1);
+        builder.bind(end);
+        builder.write(OpCode::Return, 1);
+        let chunk = builder.finish();
+
+        // the thrown value and the two constants on top of the local
+        assert_eq!(chunk.max_stack_depth(1), 4);
+        assert_eq!(chunk.max_stack_depth(0), 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "never bound")]
+    fn test_unbound_label() {
+        let mut builder = ChunkBuilder::new();
+        let label = builder.new_label();
+        builder.jump(label, 1);
+        builder.finish();
+    }
 
-        self.instructions[offset] = new_instruction;
+    #[test]
+    #[should_panic(expected = "can only jump forward")]
+    fn test_conditional_backward_jump() {
+        let mut builder = ChunkBuilder::new();
+        let label = builder.new_label();
+        builder.bind(label);
+        builder.jump_if_false(label, 1);
     }
 
-    pub fn current_offset(&self) -> usize {
-        self.instructions.len() - 1
+    #[test]
+    #[should_panic(expected = "bound twice")]
+    fn test_bind_twice() {
+        let mut builder = ChunkBuilder::new();
+        let label = builder.new_label();
+        builder.bind(label);
+        builder.bind(label);
     }
 }
@@ -0,0 +1,80 @@
+use std::{sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
+
+/// The time source of `clock()`, set with `VM::set_clock`.
+pub trait Clock: Send + Sync {
+    /// Seconds since an arbitrary start, only differences are meaningful.
+    fn now(&mut self) -> f64;
+}
+
+/// The wall time in seconds since the unix epoch, the default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&mut self) -> f64 {
+        SystemTime::now().duration_since(UNIX_EPOCH)
+            .expect("time before unix?")
+            .as_secs_f64()
+    }
+}
+
+/// Time which only passes when it is advanced, clones share the time,
+/// so a test can keep one to advance the clock of the VM.
+#[derive(Clone, Debug, Default)]
+pub struct VirtualClock {
+    time: Arc<Mutex<f64>>,
+    /// Advanced before each reading.
+    step: f64,
+}
+
+impl VirtualClock {
+    /// Starts at 0 and stands still until advanced.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advances by `step` on each reading, the readings are 1 step, 2 steps and so on.
+    pub fn ticking(step: f64) -> Self {
+        Self { step, ..Self::default() }
+    }
+
+    pub fn advance(&self, seconds: f64) {
+        *self.time.lock().expect("clock lock poisoned") += seconds;
+    }
+
+    pub fn time(&self) -> f64 {
+        *self.time.lock().expect("clock lock poisoned")
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&mut self) -> f64 {
+        let mut time = self.time.lock().expect("clock lock poisoned");
+        *time += self.step;
+        *time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{value::Value, vm::VM};
+
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let mut vm = VM::new();
+        vm.set_clock(clock.clone());
+        vm.interpret("var a = clock();".to_string(), false);
+        clock.advance(2.5);
+        vm.interpret("var b = clock() - a;".to_string(), false);
+        let globals = vm.globals();
+        assert!(globals.contains(&("a", &Value::Number(0.0))));
+        assert!(globals.contains(&("b", &Value::Number(2.5))));
+
+        let mut ticking = VirtualClock::ticking(0.5);
+        assert_eq!((ticking.now(), ticking.now()), (0.5, 1.0));
+        assert_eq!(ticking.time(), 1.0);
+    }
+}
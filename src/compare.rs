@@ -0,0 +1,61 @@
+use std::{io, path::Path, process::Command};
+
+/// What a run of a Lox implementation printed and how it exited.
+#[derive(Debug, PartialEq)]
+pub struct Output {
+    pub stdout: String,
+    pub status: Option<i32>,
+}
+
+/// Runs `interpreter script` and collects its output.
+pub fn run(interpreter: &Path, script: &Path) -> io::Result<Output> {
+    let output = Command::new(interpreter).arg(script).output()?;
+    Ok(Output {
+        stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+        status: output.status.code(),
+    })
+}
+
+/// The differences of the output to the one of the reference, one message per
+/// differing line, empty if they match.
+pub fn differences(reference: &Output, output: &Output) -> Vec<String> {
+    let expected: Vec<_> = reference.stdout.lines().collect();
+    let actual: Vec<_> = output.stdout.lines().collect();
+    let mut differences = vec![];
+    for line in 0..expected.len().max(actual.len()) {
+        match (expected.get(line), actual.get(line)) {
+            (Some(expected), Some(actual)) if expected == actual => (),
+            (Some(expected), Some(actual)) => {
+                differences.push(format!("line {}: expected `{expected}`, got `{actual}`", line + 1));
+            }
+            (Some(expected), None) => differences.push(format!("line {}: missing `{expected}`", line + 1)),
+            (None, Some(actual)) => differences.push(format!("line {}: extra `{actual}`", line + 1)),
+            (None, None) => unreachable!("line is below the longer output"),
+        }
+    }
+
+    if reference.status != output.status {
+        differences.push(format!("exit status: expected {:?}, got {:?}", reference.status, output.status));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stdout: &str, status: i32) -> Output {
+        Output { stdout: stdout.to_string(), status: Some(status) }
+    }
+
+    #[test]
+    fn test_differences() {
+        assert!(differences(&output("1\n2\n", 0), &output("1\n2\n", 0)).is_empty());
+        assert_eq!(differences(&output("1\n2\n", 0), &output("1\n3\n4\n", 70)), vec![
+            "line 2: expected `2`, got `3`",
+            "line 3: extra `4`",
+            "exit status: expected Some(0), got Some(70)",
+        ]);
+        assert_eq!(differences(&output("1\n2\n", 0), &output("1\n", 0)), vec!["line 2: missing `2`"]);
+    }
+}
@@ -1,950 +1,765 @@
-use std::{collections::HashMap, sync::LazyLock};
+use std::{collections::HashSet, fmt};
 
 use crate::{
-    op_code::OpCode,
-    scanner::{ErrorToken, Scanner, Token, TokenType}, value::Function,
+    ast::{Expr, FunctionDecl, Literal, LoopTarget, MatchArm, Pattern, Slot, Stmt},
+    chunk::{ChunkBuilder, Label},
+    lint,
+    op_code::{OpCode, Span},
+    parser::Parser,
+    resolver::Resolver,
+    runtime::NativeRegistry,
+    scanner::{Token, TokenType, KEYWORDS}, value::Function,
 };
 
-pub type CompileResult = Result<Function, ()>;
-
-#[derive(Debug, PartialEq, PartialOrd)]
-enum Precedence {
-    None,
-    Assignment, // =
-    Or,         // or
-    And,        // and
-    Equality,   // == !=
-    Comparison, // < > <= >=
-    Term,       // + -
-    Factor,     // * /
-    Unary,      // ! -
-    Call,       // . ()
-    Primary,
-}
+pub use crate::resolver::{FunctionDeclaration, GlobalCall, ResolverData};
 
-impl Precedence {
-    fn next_level(&self) -> Self {
-        match self {
-            Precedence::None => Self::Assignment,
-            Precedence::Assignment => Self::Or,
-            Precedence::Or => Self::And,
-            Precedence::And => Self::Equality,
-            Precedence::Equality => Self::Comparison,
-            Precedence::Comparison => Self::Term,
-            Precedence::Term => Self::Factor,
-            Precedence::Factor => Self::Unary,
-            Precedence::Unary => Self::Call,
-            Precedence::Call => Self::Primary,
-            Precedence::Primary => panic!("no next precedence level"),
-        }
-    }
-}
+pub type CompileResult = Result<CompiledProgram, Vec<CompileError>>;
 
-type ParseFn = fn(&mut Compiler, bool);
+/// A compiled source, with what it defines, inspectable without running it.
+#[derive(Debug)]
+pub struct CompiledProgram {
+    pub main: Function,
+    /// The globals the source declares at the top level, in declaration order.
+    pub globals_declared: Vec<String>,
+    /// The linter's findings, sorted by line.
+    pub diagnostics: Vec<Diagnostic>,
+}
 
-struct ParseRule {
-    prefix: Option<ParseFn>,
-    infix: Option<ParseFn>,
-    precedence: Precedence,
+/// A mistake in the source which stops the compilation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    pub line: i32,
+    pub span: Span,
+    /// Where in the line, ` at end` or ` at` the lexeme.
+    pub location: String,
+    pub message: String,
+    /// The code `rlox explain` describes.
+    pub code: Option<&'static str>,
 }
 
-impl ParseRule {
-    const fn new(prefix: ParseFn, infix: ParseFn, precedence: Precedence) -> Self {
-        Self {
-            prefix: Some(prefix),
-            infix: Some(infix),
-            precedence,
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Error", self.line)?;
+        if let Some(code) = self.code {
+            write!(f, "[{code}]")?;
         }
+        write!(f, "{}: {}", self.location, self.message)
     }
+}
 
-    const fn infix(infix: ParseFn, precedence: Precedence) -> Self {
-        Self {
-            prefix: None,
-            infix: Some(infix),
-            precedence,
-        }
-    }
+/// A finding about the program which does not stop the compilation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: i32,
+    pub message: String,
+}
 
-    const fn prefix(prefix: ParseFn) -> Self {
-        Self {
-            prefix: Some(prefix),
-            infix: None,
-            precedence: Precedence::None,
-        }
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] Warning: {}", self.line, self.message)
     }
+}
 
-    const fn undef() -> Self {
-        Self {
-            prefix: None,
-            infix: None,
-            precedence: Precedence::None,
-        }
+impl CompiledProgram {
+    /// The declared globals, default natives and keywords starting with `prefix`.
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        let natives = NativeRegistry::default();
+        let names = self.globals_declared.iter().chain(natives.globals().map(|(name, _)| name));
+        completions(prefix, names.cloned())
     }
 }
 
-static RULES: LazyLock<HashMap<TokenType, ParseRule>> = LazyLock::new(|| {
-    HashMap::from([
-        (
-            TokenType::LeftParen,
-            ParseRule::new(Compiler::grouping, Compiler::call, Precedence::Call),
-        ),
-        (TokenType::RightParen, ParseRule::undef()),
-        (TokenType::LeftBrace, ParseRule::undef()),
-        (TokenType::RightBrace, ParseRule::undef()),
-        (TokenType::Comma, ParseRule::undef()),
-        (TokenType::Dot, ParseRule::undef()),
-        (
-            TokenType::Minus,
-            ParseRule::new(
-                Compiler::unary,
-                Compiler::binary,
-                Precedence::Term,
-            ),
-        ),
-        (
-            TokenType::Plus,
-            ParseRule::infix(Compiler::binary, Precedence::Term),
-        ),
-        (TokenType::Semicolon, ParseRule::undef()),
-        (
-            TokenType::Slash,
-            ParseRule::infix(Compiler::binary, Precedence::Factor),
-        ),
-        (
-            TokenType::Star,
-            ParseRule::infix(Compiler::binary, Precedence::Factor),
-        ),
-        (TokenType::Bang, ParseRule::prefix(Compiler::unary)),
-        (
-            TokenType::BangEqual,
-            ParseRule::infix(Compiler::binary, Precedence::Equality),
-        ),
-        (TokenType::Equal, ParseRule::undef()),
-        (
-            TokenType::EqualEqual,
-            ParseRule::infix(Compiler::binary, Precedence::Equality),
-        ),
-        (
-            TokenType::Greater,
-            ParseRule::infix(Compiler::binary, Precedence::Comparison),
-        ),
-        (
-            TokenType::GreaterEqual,
-            ParseRule::infix(Compiler::binary, Precedence::Comparison),
-        ),
-        (
-            TokenType::Less,
-            ParseRule::infix(Compiler::binary, Precedence::Comparison),
-        ),
-        (
-            TokenType::LessEqual,
-            ParseRule::infix(Compiler::binary, Precedence::Comparison),
-        ),
-        (TokenType::Identifier, ParseRule::prefix(Compiler::variable)),
-        (TokenType::String, ParseRule::prefix(Compiler::string)),
-        (TokenType::Number, ParseRule::prefix(Compiler::number)),
-        (
-            TokenType::And,
-            ParseRule::infix(Compiler::and, Precedence::And),
-        ),
-        (TokenType::Class, ParseRule::undef()),
-        (TokenType::Else, ParseRule::undef()),
-        (TokenType::False, ParseRule::prefix(Compiler::literal)),
-        (TokenType::For, ParseRule::undef()),
-        (TokenType::Fun, ParseRule::undef()),
-        (TokenType::If, ParseRule::undef()),
-        (TokenType::Nil, ParseRule::prefix(Compiler::literal)),
-        (
-            TokenType::Or,
-            ParseRule::infix(Compiler::or, Precedence::Or),
-        ),
-        (TokenType::Print, ParseRule::undef()),
-        (TokenType::Return, ParseRule::undef()),
-        (TokenType::Super, ParseRule::undef()),
-        (TokenType::This, ParseRule::undef()),
-        (TokenType::True, ParseRule::prefix(Compiler::literal)),
-        (TokenType::Var, ParseRule::undef()),
-        (TokenType::While, ParseRule::undef()),
-        (TokenType::Eof, ParseRule::undef()),
-    ])
-});
-
-fn get_rule(token_type: TokenType) -> &'static ParseRule {
-    RULES.get(&token_type).expect("rule must exist")
+/// The names and keywords starting with `prefix`, sorted and without duplicates.
+pub(crate) fn completions(prefix: &str, names: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut candidates: Vec<String> = names
+        .into_iter()
+        .chain(KEYWORDS.iter().map(|keyword| keyword.to_string()))
+        .filter(|name| name.starts_with(prefix))
+        .collect();
+    candidates.sort();
+    candidates.dedup();
+    candidates
 }
 
-struct Parser {
-    current: Token,
-    previous: Token,
-    had_error: bool,
-    panic_mode: bool,
-}
+/// Locals of one function, including the hidden ones, the slots fit in a byte.
+pub const MAX_LOCALS: usize = 256;
 
-impl Parser {
-    fn new() -> Self {
-        Self {
-            current: Token {
-                token_type: TokenType::Eof,
-                line: 0,
-                start: 0,
-                length: 0,
-            },
-            previous: Token {
-                token_type: TokenType::Eof,
-                line: 0,
-                start: 0,
-                length: 0,
-            },
-            had_error: false,
-            panic_mode: false,
-        }
-    }
+/// The highest slot of `GetLocal` and `SetLocal`, slots above it use the wide opcodes.
+/// Lowered in tests to emit them without raising `MAX_LOCALS`.
+#[cfg(not(test))]
+const NARROW_LOCAL_MAX: usize = u8::MAX as usize;
+#[cfg(test)]
+const NARROW_LOCAL_MAX: usize = 15;
 
-    fn set_token(&mut self, token: Token) {
-        self.previous = std::mem::replace(&mut self.current, token);
-    }
+/// Parameters of a function and arguments of a call.
+pub const MAX_ARGUMENTS: usize = 255;
 
-    fn had_error(&mut self) {
-        self.had_error = true;
-    }
+/// Statements and functions nested in each other, each level is a recursion of the
+/// parser, resolver and code generator, so deeper sources could overflow the stack.
+pub const MAX_NESTED_STATEMENTS: u32 = 256;
 
-    fn panic(&mut self) {
-        self.panic_mode = true;
-    }
+/// Groups, operands, elements and arguments nested in each other, within a statement.
+pub const MAX_NESTED_EXPRESSIONS: u32 = 256;
 
-    fn no_panic(&mut self) {
-        self.panic_mode = false;
-    }
+pub fn compile(source: String, debug: bool) -> CompileResult {
+    compile_program(source, debug, false, None)
+}
 
-    fn check(&self, token_type: TokenType) -> bool {
-        self.current.token_type == token_type
-    }
+/// Like `compile`, but using a global which is not in `known_globals` and not
+/// declared in the source is a compile error.
+pub fn compile_strict(source: String, debug: bool, known_globals: HashSet<String>) -> CompileResult {
+    compile_program(source, debug, false, Some(known_globals))
 }
 
-pub fn compile(source: String, debug: bool) -> CompileResult {
-    let mut compiler = Compiler::new(source, debug);
-    if compiler.compile() {
-        Ok(compiler.context.function)
-    } else {
-        Err(())
-    }
+/// Compile a REPL input, a trailing expression without ';' prints its value.
+pub fn compile_repl(source: String) -> CompileResult {
+    compile_program(source, false, true, None)
+}
+
+pub fn compile_with_resolver_data(source: String) -> Result<(Function, ResolverData), Vec<CompileError>> {
+    compile_source(&source, false, false, None)
+}
+
+fn compile_program(
+    source: String,
+    debug: bool,
+    repl: bool,
+    known_globals: Option<HashSet<String>>,
+) -> CompileResult {
+    let (mut main, data) = compile_source(&source, debug, repl, known_globals)?;
+    main.set_source(source);
+    Ok(CompiledProgram {
+        main,
+        diagnostics: lint::diagnostics(&data),
+        globals_declared: data.globals_declared,
+    })
 }
 
-struct Local {
-    name: Token,
-    // The depth is set after the variable is initialized.
-    depth: Option<u32>,
+/// Parse, resolve and generate the code, each stage only runs if the one before succeeded.
+fn compile_source(
+    source: &str,
+    debug: bool,
+    repl: bool,
+    known_globals: Option<HashSet<String>>,
+) -> Result<(Function, ResolverData), Vec<CompileError>> {
+    let mut parser = Parser::new(source, debug, repl);
+    let mut statements = parser.parse()?;
+    let resolver = match known_globals {
+        Some(known_globals) => Resolver::strict(parser.scanner(), known_globals),
+        None => Resolver::new(parser.scanner()),
+    };
+    let mut data = resolver.resolve(&mut statements)?;
+
+    let mut compiler = Compiler::new();
+    compiler.statements(&statements);
+    let function = compiler.end_compiler(parser.previous());
+    data.conditions = compiler.conditions;
+    Ok((function, data))
 }
 
 struct CompilerContext {
     function: Function,
-    locals: Vec<Local>,
+    chunk: ChunkBuilder,
     scope_depth: u32,
+    /// The enclosing loops, innermost last.
+    loops: Vec<LoopLabels>,
+}
+
+/// Where `break` and `continue` jump to, the locals of the loop itself are still on the stack.
+#[derive(Clone, Copy)]
+struct LoopLabels {
+    break_label: Label,
+    continue_label: Label,
 }
 
 impl CompilerContext {
     fn new(function_name: String) -> Self {
         Self {
             function: Function::new(function_name),
-            locals: Vec::with_capacity(256),
+            chunk: ChunkBuilder::new(),
             scope_depth: 0,
+            loops: vec![],
         }
     }
 
-    fn mark_initialized(&mut self) {
-        if self.scope_depth == 0 {
-            return;
-        }
-
-        let pos = self.locals.len() - 1;
-        self.locals[pos].depth = Some(self.scope_depth);
-    }
-
-    fn begin_scope(&mut self) {
-        self.scope_depth += 1;
-    }
-
-    fn end_scope(&mut self, line: i32) {
-        self.scope_depth -= 1;
-
-        while !self.locals.is_empty()
-            && self.locals[self.locals.len() - 1].depth.is_some()
-            && self.locals[self.locals.len() - 1].depth.unwrap() > self.scope_depth
-        {
-            self.locals.pop();
-            self.write(OpCode::Pop, line);
-        }
-    }
-
-    fn end_function_scope(&mut self) {
-        self.scope_depth -= 1;
-    }
-
-    fn write(&mut self, code: OpCode, line: i32) {
-        self.function.write(code, line);
-    }
-
-    fn current_offset(&self) -> usize {
-        self.function.current_offset()
-    }
-
-    fn emit_jump(&mut self, code: OpCode, line: i32) -> usize {
-        self.function.emit_jump(code, line)
-    }
-
-    fn emit_loop(&mut self, offset: usize, line: i32) {
-        self.function.emit_loop(offset, line);
-    }
-
-    fn patch_jump(&mut self, offset: usize) {
-        self.function.patch_jump(offset);
+    /// Moves the finished chunk into the function.
+    fn finish(mut self) -> Function {
+        self.function.set_chunk(self.chunk.finish());
+        self.function.optimize();
+        self.function.compute_max_stack();
+        self.function
     }
 }
 
+/// Generates the code of the resolved tree.
 struct Compiler {
-    scanner: Scanner,
-    parser: Parser,
     context: CompilerContext,
-    debug: bool,
+    /// The code of the conditions, for the linter.
+    conditions: Vec<(i32, Vec<OpCode>)>,
 }
 
 impl Compiler {
-    fn new(source: String, debug: bool) -> Self {
+    fn new() -> Self {
         Self {
-            scanner: Scanner::new(&source),
-            parser: Parser::new(),
             context: CompilerContext::new("".to_string()),
-            debug,
+            conditions: vec![],
         }
     }
 
-    fn compile(&mut self) -> bool {
-        self.advance();
-        while !self.match_it(TokenType::Eof) {
-            self.declaration();
+    fn statements(&mut self, statements: &[Stmt]) {
+        for statement in statements {
+            self.statement(statement);
         }
-
-        self.end_compiler();
-        !self.parser.had_error
     }
 
-    fn advance(&mut self) {
-        loop {
-            match self.scanner.scan_token() {
-                Ok(token) => {
-                    self.parser.set_token(token);
-                    break;
+    fn statement(&mut self, statement: &Stmt) {
+        match statement {
+            Stmt::Expression(expression) => {
+                self.expression(expression);
+                self.write(OpCode::Pop, expression.token());
+            }
+            // a call of the `println` native, which embedders can replace but scripts can't
+            Stmt::Print { keyword, expressions } => {
+                for expression in expressions.iter() {
+                    self.expression(expression);
+                }
+                self.write(OpCode::PrintLine(expressions.len()), keyword);
+                self.write(OpCode::Pop, keyword);
+            }
+            Stmt::Var { name, token, initializer, constant } => {
+                match initializer {
+                    Some(initializer) => self.expression(initializer),
+                    None => self.write(OpCode::Nil, token),
+                }
+                if *constant && self.context.scope_depth == 0 {
+                    self.write(OpCode::DefineConstant(name.to_string()), token);
+                } else {
+                    self.define_variable(name, token);
+                }
+            }
+            Stmt::Function(declaration) => {
+                self.function(declaration);
+                self.define_variable(&declaration.name, &declaration.token);
+            }
+            Stmt::Block { statements, end } => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope(declarations(statements), end);
+            }
+            Stmt::If { keyword, condition, then_branch, else_branch } => {
+                self.if_statement(keyword, condition, then_branch, else_branch.as_deref());
+            }
+            Stmt::While { keyword, condition, body, .. } => {
+                self.while_statement(keyword, condition, body);
+            }
+            Stmt::For { keyword, initializer, condition, increment, body, .. } => {
+                self.for_statement(
+                    keyword,
+                    initializer.as_deref(),
+                    condition.as_ref(),
+                    increment.as_ref(),
+                    body,
+                );
+            }
+            Stmt::ForIn { keyword, collection, body, collection_slot, .. } => {
+                self.for_in_statement(keyword, collection, body, *collection_slot);
+            }
+            Stmt::Return { keyword, value } => match value {
+                Some(value) => {
+                    self.expression(value);
+                    self.write(OpCode::Return, keyword);
                 }
-                Err(err_token) => self.show_error(err_token, "error during advance"),
+                None => self.emit_return(keyword),
+            },
+            Stmt::Throw { keyword, value } => {
+                self.expression(value);
+                self.write(OpCode::Throw, keyword);
+            }
+            Stmt::Break { keyword, target, .. } => {
+                let label = self.loop_labels(target).break_label;
+                self.loop_jump(keyword, target, label);
+            }
+            Stmt::Continue { keyword, target, .. } => {
+                let label = self.loop_labels(target).continue_label;
+                self.loop_jump(keyword, target, label);
+            }
+            Stmt::Try { keyword, body, handler, end, .. } => {
+                self.try_statement(keyword, body, handler, end);
+            }
+            Stmt::Match { keyword, value, arms, value_slot } => {
+                self.match_statement(keyword, value, arms, *value_slot);
             }
         }
     }
 
-    fn declaration(&mut self) {
-        if self.match_it(TokenType::Fun) {
-            self.fun_declaration();
-        } else if self.match_it(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.statement();
-        }
-
-        if self.parser.panic_mode {
-            self.synchronize();
+    fn define_variable(&mut self, name: &str, token: &Token) {
+        // locals stay on the stack where the value was pushed
+        if self.context.scope_depth == 0 {
+            self.write(OpCode::DefineGlobal(name.to_string()), token);
         }
     }
 
-    fn statement(&mut self) {
-        if self.match_it(TokenType::Print) {
-            self.print_statement();
-        } else if self.match_it(TokenType::For) {
-            self.for_statement();
-        } else if self.match_it(TokenType::If) {
-            self.if_statement();
-        } else if self.match_it(TokenType::Return) {
-            self.return_statement();
-        } else if self.match_it(TokenType::While) {
-            self.while_statement();
-        } else if self.match_it(TokenType::LeftBrace) {
-            self.begin_scope();
-            self.block();
-            self.end_scope();
-        } else {
-            self.expression_statement();
+    fn function(&mut self, declaration: &FunctionDecl) {
+        let mut context = CompilerContext::new(declaration.name.to_string());
+        context.function.set_arity(declaration.params.len() - usize::from(declaration.variadic));
+        context.function.set_params(declaration.params.iter().map(|(name, _)| name.to_string()).collect());
+        context.function.set_line(declaration.token.line);
+        if declaration.variadic {
+            context.function.set_variadic();
         }
-    }
-
-    fn expression(&mut self) {
-        self.parse_precedence(Precedence::Assignment);
-    }
-
-    fn block(&mut self) {
-        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
-            self.declaration();
+        if declaration.generator {
+            context.function.set_generator();
         }
+        let enclosing = std::mem::replace(&mut self.context, context);
 
-        self.consume(TokenType::RightBrace, "Expect '}' after block.");
-    }
-
-    fn function(&mut self) {
-        let function_name = self.scanner.lexeme(&self.parser.previous);
-        let new_context = CompilerContext::new(function_name);
-        // todo: where is enclosing used
-        let enclosing = std::mem::replace(&mut self.context, new_context);
+        // the parameters and the body share the scope, it is not popped
         self.begin_scope();
-        self.consume(
-            TokenType::LeftParen,
-            "Expect '(' after function name.",
-        );
+        self.statements(&declaration.body);
+
+        let context = std::mem::replace(&mut self.context, enclosing);
+        let token = &declaration.token;
+        let function = Self::finish(context, token);
+        let index = self.context.chunk.add_function(function);
+        self.write(OpCode::Function(index), token);
+    }
+
+    /// An expression deciding a branch, its code is kept for the linter.
+    fn condition(&mut self, condition: &Expr) {
+        let start = self.context.chunk.instruction_count();
+        self.expression(condition);
+        let codes = self.context.chunk.codes_from(start);
+        self.conditions.push((condition.token().line, codes));
+    }
+
+    /// An `else if` chain is compiled flat, every taken branch jumps straight
+    /// to the end and the last branch without an `else` needs no jump.
+    fn if_statement<'a>(
+        &mut self,
+        mut keyword: &'a Token,
+        mut condition: &'a Expr,
+        mut then_branch: &'a Stmt,
+        mut else_branch: Option<&'a Stmt>,
+    ) {
+        let end = self.new_label();
+        loop {
+            self.condition(condition);
 
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.context.function.increase_arity();
-                let expected_none = self.parse_variable("Expected parameter name.");
-                self.define_variable(expected_none);
-                if !self.match_it(TokenType::Comma){
+            let else_label = self.new_label();
+            self.jump_if_false_pop(else_label, keyword);
+            self.statement(then_branch);
+            if else_branch.is_some() {
+                self.jump(end, keyword);
+            }
+            self.bind(else_label);
+
+            match else_branch {
+                Some(Stmt::If {
+                    keyword: next_keyword,
+                    condition: next_condition,
+                    then_branch: next_then,
+                    else_branch: next_else,
+                }) => {
+                    keyword = next_keyword;
+                    condition = next_condition;
+                    then_branch = next_then;
+                    else_branch = next_else.as_deref();
+                }
+                Some(else_branch) => {
+                    self.statement(else_branch);
                     break;
                 }
+                None => break,
             }
         }
-        
-        self.consume(
-            TokenType::RightParen,
-            "Expect ')' after parameters.",
-        );
-        self.consume(
-            TokenType::LeftBrace,
-            "Expect '{' before function body.",
-        );
-
-        self.block();
-        self.context.end_function_scope();
-        self.end_compiler();
-
-        let function_context = std::mem::replace(&mut self.context, enclosing);
-        self.write(OpCode::Function(function_context.function));
+        self.bind(end);
     }
 
-    fn fun_declaration(&mut self) {
-        let global = self.parse_variable("Expect function name.");
-        self.mark_initialized();
-        self.function();
+    fn while_statement(&mut self, keyword: &Token, condition: &Expr, body: &Stmt) {
+        let loop_start = self.new_label();
+        let exit = self.new_label();
+        self.bind(loop_start);
+        self.condition(condition);
 
-        self.define_variable(global);
-    }
-
-    fn var_declaration(&mut self) {
-        let global = self.parse_variable("Expect variable name.");
-
-        if self.match_it(TokenType::Equal) {
-            self.expression();
-        } else {
-            self.write(OpCode::Nil);
-        }
-
-        self.consume(
-            TokenType::Semicolon,
-            "Expect ';' after variable declaration.",
-        );
+        self.jump_if_false_pop(exit, keyword);
+        self.loop_body(LoopLabels { break_label: exit, continue_label: loop_start }, body);
+        self.jump(loop_start, keyword);
 
-        self.define_variable(global);
+        self.bind(exit);
     }
 
-    fn expression_statement(&mut self) {
-        self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after expression");
-        self.write(OpCode::Pop);
-    }
-
-    fn for_statement(&mut self) {
+    fn for_statement(
+        &mut self,
+        keyword: &Token,
+        initializer: Option<&Stmt>,
+        condition: Option<&Expr>,
+        increment: Option<&Expr>,
+        body: &Stmt,
+    ) {
         self.begin_scope();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
-        if self.match_it(TokenType::Semicolon) {
-            // no initializer
-        } else if self.match_it(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.expression_statement();
+        if let Some(initializer) = initializer {
+            self.statement(initializer);
         }
 
-        let mut loop_start = self.current_offset();
-        let exit_jump = if self.match_it(TokenType::Semicolon) {
-            None
-        } else {
-            self.expression();
-            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
-            let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-            self.write(OpCode::Pop);
-            Some(exit_jump)
-        };
-
-        if !self.match_it(TokenType::RightParen) {
-            let body_jump = self.emit_jump(OpCode::Jump(0));
-            let increment_start = self.current_offset();
-            self.expression();
-            self.write(OpCode::Pop);
-            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
-
-            self.emit_loop(loop_start);
-            loop_start = increment_start;
-            self.patch_jump(body_jump);
-        }
-
-        self.statement();
-        self.emit_loop(loop_start);
-
-        if let Some(exit_jump) = exit_jump {
-            self.patch_jump(exit_jump);
-            self.write(OpCode::Pop);
-        }
-
-        self.end_scope();
-    }
-
-    fn if_statement(&mut self) {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
-
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        self.write(OpCode::Pop);
-        self.statement();
-
-        let else_jump = self.emit_jump(OpCode::Jump(0));
-
-        self.patch_jump(then_jump);
-        self.write(OpCode::Pop);
-
-        if self.match_it(TokenType::Else) {
-            self.statement();
+        let mut loop_start = self.new_label();
+        self.bind(loop_start);
+        let exit = self.new_label();
+        if let Some(condition) = condition {
+            self.condition(condition);
+            self.jump_if_false_pop(exit, keyword);
         }
-        self.patch_jump(else_jump);
-    }
-
-    fn print_statement(&mut self) {
-        self.expression();
-        self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.write(OpCode::Print);
-    }
 
-    fn return_statement(&mut self) {
-        if self.match_it(TokenType::Semicolon) {
-            self.emit_return();
-        } else {
-            self.expression();
-            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
-            self.write(OpCode::Return);
-        }
-    }
+        if let Some(increment) = increment {
+            let body = self.new_label();
+            self.jump(body, keyword);
+            let increment_start = self.new_label();
+            self.bind(increment_start);
+            self.expression(increment);
+            self.write(OpCode::Pop, increment.token());
 
-    fn while_statement(&mut self) {
-        let loop_start = self.current_offset();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after statement.");
-
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        self.write(OpCode::Pop);
-        self.statement();
-        self.emit_loop(loop_start);
-
-        self.patch_jump(exit_jump);
-        self.write(OpCode::Pop);
-    }
-
-    /// Consume the token or create an error.
-    fn consume(&mut self, token_type: TokenType, message: &str) {
-        if self.check(token_type) {
-            self.advance();
-            return;
-        }
-
-        self.error_at_current(message);
-    }
-
-    fn check(&self, token_type: TokenType) -> bool {
-        self.parser.check(token_type)
-    }
-
-    /// If the token matches, consume it.
-    fn match_it(&mut self, token_type: TokenType) -> bool {
-        if !self.check(token_type) {
-            return false;
-        }
-
-        self.advance();
-        true
-    }
-
-    fn end_compiler(&mut self) {
-        self.emit_return();
-    }
-
-    fn emit_return(&mut self) {
-        self.write(OpCode::Nil);
-        self.write(OpCode::Return);
-    }
-
-    fn binary(&mut self, _can_assign: bool) {
-        if self.debug {
-            println!("binary");
-        }
-
-        let operator_type = self.parser.previous.token_type;
-        let rule = self.get_rule(operator_type);
-        self.parse_precedence(rule.precedence.next_level());
-
-        match operator_type {
-            TokenType::BangEqual => self.write2(OpCode::Equal, OpCode::Not),
-            TokenType::EqualEqual => self.write(OpCode::Equal),
-            TokenType::Greater => self.write(OpCode::Greater),
-            TokenType::GreaterEqual => self.write2(OpCode::Less, OpCode::Not),
-            TokenType::Less => self.write(OpCode::Less),
-            TokenType::LessEqual => self.write2(OpCode::Greater, OpCode::Not),
-            TokenType::Plus => self.write(OpCode::Add),
-            TokenType::Minus => self.write(OpCode::Subtract),
-            TokenType::Star => self.write(OpCode::Multiply),
-            TokenType::Slash => self.write(OpCode::Divide),
-            _ => panic!("wrong token type in binary {:?}", operator_type),
+            self.jump(loop_start, keyword);
+            loop_start = increment_start;
+            self.bind(body);
         }
-    }
 
-    fn call(&mut self, _can_assign: bool) {
-        let arg_count = self.argument_list();
-        self.write(OpCode::Call(arg_count));
-    }
-
-    fn literal(&mut self, _can_assign: bool) {
-        let token_type = self.parser.previous.token_type;
-
-        match token_type {
-            TokenType::False => self.write(OpCode::Bool(false)),
-            TokenType::Nil => self.write(OpCode::Nil),
-            TokenType::True => self.write(OpCode::Bool(true)),
-            _ => panic!("wrong token type in literal {:?}", token_type),
-        }
-    }
+        self.loop_body(LoopLabels { break_label: exit, continue_label: loop_start }, body);
+        self.jump(loop_start, keyword);
 
-    fn number(&mut self, _can_assign: bool) {
-        let num = self
-            .scanner
-            .lexeme(&self.parser.previous)
-            .parse::<f64>()
-            .expect("not a valid number");
-        self.write(OpCode::Constant(num));
+        self.bind(exit);
+        let locals = usize::from(initializer.is_some_and(Stmt::is_declaration));
+        self.end_scope(locals, keyword);
     }
 
-    fn or(&mut self, _can_assign: bool) {
-        let else_jump = self.emit_jump(OpCode::JumpIfFalse(0));
-        let end_jump = self.emit_jump(OpCode::Jump(0));
+    /// The collection and the iteration index are kept in hidden locals,
+    /// the loop variable is fresh for every iteration.
+    fn for_in_statement(&mut self, keyword: &Token, collection: &Expr, body: &Stmt, collection_slot: usize) {
+        self.begin_scope();
+        self.expression(collection);
+        self.write(OpCode::Constant(0.0), keyword);
 
-        self.patch_jump(else_jump);
-        self.write(OpCode::Pop);
+        let loop_start = self.new_label();
+        let exit = self.new_label();
+        let break_label = self.new_label();
+        self.bind(loop_start);
+        self.write(OpCode::IterNext(collection_slot), keyword);
+        self.jump_if_false_pop(exit, keyword);
 
-        self.parse_precedence(Precedence::Or);
-        self.patch_jump(end_jump);
-    }
+        self.begin_scope();
+        self.loop_body(LoopLabels { break_label, continue_label: loop_start }, body);
+        self.end_scope(1, keyword);
+        self.jump(loop_start, keyword);
 
-    fn string(&mut self, _can_assign: bool) {
-        let string = self.lexeme_string(&self.parser.previous);
-        self.write(OpCode::String(string));
+        self.bind(exit);
+        // the nil element, a break already popped the loop variable
+        self.write(OpCode::Pop, keyword);
+        self.bind(break_label);
+        self.end_scope(2, keyword);
     }
 
-    fn variable(&mut self, can_assign: bool) {
-        let name = self.lexeme(&self.parser.previous);
-        self.named_variable(name, can_assign);
+    fn loop_body(&mut self, labels: LoopLabels, body: &Stmt) {
+        self.context.loops.push(labels);
+        self.statement(body);
+        self.context.loops.pop();
     }
 
-    fn named_variable(&mut self, name: String, can_assign: bool) {
-        let local_pos = self.resolve_local(&name);
-
-        if can_assign && self.match_it(TokenType::Equal) {
-            self.expression();
-
-            self.write(match local_pos {
-                Some(pos) => OpCode::SetLocal(pos),
-                None => OpCode::SetGlobal(name),
-            });
-        } else {
-            self.write(match local_pos {
-                Some(pos) => OpCode::GetLocal(pos),
-                None => OpCode::GetGlobal(name),
-            });
-        }
+    fn loop_labels(&self, target: &LoopTarget) -> LoopLabels {
+        let loops = &self.context.loops;
+        loops[loops.len() - 1 - target.depth]
     }
 
-    fn grouping(&mut self, _can_assign: bool) {
-        if self.debug {
-            println!("grouping");
-        }
-        self.expression();
-        self.consume(TokenType::RightParen, "expected ')' after expression");
-        if self.debug {
-            println!("grouping end");
+    /// Leaves the `try` blocks and the scopes of the loop, then jumps.
+    fn loop_jump(&mut self, keyword: &Token, target: &LoopTarget, label: Label) {
+        for _ in 0..target.handlers {
+            self.write(OpCode::PopHandler, keyword);
         }
+        self.pop_locals(target.locals, keyword);
+        self.jump(label, keyword);
     }
 
-    fn unary(&mut self, _can_assign: bool) {
-        let operator_type = self.parser.previous.token_type;
+    /// The handler remembers the stack height, on a throw the VM unwinds to it
+    /// and pushes the thrown value, which becomes the catch variable.
+    fn try_statement(&mut self, keyword: &Token, body: &Stmt, handler: &[Stmt], end: &Token) {
+        let handler_label = self.new_label();
+        let end_label = self.new_label();
+        self.push_handler(handler_label, keyword);
+        self.statement(body);
+        self.write(OpCode::PopHandler, keyword);
+        self.jump(end_label, keyword);
 
-        self.parse_precedence(Precedence::Unary);
+        self.bind(handler_label);
+        self.begin_scope();
+        self.statements(handler);
+        self.end_scope(1 + declarations(handler), end);
 
-        match operator_type {
-            TokenType::Bang => self.write(OpCode::Not),
-            TokenType::Minus => self.write(OpCode::Negate),
-            _ => panic!("wrong token type in unary {:?}", operator_type),
-        }
+        self.bind(end_label);
     }
 
-    fn parse_precedence(&mut self, precedence: Precedence) {
-        if self.debug {
-            println!("parse {precedence:?}");
-        }
-
-        self.advance();
-        let can_assign = precedence <= Precedence::Assignment;
-        // todo: move get_rule to parser for previous and current token
-        let prefix_rule = self.get_rule(self.parser.previous.token_type).prefix;
-
-        if let Some(prefix_rule) = prefix_rule {
-            prefix_rule(self, can_assign);
-        } else {
-            println!("{:?}", self.parser.previous.token_type);
-            self.error("Expect expression");
-            return;
-        }
-
-        while precedence <= self.get_rule(self.parser.current.token_type).precedence {
-
-            self.advance();
-            let infix_rule = self
-                .get_rule(self.parser.previous.token_type)
-                .infix
-                .expect("infix must be defined");
+    /// The value is kept in a hidden local. Each arm tests its pattern against it
+    /// and goes on with the next arm at the first mismatch, the names of the
+    /// matching pattern are pushed as the locals of its statement.
+    fn match_statement(&mut self, keyword: &Token, value: &Expr, arms: &[MatchArm], value_slot: usize) {
+        self.begin_scope();
+        self.expression(value);
 
-            infix_rule(self, can_assign);
-        }
+        let end = self.new_label();
+        for arm in arms {
+            let next_arm = self.new_label();
+            self.pattern_test(&arm.pattern, &mut vec![], value_slot, next_arm);
 
-        if can_assign && self.match_it(TokenType::Equal) {
-            self.error("Invalid assignment");
+            self.begin_scope();
+            let bindings = arm.pattern.bindings();
+            for (_, token, path) in &bindings {
+                self.load_part(value_slot, path, token);
+            }
+            self.statement(&arm.body);
+            self.end_scope(bindings.len(), keyword);
+            self.jump(end, keyword);
+            self.bind(next_arm);
         }
-    }
-
-    fn write(&mut self, code: OpCode) {
-        let line = self.parser.previous.line;
-        self.context.write(code, line);
-    }
-
-    fn write2(&mut self, code1: OpCode, code2: OpCode) {
-        self.write(code1);
-        self.write(code2);
-    }
-
-    fn current_offset(&self) -> usize {
-        self.context.current_offset()
-    }
-
-    fn get_scope_depth(&self) -> u32 {
-        self.context.scope_depth
-    }
-
-    fn lexeme_string(&self, token: &Token) -> String {
-        self.scanner.lexeme_string(token)
-    }
-
-    fn lexeme(&self, token: &Token) -> String {
-        self.scanner.lexeme(token)
-    }
-
-    fn synchronize(&mut self) {
-        self.parser.no_panic();
-        while self.parser.current.token_type != TokenType::Eof {
-            if self.parser.previous.token_type == TokenType::Semicolon {
-                return;
+        self.write(OpCode::String("No pattern matches the value.".to_string()), keyword);
+        self.write(OpCode::Throw, keyword);
+
+        self.bind(end);
+        self.end_scope(1, keyword);
+    }
+
+    /// Jumps to `fail` if the part of the value at `path` doesn't match.
+    fn pattern_test(&mut self, pattern: &Pattern, path: &mut Vec<usize>, value_slot: usize, fail: Label) {
+        match pattern {
+            Pattern::Wildcard(_) | Pattern::Binding { .. } => (),
+            Pattern::Literal { value, token } => {
+                self.load_part(value_slot, path, token);
+                self.write(literal(value), token);
+                self.write(OpCode::StrictEqual, token);
+                self.jump_if_false_pop(fail, token);
             }
-
-            match self.parser.current.token_type {
-                TokenType::Class
-                | TokenType::Fun
-                | TokenType::Var
-                | TokenType::For
-                | TokenType::If
-                | TokenType::While
-                | TokenType::Print
-                | TokenType::Return => return,
-                _ => (),
+            Pattern::List { elements, token } => {
+                self.load_part(value_slot, path, token);
+                self.write(OpCode::IsList(elements.len()), token);
+                self.jump_if_false_pop(fail, token);
+                for (index, element) in elements.iter().enumerate() {
+                    path.push(index);
+                    self.pattern_test(element, path, value_slot, fail);
+                    path.pop();
+                }
             }
-
-            self.advance();
         }
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> Option<String> {
-        self.consume(TokenType::Identifier, error_message);
-
-        if self.get_scope_depth() == 0 {
-            Some(self.lexeme(&self.parser.previous))
-        } else {
-            self.declare_variable(self.parser.previous.clone());
-            None
+    /// Pushes the element of the nested lists in the local, one index per level.
+    fn load_part(&mut self, slot: usize, path: &[usize], token: &Token) {
+        self.write(get_local(slot), token);
+        for index in path {
+            self.write(OpCode::Index(*index), token);
         }
     }
 
-    fn mark_initialized(&mut self) {
-        self.context.mark_initialized();
-    }
-
-    fn define_variable(&mut self, id: Option<String>) {
-        match id {
-            Some(id) => {
-                if self.get_scope_depth() > 0 {
-                    self.error("Global variable but scope depth is > 0");
+    fn expression(&mut self, expression: &Expr) {
+        match expression {
+            Expr::Literal { value, token } => self.write(literal(value), token),
+            Expr::Variable { name, token, slot } => {
+                let code = match slot {
+                    Slot::Local(pos) => get_local(*pos),
+                    Slot::Global => OpCode::GetGlobal(name.to_string()),
+                };
+                self.write(code, token);
+            }
+            Expr::Assign { name, token, value, slot } => {
+                self.expression(value);
+                let code = match slot {
+                    Slot::Local(pos) if *pos > NARROW_LOCAL_MAX => OpCode::SetLocalWide(*pos),
+                    Slot::Local(pos) => OpCode::SetLocal(*pos),
+                    Slot::Global => OpCode::SetGlobal(name.to_string()),
+                };
+                self.write(code, token);
+            }
+            Expr::Unary { operator, right } => {
+                self.expression(right);
+                match operator.token_type {
+                    TokenType::Bang => self.write(OpCode::Not, operator),
+                    TokenType::Minus => self.write(OpCode::Negate, operator),
+                    _ => panic!("wrong token type in unary {:?}", operator.token_type),
                 }
-
-                self.write(OpCode::DefineGlobal(id))
-            },
-            None => {
-                if self.get_scope_depth() == 0 {
-                    self.error("Local variable but scope depth is 0");
+            }
+            Expr::Binary { left, operator, right } => {
+                self.expression(left);
+                self.expression(right);
+                self.binary(operator);
+            }
+            Expr::Logical { left, operator, right } => {
+                self.expression(left);
+                match operator.token_type {
+                    TokenType::And => self.and(operator, right),
+                    _ => self.or(operator, right),
                 }
-
-                self.mark_initialized();
             }
-        }
-    }
+            Expr::List { elements, token } => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.write(OpCode::List(elements.len()), token);
+            }
+            Expr::Get { object, name, token } => {
+                self.expression(object);
+                self.write(OpCode::GetProperty(name.to_string()), token);
+            }
+            Expr::Call { callee, paren, arguments } => {
+                let method = match callee.as_ref() {
+                    Expr::Get { object, name, .. } => {
+                        self.expression(object);
+                        Some(name)
+                    }
+                    callee => {
+                        self.expression(callee);
+                        None
+                    }
+                };
+                for argument in arguments {
+                    self.expression(argument);
+                }
 
-    fn argument_list(&mut self) -> usize {
-        let mut arg_count = 0;
-        if !self.check(TokenType::RightParen) {
-            loop {
-                self.expression();
-                arg_count += 1;
-                if !self.match_it(TokenType::Comma){
-                    break;
+                match method {
+                    Some(name) => self.write(OpCode::Invoke(name.to_string(), arguments.len()), paren),
+                    None => self.write(OpCode::Call(arguments.len()), paren),
+                }
+            }
+            Expr::Yield { keyword, value } => {
+                match value {
+                    Some(value) => self.expression(value),
+                    None => self.write(OpCode::Nil, keyword),
                 }
+                self.write(OpCode::Yield, keyword);
             }
         }
+    }
 
-        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+    fn binary(&mut self, operator: &Token) {
+        // the operator token is the span of the instructions, not the right operand
+        let codes = match operator.token_type {
+            TokenType::BangEqual => vec![OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => vec![OpCode::Equal],
+            TokenType::BangEqualEqual => vec![OpCode::StrictEqual, OpCode::Not],
+            TokenType::EqualEqualEqual => vec![OpCode::StrictEqual],
+            TokenType::Greater => vec![OpCode::Greater],
+            TokenType::GreaterEqual => vec![OpCode::GreaterEqual],
+            TokenType::Less => vec![OpCode::Less],
+            TokenType::LessEqual => vec![OpCode::LessEqual],
+            TokenType::Plus => vec![OpCode::Add],
+            TokenType::Minus => vec![OpCode::Subtract],
+            TokenType::Star => vec![OpCode::Multiply],
+            TokenType::Slash => vec![OpCode::Divide],
+            TokenType::DotDot => vec![OpCode::Range],
+            _ => panic!("wrong token type in binary {:?}", operator.token_type),
+        };
 
-        arg_count
+        for code in codes {
+            self.write(code, operator);
+        }
     }
 
-    fn and(&mut self, _can_assign: bool) {
-        let end_jump = self.emit_jump(OpCode::JumpIfFalse(0));
+    fn and(&mut self, operator: &Token, right: &Expr) {
+        let end = self.new_label();
+        self.jump_if_false(end, operator);
 
-        self.write(OpCode::Pop);
-        self.parse_precedence(Precedence::And);
+        self.write(OpCode::Pop, operator);
+        self.expression(right);
 
-        self.patch_jump(end_jump);
+        self.bind(end);
     }
 
-    fn declare_variable(&mut self, token: Token) {
-        if self.get_scope_depth() == 0 {
-            return;
-        }
+    fn or(&mut self, operator: &Token, right: &Expr) {
+        let right_label = self.new_label();
+        let end = self.new_label();
+        self.jump_if_false(right_label, operator);
+        self.jump(end, operator);
 
-        for i in (0..self.context.locals.len()).rev() {
-            let local = &self.context.locals[i];
-            if let Some(depth) = local.depth {
-                if depth < self.get_scope_depth() {
-                    break;
-                }
-            }
+        self.bind(right_label);
+        self.write(OpCode::Pop, operator);
 
-            if self.scanner.identifiers_equal(&local.name, &token) {
-                self.error("Already a variable with this name in scope.");
-            }
-        }
+        self.expression(right);
+        self.bind(end);
+    }
 
-        self.context.locals.push(Local {
-            name: token,
-            depth: None,
-        });
+    fn end_compiler(&mut self, token: &Token) -> Function {
+        let context = std::mem::replace(&mut self.context, CompilerContext::new("".to_string()));
+        Self::finish(context, token)
     }
 
-    fn resolve_local(&mut self, name: &str) -> Option<usize> {
-        for (i, local) in self.context.locals.iter().enumerate().rev() {
-            let token = &local.name;
-            if token.length == name.len() && self.scanner.lexeme(token) == name {
-                if local.depth.is_none() {
-                    self.error("Can't read variable in its own initializer");
-                }
-                return Some(i);
-            }
-        }
+    fn finish(mut context: CompilerContext, token: &Token) -> Function {
+        let span = Span { start: token.start, length: token.length };
+        context.chunk.write_span(OpCode::Nil, token.line, span);
+        context.chunk.write_span(OpCode::Return, token.line, span);
+        context.finish()
+    }
 
-        None
+    fn emit_return(&mut self, token: &Token) {
+        self.write(OpCode::Nil, token);
+        self.write(OpCode::Return, token);
     }
 
-    fn emit_jump(&mut self, code: OpCode) -> usize {
-        let line = self.parser.previous.line;
-        self.context.emit_jump(code, line)
+    fn write(&mut self, code: OpCode, token: &Token) {
+        let span = Span { start: token.start, length: token.length };
+        self.context.chunk.write_span(code, token.line, span);
     }
 
-    fn emit_loop(&mut self, offset: usize) {
-        let line = self.parser.previous.line;
-        self.context.emit_loop(offset, line);
+    fn new_label(&mut self) -> Label {
+        self.context.chunk.new_label()
     }
 
-    fn patch_jump(&mut self, offset: usize) {
-        self.context.patch_jump(offset);
+    fn bind(&mut self, label: Label) {
+        self.context.chunk.bind(label);
     }
 
-    fn begin_scope(&mut self) {
-        self.context.begin_scope();
+    fn jump(&mut self, label: Label, token: &Token) {
+        self.context.chunk.jump(label, token.line);
     }
 
-    fn end_scope(&mut self) {
-        let line = self.parser.previous.line;
-        self.context.end_scope(line);
+    fn jump_if_false(&mut self, label: Label, token: &Token) {
+        self.context.chunk.jump_if_false(label, token.line);
     }
 
-    fn get_rule(&self, operator_type: TokenType) -> &ParseRule {
-        get_rule(operator_type)
+    fn jump_if_false_pop(&mut self, label: Label, token: &Token) {
+        self.context.chunk.jump_if_false_pop(label, token.line);
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.parser.current.clone(), message);
+    fn push_handler(&mut self, label: Label, token: &Token) {
+        self.context.chunk.push_handler(label, token.line);
     }
 
-    fn error(&mut self, message: &str) {
-        self.error_at(self.parser.previous.clone(), message);
+    fn begin_scope(&mut self) {
+        self.context.scope_depth += 1;
     }
 
-    fn error_at(&mut self, token: Token, message: &str) {
-        if self.parser.panic_mode {
-            return;
-        }
+    /// Pops the locals declared in the scope.
+    fn end_scope(&mut self, locals: usize, token: &Token) {
+        self.context.scope_depth -= 1;
+        self.pop_locals(locals, token);
+    }
 
-        self.parser.panic();
-        eprint!("[line {}] Error", token.line);
-
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
-        } else {
-            eprint!(
-                " at {} ({:?})",
-                self.scanner.get_lexeme(&token),
-                token.token_type
-            );
+    fn pop_locals(&mut self, locals: usize, token: &Token) {
+        match locals {
+            0 => (),
+            1 => self.write(OpCode::Pop, token),
+            count => self.write(OpCode::PopN(count), token),
         }
+    }
+}
 
-        eprintln!(": {message}");
-        self.parser.had_error();
+fn literal(value: &Literal) -> OpCode {
+    match value {
+        Literal::Number(num) => OpCode::Constant(*num),
+        Literal::String(string) => OpCode::String(string.clone()),
+        Literal::Bool(b) => OpCode::Bool(*b),
+        Literal::Nil => OpCode::Nil,
     }
+}
 
-    fn show_error(&mut self, token: ErrorToken, message: &str) {
-        if self.parser.panic_mode {
-            return;
-        }
+fn get_local(slot: usize) -> OpCode {
+    if slot > NARROW_LOCAL_MAX { OpCode::GetLocalWide(slot) } else { OpCode::GetLocal(slot) }
+}
 
-        self.parser.panic();
-        eprint!("[line {}] Error", token.line);
-        eprint!(" at {}", self.scanner.get_lexeme_error(&token));
-        eprintln!(": {message}");
-        self.parser.had_error();
-    }
+/// The number of locals the statements declare in their scope.
+fn declarations(statements: &[Stmt]) -> usize {
+    statements.iter().filter(|statement| statement.is_declaration()).count()
 }
 
 #[cfg(test)]
@@ -978,76 +793,343 @@ mod tests {
         }
     }
 
-    fn assert_codes(expected: Vec<OpCode>, compiler: Compiler) {
+    fn assert_codes(expected: Vec<OpCode>, function: &Function) {
         let mut chunker = ChunkTester::new(expected);
-        compiler.context.function.operate_on_codes(&mut chunker);
+        function.operate_on_codes(&mut chunker);
         chunker.assert();
     }
 
     #[test]
     fn test_local_var_declaration() {
         let source = "{ var a;}".to_string();
-        let mut compiler = Compiler::new(source, false);
-        assert!(compiler.compile());
+        let function = compile(source, false).unwrap().main;
         let expected = vec![OpCode::Nil, OpCode::Pop, OpCode::Nil, OpCode::Return];
-        assert_codes(expected, compiler);
+        assert_codes(expected, &function);
     }
 
     #[test]
     fn test_local_var_set() {
         let source = "{ var a; a=1; print a;}".to_string();
-        let mut compiler = Compiler::new(source, false);
-        assert!(compiler.compile());
+        let function = compile(source, false).unwrap().main;
         let expected = vec![
             OpCode::Nil,
             OpCode::Constant(1.0),
             OpCode::SetLocal(0),
             OpCode::Pop,
             OpCode::GetLocal(0),
-            OpCode::Print,
+            OpCode::PrintLine(1),
+            OpCode::Pop,
             OpCode::Pop,
             OpCode::Nil,
             OpCode::Return,
         ];
-        assert_codes(expected, compiler);
+        assert_codes(expected, &function);
     }
 
     #[test]
     fn test_local_addition() {
         let source = "{ var a=1; var b = a + 3;print b;}".to_string();
-        let mut compiler = Compiler::new(source, false);
-        assert!(compiler.compile());
+        let function = compile(source, false).unwrap().main;
         let expected = vec![
             OpCode::Constant(1.0),
-            OpCode::GetLocal(0),
-            OpCode::Constant(3.0),
-            OpCode::Add,
+            OpCode::GetLocalAddConstant(0, 3.0),
             OpCode::GetLocal(1),
-            OpCode::Print,
-            OpCode::Pop,
+            OpCode::PrintLine(1),
             OpCode::Pop,
+            OpCode::PopN(2),
             OpCode::Nil,
             OpCode::Return,
         ];
-        assert_codes(expected, compiler);
+        assert_codes(expected, &function);
     }
 
     #[test]
     fn test_if_stmt() {
         let source = "if (true) { print 1;}".to_string();
-        let mut compiler = Compiler::new(source, false);
-        assert!(compiler.compile());
+        let function = compile(source, false).unwrap().main;
         let expected = vec![
             OpCode::Bool(true),
-            OpCode::JumpIfFalse(4),
+            OpCode::JumpIfFalsePop(3),
+            OpCode::Constant(1.0),
+            OpCode::PrintLine(1),
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_else_if_chain() {
+        let source = "if (a) print 1; else if (b) print 2; else print 3; if (a) print 1; else if (b) print 2;";
+        let function = compile(source.to_string(), false).unwrap().main;
+        let print = |value| [
+            OpCode::Constant(value),
+            OpCode::PrintLine(1),
             OpCode::Pop,
+        ];
+        let mut expected = vec![OpCode::GetGlobal("a".to_string()), OpCode::JumpIfFalsePop(4)];
+        expected.extend(print(1.0));
+        expected.extend([OpCode::Jump(9), OpCode::GetGlobal("b".to_string()), OpCode::JumpIfFalsePop(4)]);
+        expected.extend(print(2.0));
+        expected.push(OpCode::Jump(3));
+        expected.extend(print(3.0));
+        expected.extend([OpCode::GetGlobal("a".to_string()), OpCode::JumpIfFalsePop(4)]);
+        expected.extend(print(1.0));
+        expected.extend([OpCode::Jump(5), OpCode::GetGlobal("b".to_string()), OpCode::JumpIfFalsePop(3)]);
+        // no jump after the last branch without an else
+        expected.extend(print(2.0));
+        expected.extend([OpCode::Nil, OpCode::Return]);
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_print_values() {
+        let function = compile("print 1, \"a\", nil;".to_string(), false).unwrap().main;
+        let expected = vec![
+            OpCode::Constant(1.0),
+            OpCode::String("a".to_string()),
+            OpCode::Nil,
+            OpCode::PrintLine(3),
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+        assert!(compile("print 1,;".to_string(), false).is_err());
+    }
+
+    #[test]
+    fn test_repl_expression() {
+        let function = compile_repl("var a = 1; a + 2".to_string()).unwrap().main;
+        let expected = vec![
             OpCode::Constant(1.0),
-            OpCode::Print,
-            OpCode::Jump(1),
+            OpCode::DefineGlobal("a".to_string()),
+            OpCode::GetGlobal("a".to_string()),
+            OpCode::Constant(2.0),
+            OpCode::Add,
+            OpCode::PrintLine(1),
             OpCode::Pop,
             OpCode::Nil,
             OpCode::Return,
         ];
-        assert_codes(expected, compiler);
+        let mut chunker = ChunkTester::new(expected);
+        function.operate_on_codes(&mut chunker);
+        chunker.assert();
+
+        assert!(compile("a + 2".to_string(), false).is_err());
+        assert!(compile_repl("{ a + 2 }".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_invoke() {
+        let function = compile("var a = \"a\".find(\"b\").length;".to_string(), false).unwrap().main;
+        let expected = vec![
+            OpCode::String("a".to_string()),
+            OpCode::String("b".to_string()),
+            OpCode::Invoke("find".to_string(), 1),
+            OpCode::GetProperty("length".to_string()),
+            OpCode::DefineGlobal("a".to_string()),
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_match() {
+        let function = compile("match (1) { [a, 2] => print a; _ => {} }".to_string(), false).unwrap().main;
+        let expected = vec![
+            OpCode::Constant(1.0),
+            OpCode::GetLocal(0),
+            OpCode::IsList(2),
+            OpCode::JumpIfFalsePop(15),
+            OpCode::GetLocal(0),
+            OpCode::Index(1),
+            OpCode::Constant(2.0),
+            OpCode::StrictEqual,
+            OpCode::JumpIfFalsePop(10),
+            OpCode::GetLocal(0),
+            OpCode::Index(0),
+            OpCode::GetLocal(1),
+            OpCode::PrintLine(1),
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Jump(3),
+            OpCode::Jump(2),
+            OpCode::String("No pattern matches the value.".to_string()),
+            OpCode::Throw,
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_yield() {
+        let source = "var a = yield 1 + 2; yield;".to_string();
+        let function = compile(source, false).unwrap().main;
+        let expected = vec![
+            OpCode::Constant(1.0),
+            OpCode::Constant(2.0),
+            OpCode::Add,
+            OpCode::Yield,
+            OpCode::DefineGlobal("a".to_string()),
+            OpCode::Nil,
+            OpCode::Yield,
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    fn block_with_locals(count: usize) -> String {
+        let vars: String = (0..count).map(|i| format!("var v{i} = {i}; ")).collect();
+        format!("{{ {vars} print v{}; }}", count - 1)
+    }
+
+    #[test]
+    fn test_locals_limit() {
+        let function = compile(block_with_locals(MAX_LOCALS), false).unwrap().main;
+        let codes = function.codes_from(0);
+        assert!(codes.contains(&get_local(MAX_LOCALS - 1)));
+
+        assert!(compile(block_with_locals(MAX_LOCALS + 1), false).is_err());
+    }
+
+    #[test]
+    fn test_wide_locals() {
+        let slot = NARROW_LOCAL_MAX + 1;
+        let source = block_with_locals(slot + 1).replace(" print", &format!(" v{slot} = 1; v{NARROW_LOCAL_MAX} = 2; print"));
+        let codes = compile(source, false).unwrap().main.codes_from(0);
+        assert!(codes.contains(&OpCode::SetLocalWide(slot)));
+        assert!(codes.contains(&OpCode::GetLocalWide(slot)));
+        assert!(codes.contains(&OpCode::SetLocal(NARROW_LOCAL_MAX)));
+        assert!(!codes.contains(&OpCode::GetLocal(slot)));
+    }
+
+    #[test]
+    fn test_nesting_limits() {
+        // the resolver and the code generator recurse as deep as the parser
+        let statements = MAX_NESTED_STATEMENTS as usize - 1;
+        let expressions = MAX_NESTED_EXPRESSIONS as usize - 1;
+        let deepest = format!(
+            "{}print {}1{};",
+            "while (true) ".repeat(statements),
+            "-(".repeat(expressions / 2),
+            ")".repeat(expressions / 2),
+        );
+        assert!(compile(deepest, false).is_ok());
+
+        let too_deep = format!("print {}1{};", "(".repeat(expressions + 1), ")".repeat(expressions + 1));
+        let errors = compile(too_deep, false).unwrap_err();
+        assert_eq!(errors.iter().map(|error| error.message.as_str()).collect::<Vec<_>>(), ["Too many nested expressions."]);
+    }
+
+    /// Inputs like the ones the `compile` fuzz target finds, they overflowed the stack.
+    #[test]
+    fn test_fuzz_nesting() {
+        let sources = [
+            "(".repeat(100_000),
+            "-".repeat(100_000),
+            "!(-[".repeat(50_000),
+            "f(".repeat(100_000),
+            "{".repeat(100_000),
+            "if(1)".repeat(100_000),
+            format!("var a = {}1{};", "[".repeat(100_000), "]".repeat(100_000)),
+        ];
+        for source in sources {
+            let errors = compile(source.clone(), false).unwrap_err();
+            assert!(errors.iter().any(|error| error.message.starts_with("Too many nested")), "{}", &source[..10]);
+        }
+    }
+
+    #[test]
+    fn test_parameters() {
+        assert!(compile("fun f(a, a) {}".to_string(), false).is_err());
+        assert!(compile("fun f(a) { { var a; } }".to_string(), false).is_ok());
+
+        let names = |count: usize| (0..count).map(|i| format!("p{i}")).collect::<Vec<_>>().join(", ");
+        assert!(compile(format!("fun f({}) {{}}", names(MAX_ARGUMENTS)), false).is_ok());
+        assert!(compile(format!("fun f({}) {{}}", names(MAX_ARGUMENTS + 1)), false).is_err());
+
+        let args = |count: usize| vec!["1"; count].join(", ");
+        assert!(compile(format!("f({});", args(MAX_ARGUMENTS)), false).is_ok());
+        assert!(compile(format!("f({});", args(MAX_ARGUMENTS + 1)), false).is_err());
+    }
+
+    #[test]
+    fn test_scope_pops() {
+        let source = "{ var a; var b; { var c; } var d; }".to_string();
+        let function = compile(source, false).unwrap().main;
+        let expected = vec![
+            OpCode::Nil,
+            OpCode::Nil,
+            OpCode::Nil,
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::PopN(3),
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_top_level_return() {
+        assert!(compile("return;".to_string(), false).is_err());
+        assert!(compile("{ return 1; }".to_string(), false).is_err());
+        assert!(compile("fun f() { return 1; }".to_string(), false).is_ok());
+    }
+
+    #[test]
+    fn test_constants() {
+        assert!(compile("const a = 1; print a;".to_string(), false).is_ok());
+        assert!(compile("const a = 1; a = 2;".to_string(), false).is_err());
+        assert!(compile("const a = 1; var a = 2;".to_string(), false).is_err());
+        assert!(compile("const a;".to_string(), false).is_err());
+        assert!(compile("{ const a = 1; a = 2; }".to_string(), false).is_err());
+        assert!(compile("const a = 1; fun f(a) { a = 2; }".to_string(), false).is_ok());
+        assert!(compile("{ const a = 1; { var a = 2; a = 3; } }".to_string(), false).is_ok());
+
+        let redeclared = |source: &str| {
+            let errors = compile(source.to_string(), false).unwrap_err();
+            errors.iter().any(|error| error.message == "Already a constant with this name.")
+        };
+        assert!(redeclared("const a = 1; const a = 2; print a;"));
+        assert!(redeclared("const a = 1; fun a() {} print a;"));
+        assert!(redeclared("const a = 1; var a = 2;"));
+        assert!(redeclared("{ const a = 1; var a = 2; }"));
+        assert!(redeclared("{ const a = 1; const a = 2; }"));
+    }
+
+    #[test]
+    fn test_unicode_local() {
+        let source = "{ var größe = 1; print größe;}".to_string();
+        let function = compile(source, false).unwrap().main;
+        let expected = vec![
+            OpCode::Constant(1.0),
+            OpCode::GetLocal(0),
+            OpCode::PrintLine(1),
+            OpCode::Pop,
+            OpCode::Pop,
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_codes(expected, &function);
+    }
+
+    #[test]
+    fn test_compiled_program() {
+        let source = "var a = 1; fun f() { var b = 2; } { var c = 1; print c; } var a = 3; const d = a;";
+        let program = compile(source.to_string(), false).unwrap();
+        assert_eq!(program.globals_declared, vec!["a", "f", "d"]);
+        assert_eq!(program.complete("d"), vec!["d", "dump"]);
+        assert_eq!(program.diagnostics, vec![
+            Diagnostic { line: 1, message: "Function 'f' is never used.".to_string() },
+            Diagnostic { line: 1, message: "Value of local 'b' is never read.".to_string() },
+        ]);
     }
 }
@@ -0,0 +1,104 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Write, sync::Arc};
+
+use crate::{value::Function, vm::VmObserver};
+
+/// Hit counters for every instruction of the functions which ran and the functions
+/// declared in them, so code which never ran is reported with 0 hits.
+#[derive(Default)]
+pub struct Coverage {
+    chunks: Vec<(Arc<Function>, Vec<u64>)>,
+    /// Index into `chunks` by the address of the function.
+    indexes: HashMap<usize, usize>,
+}
+
+impl Coverage {
+    fn hit(&mut self, function: &Arc<Function>, ip: usize) {
+        let index = match self.indexes.get(&address(function)) {
+            Some(index) => *index,
+            None => self.add(function),
+        };
+        self.chunks[index].1[ip] += 1;
+    }
+
+    fn add(&mut self, function: &Arc<Function>) -> usize {
+        let index = self.chunks.len();
+        self.indexes.insert(address(function), index);
+        self.chunks.push((Arc::clone(function), vec![0; function.instruction_count()]));
+        for nested in function.functions() {
+            if !self.indexes.contains_key(&address(nested)) {
+                self.add(nested);
+            }
+        }
+        index
+    }
+
+    /// The hits of each line with code, those of its most executed instruction.
+    pub fn line_hits(&self) -> BTreeMap<i32, u64> {
+        let mut lines = BTreeMap::new();
+        for (function, hits) in &self.chunks {
+            for (ip, hits) in hits.iter().enumerate() {
+                let line = lines.entry(function.read_instruction(ip).line).or_insert(0);
+                *line = (*line).max(*hits);
+            }
+        }
+        lines
+    }
+
+    /// One line per line with code, its hits and the source line if the script carries its source.
+    pub fn report(&self) -> String {
+        let source = self.chunks.first().and_then(|(main, _)| main.source());
+        let mut report = String::new();
+        for (line, hits) in self.line_hits() {
+            let text = source
+                .and_then(|source| source.lines().nth((line - 1) as usize))
+                .unwrap_or_default();
+            writeln!(report, "{hits:>8} {line:>5}: {text}").unwrap();
+        }
+        report
+    }
+
+    /// The line hits as an lcov tracefile of `source_file`.
+    pub fn lcov(&self, source_file: &str) -> String {
+        let lines = self.line_hits();
+        let mut lcov = format!("TN:\nSF:{source_file}\n");
+        for (line, hits) in &lines {
+            writeln!(lcov, "DA:{line},{hits}").unwrap();
+        }
+        let hit = lines.values().filter(|hits| **hits > 0).count();
+        writeln!(lcov, "LF:{}\nLH:{hit}\nend_of_record", lines.len()).unwrap();
+        lcov
+    }
+}
+
+impl VmObserver for Coverage {
+    fn instruction_executed(&mut self, function: &Arc<Function>, ip: usize) {
+        self.hit(function, ip);
+    }
+}
+
+fn address(function: &Arc<Function>) -> usize {
+    Arc::as_ptr(function) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::VM;
+
+    #[test]
+    fn test_line_hits() {
+        let source = "fun unused() {\n  return 1;\n}\nfor (var i = 0; i < 3; i = i + 1) {\n  print i;\n}\n";
+        let mut vm = VM::new();
+        vm.enable_coverage();
+        vm.interpret(source.to_string(), false);
+
+        let coverage = vm.coverage().unwrap();
+        let hits = coverage.line_hits();
+        assert_eq!(hits[&2], 0);
+        assert_eq!(hits[&5], 3);
+        assert!(coverage.report().contains("       3     5:   print i;"));
+        let lcov = coverage.lcov("loop.lox");
+        assert!(lcov.starts_with("TN:\nSF:loop.lox\n"));
+        assert!(lcov.contains("DA:2,0\n"));
+        assert!(lcov.ends_with("end_of_record\n"));
+    }
+}
@@ -1,26 +1,72 @@
 use crate::{
     chunk::OpCodeVisitor,
-    op_code::OpCode, value::Function,
+    op_code::{OpCode, Span}, value::Function,
 };
 
+#[derive(Default)]
 pub struct Debugger {
     last_line: i32,
+    source: Option<String>,
 }
 
 impl Debugger {
     pub fn new() -> Self {
-        Self { last_line: 0 }
+        Self { last_line: 0, source: None }
     }
 
+    /// Disassemble, if the function carries its source the lines are interleaved.
+    /// The functions declared in the chunk follow.
     pub fn disassemble_chunk(&mut self, function: &Function, name: &str) {
-        println!("== {} ==", name);
+        self.source = function.source().map(str::to_string);
+        self.disassemble_function(function, name);
+    }
 
+    fn disassemble_function(&mut self, function: &Function, name: &str) {
+        println!("== {} == max stack {}", name, function.max_stack());
         function.operate_on_codes(self);
+
+        for nested in function.functions() {
+            self.last_line = 0;
+            self.disassemble_function(nested, &format!("{} line {}", nested.signature(), nested.line()));
+        }
+    }
+}
+
+/// The byte offset where the 1-based line starts and its text.
+pub fn source_line(source: &str, line: i32) -> Option<(usize, &str)> {
+    let mut offset = 0;
+    for (i, text) in source.split('\n').enumerate() {
+        if i as i32 + 1 == line {
+            return Some((offset, text.trim_end_matches('\r')));
+        }
+        offset += text.len() + 1;
+    }
+
+    None
+}
+
+/// The source line with the span underlined, `None` if the span is not on that line.
+pub fn snippet(source: &str, line: i32, span: Span) -> Option<String> {
+    let (line_start, text) = source_line(source, line)?;
+    let before = text.get(..span.start.checked_sub(line_start)?)?;
+    let underlined = &text[before.len()..];
+    if underlined.is_empty() {
+        return None;
     }
+    let column = before.chars().count();
+    let width = underlined.get(..span.length).unwrap_or(underlined).chars().count().max(1);
+
+    Some(format!("{line:>4} | {text}\n     | {}{}", " ".repeat(column), "^".repeat(width)))
 }
 
 impl OpCodeVisitor for Debugger {
     fn operate(&mut self, code: &OpCode, line: i32) {
+        if line != self.last_line
+            && let Some((_, text)) = self.source.as_deref().and_then(|source| source_line(source, line))
+        {
+            println!("     ; {}", text.trim());
+        }
+
         let line_or_placeholder = if line == self.last_line {
             "|".to_string()
         } else {
@@ -31,3 +77,20 @@ impl OpCodeVisitor for Debugger {
         self.last_line = line;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snippet() {
+        let source = "var a = 1;\nprint a + nil;";
+        let span = Span { start: 19, length: 1 };
+        assert_eq!(snippet(source, 2, span).unwrap(), "   2 | print a + nil;\n     |         ^");
+        assert_eq!(snippet(source, 1, span), None);
+
+        let source = "var é = \"ü\" + nil;";
+        let span = Span { start: 16, length: 3 };
+        assert_eq!(snippet(source, 1, span).unwrap(), "   1 | var é = \"ü\" + nil;\n     |               ^^^");
+    }
+}
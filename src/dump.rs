@@ -0,0 +1,87 @@
+use serde::Serialize;
+
+use crate::{
+    scanner::{Scanner, TokenType},
+    value::Function,
+};
+
+#[derive(Serialize)]
+struct TokenEntry {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<TokenType>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    lexeme: String,
+    line: i32,
+    start: usize,
+    length: usize,
+}
+
+/// The token stream of the source as a JSON array, scan errors are included as entries.
+pub fn dump_tokens(source: &str) -> String {
+    let mut scanner = Scanner::new(source);
+    let mut entries = vec![];
+    loop {
+        match scanner.scan_token() {
+            Ok(token) => {
+                entries.push(TokenEntry {
+                    token_type: Some(token.token_type),
+                    error: None,
+                    lexeme: scanner.lexeme(&token).to_string(),
+                    line: token.line,
+                    start: token.start,
+                    length: token.length,
+                });
+
+                if token.token_type == TokenType::Eof {
+                    break;
+                }
+            }
+            Err(error) => entries.push(TokenEntry {
+                token_type: None,
+                lexeme: scanner.get_lexeme_error(&error).to_string(),
+                error: Some(error.message),
+                line: error.line,
+                start: error.start,
+                length: error.length,
+            }),
+        }
+    }
+
+    serde_json::to_string_pretty(&entries).expect("tokens serialize to json")
+}
+
+/// The compiled function with its chunk as JSON.
+pub fn dump_chunk(function: &Function) -> String {
+    serde_json::to_string_pretty(function).expect("function serializes to json")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::compiler::compile;
+
+    use super::*;
+
+    #[test]
+    fn test_dump_tokens() {
+        let json: Value = serde_json::from_str(&dump_tokens("print \"a\"; €")).unwrap();
+        let entries = json.as_array().unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[0]["token_type"], "Print");
+        assert_eq!(entries[1]["lexeme"], "\"a\"");
+        assert_eq!(entries[3]["error"], "Unexpected character");
+        assert_eq!(entries[4]["token_type"], "Eof");
+    }
+
+    #[test]
+    fn test_dump_chunk() {
+        let function = compile("print 1;".to_string(), false).unwrap().main;
+        let json: Value = serde_json::from_str(&dump_chunk(&function)).unwrap();
+        let instructions = json["chunk"]["instructions"].as_array().unwrap();
+        assert_eq!(instructions[0]["code"]["Constant"], 1.0);
+        assert_eq!(instructions[1]["code"]["PrintLine"], 1);
+        assert_eq!(instructions[1]["line"], 1);
+    }
+}
@@ -0,0 +1,598 @@
+use crate::glob;
+
+/// A stable code for a kind of compile or runtime error, `rlox explain` prints the explanation.
+pub struct ErrorCode {
+    pub code: &'static str,
+    pub title: &'static str,
+    /// The messages with this code, `*` stands for the names and values in them.
+    messages: &'static [&'static str],
+    pub explanation: &'static str,
+}
+
+/// Compile errors are numbered from E0001, runtime errors from E0101. Codes are never reused.
+pub const ERROR_CODES: [ErrorCode; 40] = [
+    ErrorCode {
+        code: "E0001",
+        title: "Expect ';' after value",
+        messages: &["Expect ';' after *"],
+        explanation: "\
+Statements end with a semicolon, after a `print` value, an expression, a variable
+declaration, a `return` or `throw` value and the condition in a `for` loop.
+
+    print 1 + 2      // error
+    print 1 + 2;     // ok
+
+The error is reported at the token after the missing ';', often on the next line.",
+    },
+    ErrorCode {
+        code: "E0002",
+        title: "Expect expression",
+        messages: &["Expect expression"],
+        explanation: "\
+A value was expected, like a number, a string, a variable, a call or an expression in
+parentheses, but the token can't start one.
+
+    var a = ;        // error
+    print 1 + ;      // error
+    var a = 1 + 2;   // ok",
+    },
+    ErrorCode {
+        code: "E0003",
+        title: "Missing closing bracket",
+        messages: &["Expect ')' *", "expected ')' *", "Expect '}' *", "Expect ']' *"],
+        explanation: "\
+Every '(', '{' and '[' needs its closing ')', '}' or ']'. Calls, conditions, blocks,
+lists and list patterns are closed where they end.
+
+    print max(1, 2;          // error
+    if (a > 1 { print a; }   // error
+    print max(1, 2);         // ok",
+    },
+    ErrorCode {
+        code: "E0004",
+        title: "Missing opening bracket",
+        messages: &["Expect '(' *", "Expect '{' *"],
+        explanation: "\
+The conditions of `if`, `while`, `for`, `match` and the `catch` variable are in
+parentheses, the bodies of `try` and `catch` and the arms of `match` are in braces.
+
+    if a > 1 print a;        // error
+    if (a > 1) print a;      // ok
+    try print a;             // error
+    try { print a; } catch (e) { print e; }   // ok",
+    },
+    ErrorCode {
+        code: "E0005",
+        title: "Expect a name",
+        messages: &["Expect variable name.", "Expect function name.", "Expected parameter name.", "Expect property name after '.'.", "Expect exception variable name."],
+        explanation: "\
+Declarations, parameters, properties and the `catch` variable need an identifier,
+a keyword or a literal can't be used as a name.
+
+    var 1 = 2;           // error
+    fun (a) {}           // error
+    fun add(a, b) {}     // ok",
+    },
+    ErrorCode {
+        code: "E0006",
+        title: "Invalid assignment target",
+        messages: &["Invalid assignment"],
+        explanation: "\
+Only variables can be assigned to, not the results of expressions or calls.
+
+    a + b = 3;       // error
+    f() = 3;         // error
+    a = 3;           // ok",
+    },
+    ErrorCode {
+        code: "E0007",
+        title: "Undefined variable",
+        messages: &["Undefined variable '*'."],
+        explanation: "\
+The variable is not declared. With `--strict-globals` globals which are neither natives
+nor declared in the source are compile errors, without it they fail when they are used,
+see E0116.
+
+    print count;         // error with --strict-globals
+    var count = 0;
+    print count;         // ok",
+    },
+    ErrorCode {
+        code: "E0008",
+        title: "Already declared",
+        messages: &["Already a variable with this name in this scope.", "Already a constant with this name."],
+        explanation: "\
+A local variable can be declared once in each block, a constant once in the source.
+Use a new block or a different name, or assign instead of declaring again.
+
+    { var a = 1; var a = 2; }    // error
+    { var a = 1; a = 2; }        // ok",
+    },
+    ErrorCode {
+        code: "E0009",
+        title: "Variable read in its own initializer",
+        messages: &["Can't read variable in its own initializer"],
+        explanation: "\
+A local variable exists only after its initializer ran, so the initializer can't
+use it. An outer variable with the same name is shadowed already.
+
+    var a = 1;
+    { var a = a + 1; }           // error
+    { var b = a + 1; }           // ok",
+    },
+    ErrorCode {
+        code: "E0010",
+        title: "Assignment to a constant",
+        messages: &["Can't assign to constant '*'."],
+        explanation: "\
+A value declared with `const` can't be changed. Declare it with `var` to assign to it.
+
+    const limit = 10;
+    limit = 20;          // error
+    var max = 10;
+    max = 20;            // ok",
+    },
+    ErrorCode {
+        code: "E0011",
+        title: "Return outside a function",
+        messages: &["Can't return from top-level code."],
+        explanation: "\
+`return` ends a function call, the top level code of a script has no caller.
+
+    return 1;                    // error
+    fun one() { return 1; }      // ok",
+    },
+    ErrorCode {
+        code: "E0012",
+        title: "Break or continue outside a loop",
+        messages: &["Can't use '*' outside of a loop.", "No enclosing loop is labeled '*'.", "Label '*' is already used by an enclosing loop.", "Expect a loop after a label."],
+        explanation: "\
+`break` and `continue` only work in loops, and a label names one of the loops around
+them. Labels are written before a loop and are unique among the nested loops.
+
+    break;                                           // error
+    outer: for (var i = 0; i < 3; i = i + 1) {
+        while (true) { break outer; }                // ok
+    }",
+    },
+    ErrorCode {
+        code: "E0013",
+        title: "Too many parameters, arguments or locals",
+        messages: &["Can't have more than 255 *", "Too many local variables in function."],
+        explanation: "\
+A function has at most 255 parameters and a call at most 255 arguments, a function
+has at most 256 local variables. Pass a list instead of many values, or split the
+function.
+
+    fun sum(numbers) { var total = 0; for (var n in numbers) total = total + n; return total; }",
+    },
+    ErrorCode {
+        code: "E0014",
+        title: "Rest parameter not last",
+        messages: &["The rest parameter must be the last one."],
+        explanation: "\
+The rest parameter collects the remaining arguments into a list, so no parameter
+can follow it.
+
+    fun log(...parts, level) {}      // error
+    fun log(level, ...parts) {}      // ok",
+    },
+    ErrorCode {
+        code: "E0015",
+        title: "Missing keyword",
+        messages: &["Expect 'catch' *", "Expect 'in' *", "Expect '=>' *", "Expect '=' after constant name."],
+        explanation: "\
+Some statements need a keyword or an operator between their parts: `try` blocks are
+followed by `catch`, `for` loops over values use `in`, match arms use `=>` and a
+constant has a value.
+
+    for (var c \"abc\") print c;       // error
+    for (var c in \"abc\") print c;    // ok
+    const limit;                     // error
+    const limit = 10;                // ok",
+    },
+    ErrorCode {
+        code: "E0016",
+        title: "Expect pattern",
+        messages: &["Expect pattern."],
+        explanation: "\
+A match arm starts with a pattern: a literal, a range, a list pattern, a variable
+name which binds the value, or `_` which matches everything.
+
+    match (x) { + => print 1; }          // error
+    match (x) { 1 => print 1; _ => print 2; }   // ok",
+    },
+    ErrorCode {
+        code: "E0017",
+        title: "Unexpected character",
+        messages: &["Unexpected character"],
+        explanation: "\
+The character is not part of Lox, it can only be used in strings and comments.
+
+    print 1 # 2;         // error
+    print \"1 # 2\";       // ok",
+    },
+    ErrorCode {
+        code: "E0018",
+        title: "Unterminated string",
+        messages: &["Undetermined string"],
+        explanation: "\
+A string ends with the same quote it starts with. Strings can span lines, so the
+error is reported at the end of the file.
+
+    print \"hello;        // error
+    print \"hello\";       // ok",
+    },
+    ErrorCode {
+        code: "E0019",
+        title: "Nested too deep",
+        messages: &["Too many nested *"],
+        explanation: "\
+Up to 256 statements and functions can be nested in each other, an `if` with a block
+counts as two, and up to 256 expressions in each statement. The compiler recurses for
+each level, deeper sources are rejected instead of overflowing its stack. Move the
+inner parts into functions or variables instead of nesting.
+
+    if (a) { if (b) { if (c) { ... } } }    // error beyond 128 of them
+    print ((((((1))))));                    // error beyond 256 parentheses",
+    },
+    ErrorCode {
+        code: "E0101",
+        title: "Operands must be numbers",
+        messages: &["Operand must be a number", "Operands must be numbers, *", "Operands must be two numbers or two strings, *", "Range bounds must be numbers, *"],
+        explanation: "\
+Arithmetic, comparisons and ranges need numbers, `+` also joins two strings.
+Values are not converted, convert them explicitly.
+
+    print 1 + \"2\";       // error
+    print -nil;          // error
+    print \"1\" + \"2\";     // ok",
+    },
+    ErrorCode {
+        code: "E0102",
+        title: "Wrong number of arguments",
+        messages: &["Expected * arguments but got *"],
+        explanation: "\
+A call passes as many arguments as the function has parameters, a function with a
+rest parameter takes at least the others. The message names missing parameters.
+
+    fun add(a, b) { return a + b; }
+    add(1);              // error
+    add(1, 2);           // ok",
+    },
+    ErrorCode {
+        code: "E0103",
+        title: "Not callable",
+        messages: &["Can only call functions and classes."],
+        explanation: "\
+Only functions and natives can be called.
+
+    var name = \"lox\";
+    name();              // error
+    print len(name);     // ok",
+    },
+    ErrorCode {
+        code: "E0104",
+        title: "Invalid index",
+        messages: &["Index * is out of range.", "Only lists can be indexed, not *", "The iteration index is not a number."],
+        explanation: "\
+List elements are numbered from 0 to the length minus one.
+
+    var list = [1, 2];
+    print list.get(2);   // error
+    print list.get(1);   // ok",
+    },
+    ErrorCode {
+        code: "E0105",
+        title: "Undefined property or method",
+        messages: &["Undefined property *", "Undefined method *", "Only strings and lists have *"],
+        explanation: "\
+Only strings and lists have properties and methods, and only the built-in ones.
+
+    print \"abc\".size;    // error
+    print \"abc\".length;  // ok
+    print 1.length;      // error",
+    },
+    ErrorCode {
+        code: "E0106",
+        title: "Not iterable",
+        messages: &["Can only iterate over *"],
+        explanation: "\
+A `for in` loop goes over the characters of a string, the numbers of a range or the
+elements of a list.
+
+    for (var i in 10) print i;       // error
+    for (var i in 0..10) print i;    // ok",
+    },
+    ErrorCode {
+        code: "E0107",
+        title: "Wrong argument type",
+        messages: &["*() needs *", "*() not supported for *", "Expected a socket, *", "* can't be a key."],
+        explanation: "\
+A native function or method got an argument of a type it can't handle, the message
+names the expected type.
+
+    print len(1);            // error
+    print len(\"abc\");        // ok",
+    },
+    ErrorCode {
+        code: "E0108",
+        title: "Comparison of different types",
+        messages: &["Can't compare values of different types *"],
+        explanation: "\
+With `--strict-equality`, `==` and `!=` of values of different types are errors,
+because they are always unequal. `===` and `!==` compare any values.
+
+    print 1 == \"1\";      // error with --strict-equality
+    print 1 === \"1\";     // ok",
+    },
+    ErrorCode {
+        code: "E0109",
+        title: "Integer beyond 2^53",
+        messages: &["Integer result * is beyond 2^53 and not exact."],
+        explanation: "\
+With `--safe-integers`, integer arithmetic with a result beyond 2^53 is an error,
+numbers are 64 bit floats and can't represent every integer beyond it.
+
+    print 9007199254740992 + 1;      // error with --safe-integers",
+    },
+    ErrorCode {
+        code: "E0110",
+        title: "No pattern matches",
+        messages: &["*No pattern matches the value.*"],
+        explanation: "\
+None of the arms of a `match` matched the value. Add a `_` arm for the other values.
+
+    match (3) { 1 => print 1; }              // error
+    match (3) { 1 => print 1; _ => print 2; }    // ok",
+    },
+    ErrorCode {
+        code: "E0111",
+        title: "Uncaught exception",
+        messages: &["Uncaught exception *"],
+        explanation: "\
+A value was thrown and no `try` block around it caught it.
+
+    throw \"failed\";                                      // error
+    try { throw \"failed\"; } catch (e) { print e; }       // ok",
+    },
+    ErrorCode {
+        code: "E0112",
+        title: "Running commands is not allowed",
+        messages: &["Running commands is not allowed, run with --allow-exec."],
+        explanation: "\
+`exec()` and `shell()` run programs on the machine, scripts can only use them when
+rlox runs with `--allow-exec`.
+
+    rlox --allow-exec script.lox",
+    },
+    ErrorCode {
+        code: "E0113",
+        title: "Sorting failed",
+        messages: &["Can't sort NaN.", "Can only sort *", "The comparator *", "Inconsistent comparator*"],
+        explanation: "\
+`sort()` orders numbers or strings, other values need a comparator which returns a
+negative number, 0 or a positive number, consistently for the same elements.
+
+    print sort([[2], [1]]);                                      // error
+    fun by_first(a, b) { return a.get(0) - b.get(0); }
+    print sort_by([[2], [1]], by_first);                         // ok",
+    },
+    ErrorCode {
+        code: "E0114",
+        title: "Instruction limit exceeded",
+        messages: &["Instruction limit exceeded."],
+        explanation: "\
+The host running the script limits the number of instructions, the script ran
+longer, e.g. in an endless loop.
+
+    while (true) {}      // error when the host sets a limit",
+    },
+    ErrorCode {
+        code: "E0115",
+        title: "Needs a host",
+        messages: &["Can only yield in scripts started with VM::start.", "Generator is already running.", "Async natives can only be called *", "*() calls a function and needs the VM."],
+        explanation: "\
+`yield` and async natives hand control back to the program embedding rlox, they only
+work in scripts the host started with `VM::start`, not in scripts run from the
+command line. A generator can't resume itself while it is running, and list methods
+calling a function, like `map`, need the VM and can't be run with `Value::invoke`.",
+    },
+    ErrorCode {
+        code: "E0116",
+        title: "Undefined variable",
+        messages: &["Undefined variable '*'."],
+        explanation: "\
+A global was read or assigned before any script defined it. Globals are defined
+when their `var` runs, so a function can only use globals defined before it is called.
+
+    fun show() { print count; }
+    show();              // error
+    var count = 0;
+    show();              // ok",
+    },
+    ErrorCode {
+        code: "E0117",
+        title: "Command failed",
+        messages: &["Can't run command: *", "Command failed with *"],
+        explanation: "\
+The program run by `exec()` or `shell()` could not be started, or it exited with an
+error. The message has its exit status and error output.
+
+    exec([\"ls\", \"missing\"]);     // error
+    exec([\"ls\", \".\"]);           // ok",
+    },
+    ErrorCode {
+        code: "E0118",
+        title: "Invalid template",
+        messages: &["Unclosed '{{' in template.", "Undefined template key '*'.", "A template value must be *"],
+        explanation: "\
+`template()` replaces each `{{key}}` with the value of the key, the values are a list
+of [key, value] pairs. Every key in the template needs a value.
+
+    print template(\"hi {{name}}\", []);                  // error
+    print template(\"hi {{name\", [[\"name\", \"lox\"]]);     // error
+    print template(\"hi {{name}}\", [[\"name\", \"lox\"]]);   // ok",
+    },
+    ErrorCode {
+        code: "E0119",
+        title: "Unknown native function",
+        messages: &["Unknown native function."],
+        explanation: "\
+The native is not registered in this VM, e.g. a host function restored from the
+snapshot of a VM of another runtime. Register it in the `Runtime` the VM was
+spawned from.",
+    },
+    ErrorCode {
+        code: "E0120",
+        title: "Network error",
+        messages: &["Can't connect to *", "Can't send: *", "Can't receive: *", "The socket is closed.", "Invalid port *"],
+        explanation: "\
+A socket could not be opened, written or read, the message has the reason from the
+operating system. Ports are whole numbers up to 65535, and a closed socket can't be
+used anymore.
+
+    var socket = tcp_connect(\"localhost\", 70000);    // error
+    var socket = tcp_connect(\"localhost\", 7000);     // ok if something listens",
+    },
+    ErrorCode {
+        code: "E0121",
+        title: "Change of a constant",
+        messages: &["Can't assign to constant '*'.", "Can't redefine constant '*'."],
+        explanation: "\
+A global declared with `const` by an earlier script, e.g. a previous REPL input, can't
+be assigned or declared again with `var` or `fun`. In the same source this is E0008 or
+E0010 at compile time. Declaring it again with `const` is allowed, so a script can run
+again in the same VM.
+
+    > const limit = 10;
+    > limit = 20;          // error
+    > var limit = 20;      // error",
+    },
+];
+
+/// The code of the compile error with the message, if it has one.
+pub fn compile_code(message: &str) -> Option<&'static str> {
+    code_for("E00", message)
+}
+
+/// The code of the runtime error with the message, if it has one.
+pub fn runtime_code(message: &str) -> Option<&'static str> {
+    code_for("E01", message)
+}
+
+/// Compile and runtime errors can have the same message, like undefined variables.
+fn code_for(range: &str, message: &str) -> Option<&'static str> {
+    ERROR_CODES.iter()
+        .filter(|error_code| error_code.code.starts_with(range))
+        .find(|error_code| error_code.messages.iter().any(|pattern| glob::matches(pattern, message)))
+        .map(|error_code| error_code.code)
+}
+
+/// The error with the code, `e0001` works like `E0001`.
+pub fn explain(code: &str) -> Option<&'static ErrorCode> {
+    ERROR_CODES.iter().find(|error_code| error_code.code.eq_ignore_ascii_case(code))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn test_codes_are_unique_and_sorted() {
+        assert!(ERROR_CODES.windows(2).all(|pair| pair[0].code < pair[1].code));
+        assert_eq!(explain("e0001").map(|error_code| error_code.title), Some("Expect ';' after value"));
+        assert!(explain("E9999").is_none());
+    }
+
+    #[test]
+    fn test_code_for() {
+        assert_eq!(compile_code("Expect ';' after value."), Some("E0001"));
+        assert_eq!(compile_code("Undefined variable 'count'."), Some("E0007"));
+        assert_eq!(runtime_code("Undefined variable 'count'."), Some("E0116"));
+        assert_eq!(runtime_code("Expected 3 arguments but got 1, missing 'b', 'c'."), Some("E0102"));
+        assert_eq!(runtime_code("len() not supported for Nil."), Some("E0107"));
+        assert_eq!(runtime_code("Expect ';' after value."), None);
+        assert_eq!(compile_code("Something else."), None);
+    }
+
+    #[test]
+    fn test_compile_errors_have_codes() {
+        let sources = [
+            "print 1", "var a = ;", "print f(1;", "if a {}", "var 1 = 2;", "1 = 2;", "{ var a; var a; }",
+            "{ var a = a; }", "const a = 1; a = 2;", "return 1;", "break;", "fun f(...a, b) {}",
+            "const a;", "match (1) { + => print 1; }", "print 1 # 2;", "print \"a;",
+        ];
+        for source in sources {
+            let errors = compile(source.to_string(), false).unwrap_err();
+            assert!(errors.iter().all(|error| error.code.is_some()), "{source}: {errors:?}");
+        }
+    }
+
+    /// The messages passed to the calls in the code before the tests, the first
+    /// argument is skipped if it isn't the message. `format!` arguments become `x`.
+    fn raised_messages(source: &str, calls: &[&str]) -> Vec<String> {
+        let code = source.split("#[cfg(test)]").next().unwrap();
+        let mut messages = vec![];
+        for call in calls {
+            for (start, _) in code.match_indices(call) {
+                let mut rest = code[start + call.len()..].trim_start();
+                if !rest.starts_with(['"', '&']) && !rest.starts_with("format!(")
+                    && let Some(comma) = rest.find(',').filter(|comma| !rest[..*comma].contains(')'))
+                {
+                    rest = rest[comma + 1..].trim_start();
+                }
+                let rest = rest.strip_prefix('&').unwrap_or(rest);
+                let (rest, formatted) = match rest.strip_prefix("format!(") {
+                    Some(rest) => (rest.trim_start(), true),
+                    None => (rest, false),
+                };
+                let Some(rest) = rest.strip_prefix('"') else { continue };
+                let message = &rest[..rest.find('"').unwrap()];
+                messages.push(if formatted { fill_placeholders(message) } else { message.to_string() });
+            }
+        }
+        messages
+    }
+
+    fn fill_placeholders(format: &str) -> String {
+        let mut message = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => message.push(chars.next().unwrap()),
+                '}' if chars.peek() == Some(&'}') => message.push(chars.next().unwrap()),
+                '{' => {
+                    chars.by_ref().find(|c| *c == '}');
+                    message.push('x');
+                }
+                c => message.push(c),
+            }
+        }
+        message
+    }
+
+    #[test]
+    fn test_raised_messages_have_codes() {
+        let compile_sources = [include_str!("parser.rs"), include_str!("resolver.rs"), include_str!("scanner.rs")];
+        let compile_calls = ["error(", "error_at_current(", "consume(", "consume_before_body(", "loop_jump(", "error_token("];
+        let compile_messages: Vec<String> = compile_sources.iter()
+            .flat_map(|source| raised_messages(source, &compile_calls))
+            .collect();
+        assert!(compile_messages.len() > 50);
+        let uncoded: Vec<&String> = compile_messages.iter().filter(|message| compile_code(message).is_none()).collect();
+        assert!(uncoded.is_empty(), "compile errors without a code: {uncoded:?}");
+
+        let runtime_sources = [include_str!("vm.rs"), include_str!("value.rs"), include_str!("template.rs"), include_str!("net.rs")];
+        let runtime_calls = ["runtime_error(", "Err(", "|error| ", "|| "];
+        let runtime_messages: Vec<String> = runtime_sources.iter()
+            .flat_map(|source| raised_messages(source, &runtime_calls))
+            // returned to the host by `VM::restore`, scripts don't see them
+            .filter(|message| !message.starts_with("invalid snapshot"))
+            .collect();
+        assert!(runtime_messages.len() > 50);
+        let uncoded: Vec<&String> = runtime_messages.iter().filter(|message| runtime_code(message).is_none()).collect();
+        assert!(uncoded.is_empty(), "runtime errors without a code: {uncoded:?}");
+    }
+}
@@ -0,0 +1,115 @@
+use serde::Serialize;
+
+use crate::{
+    compiler::{CompileError, Diagnostic},
+    op_code::Span,
+    vm::LoxError,
+};
+
+/// How the command line prints errors and warnings, `--error-format=json` prints
+/// one JSON object per line for editors and CI.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonDiagnostic<'a> {
+    file: &'a str,
+    /// `null` for failures of the whole file, e.g. if it can't be read.
+    line: Option<i32>,
+    /// In bytes, `null` if the position is not known, e.g. without a source map.
+    span: Option<Span>,
+    severity: &'static str,
+    code: Option<&'a str>,
+    message: &'a str,
+}
+
+impl ErrorFormat {
+    /// The format named by `--error-format=name`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(Self::Human),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    pub fn compile_error(self, file: &str, error: &CompileError) -> String {
+        match self {
+            Self::Human => error.to_string(),
+            Self::Json => json_line(file, Some(error.line), Some(error.span), "error", error.code, &error.message),
+        }
+    }
+
+    /// Without the call stack in the JSON line, the span locates the failure.
+    pub fn runtime_error(self, file: &str, error: &LoxError) -> String {
+        match self {
+            Self::Human => error.to_string(),
+            Self::Json => json_line(file, Some(error.line), error.span, "error", error.code, &error.message),
+        }
+    }
+
+    pub fn warning(self, file: &str, diagnostic: &Diagnostic) -> String {
+        match self {
+            Self::Human => diagnostic.to_string(),
+            Self::Json => json_line(file, Some(diagnostic.line), None, "warning", None, &diagnostic.message),
+        }
+    }
+
+    /// A failure of the whole file, e.g. that it can't be read or didn't compile.
+    pub fn file_error(self, file: &str, message: &str) -> String {
+        match self {
+            Self::Human => format!("{file}: {message}"),
+            Self::Json => json_line(file, None, None, "error", None, message),
+        }
+    }
+}
+
+fn json_line(
+    file: &str,
+    line: Option<i32>,
+    span: Option<Span>,
+    severity: &'static str,
+    code: Option<&str>,
+    message: &str,
+) -> String {
+    let diagnostic = JsonDiagnostic { file, line, span, severity, code, message };
+    serde_json::to_string(&diagnostic).expect("diagnostics serialize to json")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, lint::lint, vm::{InterpretResult, VM}};
+
+    #[test]
+    fn test_json_lines() {
+        let Err(errors) = compile("var a = 1;\nprint a +;".to_string(), false) else {
+            panic!("the source has an error");
+        };
+        assert_eq!(
+            ErrorFormat::Json.compile_error("a.lox", &errors[0]),
+            r#"{"file":"a.lox","line":2,"span":{"start":20,"length":1},"severity":"error","code":"E0002","message":"Expect expression"}"#
+        );
+        assert_eq!(ErrorFormat::Human.compile_error("a.lox", &errors[0]), "[line 2] Error[E0002] at ; (Semicolon): Expect expression");
+
+        let mut vm = VM::new();
+        let InterpretResult::RuntimeError(error) = vm.interpret("var a;\nprint -a;".to_string(), false) else {
+            panic!("the source fails at runtime");
+        };
+        let json: serde_json::Value = serde_json::from_str(&ErrorFormat::Json.runtime_error("a.lox", &error)).unwrap();
+        assert_eq!(json["line"], 2);
+        assert_eq!(json["span"]["start"], 13);
+        assert_eq!(json["severity"], "error");
+        assert_eq!(json["code"], "E0101");
+
+        let diagnostics = lint("fun f() {}".to_string()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&ErrorFormat::Json.warning("a.lox", &diagnostics[0])).unwrap();
+        assert_eq!(json["severity"], "warning");
+        assert!(json["span"].is_null());
+        assert!(ErrorFormat::Json.file_error("a.lox", "compilation failed").contains(r#""line":null"#));
+    }
+}
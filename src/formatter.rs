@@ -0,0 +1,212 @@
+use crate::scanner::{Scanner, Token, TokenType};
+
+const INDENT: &str = "    ";
+
+/// Reprint the source with consistent indentation and spacing, comments are kept
+/// and runs of blank lines collapse to one.
+pub fn format_source(source: &str) -> Result<String, String> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut tokens = vec![];
+    loop {
+        match scanner.scan_token() {
+            Ok(token) if token.token_type == TokenType::Eof => break,
+            Ok(token) => tokens.push(token),
+            Err(error) => return Err(format!("[line {}] Error: {}", error.line, error.message)),
+        }
+    }
+
+    let mut formatter = Formatter::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).map(|token| token.token_type);
+        formatter.token(token, scanner.lexeme(token), next);
+    }
+
+    Ok(formatter.finish())
+}
+
+struct Formatter {
+    output: String,
+    line: String,
+    indent: usize,
+    paren_depth: usize,
+    previous: Option<Token>,
+    previous_unary: bool,
+}
+
+impl Formatter {
+    fn new() -> Self {
+        Self {
+            output: String::new(),
+            line: String::new(),
+            indent: 0,
+            paren_depth: 0,
+            previous: None,
+            previous_unary: false,
+        }
+    }
+
+    fn token(&mut self, token: &Token, lexeme: &str, next: Option<TokenType>) {
+        let token_type = token.token_type;
+        let previous_line = self.previous.as_ref().map(|previous| previous.line);
+
+        if token_type == TokenType::RightBrace {
+            self.flush();
+            self.indent = self.indent.saturating_sub(1);
+        }
+
+        if token_type == TokenType::Comment {
+            if previous_line != Some(token.line) {
+                self.flush();
+            } else if self.line.is_empty() && self.output.ends_with('\n') {
+                // trailing comment of a line which is already complete, reopen it
+                self.output.pop();
+                let line_start = self.output.rfind('\n').map_or(0, |pos| pos + 1);
+                self.line = self.output.split_off(line_start);
+            }
+        }
+
+        if self.line.is_empty() {
+            self.blank_line(token, lexeme, previous_line);
+            self.line.push_str(&INDENT.repeat(self.indent));
+        } else if self.needs_space(token_type) {
+            self.line.push(' ');
+        }
+        self.line.push_str(lexeme);
+
+        self.previous_unary = match token_type {
+            TokenType::Bang => true,
+            TokenType::Minus => self.is_operand_expected(),
+            _ => false,
+        };
+        self.previous = Some(token.clone());
+
+        match token_type {
+            TokenType::LeftParen => self.paren_depth += 1,
+            TokenType::RightParen => self.paren_depth = self.paren_depth.saturating_sub(1),
+            TokenType::Semicolon if self.paren_depth == 0 => self.flush(),
+            TokenType::LeftBrace => {
+                self.indent += 1;
+                self.flush();
+            }
+            TokenType::RightBrace
+                if !matches!(next, Some(TokenType::Else | TokenType::Catch | TokenType::Semicolon)) =>
+            {
+                self.flush()
+            }
+            TokenType::Comment => self.flush(),
+            _ => (),
+        }
+    }
+
+    /// Keep one blank line where the source had at least one.
+    fn blank_line(&mut self, token: &Token, lexeme: &str, previous_line: Option<i32>) {
+        // strings can span lines, the token line is the one they end on
+        let first_line = token.line - lexeme.matches('\n').count() as i32;
+        if let Some(previous_line) = previous_line
+            && first_line > previous_line + 1
+            && token.token_type != TokenType::RightBrace
+            && !self.output.ends_with("{\n")
+        {
+            self.output.push('\n');
+        }
+    }
+
+    fn needs_space(&self, token_type: TokenType) -> bool {
+        let Some(previous) = &self.previous else {
+            return false;
+        };
+
+        if self.previous_unary
+            || matches!(
+                previous.token_type,
+                TokenType::LeftParen
+                    | TokenType::LeftBracket
+                    | TokenType::Dot
+                    | TokenType::DotDot
+                    | TokenType::DotDotDot
+            )
+        {
+            return false;
+        }
+
+        match token_type {
+            TokenType::Semicolon
+            | TokenType::Colon
+            | TokenType::Comma
+            | TokenType::RightParen
+            | TokenType::RightBracket
+            | TokenType::Dot
+            | TokenType::DotDot => false,
+            TokenType::LeftParen => !matches!(
+                previous.token_type,
+                TokenType::Identifier | TokenType::RightParen
+            ),
+            _ => true,
+        }
+    }
+
+    /// A minus after these starts an operand, so it is the unary one.
+    fn is_operand_expected(&self) -> bool {
+        match &self.previous {
+            None => true,
+            Some(previous) => !matches!(
+                previous.token_type,
+                TokenType::Identifier
+                    | TokenType::Number
+                    | TokenType::String
+                    | TokenType::RightParen
+                    | TokenType::RightBracket
+                    | TokenType::True
+                    | TokenType::False
+                    | TokenType::Nil
+                    | TokenType::This
+            ),
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.line.is_empty() {
+            return;
+        }
+
+        self.output.push_str(self.line.trim_end());
+        self.output.push('\n');
+        self.line.clear();
+    }
+
+    fn finish(mut self) -> String {
+        self.flush();
+        self.output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = "fun f(a,b){\n// add\nreturn a+b;}\n\n\n\nvar x=-f(1 , 2);if(x<0){print x;}else{print -x;} // done\n";
+        let expected = "fun f(a, b) {\n    // add\n    return a + b;\n}\n\nvar x = -f(1, 2);\nif (x < 0) {\n    print x;\n} else {\n    print -x;\n} // done\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+        assert_eq!(format_source(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_for() {
+        let source = "for(var i=0;i<10;i=i+1) print i;\nfor (var c in 0..3) {}";
+        let expected = "for (var i = 0; i < 10; i = i + 1) print i;\nfor (var c in 0..3) {\n}\n";
+        assert_eq!(format_source(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_format_list() {
+        let source = "var l=[ 1,-2 ,[]].map( f ).length;";
+        assert_eq!(format_source(source).unwrap(), "var l = [1, -2, []].map(f).length;\n");
+    }
+
+    #[test]
+    fn test_format_scan_error() {
+        assert!(format_source("var s = \"open").is_err());
+    }
+}
@@ -0,0 +1,85 @@
+use std::{fs, io, path::{Path, PathBuf}};
+
+/// The files matching the pattern, sorted. Only the file name can contain the wildcards
+/// `*` (any chars) and `?` (one char), so patterns work in shells which don't expand them.
+/// A path without wildcards is returned as it is, even if it doesn't exist.
+pub fn expand(pattern: &str) -> io::Result<Vec<PathBuf>> {
+    let path = Path::new(pattern);
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let matching = entry.file_name().to_str().is_some_and(|file_name| matches(name, file_name));
+        if matching && entry.file_type()?.is_file() {
+            files.push(path.with_file_name(entry.file_name()));
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Whether the name matches the pattern with `*` and `?` wildcards.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // after a `*` the pattern index after it and the name index it matches up to
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                // let the `*` match one more char
+                Some((after, matched)) => {
+                    star = Some((after, matched + 1));
+                    p = after;
+                    n = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        assert!(matches("*.lox", "a.lox"));
+        assert!(matches("*.lox", ".lox"));
+        assert!(!matches("*.lox", "a.loxer"));
+        assert!(matches("a?c*", "abc"));
+        assert!(matches("*b*b", "abab"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("größe*", "größe.lox"));
+    }
+
+    #[test]
+    fn test_expand() {
+        let sources = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/codegen");
+        let files = expand(&format!("{}/*.lox", sources.display())).unwrap();
+        assert!(files.len() > 1);
+        assert!(files.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(files.iter().all(|file| file.extension().is_some_and(|extension| extension == "lox")));
+        assert_eq!(expand("missing.lox").unwrap(), vec![PathBuf::from("missing.lox")]);
+    }
+}
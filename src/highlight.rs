@@ -0,0 +1,134 @@
+use serde::Serialize;
+
+use crate::{
+    op_code::Span,
+    scanner::{Scanner, TokenType},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum TokenClass {
+    Keyword,
+    String,
+    Number,
+    Identifier,
+    Operator,
+    Punctuation,
+    Comment,
+    /// Text the scanner could not make sense of, e.g. an unterminated string.
+    Error,
+}
+
+impl From<TokenType> for TokenClass {
+    fn from(token_type: TokenType) -> Self {
+        match token_type {
+            TokenType::LeftParen
+            | TokenType::RightParen
+            | TokenType::LeftBrace
+            | TokenType::RightBrace
+            | TokenType::LeftBracket
+            | TokenType::RightBracket
+            | TokenType::Comma
+            | TokenType::Dot
+            | TokenType::Semicolon
+            | TokenType::Colon
+            | TokenType::FatArrow
+            | TokenType::Eof => TokenClass::Punctuation,
+            TokenType::DotDot
+            | TokenType::DotDotDot
+            | TokenType::Minus
+            | TokenType::Plus
+            | TokenType::Slash
+            | TokenType::Star
+            | TokenType::Bang
+            | TokenType::BangEqual
+            | TokenType::BangEqualEqual
+            | TokenType::Equal
+            | TokenType::EqualEqual
+            | TokenType::EqualEqualEqual
+            | TokenType::Greater
+            | TokenType::GreaterEqual
+            | TokenType::Less
+            | TokenType::LessEqual => TokenClass::Operator,
+            TokenType::Identifier => TokenClass::Identifier,
+            TokenType::String => TokenClass::String,
+            TokenType::Number => TokenClass::Number,
+            TokenType::Comment => TokenClass::Comment,
+            TokenType::And
+            | TokenType::Class
+            | TokenType::Else
+            | TokenType::False
+            | TokenType::For
+            | TokenType::Fun
+            | TokenType::If
+            | TokenType::In
+            | TokenType::Nil
+            | TokenType::Or
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Super
+            | TokenType::This
+            | TokenType::Throw
+            | TokenType::True
+            | TokenType::Try
+            | TokenType::Var
+            | TokenType::While
+            | TokenType::Catch
+            | TokenType::Yield
+            | TokenType::Const
+            | TokenType::Match
+            | TokenType::Break
+            | TokenType::Continue => TokenClass::Keyword,
+        }
+    }
+}
+
+/// Classify the source for syntax highlighting, spans are byte offsets.
+/// Whitespace is not part of any span.
+pub fn classify_tokens(source: &str) -> Vec<(Span, TokenClass)> {
+    let mut scanner = Scanner::with_comments(source);
+    let mut classes = vec![];
+    loop {
+        match scanner.scan_token() {
+            Ok(token) if token.token_type == TokenType::Eof => break,
+            Ok(token) => classes.push((
+                Span { start: token.start, length: token.length },
+                token.token_type.into(),
+            )),
+            Err(error) => classes.push((
+                Span { start: error.start, length: error.length },
+                TokenClass::Error,
+            )),
+        }
+    }
+
+    classes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_tokens() {
+        let classes: Vec<_> = classify_tokens("var x = \"s\" + 1; // c")
+            .into_iter()
+            .map(|(span, class)| (span.start, class))
+            .collect();
+        assert_eq!(classes, vec![
+            (0, TokenClass::Keyword),
+            (4, TokenClass::Identifier),
+            (6, TokenClass::Operator),
+            (8, TokenClass::String),
+            (12, TokenClass::Operator),
+            (14, TokenClass::Number),
+            (15, TokenClass::Punctuation),
+            (17, TokenClass::Comment),
+        ]);
+    }
+
+    #[test]
+    fn test_classify_error() {
+        let classes = classify_tokens("print \"open");
+        assert_eq!(classes[1], (Span { start: 6, length: 5 }, TokenClass::Error));
+    }
+}
@@ -0,0 +1,30 @@
+pub mod ast;
+pub mod bundle;
+pub mod bytecode;
+pub mod chunk;
+pub mod clock;
+pub mod compare;
+pub mod compiler;
+pub mod coverage;
+pub mod debug;
+pub mod dump;
+pub mod error_codes;
+pub mod error_format;
+pub mod formatter;
+pub mod glob;
+pub mod highlight;
+pub mod lint;
+#[cfg(feature = "net")]
+mod net;
+pub mod op_code;
+mod parser;
+mod peephole;
+pub mod repl;
+mod resolver;
+pub mod runtime;
+pub mod scanner;
+mod template;
+pub mod value;
+pub mod version;
+pub mod vm;
+pub mod watch;
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+
+use crate::{
+    compiler::{compile, CompileError, Diagnostic, ResolverData},
+    op_code::OpCode,
+    value::Value,
+};
+
+/// Compile the source and report suspicious code without running it.
+pub fn lint(source: String) -> Result<Vec<Diagnostic>, Vec<CompileError>> {
+    compile(source, false).map(|program| program.diagnostics)
+}
+
+/// The findings about a resolved program, sorted by line.
+pub(crate) fn diagnostics(data: &ResolverData) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+
+    unused_functions(data, &mut diagnostics);
+    dead_stores(data, &mut diagnostics);
+    constant_conditions(data, &mut diagnostics);
+    wrong_arity_calls(data, &mut diagnostics);
+
+    diagnostics.sort_by_key(|diagnostic| diagnostic.line);
+    diagnostics
+}
+
+fn unused_functions(data: &ResolverData, diagnostics: &mut Vec<Diagnostic>) {
+    for function in &data.global_functions {
+        if !data.global_reads.contains(&function.name) {
+            diagnostics.push(Diagnostic {
+                line: function.line,
+                message: format!("Function '{}' is never used.", function.name),
+            });
+        }
+    }
+}
+
+fn dead_stores(data: &ResolverData, diagnostics: &mut Vec<Diagnostic>) {
+    for (name, line) in &data.unread_locals {
+        diagnostics.push(Diagnostic {
+            line: *line,
+            message: format!("Value of local '{}' is never read.", name),
+        });
+    }
+}
+
+fn constant_conditions(data: &ResolverData, diagnostics: &mut Vec<Diagnostic>) {
+    for (line, codes) in &data.conditions {
+        if let Some(value) = evaluate_constant(codes) {
+            let always = !matches!(value, Value::Nil | Value::Bool(false));
+            diagnostics.push(Diagnostic {
+                line: *line,
+                message: format!("Condition is always {}.", always),
+            });
+        }
+    }
+}
+
+fn wrong_arity_calls(data: &ResolverData, diagnostics: &mut Vec<Diagnostic>) {
+    // a redefined name can have several arities, only unambiguous ones are checked
+    let mut arities: HashMap<&str, Option<(usize, bool)>> = HashMap::new();
+    for function in &data.global_functions {
+        let arity = (function.arity, function.variadic);
+        arities
+            .entry(function.name.as_str())
+            .and_modify(|known| *known = known.filter(|known| *known == arity))
+            .or_insert(Some(arity));
+    }
+
+    for call in &data.global_calls {
+        let expected = match arities.get(call.name.as_str()) {
+            Some(Some((arity, false))) if *arity != call.arg_count => arity.to_string(),
+            Some(Some((arity, true))) if *arity > call.arg_count => format!("at least {arity}"),
+            _ => continue,
+        };
+        diagnostics.push(Diagnostic {
+            line: call.line,
+            message: format!(
+                "Function '{}' expects {} arguments but is called with {}.",
+                call.name, expected, call.arg_count
+            ),
+        });
+    }
+}
+
+/// Evaluate code which only consists of literals and operators, `None` otherwise.
+fn evaluate_constant(codes: &[OpCode]) -> Option<Value> {
+    let mut stack = vec![];
+    for code in codes {
+        let value = match code {
+            OpCode::Constant(n) => Value::Number(*n),
+            OpCode::Bool(b) => Value::Bool(*b),
+            OpCode::String(s) => s.as_str().into(),
+            OpCode::Nil => Value::Nil,
+            OpCode::Not => Value::Bool(matches!(stack.pop()?, Value::Nil | Value::Bool(false))),
+            OpCode::Negate => match stack.pop()? {
+                Value::Number(n) => Value::Number(-n),
+                _ => return None,
+            },
+            OpCode::Equal | OpCode::StrictEqual => {
+                let b = stack.pop()?;
+                Value::Bool(stack.pop()? == b)
+            }
+            OpCode::Greater | OpCode::Less | OpCode::GreaterEqual | OpCode::LessEqual
+            | OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
+                let (Value::Number(b), Value::Number(a)) = (stack.pop()?, stack.pop()?) else {
+                    return None;
+                };
+                match code {
+                    OpCode::Greater => Value::Bool(a > b),
+                    OpCode::Less => Value::Bool(a < b),
+                    OpCode::GreaterEqual => Value::Bool(a >= b),
+                    OpCode::LessEqual => Value::Bool(a <= b),
+                    OpCode::Add => Value::Number(a + b),
+                    OpCode::Subtract => Value::Number(a - b),
+                    OpCode::Multiply => Value::Number(a * b),
+                    _ => Value::Number(a / b),
+                }
+            }
+            _ => return None,
+        };
+        stack.push(value);
+    }
+
+    if stack.len() == 1 { stack.pop() } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messages(source: &str) -> Vec<String> {
+        lint(source.to_string())
+            .unwrap()
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_unused_function_and_arity() {
+        let found = messages("fun unused() {}\nfun add(a, b) { return a + b; }\nprint add(1);");
+        assert_eq!(found, vec![
+            "Function 'unused' is never used.",
+            "Function 'add' expects 2 arguments but is called with 1.",
+        ]);
+
+        let found = messages("fun log(format, ...args) {}\nlog(1, 2, 3);\nlog();");
+        assert_eq!(found, vec!["Function 'log' expects at least 1 arguments but is called with 0."]);
+    }
+
+    #[test]
+    fn test_dead_store() {
+        let found = messages("{ var a = 1; var b = 2; print b; }");
+        assert_eq!(found, vec!["Value of local 'a' is never read."]);
+    }
+
+    #[test]
+    fn test_constant_conditions() {
+        let found = messages("var x = 1;\nif (1 > 2) print x;\nwhile (!nil) print x;\nif (x > 2) print x;");
+        assert_eq!(found, vec!["Condition is always false.", "Condition is always true."]);
+    }
+
+    #[test]
+    fn test_compile_error() {
+        assert!(lint("print ;".to_string()).is_err());
+    }
+}
@@ -1,77 +1,496 @@
 // (setq rustic-run-arguments "-- c:/tmp/simple.lox")
-use std::{env, fs::{self, File}, io::Write, process::exit};
+use std::{env, fs::{self, File}, io::Write, path::{Path, PathBuf}, process::exit};
 
-use compiler::compile;
-use debug::Debugger;
-use value::Function;
-use vm::{InterpretResult, VM};
-
-mod chunk;
-mod compiler;
-mod debug;
-mod op_code;
-mod scanner;
-mod value;
-mod vm;
+use rlox::{
+    bundle, bytecode, compare,
+    compiler::{compile, CompileError, CompiledProgram},
+    debug::Debugger,
+    dump, error_codes,
+    error_format::ErrorFormat,
+    formatter, glob, lint, repl,
+    value::Function,
+    version, watch,
+    vm::{InterpretResult, VM},
+};
 
 fn main() {
+    run_bundled();
+
     let arguments: Vec<String> = env::args().collect();
-    if arguments.len() >= 2 {
+    if arguments.len() == 2 && arguments[1] == "--version" {
+        println!("{}", version::build_info());
+    } else if arguments.len() >= 3 && arguments[1] == "bundle" {
+        bundle_file(&arguments);
+    } else if arguments.len() >= 3 && arguments[1] == "explain" {
+        explain(&arguments[2]);
+    } else if arguments.len() >= 3 && arguments[1] == "upgrade" {
+        upgrade_file(&arguments[2]);
+    } else if arguments.len() >= 3 && arguments[1] == "compare" {
+        compare_file(&arguments);
+    } else if arguments.len() >= 3 && arguments[1] == "fmt" {
+        let filename = &arguments[arguments.len() - 1];
+        let file = fs::read_to_string(filename)
+            .unwrap_or_else(|_| panic!("file '{}' not found", filename));
+        format(filename, file, &arguments);
+    } else if arguments.len() >= 3 && arguments[1] == "lint" {
+        let filename = &arguments[arguments.len() - 1];
+        let file = fs::read_to_string(filename)
+            .unwrap_or_else(|_| panic!("file '{}' not found", filename));
+        lint_file(filename, file, error_format(&arguments));
+    } else if arguments.len() >= 3 && arguments[1] == "watch" {
+        let filename = &arguments[arguments.len() - 1];
+        let keep_globals = arguments.contains(&"--keep-globals".to_string());
+        let debug_switch = arguments.contains(&"--debug".to_string())
+            || arguments.contains(&"-d".to_string());
+        watch::run(filename, keep_globals, debug_switch);
+    } else if arguments.len() >= 3 && arguments.contains(&"--check".to_string()) {
+        check_files(&arguments);
+    } else if arguments.len() >= 3 && arguments.contains(&"--compile".to_string()) {
+        compile_files(&arguments);
+    } else if arguments.len() >= 2 {
         let filename = &arguments[arguments.len() - 1];
     
         let debug_switch = arguments.len() >= 3
             && (arguments.contains(&"--debug".to_string())
                 || arguments.contains(&"-d".to_string()));
+        let options = RunOptions {
+            seed: deterministic_seed(&arguments),
+            allow_exec: arguments.contains(&"--allow-exec".to_string()),
+            strict_globals: arguments.contains(&"--strict-globals".to_string()),
+            safe_integers: arguments.contains(&"--safe-integers".to_string()),
+            strict_equality: arguments.contains(&"--strict-equality".to_string()),
+            coverage: arguments.contains(&"--coverage".to_string()),
+            error_format: error_format(&arguments),
+            lcov: arguments.iter().position(|argument| argument == "--lcov").map(|index| {
+                arguments.get(index + 1).cloned().unwrap_or_else(|| panic!("--lcov needs an output file"))
+            }),
+        };
 
         if arguments.len() >= 3 && arguments.contains(&"--run".to_string()) {
-            let file = fs::read(filename).expect(&format!("file '{}' not found", filename));
-            run(file, debug_switch);
+            let file = fs::read(filename)
+                .unwrap_or_else(|_| panic!("file '{}' not found", filename));
+            run(filename, file, debug_switch, &options);
         } else {
-            let file = fs::read_to_string(filename).expect(&format!("file '{}' not found", filename));
-            if arguments.contains(&"--compile".to_string()) {
-                only_compile(filename, file, debug_switch);
+            let file = fs::read_to_string(filename)
+                .unwrap_or_else(|_| panic!("file '{}' not found", filename));
+            if arguments.contains(&"--tokens".to_string()) {
+                println!("{}", dump::dump_tokens(&file));
+            } else if arguments.contains(&"--ast".to_string()) {
+                dump_chunk(filename, file, options.error_format);
             } else {
-                compile_and_run(file, debug_switch);
+                compile_and_run(filename, file, debug_switch, &options);
             }
         }
     } else {
-        eprintln!("missing filename");
+        repl::run();
+    }
+}
+
+/// Flags followed by a value, the value is not a file.
+const VALUE_FLAGS: [&str; 5] = ["-o", "--output", "--out-dir", "--seed", "--lcov"];
+
+/// The arguments which are neither flags nor their values.
+fn file_arguments(arguments: &[String]) -> Vec<&str> {
+    let mut files = vec![];
+    let mut arguments = arguments.iter().skip(1);
+    while let Some(argument) = arguments.next() {
+        if VALUE_FLAGS.contains(&argument.as_str()) {
+            arguments.next();
+        } else if !argument.starts_with('-') {
+            files.push(argument.as_str());
+        }
+    }
+    files
+}
+
+/// The argument after the first of the flags.
+fn option_value<'a>(arguments: &'a [String], flags: &[&str]) -> Option<&'a str> {
+    let index = arguments.iter().position(|argument| flags.contains(&argument.as_str()))?;
+    let value = arguments.get(index + 1).unwrap_or_else(|| panic!("{} needs a value", arguments[index]));
+    Some(value)
+}
+
+/// `--error-format=json` prints the errors and warnings as JSON lines.
+fn error_format(arguments: &[String]) -> ErrorFormat {
+    let Some(name) = arguments.iter().find_map(|argument| argument.strip_prefix("--error-format=")) else {
+        return ErrorFormat::Human;
+    };
+    ErrorFormat::from_name(name).unwrap_or_else(|| {
+        eprintln!("unknown error format '{name}', use human or json");
+        exit(64);
+    })
+}
+
+/// `--deterministic [--seed n]`, the seed for `random()` defaults to 0.
+fn deterministic_seed(arguments: &[String]) -> Option<u64> {
+    if !arguments.contains(&"--deterministic".to_string()) {
+        return None;
     }
+
+    let seed = match arguments.iter().position(|argument| argument == "--seed") {
+        Some(index) => {
+            let seed = arguments.get(index + 1).and_then(|seed| seed.parse().ok());
+            seed.unwrap_or_else(|| panic!("--seed needs a positive integer"))
+        }
+        None => 0,
+    };
+    Some(seed)
+}
+
+#[derive(Default)]
+struct RunOptions {
+    seed: Option<u64>,
+    /// `--allow-exec`, scripts can run commands with `exec()` and `shell()`.
+    allow_exec: bool,
+    /// `--strict-globals`, using an undeclared global is a compile error.
+    strict_globals: bool,
+    /// `--safe-integers`, integer arithmetic beyond 2^53 is a runtime error.
+    safe_integers: bool,
+    /// `--strict-equality`, `==` of values of different types is a runtime error.
+    strict_equality: bool,
+    /// `--coverage`, prints how often each line ran to stderr.
+    coverage: bool,
+    /// `--lcov file`, writes how often each line ran as an lcov tracefile.
+    lcov: Option<String>,
+    error_format: ErrorFormat,
 }
 
-fn compile_and_run(file: String, debug: bool) {
+fn new_vm(options: &RunOptions) -> VM {
     let mut vm = VM::new();
-    match vm.interpret(file, debug) {
+    if let Some(seed) = options.seed {
+        vm.set_deterministic(seed);
+    }
+    vm.set_allow_exec(options.allow_exec);
+    vm.set_strict_globals(options.strict_globals);
+    vm.set_safe_integers(options.safe_integers);
+    vm.set_strict_equality(options.strict_equality);
+    if options.coverage || options.lcov.is_some() {
+        vm.enable_coverage();
+    }
+    vm
+}
+
+fn compile_and_run(filepath: &str, file: String, debug: bool, options: &RunOptions) {
+    let mut vm = new_vm(options);
+    let result = vm.interpret(file, debug);
+    finish_run(&vm, result, filepath, options);
+}
+
+/// Reports the coverage, then exits if the run failed.
+fn finish_run(vm: &VM, result: InterpretResult, filepath: &str, options: &RunOptions) {
+    if let Some(coverage) = vm.coverage() {
+        if options.coverage {
+            eprint!("{}", coverage.report());
+        }
+        if let Some(lcov) = &options.lcov {
+            fs::write(lcov, coverage.lcov(filepath)).expect("lcov file could not be written.");
+        }
+    }
+
+    match result {
         InterpretResult::Ok => (),
-        InterpretResult::CompileError => exit(65),
-        InterpretResult::RuntimeError => exit(70),
+        InterpretResult::CompileError(errors) => {
+            print_compile_errors(filepath, &errors, options.error_format);
+            exit(65);
+        }
+        InterpretResult::RuntimeError(error) => {
+            eprintln!("{}", options.error_format.runtime_error(filepath, &error));
+            exit(70);
+        }
     }
 }
 
-fn only_compile(filepath: &str, file: String, debug: bool) {
-    match compile(file, debug) {
-        Ok(function) => {
-            if debug {
-                let mut debugger = Debugger::new();
-                debugger.disassemble_chunk(&function, "code");
+fn print_compile_errors(filepath: &str, errors: &[CompileError], error_format: ErrorFormat) {
+    for error in errors {
+        eprintln!("{}", error_format.compile_error(filepath, error));
+    }
+}
+
+/// `--compile [-o output | --out-dir directory] files`, the files can be patterns like
+/// `src/*.lox`. The failures are summarized, any of them exits with 65.
+fn compile_files(arguments: &[String]) {
+    let debug = arguments.contains(&"--debug".to_string()) || arguments.contains(&"-d".to_string());
+    let source_map = arguments.contains(&"--source-map".to_string());
+    let output = option_value(arguments, &["-o", "--output"]).map(Path::new);
+    let out_dir = option_value(arguments, &["--out-dir"]).map(Path::new);
+    let error_format = error_format(arguments);
+
+    let sources = source_files(arguments);
+    if output.is_some() && sources.len() > 1 {
+        eprintln!("-o names a single file, use --out-dir for {} files", sources.len());
+        exit(64);
+    }
+
+    let mut failed = 0;
+    for source in &sources {
+        if let Err(err) = compile_file(source, output, out_dir, debug, source_map, error_format) {
+            eprintln!("{}", error_format.file_error(&source.to_string_lossy(), &err));
+            failed += 1;
+        }
+    }
+    if sources.len() > 1 {
+        println!("{} compiled, {failed} failed", sources.len() - failed);
+    }
+    if failed > 0 {
+        exit(65);
+    }
+}
+
+/// `--check files`, compiles and lints the files without running or writing anything,
+/// for checks on save and in CI. Exits with 65 if any file doesn't compile.
+fn check_files(arguments: &[String]) {
+    let error_format = error_format(arguments);
+    let mut failed = 0;
+    for source in source_files(arguments) {
+        let filepath = source.to_string_lossy();
+        let result = fs::read_to_string(&source)
+            .map_err(|err| format!("could not be read, {err}"))
+            .and_then(|file| lint::lint(file).map_err(|errors| {
+                print_compile_errors(&filepath, &errors, error_format);
+                "compilation failed".to_string()
+            }));
+        match result {
+            Ok(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    let warning = error_format.warning(&filepath, diagnostic);
+                    match error_format {
+                        ErrorFormat::Human => eprintln!("{filepath}: {warning}"),
+                        ErrorFormat::Json => eprintln!("{warning}"),
+                    }
+                }
             }
+            Err(err) => {
+                eprintln!("{}", error_format.file_error(&filepath, &err));
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        exit(65);
+    }
+}
 
-            let path = filepath.replace(".lox", ".loxer");
-            let data = bson::to_vec(&function).expect("Serialize to bson failed.");
-            let mut file = File::create(&path).expect("loxer file creation failed.");
-            file.write_all(&data).expect("loxer file could not be written.");
-            println!("file {} written", path);
+/// The files of the arguments with the patterns expanded, exits if there are none.
+fn source_files(arguments: &[String]) -> Vec<PathBuf> {
+    let mut sources = vec![];
+    for pattern in file_arguments(arguments) {
+        match glob::expand(pattern) {
+            Ok(files) if files.is_empty() => eprintln!("no files match {pattern}"),
+            Ok(files) => sources.extend(files),
+            Err(err) => eprintln!("{pattern} could not be read: {err}"),
         }
-        Err(_) => eprintln!("compilation failed"),
     }
+    if sources.is_empty() {
+        eprintln!("no source files given");
+        exit(64);
+    }
+    sources
 }
 
-fn run(file: Vec<u8>, debug: bool) {
-    let function : Function = bson::from_slice(&file).unwrap();
-    let mut vm = VM::new();
-    match vm.run_function(function, debug) {
-        InterpretResult::Ok => (),
-        InterpretResult::CompileError => exit(65),
-        InterpretResult::RuntimeError => exit(70),
+fn compile_file(
+    source: &Path,
+    output: Option<&Path>,
+    out_dir: Option<&Path>,
+    debug: bool,
+    source_map: bool,
+    error_format: ErrorFormat,
+) -> Result<(), String> {
+    let file = fs::read_to_string(source).map_err(|err| format!("could not be read, {err}"))?;
+    let output = bytecode::output_path(source, output, out_dir)?;
+    let mut function = match compile(file, debug) {
+        Ok(CompiledProgram { main, .. }) => main,
+        Err(errors) => {
+            print_compile_errors(&source.to_string_lossy(), &errors, error_format);
+            return Err("compilation failed".to_string());
+        }
+    };
+    if !source_map {
+        function.strip_source_map();
     }
+
+    if debug {
+        let mut debugger = Debugger::new();
+        debugger.disassemble_chunk(&function, "code");
+    }
+
+    if let Some(directory) = output.parent() {
+        fs::create_dir_all(directory).map_err(|err| format!("{} could not be created, {err}", directory.display()))?;
+    }
+    let data = bytecode::encode(&function);
+    let mut file = File::create(&output).map_err(|err| format!("{} could not be created, {err}", output.display()))?;
+    file.write_all(&data).map_err(|err| format!("{} could not be written, {err}", output.display()))?;
+    println!("file {} written", output.display());
+    Ok(())
+}
+
+/// An executable made by `bundle` runs its program and exits, the arguments are ignored.
+fn run_bundled() {
+    let Ok(executable) = env::current_exe() else {
+        return;
+    };
+    match bundle::read_bundle(&executable) {
+        Ok(Some(function)) => {
+            run_function(&executable.to_string_lossy(), function, false, &RunOptions::default());
+            exit(0);
+        }
+        Ok(None) => (),
+        Err(err) => {
+            eprintln!("bundled program could not be read: {err}");
+            exit(65);
+        }
+    }
+}
+
+/// `bundle file [-o output]`, the output defaults to the file name without `.lox`.
+fn bundle_file(arguments: &[String]) {
+    let filepath = &arguments[2];
+    let output = match arguments.iter().position(|argument| argument == "-o") {
+        Some(index) => arguments.get(index + 1).cloned()
+            .unwrap_or_else(|| panic!("-o needs an output file")),
+        None => filepath.trim_end_matches(".lox").to_string(),
+    };
+    let file = fs::read_to_string(filepath)
+        .unwrap_or_else(|_| panic!("file '{}' not found", filepath));
+
+    let mut function = match compile(file, false) {
+        Ok(CompiledProgram { main, .. }) => main,
+        Err(errors) => {
+            print_compile_errors(filepath, &errors, error_format(arguments));
+            exit(65);
+        }
+    };
+    function.strip_source_map();
+    let executable = env::current_exe().expect("path of the rlox executable");
+    match bundle::write_bundle(&executable, &function, Path::new(&output)) {
+        Ok(()) => println!("file {} written", output),
+        Err(err) => {
+            eprintln!("bundle could not be written: {err}");
+            exit(74);
+        }
+    }
+}
+
+/// `compare file --reference interpreter`, runs the file with rlox and the reference
+/// interpreter, for example clox or jlox, and reports where their outputs differ.
+fn compare_file(arguments: &[String]) {
+    let filepath = Path::new(&arguments[2]);
+    let reference = match arguments.iter().position(|argument| argument == "--reference") {
+        Some(index) => arguments.get(index + 1)
+            .unwrap_or_else(|| panic!("--reference needs an interpreter")),
+        None => panic!("compare needs --reference interpreter"),
+    };
+    let executable = env::current_exe().expect("path of the rlox executable");
+
+    let run = |interpreter: &Path| {
+        compare::run(interpreter, filepath).unwrap_or_else(|err| {
+            eprintln!("{} could not be run: {err}", interpreter.display());
+            exit(74);
+        })
+    };
+    let expected = run(Path::new(reference));
+    let actual = run(&executable);
+
+    let differences = compare::differences(&expected, &actual);
+    if differences.is_empty() {
+        println!("outputs match");
+    } else {
+        for difference in &differences {
+            println!("{difference}");
+        }
+        exit(1);
+    }
+}
+
+/// `fmt [--check | --stdout] file`, without a flag the file is rewritten in place.
+fn format(filepath: &str, file: String, arguments: &[String]) {
+    let formatted = match formatter::format_source(&file) {
+        Ok(formatted) => formatted,
+        Err(message) => {
+            eprintln!("{message}");
+            exit(65);
+        }
+    };
+
+    if arguments.contains(&"--check".to_string()) {
+        if formatted != file {
+            eprintln!("file {} is not formatted", filepath);
+            exit(1);
+        }
+    } else if arguments.contains(&"--stdout".to_string()) {
+        print!("{formatted}");
+    } else if formatted != file {
+        fs::write(filepath, formatted).expect("formatted file could not be written.");
+        println!("file {} formatted", filepath);
+    }
+}
+
+fn lint_file(filepath: &str, file: String, error_format: ErrorFormat) {
+    match lint::lint(file) {
+        Ok(diagnostics) => {
+            for diagnostic in &diagnostics {
+                eprintln!("{}", error_format.warning(filepath, diagnostic));
+            }
+
+            if !diagnostics.is_empty() {
+                exit(1);
+            }
+        }
+        Err(errors) => {
+            print_compile_errors(filepath, &errors, error_format);
+            exit(65);
+        }
+    }
+}
+
+fn dump_chunk(filepath: &str, file: String, error_format: ErrorFormat) {
+    match compile(file, false) {
+        Ok(program) => println!("{}", dump::dump_chunk(&program.main)),
+        Err(errors) => {
+            print_compile_errors(filepath, &errors, error_format);
+            exit(65);
+        }
+    }
+}
+
+fn run(filepath: &str, file: Vec<u8>, debug: bool, options: &RunOptions) {
+    match bytecode::decode(&file) {
+        Ok(function) => run_function(filepath, function, debug, options),
+        Err(message) => {
+            eprintln!("{message}");
+            exit(65);
+        }
+    }
+}
+
+/// `explain E0001`, describes the error with the code, with examples.
+fn explain(code: &str) {
+    match error_codes::explain(code) {
+        Some(error_code) => println!("{}: {}\n\n{}", error_code.code, error_code.title, error_code.explanation),
+        None => {
+            eprintln!("unknown error code '{code}', the errors show their codes like [E0001]");
+            exit(64);
+        }
+    }
+}
+
+/// `upgrade file.loxer`, rewrites the file with the current bytecode version.
+fn upgrade_file(filepath: &str) {
+    let file = fs::read(filepath)
+        .unwrap_or_else(|_| panic!("file '{}' not found", filepath));
+    match bytecode::upgrade(&file) {
+        Ok(function) => {
+            fs::write(filepath, bytecode::encode(&function)).expect("loxer file could not be written.");
+            println!("file {} upgraded", filepath);
+        }
+        Err(message) => {
+            eprintln!("{message}");
+            exit(65);
+        }
+    }
+}
+
+fn run_function(filepath: &str, function: Function, debug: bool, options: &RunOptions) {
+    let mut vm = new_vm(options);
+    let result = vm.run_function(function, debug);
+    finish_run(&vm, result, filepath, options);
 }
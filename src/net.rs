@@ -0,0 +1,208 @@
+use std::{collections::HashMap, io::{self, Read, Write}, mem, net::{TcpStream, ToSocketAddrs}, str, time::Duration};
+
+use crate::value::Value;
+
+/// Bytes read by one `tcp_recv()`.
+const RECEIVE_SIZE: usize = 4096;
+
+/// How long connecting, sending and receiving wait before they fail.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The open connections of a VM, scripts only see their handles.
+pub(crate) struct Sockets {
+    streams: HashMap<u32, Connection>,
+    next_handle: u32,
+    timeout: Duration,
+}
+
+struct Connection {
+    stream: TcpStream,
+    /// The start of a char split between two reads.
+    partial: Vec<u8>,
+}
+
+impl Default for Sockets {
+    fn default() -> Self {
+        Self { streams: HashMap::new(), next_handle: 0, timeout: TIMEOUT }
+    }
+}
+
+impl Sockets {
+    pub(crate) fn connect(&mut self, host: &Value, port: &Value) -> Result<Value, String> {
+        let (Value::String(host), Value::Number(port)) = (host, port) else {
+            return Err(format!("tcp_connect() needs a host and a port, not {:?} and {:?}.", host, port));
+        };
+        if port.fract() != 0.0 || !(0.0..=u16::MAX as f64).contains(port) {
+            return Err(format!("Invalid port {port}."));
+        }
+
+        let stream = self.open(host, *port as u16)
+            .map_err(|error| format!("Can't connect to {host}:{port}: {error}."))?;
+        self.next_handle += 1;
+        self.streams.insert(self.next_handle, Connection { stream, partial: vec![] });
+        Ok(Value::Handle(self.next_handle))
+    }
+
+    /// Tries the addresses of the host in turn, each for the timeout.
+    fn open(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no address found");
+        for address in (host, port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, self.timeout) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(self.timeout))?;
+                    stream.set_write_timeout(Some(self.timeout))?;
+                    return Ok(stream);
+                }
+                Err(error) => last_error = error,
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Writes the whole string, returns the number of bytes.
+    pub(crate) fn send(&mut self, handle: &Value, data: &Value) -> Result<Value, String> {
+        let Value::String(data) = data else {
+            return Err(format!("tcp_send() needs a string, not {:?}.", data));
+        };
+        self.connection(handle)?
+            .stream
+            .write_all(data.as_bytes())
+            .map_err(|error| format!("Can't send: {error}."))?;
+        Ok(Value::Number(data.len() as f64))
+    }
+
+    /// The next bytes as a string, nil once the other side closed the connection.
+    /// A char split between reads is returned once all its bytes arrived.
+    pub(crate) fn receive(&mut self, handle: &Value) -> Result<Value, String> {
+        let connection = self.connection(handle)?;
+        let mut buffer = [0; RECEIVE_SIZE];
+        loop {
+            let count = connection.stream
+                .read(&mut buffer)
+                .map_err(|error| format!("Can't receive: {error}."))?;
+            if count == 0 {
+                if connection.partial.is_empty() {
+                    return Ok(Value::Nil);
+                }
+                // a char the other side didn't finish is replaced
+                let partial = mem::take(&mut connection.partial);
+                return Ok(String::from_utf8_lossy(&partial).into_owned().into());
+            }
+
+            connection.partial.extend_from_slice(&buffer[..count]);
+            let end = complete_end(&connection.partial);
+            if end > 0 {
+                let text = String::from_utf8_lossy(&connection.partial[..end]).into_owned();
+                connection.partial.drain(..end);
+                return Ok(text.into());
+            }
+        }
+    }
+
+    pub(crate) fn close(&mut self, handle: &Value) -> Result<Value, String> {
+        match handle {
+            Value::Handle(handle) if self.streams.remove(handle).is_some() => Ok(Value::Nil),
+            handle => self.connection(handle).map(|_| Value::Nil),
+        }
+    }
+
+    fn connection(&mut self, handle: &Value) -> Result<&mut Connection, String> {
+        match handle {
+            Value::Handle(handle) => self.streams.get_mut(handle).ok_or_else(|| "The socket is closed.".to_string()),
+            other => Err(format!("Expected a socket, not {:?}.", other)),
+        }
+    }
+}
+
+/// The length of the bytes up to an incomplete char at the end, invalid bytes
+/// before it are decoded as replacement chars.
+fn complete_end(bytes: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match str::from_utf8(&bytes[start..]) {
+            Ok(_) => return bytes.len(),
+            Err(error) => match error.error_len() {
+                Some(invalid) => start += error.valid_up_to() + invalid,
+                None => return start + error.valid_up_to(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use crate::vm::{InterpretResult, VM};
+
+    use super::*;
+
+    #[test]
+    fn test_echo() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(&buffer).unwrap();
+        });
+
+        let mut vm = VM::new();
+        let source = format!("
+            var socket = tcp_connect(\"127.0.0.1\", {port});
+            var sent = tcp_send(socket, \"ping\");
+            var received = tcp_recv(socket);
+            var end = tcp_recv(socket);
+            tcp_close(socket);
+            var error;
+            try {{ tcp_send(socket, \"x\"); }} catch (e) {{ error = e; }}");
+        assert!(matches!(vm.interpret(source, false), InterpretResult::Ok));
+        server.join().unwrap();
+
+        let global = |name: &str| {
+            vm.globals().into_iter().find(|(global, _)| *global == name).map(|(_, value)| value.clone())
+        };
+        assert_eq!(global("sent"), Some(Value::Number(4.0)));
+        assert_eq!(global("received"), Some(Value::from("ping")));
+        assert_eq!(global("end"), Some(Value::Nil));
+        assert_eq!(global("error"), Some(Value::from("The socket is closed.")));
+    }
+
+    #[test]
+    fn test_split_chars() {
+        assert_eq!(complete_end("aé".as_bytes()), 3);
+        assert_eq!(complete_end(&"aé".as_bytes()[..2]), 1);
+        assert_eq!(complete_end(&[0xff, b'a', 0xe2, 0x86]), 2);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let arrow = "→".as_bytes();
+            stream.write_all(&arrow[..1]).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            stream.write_all(&arrow[1..]).unwrap();
+            stream.write_all(&arrow[..1]).unwrap();
+        });
+
+        let mut sockets = Sockets::default();
+        let socket = sockets.connect(&Value::from("127.0.0.1"), &Value::Number(port as f64)).unwrap();
+        assert_eq!(sockets.receive(&socket), Ok(Value::from("→")));
+        server.join().unwrap();
+        // the other side closed in the middle of a char
+        assert_eq!(sockets.receive(&socket), Ok(Value::from("\u{fffd}")));
+        assert_eq!(sockets.receive(&socket), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn test_receive_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let mut sockets = Sockets { timeout: Duration::from_millis(50), ..Sockets::default() };
+        let socket = sockets.connect(&Value::from("127.0.0.1"), &Value::Number(port as f64)).unwrap();
+        let (_stream, _) = listener.accept().unwrap();
+        assert!(sockets.receive(&socket).unwrap_err().starts_with("Can't receive: "));
+    }
+}
@@ -1,39 +1,250 @@
 use serde::{Serialize, Deserialize};
 
-use crate::value::Function;
-
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum OpCode {
     Constant(f64),
     Bool(bool),
     String(String),
-    Function(Function),
+    /// Index into the functions of the chunk.
+    Function(usize),
     Pop,
+    /// Pop this many values, e.g. the locals of a scope.
+    PopN(usize),
     GetLocal(usize),
     SetLocal(usize),
+    /// Slots above `NARROW_LOCAL_MAX` of the compiler, only emitted when `MAX_LOCALS` is raised
+    /// beyond a byte.
+    GetLocalWide(usize),
+    SetLocalWide(usize),
     GetGlobal(String),
     DefineGlobal(String),
+    /// Like `DefineGlobal`, later assignments and declarations of the global fail.
+    DefineConstant(String),
     SetGlobal(String),
+    /// The native of the VM with this name, unlike a global scripts can't redefine it.
+    /// Not emitted anymore, kept to run bytecode of version 4.
+    GetNative(String),
     Equal,
+    /// `===`, unlike `Equal` it never fails for values of different types.
+    StrictEqual,
     Greater,
     Less,
+    /// Not `Less` and `Greater` negated, which would be true for NaN.
+    GreaterEqual,
+    LessEqual,
     Nil,
     Add,
     Subtract,
     Multiply,
     Divide,
+    Range,
     Not,
     Negate,
+    /// Not emitted anymore, the `print` statement calls the `println` native.
+    /// Kept to run bytecode compiled before.
     Print,
+    /// The `print` statement, replaces this many values with the result of the
+    /// `println` native of the VM. Scripts can't redefine it, embedders can.
+    PrintLine(usize),
     Jump(usize),
     JumpIfFalse(usize),
+    JumpIfTrue(usize),
+    /// Like the jumps above, but the condition is popped in both cases.
+    JumpIfFalsePop(usize),
+    JumpIfTruePop(usize),
+    /// Superinstructions made by the peephole pass.
+    /// `GetLocal(slot); Constant(c); Add`
+    GetLocalAddConstant(usize, f64),
+    /// `Less; JumpIfFalsePop(offset)`
+    CompareLessJumpIfFalse(usize),
+    /// `Constant(c); Call(arg_count)`
+    ConstantCall(f64, usize),
     Loop(usize),
+    /// Advance the iterator whose collection is in the given local slot
+    /// (index in the slot after it), pushes the element and whether it exists.
+    IterNext(usize),
     Call(usize),
+    /// Replaces this many values with a list of them.
+    List(usize),
+    /// Replaces the value with whether it is a list of this length.
+    IsList(usize),
+    /// Replaces a list with its element at the index, `match` checks the length before.
+    Index(usize),
+    /// Replaces the object with the value of its property.
+    GetProperty(String),
+    /// Calls the method of the receiver below the arguments.
+    Invoke(String, usize),
+    /// Install an exception handler, the catch block starts after the offset.
+    PushHandler(usize),
+    PopHandler,
+    Throw,
+    /// Suspend the script with the popped value, the value it is resumed with is pushed.
+    Yield,
     Return,
 }
 
+/// Number of opcodes, the length of a table indexed by `OpCode::index`.
+pub const OPCODE_COUNT: usize = 52;
+
+impl OpCode {
+    /// Dense number of the opcode without its operands, for table dispatch.
+    pub const fn index(&self) -> u8 {
+        match self {
+            OpCode::Constant(_) => 0,
+            OpCode::Bool(_) => 1,
+            OpCode::String(_) => 2,
+            OpCode::Function(_) => 3,
+            OpCode::Pop => 4,
+            OpCode::PopN(_) => 5,
+            OpCode::GetLocal(_) => 6,
+            OpCode::SetLocal(_) => 7,
+            OpCode::GetLocalWide(_) => 8,
+            OpCode::SetLocalWide(_) => 9,
+            OpCode::GetGlobal(_) => 10,
+            OpCode::DefineGlobal(_) => 11,
+            OpCode::DefineConstant(_) => 12,
+            OpCode::SetGlobal(_) => 13,
+            OpCode::GetNative(_) => 14,
+            OpCode::Equal => 15,
+            OpCode::StrictEqual => 16,
+            OpCode::Greater => 17,
+            OpCode::Less => 18,
+            OpCode::GreaterEqual => 19,
+            OpCode::LessEqual => 20,
+            OpCode::Nil => 21,
+            OpCode::Add => 22,
+            OpCode::Subtract => 23,
+            OpCode::Multiply => 24,
+            OpCode::Divide => 25,
+            OpCode::Range => 26,
+            OpCode::Not => 27,
+            OpCode::Negate => 28,
+            OpCode::Print => 29,
+            OpCode::PrintLine(_) => 30,
+            OpCode::Jump(_) => 31,
+            OpCode::JumpIfFalse(_) => 32,
+            OpCode::JumpIfTrue(_) => 33,
+            OpCode::JumpIfFalsePop(_) => 34,
+            OpCode::JumpIfTruePop(_) => 35,
+            OpCode::GetLocalAddConstant(..) => 36,
+            OpCode::CompareLessJumpIfFalse(_) => 37,
+            OpCode::ConstantCall(..) => 38,
+            OpCode::Loop(_) => 39,
+            OpCode::IterNext(_) => 40,
+            OpCode::Call(_) => 41,
+            OpCode::List(_) => 42,
+            OpCode::IsList(_) => 43,
+            OpCode::Index(_) => 44,
+            OpCode::GetProperty(_) => 45,
+            OpCode::Invoke(..) => 46,
+            OpCode::PushHandler(_) => 47,
+            OpCode::PopHandler => 48,
+            OpCode::Throw => 49,
+            OpCode::Yield => 50,
+            OpCode::Return => 51,
+        }
+    }
+
+    /// Offset of a jump, counted from the instruction after it.
+    /// All jumps go forward except `Loop`.
+    pub fn jump_offset(&self) -> Option<usize> {
+        match self {
+            OpCode::Jump(offset)
+            | OpCode::JumpIfFalse(offset)
+            | OpCode::JumpIfTrue(offset)
+            | OpCode::JumpIfFalsePop(offset)
+            | OpCode::JumpIfTruePop(offset)
+            | OpCode::CompareLessJumpIfFalse(offset)
+            | OpCode::PushHandler(offset)
+            | OpCode::Loop(offset) => Some(*offset),
+            _ => None,
+        }
+    }
+
+    /// How many values the instruction pushes minus how many it pops.
+    pub fn stack_effect(&self) -> isize {
+        match self {
+            OpCode::Constant(_)
+            | OpCode::Bool(_)
+            | OpCode::String(_)
+            | OpCode::Function(_)
+            | OpCode::Nil
+            | OpCode::GetLocal(_)
+            | OpCode::GetLocalWide(_)
+            | OpCode::GetGlobal(_)
+            | OpCode::GetNative(_)
+            | OpCode::GetLocalAddConstant(..) => 1,
+            OpCode::SetLocal(_)
+            | OpCode::SetLocalWide(_)
+            | OpCode::SetGlobal(_)
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::GetProperty(_)
+            | OpCode::IsList(_)
+            | OpCode::Index(_)
+            | OpCode::Jump(_)
+            | OpCode::JumpIfFalse(_)
+            | OpCode::JumpIfTrue(_)
+            | OpCode::Loop(_)
+            | OpCode::PushHandler(_)
+            | OpCode::PopHandler
+            | OpCode::Yield
+            | OpCode::Return => 0,
+            OpCode::Pop
+            | OpCode::DefineGlobal(_)
+            | OpCode::DefineConstant(_)
+            | OpCode::Equal
+            | OpCode::StrictEqual
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GreaterEqual
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Range
+            | OpCode::Print
+            | OpCode::JumpIfFalsePop(_)
+            | OpCode::JumpIfTruePop(_)
+            | OpCode::Throw => -1,
+            OpCode::CompareLessJumpIfFalse(_) => -2,
+            OpCode::PopN(count) => -(*count as isize),
+            // the callee and the arguments are replaced by the result
+            OpCode::Call(arg_count) | OpCode::Invoke(_, arg_count) => -(*arg_count as isize),
+            OpCode::ConstantCall(_, arg_count) | OpCode::PrintLine(arg_count) => 1 - *arg_count as isize,
+            OpCode::List(count) => 1 - *count as isize,
+            // the element and whether there is one
+            OpCode::IterNext(_) => 2,
+        }
+    }
+
+    pub fn set_jump_offset(&mut self, new_offset: usize) {
+        match self {
+            OpCode::Jump(offset)
+            | OpCode::JumpIfFalse(offset)
+            | OpCode::JumpIfTrue(offset)
+            | OpCode::JumpIfFalsePop(offset)
+            | OpCode::JumpIfTruePop(offset)
+            | OpCode::CompareLessJumpIfFalse(offset)
+            | OpCode::PushHandler(offset)
+            | OpCode::Loop(offset) => *offset = new_offset,
+            other => panic!("{other:?} is not a jump"),
+        }
+    }
+}
+
+/// Position of the source token an instruction was compiled from, in bytes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub length: usize,
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Instruction {
     pub code: OpCode,
     pub line: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
 }
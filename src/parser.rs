@@ -0,0 +1,1159 @@
+use std::{collections::HashSet, rc::Rc};
+
+use crate::{
+    ast::{Expr, FunctionDecl, Literal, LoopTarget, MatchArm, Name, Pattern, Slot, Stmt},
+    compiler::{CompileError, MAX_ARGUMENTS, MAX_NESTED_EXPRESSIONS, MAX_NESTED_STATEMENTS},
+    error_codes,
+    op_code::Span,
+    scanner::{ErrorToken, Scanner, Token, TokenType},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Precedence {
+    None,
+    Assignment, // =
+    Or,         // or
+    And,        // and
+    Equality,   // == !=
+    Comparison, // < > <= >=
+    Range,      // ..
+    Term,       // + -
+    Factor,     // * /
+    Unary,      // ! -
+    Call,       // . ()
+    Primary,
+}
+
+impl Precedence {
+    fn next_level(&self) -> Self {
+        match self {
+            Precedence::None => Self::Assignment,
+            Precedence::Assignment => Self::Or,
+            Precedence::Or => Self::And,
+            Precedence::And => Self::Equality,
+            Precedence::Equality => Self::Comparison,
+            Precedence::Comparison => Self::Range,
+            Precedence::Range => Self::Term,
+            Precedence::Term => Self::Factor,
+            Precedence::Factor => Self::Unary,
+            Precedence::Unary => Self::Call,
+            Precedence::Call => Self::Primary,
+            Precedence::Primary => panic!("no next precedence level"),
+        }
+    }
+}
+
+type PrefixFn<'a> = fn(&mut Parser<'a>, bool) -> Expr;
+type InfixFn<'a> = fn(&mut Parser<'a>, Expr, bool) -> Expr;
+
+#[derive(Clone, Copy)]
+struct ParseRule<'a> {
+    prefix: Option<PrefixFn<'a>>,
+    infix: Option<InfixFn<'a>>,
+    precedence: Precedence,
+}
+
+impl<'a> ParseRule<'a> {
+    const fn new(prefix: PrefixFn<'a>, infix: InfixFn<'a>, precedence: Precedence) -> Self {
+        Self {
+            prefix: Some(prefix),
+            infix: Some(infix),
+            precedence,
+        }
+    }
+
+    const fn infix(infix: InfixFn<'a>, precedence: Precedence) -> Self {
+        Self {
+            prefix: None,
+            infix: Some(infix),
+            precedence,
+        }
+    }
+
+    const fn prefix(prefix: PrefixFn<'a>) -> Self {
+        Self {
+            prefix: Some(prefix),
+            infix: None,
+            precedence: Precedence::None,
+        }
+    }
+
+    const fn undef() -> Self {
+        Self {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        }
+    }
+}
+
+/// The rules by token type, the match is exhaustive so every token type has one.
+fn get_rule<'a>(token_type: TokenType) -> ParseRule<'a> {
+    match token_type {
+        TokenType::LeftParen => ParseRule::new(Parser::grouping, Parser::call, Precedence::Call),
+        TokenType::LeftBracket => ParseRule::prefix(Parser::list),
+        TokenType::Dot => ParseRule::infix(Parser::dot, Precedence::Call),
+        TokenType::DotDot => ParseRule::infix(Parser::binary, Precedence::Range),
+        TokenType::Minus => ParseRule::new(Parser::unary, Parser::binary, Precedence::Term),
+        TokenType::Plus => ParseRule::infix(Parser::binary, Precedence::Term),
+        TokenType::Slash => ParseRule::infix(Parser::binary, Precedence::Factor),
+        TokenType::Star => ParseRule::infix(Parser::binary, Precedence::Factor),
+        TokenType::Bang => ParseRule::prefix(Parser::unary),
+        TokenType::BangEqual => ParseRule::infix(Parser::binary, Precedence::Equality),
+        TokenType::EqualEqual => ParseRule::infix(Parser::binary, Precedence::Equality),
+        TokenType::BangEqualEqual => ParseRule::infix(Parser::binary, Precedence::Equality),
+        TokenType::EqualEqualEqual => ParseRule::infix(Parser::binary, Precedence::Equality),
+        TokenType::Greater => ParseRule::infix(Parser::binary, Precedence::Comparison),
+        TokenType::GreaterEqual => ParseRule::infix(Parser::binary, Precedence::Comparison),
+        TokenType::Less => ParseRule::infix(Parser::binary, Precedence::Comparison),
+        TokenType::LessEqual => ParseRule::infix(Parser::binary, Precedence::Comparison),
+        TokenType::Identifier => ParseRule::prefix(Parser::variable),
+        TokenType::String => ParseRule::prefix(Parser::string),
+        TokenType::Number => ParseRule::prefix(Parser::number),
+        TokenType::And => ParseRule::infix(Parser::logical, Precedence::And),
+        TokenType::False => ParseRule::prefix(Parser::literal),
+        TokenType::Nil => ParseRule::prefix(Parser::literal),
+        TokenType::Or => ParseRule::infix(Parser::logical, Precedence::Or),
+        TokenType::True => ParseRule::prefix(Parser::literal),
+        TokenType::Yield => ParseRule::prefix(Parser::yield_),
+        TokenType::RightParen
+        | TokenType::LeftBrace
+        | TokenType::RightBrace
+        | TokenType::RightBracket
+        | TokenType::Comma
+        | TokenType::DotDotDot
+        | TokenType::Semicolon
+        | TokenType::Colon
+        | TokenType::Equal
+        | TokenType::FatArrow
+        | TokenType::Class
+        | TokenType::Else
+        | TokenType::For
+        | TokenType::Fun
+        | TokenType::If
+        | TokenType::In
+        | TokenType::Print
+        | TokenType::Return
+        | TokenType::Super
+        | TokenType::This
+        | TokenType::Throw
+        | TokenType::Try
+        | TokenType::Var
+        | TokenType::While
+        | TokenType::Catch
+        | TokenType::Const
+        | TokenType::Match
+        | TokenType::Break
+        | TokenType::Continue
+        | TokenType::Comment
+        | TokenType::Eof => ParseRule::undef(),
+    }
+}
+
+/// A compile error at the token.
+pub(crate) fn error_at(scanner: &Scanner, token: &Token, message: &str) -> CompileError {
+    let location = if token.token_type == TokenType::Eof {
+        " at end".to_string()
+    } else {
+        format!(" at {} ({:?})", scanner.lexeme(token), token.token_type)
+    };
+
+    CompileError {
+        line: token.line,
+        span: Span { start: token.start, length: token.length },
+        location,
+        message: message.to_string(),
+        code: error_codes::compile_code(message),
+    }
+}
+
+/// The keywords a statement starts with, parsing can continue there after an error.
+fn starts_statement(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::Class
+            | TokenType::Fun
+            | TokenType::Var
+            | TokenType::Const
+            | TokenType::For
+            | TokenType::If
+            | TokenType::While
+            | TokenType::Print
+            | TokenType::Return
+            | TokenType::Throw
+            | TokenType::Try
+            | TokenType::Match
+            | TokenType::Break
+            | TokenType::Continue
+    )
+}
+
+/// Builds the syntax tree, the first stage of the compiler.
+pub(crate) struct Parser<'a> {
+    scanner: Scanner<'a>,
+    current: Token,
+    previous: Token,
+    errors: Vec<CompileError>,
+    panic_mode: bool,
+    /// Blocks, loops and functions around the current statement.
+    depth: u32,
+    /// All statements around the current one, see `nested`.
+    nesting: u32,
+    /// The expressions around the current one, see `parse_precedence`.
+    expression_depth: u32,
+    debug: bool,
+    repl: bool,
+    /// The identifiers seen so far, see `name`.
+    names: HashSet<Name>,
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(source: &'a str, debug: bool, repl: bool) -> Self {
+        let eof = Token {
+            token_type: TokenType::Eof,
+            line: 0,
+            start: 0,
+            length: 0,
+        };
+        Self {
+            scanner: Scanner::new(source),
+            current: eof.clone(),
+            previous: eof,
+            errors: vec![],
+            panic_mode: false,
+            depth: 0,
+            nesting: 0,
+            expression_depth: 0,
+            debug,
+            repl,
+            names: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn scanner(&self) -> &Scanner<'a> {
+        &self.scanner
+    }
+
+    /// After parsing the `Eof` token.
+    pub(crate) fn previous(&self) -> &Token {
+        &self.previous
+    }
+
+    /// The statements of the source, or all errors found in it.
+    pub(crate) fn parse(&mut self) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let mut statements = vec![];
+        self.advance();
+        while !self.match_it(TokenType::Eof) {
+            statements.push(self.declaration());
+        }
+
+        if self.errors.is_empty() { Ok(statements) } else { Err(std::mem::take(&mut self.errors)) }
+    }
+
+    fn advance(&mut self) {
+        loop {
+            match self.scanner.scan_token() {
+                Ok(token) => {
+                    self.previous = std::mem::replace(&mut self.current, token);
+                    break;
+                }
+                Err(err_token) => self.show_error(err_token),
+            }
+        }
+    }
+
+    fn declaration(&mut self) -> Stmt {
+        let statement = if self.match_it(TokenType::Fun) {
+            self.nested(Self::fun_declaration)
+        } else if self.match_it(TokenType::Var) {
+            self.var_declaration(false)
+        } else if self.match_it(TokenType::Const) {
+            self.var_declaration(true)
+        } else {
+            self.statement()
+        };
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+        statement
+    }
+
+    fn statement(&mut self) -> Stmt {
+        self.nested(Self::parse_statement)
+    }
+
+    /// Parses a statement inside the others, a statement nested too deep is skipped
+    /// with an error instead of recursing further.
+    fn nested(&mut self, parse: fn(&mut Self) -> Stmt) -> Stmt {
+        if self.nesting == MAX_NESTED_STATEMENTS {
+            self.error_at_current("Too many nested statements.");
+            self.skip_statement();
+            // a placeholder, the tree is not compiled after an error
+            return Stmt::Expression(Expr::Literal { value: Literal::Nil, token: self.previous.clone() });
+        }
+
+        self.nesting += 1;
+        let statement = parse(self);
+        self.nesting -= 1;
+        statement
+    }
+
+    /// Up to the `;` or the closing `}` ending the statement, without parsing it.
+    fn skip_statement(&mut self) {
+        let mut open = 0;
+        while !self.check(TokenType::Eof) {
+            match self.current.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => open += 1,
+                // the end of the enclosing block
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket if open == 0 => return,
+                TokenType::RightParen | TokenType::RightBracket => open -= 1,
+                TokenType::RightBrace => {
+                    open -= 1;
+                    if open == 0 {
+                        self.advance();
+                        if !self.check(TokenType::Else) && !self.check(TokenType::Catch) {
+                            return;
+                        }
+                        continue;
+                    }
+                }
+                TokenType::Semicolon if open == 0 => {
+                    self.advance();
+                    return;
+                }
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    fn parse_statement(&mut self) -> Stmt {
+        if self.match_it(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_it(TokenType::For) {
+            self.for_statement()
+        } else if self.match_it(TokenType::If) {
+            self.if_statement()
+        } else if self.match_it(TokenType::Return) {
+            self.return_statement()
+        } else if self.match_it(TokenType::While) {
+            self.while_statement()
+        } else if self.match_it(TokenType::Throw) {
+            self.throw_statement()
+        } else if self.match_it(TokenType::Try) {
+            self.try_statement()
+        } else if self.match_it(TokenType::Match) {
+            self.match_statement()
+        } else if self.match_it(TokenType::Break) {
+            let (keyword, label) = self.loop_jump("Expect ';' after 'break'.");
+            Stmt::Break { keyword, label, target: LoopTarget::default() }
+        } else if self.match_it(TokenType::Continue) {
+            let (keyword, label) = self.loop_jump("Expect ';' after 'continue'.");
+            Stmt::Continue { keyword, label, target: LoopTarget::default() }
+        } else if self.match_it(TokenType::LeftBrace) {
+            self.block_statement()
+        } else if self.check(TokenType::Identifier)
+            && matches!(self.scanner.peek_token(), Ok(Token { token_type: TokenType::Colon, .. }))
+        {
+            self.labeled_statement()
+        } else {
+            self.expression_statement()
+        }
+    }
+
+    fn expression(&mut self) -> Expr {
+        self.parse_precedence(Precedence::Assignment)
+    }
+
+    /// The keyword and the optional label of `break` or `continue`.
+    fn loop_jump(&mut self, message: &str) -> (Token, Option<(Name, Token)>) {
+        let keyword = self.previous.clone();
+        let label = if self.match_it(TokenType::Identifier) {
+            let token = self.previous.clone();
+            Some((self.name(&token), token))
+        } else {
+            None
+        };
+        self.consume(TokenType::Semicolon, message);
+        (keyword, label)
+    }
+
+    /// `label: ` before a loop.
+    fn labeled_statement(&mut self) -> Stmt {
+        self.advance();
+        let token = self.previous.clone();
+        let name = self.name(&token);
+        self.advance();
+
+        let mut statement = if self.match_it(TokenType::For) {
+            self.for_statement()
+        } else if self.match_it(TokenType::While) {
+            self.while_statement()
+        } else {
+            self.error_at_current("Expect a loop after a label.");
+            return self.statement();
+        };
+        if let Stmt::While { label, .. } | Stmt::For { label, .. } | Stmt::ForIn { label, .. } = &mut statement {
+            *label = Some(name);
+        }
+        statement
+    }
+
+    fn block_statement(&mut self) -> Stmt {
+        self.depth += 1;
+        let statements = self.block();
+        self.depth -= 1;
+        Stmt::Block { statements, end: self.previous.clone() }
+    }
+
+    fn block(&mut self) -> Vec<Stmt> {
+        let mut statements = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            statements.push(self.declaration());
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after block.");
+        statements
+    }
+
+    fn function(&mut self, name: Name, token: Token) -> FunctionDecl {
+        self.depth += 1;
+        self.consume(
+            TokenType::LeftParen,
+            "Expect '(' after function name.",
+        );
+
+        let mut params = vec![];
+        let mut variadic = false;
+        if !self.check(TokenType::RightParen) {
+            loop {
+                if params.len() == MAX_ARGUMENTS {
+                    self.error_at_current("Can't have more than 255 parameters.");
+                }
+                variadic = self.match_it(TokenType::DotDotDot);
+                self.consume(TokenType::Identifier, "Expected parameter name.");
+                let token = self.previous.clone();
+                params.push((self.name(&token), token));
+                if variadic && self.check(TokenType::Comma) {
+                    self.error_at_current("The rest parameter must be the last one.");
+                }
+                if !self.match_it(TokenType::Comma){
+                    break;
+                }
+            }
+        }
+
+        self.consume_before_body("Expect ')' after parameters.");
+        self.consume(
+            TokenType::LeftBrace,
+            "Expect '{' before function body.",
+        );
+
+        let body = self.block();
+        self.depth -= 1;
+
+        FunctionDecl { name, token, params, variadic, body, generator: false }
+    }
+
+    fn fun_declaration(&mut self) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect function name.");
+        let token = self.previous.clone();
+        let name = self.name(&token);
+        Stmt::Function(self.function(name, token))
+    }
+
+    /// `var` or `const`, a constant needs an initializer.
+    fn var_declaration(&mut self, constant: bool) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let token = self.previous.clone();
+        let name = self.name(&token);
+
+        let initializer = if self.match_it(TokenType::Equal) {
+            Some(self.expression())
+        } else {
+            if constant {
+                self.error_at_current("Expect '=' after constant name.");
+            }
+            None
+        };
+
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        Stmt::Var { name, token, initializer, constant }
+    }
+
+    fn expression_statement(&mut self) -> Stmt {
+        let expression = self.expression();
+        if self.repl && self.check(TokenType::Eof) && self.depth == 0 {
+            let keyword = expression.token().clone();
+            return Stmt::Print { keyword, expressions: vec![expression] };
+        }
+
+        self.consume(TokenType::Semicolon, "Expect ';' after expression");
+        Stmt::Expression(expression)
+    }
+
+    fn for_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.depth += 1;
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        let initializer = if self.match_it(TokenType::Semicolon) {
+            None
+        } else if self.match_it(TokenType::Var) {
+            if self.is_for_in() {
+                let statement = self.for_in_statement(keyword);
+                self.depth -= 1;
+                return statement;
+            }
+
+            Some(Box::new(self.var_declaration(false)))
+        } else {
+            Some(Box::new(self.expression_statement()))
+        };
+
+        let condition = if self.match_it(TokenType::Semicolon) {
+            None
+        } else {
+            let condition = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+            Some(condition)
+        };
+
+        let increment = if self.match_it(TokenType::RightParen) {
+            None
+        } else {
+            let increment = self.expression();
+            self.consume_before_body("Expect ')' after for clauses.");
+            Some(increment)
+        };
+
+        let body = Box::new(self.statement());
+        self.depth -= 1;
+
+        Stmt::For { keyword, initializer, condition, increment, body, label: None }
+    }
+
+    fn is_for_in(&mut self) -> bool {
+        self.check(TokenType::Identifier)
+            && matches!(self.scanner.peek_token(), Ok(Token { token_type: TokenType::In, .. }))
+    }
+
+    /// `for (var x in collection)`, the loop variable is fresh for every iteration.
+    fn for_in_statement(&mut self, keyword: Token) -> Stmt {
+        self.consume(TokenType::Identifier, "Expect variable name.");
+        let token = self.previous.clone();
+        let name = self.name(&token);
+        self.consume(TokenType::In, "Expect 'in' after loop variable.");
+        let collection = self.expression();
+        self.consume_before_body("Expect ')' after for clauses.");
+
+        self.depth += 1;
+        let body = Box::new(self.statement());
+        self.depth -= 1;
+
+        Stmt::ForIn { keyword, name, token, collection, body, collection_slot: 0, label: None }
+    }
+
+    fn if_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        let condition = self.expression();
+        self.consume_before_body("Expect ')' after condition.");
+
+        let then_branch = Box::new(self.statement());
+        let else_branch = if self.match_it(TokenType::Else) {
+            Some(Box::new(self.statement()))
+        } else {
+            None
+        };
+
+        Stmt::If { keyword, condition, then_branch, else_branch }
+    }
+
+    fn print_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let mut expressions = vec![self.expression()];
+        while self.match_it(TokenType::Comma) {
+            expressions.push(self.expression());
+        }
+        self.consume(TokenType::Semicolon, "Expect ';' after value.");
+        Stmt::Print { keyword, expressions }
+    }
+
+    fn return_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let value = if self.match_it(TokenType::Semicolon) {
+            None
+        } else {
+            let value = self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            Some(value)
+        };
+
+        Stmt::Return { keyword, value }
+    }
+
+    fn throw_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        let value = self.expression();
+        self.consume(TokenType::Semicolon, "Expect ';' after thrown value.");
+        Stmt::Throw { keyword, value }
+    }
+
+    fn try_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftBrace, "Expect '{' after 'try'.");
+        let body = Box::new(self.block_statement());
+
+        self.consume(TokenType::Catch, "Expect 'catch' after try block.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'catch'.");
+        self.consume(TokenType::Identifier, "Expect exception variable name.");
+        let token = self.previous.clone();
+        let name = self.name(&token);
+        self.consume_before_body("Expect ')' after exception variable.");
+        self.consume(TokenType::LeftBrace, "Expect '{' after catch clause.");
+
+        self.depth += 1;
+        let handler = self.block();
+        self.depth -= 1;
+
+        Stmt::Try { keyword, body, name, token, handler, end: self.previous.clone() }
+    }
+
+    /// `match (value) { pattern => statement ... }`
+    fn match_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'match'.");
+        let value = self.expression();
+        self.consume_before_body("Expect ')' after match value.");
+        self.consume(TokenType::LeftBrace, "Expect '{' before match arms.");
+
+        self.depth += 1;
+        let mut arms = vec![];
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Eof) {
+            let pattern = self.pattern();
+            self.consume(TokenType::FatArrow, "Expect '=>' after pattern.");
+            let body = self.statement();
+            arms.push(MatchArm { pattern, body });
+        }
+        self.depth -= 1;
+
+        self.consume(TokenType::RightBrace, "Expect '}' after match arms.");
+        Stmt::Match { keyword, value, arms, value_slot: 0 }
+    }
+
+    /// A literal, `_`, a name or a list of patterns, always consumes a token.
+    fn pattern(&mut self) -> Pattern {
+        self.advance();
+        let token = self.previous.clone();
+        let value = match token.token_type {
+            TokenType::Number => Literal::Number(self.number_value(&token)),
+            TokenType::Minus if self.match_it(TokenType::Number) => {
+                Literal::Number(-self.number_value(&self.previous))
+            }
+            TokenType::String => Literal::String(self.scanner.lexeme_string(&token).to_string()),
+            TokenType::True => Literal::Bool(true),
+            TokenType::False => Literal::Bool(false),
+            TokenType::Nil => Literal::Nil,
+            TokenType::Identifier => {
+                let name = self.name(&token);
+                return if &*name == "_" { Pattern::Wildcard(token) } else { Pattern::Binding { name, token } };
+            }
+            TokenType::LeftBracket => {
+                let mut elements = vec![];
+                if !self.check(TokenType::RightBracket) {
+                    loop {
+                        elements.push(self.pattern());
+                        if !self.match_it(TokenType::Comma) {
+                            break;
+                        }
+                    }
+                }
+                self.consume(TokenType::RightBracket, "Expect ']' after list pattern.");
+                return Pattern::List { elements, token };
+            }
+            _ => {
+                self.error("Expect pattern.");
+                return Pattern::Wildcard(token);
+            }
+        };
+
+        Pattern::Literal { value, token }
+    }
+
+    fn while_statement(&mut self) -> Stmt {
+        let keyword = self.previous.clone();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        let condition = self.expression();
+        self.consume_before_body("Expect ')' after statement.");
+
+        let body = Box::new(self.statement());
+        Stmt::While { keyword, condition, body, label: None }
+    }
+
+    /// Consume the token or create an error.
+    fn consume(&mut self, token_type: TokenType, message: &str) {
+        if self.check(token_type) {
+            self.advance();
+            return;
+        }
+
+        self.error_at_current(message);
+    }
+
+    /// The `)` before the body of a statement or function. If it is missing but the body
+    /// starts, the error is recovered and the body reports its own errors.
+    fn consume_before_body(&mut self, message: &str) {
+        self.consume(TokenType::RightParen, message);
+        if self.panic_mode && (self.check(TokenType::LeftBrace) || starts_statement(self.current.token_type)) {
+            self.panic_mode = false;
+        }
+    }
+
+    fn check(&self, token_type: TokenType) -> bool {
+        self.current.token_type == token_type
+    }
+
+    /// If the token matches, consume it.
+    fn match_it(&mut self, token_type: TokenType) -> bool {
+        if !self.check(token_type) {
+            return false;
+        }
+
+        self.advance();
+        true
+    }
+
+    fn binary(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        if self.debug {
+            println!("binary");
+        }
+
+        let operator = self.previous.clone();
+        let rule = get_rule(operator.token_type);
+        let right = self.parse_precedence(rule.precedence.next_level());
+
+        Expr::Binary { left: Box::new(left), operator, right: Box::new(right) }
+    }
+
+    fn call(&mut self, callee: Expr, _can_assign: bool) -> Expr {
+        let arguments = self.argument_list();
+        Expr::Call { callee: Box::new(callee), paren: self.previous.clone(), arguments }
+    }
+
+    fn dot(&mut self, object: Expr, _can_assign: bool) -> Expr {
+        self.consume(TokenType::Identifier, "Expect property name after '.'.");
+        let token = self.previous.clone();
+        let name = self.name(&token);
+        Expr::Get { object: Box::new(object), name, token }
+    }
+
+    fn list(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let mut elements = vec![];
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                elements.push(self.expression());
+                if !self.match_it(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after list elements.");
+        Expr::List { elements, token }
+    }
+
+    fn literal(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+
+        let value = match token.token_type {
+            TokenType::False => Literal::Bool(false),
+            TokenType::Nil => Literal::Nil,
+            TokenType::True => Literal::Bool(true),
+            _ => panic!("wrong token type in literal {:?}", token.token_type),
+        };
+        Expr::Literal { value, token }
+    }
+
+    fn number(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let num = self.number_value(&token);
+        Expr::Literal { value: Literal::Number(num), token }
+    }
+
+    fn number_value(&self, token: &Token) -> f64 {
+        self.lexeme(token).parse::<f64>().expect("not a valid number")
+    }
+
+    /// `and` and `or`, the right operand binds tighter than the operator.
+    fn logical(&mut self, left: Expr, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let precedence = match operator.token_type {
+            TokenType::And => Precedence::And,
+            _ => Precedence::Or,
+        };
+        let right = self.parse_precedence(precedence);
+
+        Expr::Logical { left: Box::new(left), operator, right: Box::new(right) }
+    }
+
+    fn string(&mut self, _can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let string = self.scanner.lexeme_string(&token).to_string();
+        Expr::Literal { value: Literal::String(string), token }
+    }
+
+    fn variable(&mut self, can_assign: bool) -> Expr {
+        let token = self.previous.clone();
+        let name = self.name(&token);
+
+        if can_assign && self.match_it(TokenType::Equal) {
+            let value = Box::new(self.expression());
+            Expr::Assign { name, token, value, slot: Slot::Global }
+        } else {
+            Expr::Variable { name, token, slot: Slot::Global }
+        }
+    }
+
+    fn grouping(&mut self, _can_assign: bool) -> Expr {
+        if self.debug {
+            println!("grouping");
+        }
+        let expression = self.expression();
+        self.consume(TokenType::RightParen, "expected ')' after expression");
+        if self.debug {
+            println!("grouping end");
+        }
+        expression
+    }
+
+    fn unary(&mut self, _can_assign: bool) -> Expr {
+        let operator = self.previous.clone();
+        let right = self.parse_precedence(Precedence::Unary);
+
+        match operator.token_type {
+            TokenType::Bang | TokenType::Minus => Expr::Unary { operator, right: Box::new(right) },
+            _ => panic!("wrong token type in unary {:?}", operator.token_type),
+        }
+    }
+
+    fn yield_(&mut self, _can_assign: bool) -> Expr {
+        let keyword = self.previous.clone();
+
+        let value = if self.check(TokenType::Semicolon) || self.check(TokenType::RightParen) {
+            None
+        } else {
+            Some(Box::new(self.parse_precedence(Precedence::Assignment)))
+        };
+
+        Expr::Yield { keyword, value }
+    }
+
+    /// An expression nested too deep is skipped with an error instead of recursing further.
+    fn parse_precedence(&mut self, precedence: Precedence) -> Expr {
+        if self.expression_depth == MAX_NESTED_EXPRESSIONS {
+            self.error_at_current("Too many nested expressions.");
+            self.skip_expression();
+            return Expr::Literal { value: Literal::Nil, token: self.previous.clone() };
+        }
+
+        self.expression_depth += 1;
+        let expression = self.parse_operators(precedence);
+        self.expression_depth -= 1;
+        expression
+    }
+
+    /// Up to the `)`, `]`, `,` or `;` after the expression, without parsing it.
+    fn skip_expression(&mut self) {
+        let mut open = 0;
+        while !self.check(TokenType::Eof) {
+            match self.current.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => open += 1,
+                TokenType::RightParen
+                | TokenType::RightBrace
+                | TokenType::RightBracket
+                | TokenType::Comma
+                | TokenType::Semicolon if open == 0 => return,
+                TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => open -= 1,
+                _ => (),
+            }
+            self.advance();
+        }
+    }
+
+    fn parse_operators(&mut self, precedence: Precedence) -> Expr {
+        if self.debug {
+            println!("parse {precedence:?}");
+        }
+
+        let Some(prefix_rule) = get_rule(self.current.token_type).prefix else {
+            self.error_at_current("Expect expression");
+            // the closing brace is left for the block, so the block ends where it should
+            if self.current.token_type != TokenType::RightBrace || self.depth == 0 {
+                self.advance();
+            }
+            // a placeholder, the tree is not compiled after an error
+            return Expr::Literal { value: Literal::Nil, token: self.previous.clone() };
+        };
+        self.advance();
+        let can_assign = precedence <= Precedence::Assignment;
+
+        let mut expression = prefix_rule(self, can_assign);
+
+        while precedence <= get_rule(self.current.token_type).precedence {
+
+            self.advance();
+            let infix_rule = get_rule(self.previous.token_type)
+                .infix
+                .expect("infix must be defined");
+
+            expression = infix_rule(self, expression, can_assign);
+        }
+
+        if can_assign && self.match_it(TokenType::Equal) {
+            self.error("Invalid assignment");
+        }
+        expression
+    }
+
+    fn argument_list(&mut self) -> Vec<Expr> {
+        let mut arguments = vec![];
+        if !self.check(TokenType::RightParen) {
+            loop {
+                let argument = self.expression();
+                if arguments.len() == MAX_ARGUMENTS {
+                    self.error("Can't have more than 255 arguments.");
+                }
+                arguments.push(argument);
+                if !self.match_it(TokenType::Comma){
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightParen, "Expect ')' after arguments.");
+
+        arguments
+    }
+
+    fn lexeme(&self, token: &Token) -> &'a str {
+        self.scanner.lexeme(token)
+    }
+
+    /// The identifier of the token, all uses of a name share one allocation.
+    fn name(&mut self, token: &Token) -> Name {
+        let lexeme = self.scanner.lexeme(token);
+        if let Some(name) = self.names.get(lexeme) {
+            return Rc::clone(name);
+        }
+        let name: Name = lexeme.into();
+        self.names.insert(Rc::clone(&name));
+        name
+    }
+
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while self.current.token_type != TokenType::Eof {
+            if self.previous.token_type == TokenType::Semicolon {
+                return;
+            }
+            // the end of the enclosing block, e.g. after a missing ';' before it
+            if self.current.token_type == TokenType::RightBrace && self.depth > 0 {
+                return;
+            }
+
+            if starts_statement(self.current.token_type) {
+                return;
+            }
+
+            self.advance();
+        }
+    }
+
+    fn error_at_current(&mut self, message: &str) {
+        self.error_at(self.current.clone(), message);
+    }
+
+    fn error(&mut self, message: &str) {
+        self.error_at(self.previous.clone(), message);
+    }
+
+    fn error_at(&mut self, token: Token, message: &str) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.errors.push(error_at(&self.scanner, &token, message));
+    }
+
+    fn show_error(&mut self, token: ErrorToken) {
+        if self.panic_mode {
+            return;
+        }
+
+        self.panic_mode = true;
+        self.errors.push(CompileError {
+            line: token.line,
+            span: Span { start: token.start, length: token.length },
+            location: format!(" at {}", self.scanner.get_lexeme_error(&token)),
+            code: error_codes::compile_code(&token.message),
+            message: token.message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Vec<Stmt> {
+        Parser::new(source, false, false).parse().unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let statements = parse("print 1 + 2 * 3 == x or !y;");
+        let Stmt::Print { expressions, .. } = &statements[0] else { panic!() };
+        let [Expr::Logical { left, right, .. }] = &expressions[..] else {
+            panic!("expected print of or, got {statements:?}");
+        };
+        let Expr::Binary { left: sum, operator, .. } = left.as_ref() else {
+            panic!("expected ==, got {left:?}");
+        };
+        assert_eq!(operator.token_type, TokenType::EqualEqual);
+        assert!(matches!(sum.as_ref(), Expr::Binary { right, .. } if matches!(right.as_ref(), Expr::Binary { .. })));
+        assert!(matches!(right.as_ref(), Expr::Unary { .. }));
+    }
+
+    #[test]
+    fn test_statements() {
+        let statements = parse("var a = 1; fun f(x, y) { return x; } for (var i in a) {}");
+        assert!(matches!(&statements[0], Stmt::Var { name, constant: false, .. } if &**name == "a"));
+        assert!(matches!(&statements[1], Stmt::Function(FunctionDecl { params, body, .. })
+            if params.len() == 2 && body.len() == 1));
+        assert!(matches!(&statements[2], Stmt::ForIn { name, .. } if &**name == "i"));
+
+        let statements = parse("fun log(format, ...args) {}");
+        assert!(matches!(&statements[0], Stmt::Function(FunctionDecl { params, variadic: true, .. })
+            if params.len() == 2));
+        assert!(Parser::new("fun f(...a, b) {}", false, false).parse().is_err());
+    }
+
+    #[test]
+    fn test_match() {
+        let statements = parse("match (x) { -1 => print 1; [a, _, \"s\"] => {} y => print y; }");
+        let Stmt::Match { arms, .. } = &statements[0] else {
+            panic!("expected match, got {statements:?}");
+        };
+        assert!(matches!(&arms[0].pattern, Pattern::Literal { value: Literal::Number(n), .. } if *n == -1.0));
+        let Pattern::List { elements, .. } = &arms[1].pattern else { panic!() };
+        assert!(matches!(elements.as_slice(), [
+            Pattern::Binding { .. },
+            Pattern::Wildcard(_),
+            Pattern::Literal { value: Literal::String(_), .. },
+        ]));
+        assert!(matches!(&arms[2].pattern, Pattern::Binding { name, .. } if &**name == "y"));
+
+        assert!(Parser::new("match (x) { 1 print 1; }", false, false).parse().is_err());
+        assert!(Parser::new("match (x) { 1 + 2 => print 1; }", false, false).parse().is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let source = "( ) { } [ ] , . .. ... - + ; : / * ! != !== = == === > >= < <= => name \"s\" 1 and class else false
+            for fun if in nil or print return super this throw true try var while catch yield const match
+            break continue // c";
+        let mut scanner = Scanner::with_comments(source);
+        let mut token_types = HashSet::new();
+        loop {
+            let token_type = scanner.scan_token().unwrap().token_type;
+            token_types.insert(token_type);
+            if token_type == TokenType::Eof {
+                break;
+            }
+        }
+        assert_eq!(token_types.len(), usize::from(TokenType::Eof) + 1, "not every token type is scanned");
+
+        for token_type in token_types {
+            let rule = get_rule(token_type);
+            assert_eq!(rule.infix.is_some(), rule.precedence != Precedence::None, "{token_type:?}");
+        }
+    }
+
+    #[test]
+    fn test_interned_names() {
+        let statements = parse("var a = 1; a = a;");
+        let (Stmt::Var { name, .. }, Stmt::Expression(Expr::Assign { name: target, value, .. })) = (&statements[0], &statements[1]) else {
+            panic!("expected a declaration and an assignment, got {statements:?}");
+        };
+        let Expr::Variable { name: read, .. } = value.as_ref() else {
+            panic!("expected a variable, got {value:?}");
+        };
+        assert!(Rc::ptr_eq(name, target) && Rc::ptr_eq(name, read));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Parser::new("(a) = 1;", false, false).parse().is_err());
+        assert!(Parser::new("var;", false, false).parse().is_err());
+        assert!(Parser::new("{ print 1;", false, false).parse().is_err());
+    }
+
+    fn error_lines(source: &str) -> Vec<(i32, String)> {
+        let errors = Parser::new(source, false, false).parse().unwrap_err();
+        errors.into_iter().map(|error| (error.line, error.message)).collect()
+    }
+
+    #[test]
+    fn test_independent_errors() {
+        let source = "
+            fun area(width, height) {
+                return width * height
+            }
+            print area(1, , 2);
+            if (area(1, 2) > 1 {
+                print 1 +;
+            }";
+        assert_eq!(error_lines(source), vec![
+            (4, "Expect ';' after return value.".to_string()),
+            (5, "Expect expression".to_string()),
+            (6, "Expect ')' after condition.".to_string()),
+            (7, "Expect expression".to_string()),
+        ]);
+        assert_eq!(error_lines("if (1 > 1 {\n print 1 +;\n}"), vec![
+            (1, "Expect ')' after condition.".to_string()),
+            (2, "Expect expression".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn test_recovery_keeps_blocks() {
+        // the closing braces end the blocks, none of them is reported missing at the end
+        for source in ["{ print 1 + }", "fun f() { return g(1, }", "match (x) { 1 => }", "{ print 1 }", "while (true) { var a = [1, }"] {
+            assert_eq!(error_lines(source).len(), 1, "{source}");
+            assert_eq!(error_lines(&format!("{source}\nprint 2 +;")).len(), 2, "{source}");
+        }
+        assert_eq!(error_lines("print 1; }\nprint 2 +;").len(), 2);
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let blocks = |n: usize| format!("{}{} print 1;", "{".repeat(n), "}".repeat(n));
+        assert!(Parser::new(&blocks(MAX_NESTED_STATEMENTS as usize - 1), false, false).parse().is_ok());
+        assert_eq!(error_lines(&blocks(100_000)), vec![(1, "Too many nested statements.".to_string())]);
+
+        let source = format!("{} print 1; else print 2;\nprint 3 +;", "if (true)".repeat(100_000));
+        assert_eq!(error_lines(&source), vec![
+            (1, "Too many nested statements.".to_string()),
+            (2, "Expect expression".to_string()),
+        ]);
+        let functions = format!("{}{}", "fun f() {".repeat(100_000), "}".repeat(100_000));
+        assert_eq!(error_lines(&functions).len(), 1);
+    }
+}
@@ -0,0 +1,366 @@
+use crate::{chunk::{backward_offset, forward_offset}, op_code::{Instruction, OpCode}};
+
+/// Rewrites jumps in a single pass over the instructions of one function:
+/// jumps to unconditional jumps go to the final target, `Not; JumpIfFalsePop`
+/// becomes `JumpIfTruePop`, `Not; JumpIfFalse` becomes `JumpIfTrue` when both
+/// branches pop the condition, and jumps to the next instruction are dropped.
+/// Common sequences are fused into the superinstructions, see `OpCode`.
+pub(crate) fn optimize(instructions: &mut Vec<Instruction>) {
+    let len = instructions.len();
+    let mut targets: Vec<Option<usize>> = instructions
+        .iter()
+        .enumerate()
+        .map(|(index, instruction)| target(index, &instruction.code))
+        .collect();
+    let mut removed = vec![false; len];
+
+    for index in 0..len {
+        if let Some(target) = targets[index]
+            && is_threadable(&instructions[index].code)
+        {
+            let threaded = thread(instructions, &targets, target);
+            // only unconditional jumps can change their direction
+            let conditional = !matches!(instructions[index].code, OpCode::Jump(_) | OpCode::Loop(_));
+            if !conditional || threaded > index {
+                targets[index] = Some(threaded);
+            }
+        }
+    }
+
+    for index in 0..len.saturating_sub(2) {
+        let fusable = instructions[index].code == OpCode::Not
+            && matches!(instructions[index + 1].code, OpCode::JumpIfFalse(_))
+            && instructions[index + 2].code == OpCode::Pop
+            && targets[index + 1].is_some_and(|target| instructions.get(target).is_some_and(|i| i.code == OpCode::Pop))
+            // a jump straight to the JumpIfFalse would skip the negation
+            && !targets.contains(&Some(index + 1));
+        if fusable {
+            removed[index] = true;
+            instructions[index + 1].code = OpCode::JumpIfTrue(0);
+        }
+    }
+
+    for index in 0..len.saturating_sub(1) {
+        if instructions[index].code == OpCode::Not
+            && matches!(instructions[index + 1].code, OpCode::JumpIfFalsePop(_))
+            && !targets.contains(&Some(index + 1))
+        {
+            removed[index] = true;
+            instructions[index + 1].code = OpCode::JumpIfTruePop(0);
+        }
+    }
+
+    fuse_superinstructions(instructions, &targets, &mut removed);
+
+    for index in 0..len {
+        // the popping jumps are not a no-op
+        let removable = matches!(
+            instructions[index].code,
+            OpCode::Jump(_) | OpCode::JumpIfFalse(_) | OpCode::JumpIfTrue(_)
+        );
+        if let Some(target) = targets[index]
+            && removable
+            && target > index
+            && (index + 1..target).all(|between| removed[between])
+        {
+            removed[index] = true;
+        }
+    }
+
+    // removed instructions map to the next one kept
+    let mut new_index = vec![0; len + 1];
+    let mut next = removed.iter().filter(|removed| !**removed).count();
+    new_index[len] = next;
+    for index in (0..len).rev() {
+        if !removed[index] {
+            next -= 1;
+        }
+        new_index[index] = next;
+    }
+
+    let old = std::mem::take(instructions);
+    for (index, mut instruction) in old.into_iter().enumerate() {
+        if removed[index] {
+            continue;
+        }
+
+        if let Some(target) = targets[index] {
+            instruction.code = retarget(&instruction.code, new_index[index], new_index[target]);
+        }
+        instructions.push(instruction);
+    }
+}
+
+/// The last instruction of a sequence is replaced, the ones before are removed.
+fn fuse_superinstructions(instructions: &mut [Instruction], targets: &[Option<usize>], removed: &mut [bool]) {
+    // nothing may jump into the middle of a sequence
+    let is_target = |index: usize| targets.contains(&Some(index));
+    let mut index = 0;
+    while index < instructions.len() {
+        let rest = &instructions[index..];
+        let fused = match rest {
+            [
+                Instruction { code: OpCode::GetLocal(slot), .. },
+                Instruction { code: OpCode::Constant(constant), .. },
+                Instruction { code: OpCode::Add, .. },
+                ..
+            ] if !is_target(index + 1) && !is_target(index + 2) => {
+                Some((3, OpCode::GetLocalAddConstant(*slot, *constant)))
+            }
+            [
+                Instruction { code: OpCode::Less, .. },
+                Instruction { code: OpCode::JumpIfFalsePop(_), .. },
+                ..
+            ] if !is_target(index + 1) => Some((2, OpCode::CompareLessJumpIfFalse(0))),
+            [
+                Instruction { code: OpCode::Constant(constant), .. },
+                Instruction { code: OpCode::Call(arg_count), .. },
+                ..
+            ] if !is_target(index + 1) => Some((2, OpCode::ConstantCall(*constant, *arg_count))),
+            _ => None,
+        };
+
+        match fused {
+            Some((count, code)) if !removed[index..index + count].contains(&true) => {
+                let last = index + count - 1;
+                removed[index..last].fill(true);
+                instructions[last].code = code;
+                index += count;
+            }
+            _ => index += 1,
+        }
+    }
+}
+
+fn is_threadable(code: &OpCode) -> bool {
+    matches!(
+        code,
+        OpCode::Jump(_)
+            | OpCode::JumpIfFalse(_)
+            | OpCode::JumpIfTrue(_)
+            | OpCode::JumpIfFalsePop(_)
+            | OpCode::JumpIfTruePop(_)
+            | OpCode::Loop(_)
+    )
+}
+
+/// Index of the instruction a jump at `index` continues with.
+fn target(index: usize, code: &OpCode) -> Option<usize> {
+    let offset = code.jump_offset()?;
+    match code {
+        OpCode::Loop(_) => Some(index + 1 - offset),
+        _ => Some(index + 1 + offset),
+    }
+}
+
+/// Follows unconditional jumps, for a cycle of jumps any of them will do.
+fn thread(instructions: &[Instruction], targets: &[Option<usize>], mut target: usize) -> usize {
+    for _ in 0..instructions.len() {
+        match instructions.get(target) {
+            Some(Instruction { code: OpCode::Jump(_) | OpCode::Loop(_), .. }) => {
+                target = targets[target].expect("jumps have a target");
+            }
+            _ => break,
+        }
+    }
+
+    target
+}
+
+fn retarget(code: &OpCode, index: usize, target: usize) -> OpCode {
+    match code {
+        OpCode::Jump(_) | OpCode::Loop(_) if target > index => OpCode::Jump(forward_offset(index, target)),
+        OpCode::Jump(_) | OpCode::Loop(_) => OpCode::Loop(backward_offset(index, target)),
+        other => {
+            let mut code = other.clone();
+            code.set_jump_offset(forward_offset(index, target));
+            code
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{chunk::Chunk, compiler::compile, value::{Function, Value}, vm::{InterpretResult, VM}};
+
+    use super::*;
+
+    fn function(codes: &[OpCode]) -> Function {
+        let mut chunk = Chunk::new();
+        for code in codes {
+            chunk.write(code.clone(), 1);
+        }
+        Function::new_from_chunk("test".to_string(), chunk)
+    }
+
+    fn optimized(codes: &[OpCode]) -> Vec<OpCode> {
+        let mut function = function(codes);
+        function.optimize();
+        function.codes_from(0)
+    }
+
+    fn global(vm: &VM, name: &str) -> Value {
+        let (_, value) = vm.globals().into_iter().find(|(global, _)| *global == name).unwrap();
+        value.clone()
+    }
+
+    /// Both versions of the chunk define the same global `r`.
+    fn assert_equivalent(codes: &[OpCode]) {
+        let mut results = vec![];
+        for codes in [codes.to_vec(), optimized(codes)] {
+            let mut vm = VM::new();
+            assert!(matches!(vm.run_function(function(&codes), false), InterpretResult::Ok));
+            results.push(global(&vm, "r"));
+        }
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[test]
+    fn test_thread_jump_chain() {
+        let codes = [
+            OpCode::Bool(false),
+            OpCode::JumpIfFalse(1),
+            OpCode::Nil,
+            OpCode::Jump(1),
+            OpCode::Nil,
+            OpCode::DefineGlobal("r".to_string()),
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_eq!(optimized(&codes)[1], OpCode::JumpIfFalse(3));
+        assert_equivalent(&codes);
+    }
+
+    #[test]
+    fn test_thread_into_loop() {
+        let codes = [OpCode::Nil, OpCode::Pop, OpCode::Jump(1), OpCode::Nil, OpCode::Loop(4)];
+        assert_eq!(
+            optimized(&codes),
+            vec![OpCode::Nil, OpCode::Pop, OpCode::Loop(2), OpCode::Nil, OpCode::Loop(4)]
+        );
+    }
+
+    #[test]
+    fn test_fuse_not_jump_if_false() {
+        for condition in [true, false] {
+            let codes = [
+                OpCode::Bool(condition),
+                OpCode::Not,
+                OpCode::JumpIfFalse(3),
+                OpCode::Pop,
+                OpCode::Constant(1.0),
+                OpCode::Jump(2),
+                OpCode::Pop,
+                OpCode::Constant(2.0),
+                OpCode::DefineGlobal("r".to_string()),
+                OpCode::Nil,
+                OpCode::Return,
+            ];
+            let expected = vec![
+                OpCode::Bool(condition),
+                OpCode::JumpIfTrue(3),
+                OpCode::Pop,
+                OpCode::Constant(1.0),
+                OpCode::Jump(2),
+                OpCode::Pop,
+                OpCode::Constant(2.0),
+                OpCode::DefineGlobal("r".to_string()),
+                OpCode::Nil,
+                OpCode::Return,
+            ];
+            assert_eq!(optimized(&codes), expected);
+            assert_equivalent(&codes);
+        }
+    }
+
+    #[test]
+    fn test_keep_not_when_value_is_used() {
+        // `!a and b` keeps the negated value when it is false
+        let codes = [
+            OpCode::Bool(true),
+            OpCode::Not,
+            OpCode::JumpIfFalse(2),
+            OpCode::Pop,
+            OpCode::Bool(true),
+            OpCode::DefineGlobal("r".to_string()),
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_eq!(optimized(&codes), codes);
+    }
+
+    #[test]
+    fn test_remove_jump_to_next() {
+        let codes = [
+            OpCode::Bool(true),
+            OpCode::Jump(0),
+            OpCode::DefineGlobal("r".to_string()),
+            OpCode::Nil,
+            OpCode::Return,
+        ];
+        assert_eq!(optimized(&codes).len(), 4);
+        assert_equivalent(&codes);
+    }
+
+    #[test]
+    fn test_superinstructions() {
+        let source = "
+            fun twice(x) { return x * 2; }
+            var r = 0;
+            for (var i = 0; i < 10; i = i + 1) { r = r + twice(3); }";
+        let function = compile(source.to_string(), false).unwrap().main;
+        let codes = function.codes_from(0);
+        assert!(codes.contains(&OpCode::GetLocalAddConstant(0, 1.0)));
+        assert!(codes.iter().any(|code| matches!(code, OpCode::CompareLessJumpIfFalse(_))));
+        assert!(codes.contains(&OpCode::ConstantCall(3.0, 1)));
+
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret(source.to_string(), false), InterpretResult::Ok));
+        assert_eq!(global(&vm, "r"), Value::Number(60.0));
+    }
+
+    #[test]
+    fn test_superinstruction_errors() {
+        for (source, message) in [
+            ("{ var s = \"a\"; s = s + 1; }", "Operands must be two numbers or two strings, are String(\"a\") and Number(1.0)"),
+            ("var a = \"a\"; while (a < 1) {}", "Operands must be numbers, are String(\"a\") and Number(1.0)"),
+            ("nil(1);", "Can only call functions and classes."),
+        ] {
+            let mut vm = VM::new();
+            let result = vm.interpret(source.to_string(), false);
+            assert!(matches!(result, InterpretResult::RuntimeError(error) if error.message == message), "{source}");
+        }
+    }
+
+    #[test]
+    fn test_no_fusion_at_jump_target() {
+        let codes = [
+            OpCode::Bool(true),
+            OpCode::JumpIfFalsePop(1),
+            OpCode::Constant(1.0),
+            OpCode::Call(0),
+        ];
+        assert_eq!(optimized(&codes), codes);
+    }
+
+    #[test]
+    fn test_compiled_branches() {
+        let source = "
+            var r = 0;
+            if (!a) { if (b) r = 1; else r = 2; } else r = 3;
+            while (!(r > 5)) r = r + 2;";
+        let function = compile(format!("var a = false; var b = true; {source}"), false).unwrap().main;
+        let codes = function.codes_from(0);
+        assert!(codes.contains(&OpCode::JumpIfTruePop(10)));
+        assert!(!codes.contains(&OpCode::Not));
+        // the inner then branch jumps over the outer else branch directly
+        assert!(codes.contains(&OpCode::Jump(7)));
+
+        for (a, b, r) in [(false, true, 7.0), (false, false, 6.0), (true, true, 7.0)] {
+            let mut vm = VM::new();
+            let source = format!("var a = {a}; var b = {b}; {source}");
+            assert!(matches!(vm.interpret(source, false), InterpretResult::Ok));
+            assert_eq!(global(&vm, "r"), Value::Number(r));
+        }
+    }
+}
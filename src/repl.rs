@@ -0,0 +1,184 @@
+use std::{env, fs, path::PathBuf};
+
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+
+use crate::{
+    debug::Debugger,
+    scanner::{Scanner, TokenType},
+    value::Value,
+    vm::VM,
+};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ". ";
+
+const HELP: &str = "\
+:globals         list the globals and their values
+:dis <name>      disassemble the function <name>
+:load <file>     run the file in this session
+:save <file>     write the globals to a snapshot
+:restore <file>  define the globals of a snapshot
+:reset           start over with a fresh VM
+:help            show this help";
+
+/// Interactive loop, input with open braces, parens or strings continues on the next line,
+/// Ctrl-C drops the current input and Ctrl-D ends the session.
+/// Lines starting with ':' are commands, see `HELP`.
+pub fn run() {
+    let mut editor = Editor::<Completions, DefaultHistory>::new().expect("line editor could not be created.");
+    let history = history_path();
+    if let Some(history) = &history {
+        // there is no history on the first start
+        let _ = editor.load_history(history);
+    }
+
+    let mut vm = VM::new();
+    let mut input = String::new();
+    loop {
+        editor.set_helper(Some(Completions { names: vm.complete("") }));
+        let prompt = if input.is_empty() { PROMPT } else { CONTINUATION_PROMPT };
+        match editor.readline(prompt) {
+            Ok(line) if input.is_empty() && line.starts_with(':') => {
+                let _ = editor.add_history_entry(&line);
+                command(&mut vm, &line);
+            }
+            Ok(line) => {
+                input.push_str(&line);
+                input.push('\n');
+                if !is_input_complete(&input) {
+                    continue;
+                }
+
+                let _ = editor.add_history_entry(input.trim_end());
+                vm.interpret_repl(input.clone(), false).print_errors();
+                input.clear();
+            }
+            Err(ReadlineError::Interrupted) => input.clear(),
+            Err(ReadlineError::Eof) => break,
+            Err(err) => {
+                eprintln!("{err}");
+                break;
+            }
+        }
+    }
+
+    if let Some(history) = &history
+        && let Err(err) = editor.save_history(history)
+    {
+        eprintln!("history could not be saved: {err}");
+    }
+}
+
+fn command(vm: &mut VM, line: &str) {
+    let mut parts = line.split_whitespace();
+    let name = parts.next().unwrap_or_default();
+    let argument = parts.next();
+
+    match (name, argument) {
+        (":globals", None) => {
+            for (name, value) in vm.globals() {
+                match value {
+                    Value::Function(function) => {
+                        println!("{name} = <fn {}>", function.signature())
+                    }
+                    _ => println!("{name} = {value:?}"),
+                }
+            }
+        }
+        (":dis", Some(function_name)) => match vm.get_function(function_name) {
+            Some(function) => Debugger::new().disassemble_chunk(function, function_name),
+            None => eprintln!("no function '{function_name}' defined"),
+        },
+        (":load", Some(path)) => match fs::read_to_string(path) {
+            Ok(source) => vm.interpret(source, false).print_errors(),
+            Err(err) => eprintln!("file '{path}' could not be read: {err}"),
+        },
+        (":save", Some(path)) => {
+            if let Err(err) = fs::write(path, vm.snapshot(false)) {
+                eprintln!("file '{path}' could not be written: {err}");
+            }
+        }
+        (":restore", Some(path)) => {
+            let restored = fs::read(path)
+                .map_err(|err| format!("file '{path}' could not be read: {err}"))
+                .and_then(|snapshot| vm.restore(&snapshot));
+            if let Err(message) = restored {
+                eprintln!("{message}");
+            }
+        }
+        (":reset", None) => vm.reset_globals(),
+        (":help", None) => println!("{HELP}"),
+        _ => eprintln!("unknown command '{line}', see :help"),
+    }
+}
+
+/// Tab completion of the globals and keywords known before the current line.
+struct Completions {
+    names: Vec<String>,
+}
+
+impl Completer for Completions {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .map_or(0, |index| index + line[index..].chars().next().map_or(1, char::len_utf8));
+        let prefix = &line[start..pos];
+        let candidates = self.names.iter().filter(|name| name.starts_with(prefix)).cloned().collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completions {
+    type Hint = String;
+}
+
+impl Highlighter for Completions {}
+
+impl Validator for Completions {}
+
+impl Helper for Completions {}
+
+fn history_path() -> Option<PathBuf> {
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    Some(PathBuf::from(home).join(".rlox_history"))
+}
+
+/// False while braces or parens are unbalanced or a string is not terminated.
+pub fn is_input_complete(input: &str) -> bool {
+    let mut scanner = Scanner::new(input);
+    let mut depth = 0;
+    loop {
+        match scanner.scan_token() {
+            Ok(token) => match token.token_type {
+                TokenType::LeftParen | TokenType::LeftBrace => depth += 1,
+                TokenType::RightParen | TokenType::RightBrace => depth -= 1,
+                TokenType::Eof => return depth <= 0,
+                _ => (),
+            },
+            Err(error) if error.message == "Undetermined string" => return false,
+            // the compiler reports other errors
+            Err(_) => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_input_complete() {
+        assert!(is_input_complete("print 1;"));
+        assert!(!is_input_complete("fun f() {\n"));
+        assert!(!is_input_complete("print (1 +\n"));
+        assert!(!is_input_complete("print \"a\n"));
+        assert!(is_input_complete("fun f() {\n}\n"));
+        assert!(is_input_complete("print \"{\";"));
+        assert!(is_input_complete("}"));
+    }
+}
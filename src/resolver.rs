@@ -0,0 +1,613 @@
+use std::{collections::HashSet, rc::Rc};
+
+use crate::{
+    ast::{Expr, FunctionDecl, LoopTarget, Name, Slot, Stmt},
+    compiler::{CompileError, MAX_LOCALS},
+    op_code::OpCode,
+    parser::error_at,
+    scanner::{Scanner, Token},
+};
+
+pub struct FunctionDeclaration {
+    pub name: String,
+    /// Without the rest parameter of a variadic function.
+    pub arity: usize,
+    pub variadic: bool,
+    pub line: i32,
+}
+
+pub struct GlobalCall {
+    pub name: String,
+    pub arg_count: usize,
+    pub line: i32,
+}
+
+/// What the compiler learned while resolving names, used by the linter.
+#[derive(Default)]
+pub struct ResolverData {
+    pub global_functions: Vec<FunctionDeclaration>,
+    /// The variables and functions of the top level, in declaration order.
+    pub globals_declared: Vec<String>,
+    pub global_reads: HashSet<String>,
+    /// Locals with a value which is never read, name and declaration line.
+    pub unread_locals: Vec<(String, i32)>,
+    pub global_calls: Vec<GlobalCall>,
+    /// The code of `if`, `while` and `for` conditions, filled in by the code generator.
+    pub conditions: Vec<(i32, Vec<OpCode>)>,
+}
+
+struct Local {
+    name: Name,
+    line: i32,
+    // The depth is set after the variable is initialized.
+    depth: Option<u32>,
+    read: bool,
+    constant: bool,
+}
+
+/// Methods and initializers join once there are classes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FunctionType {
+    Script,
+    Function,
+}
+
+/// A loop `break` and `continue` can target.
+struct LoopScope {
+    label: Option<Name>,
+    /// The locals declared before the body, which stay on the stack.
+    locals: usize,
+    /// The `try` blocks the loop is in.
+    handlers: usize,
+}
+
+struct FunctionScope {
+    locals: Vec<Local>,
+    scope_depth: u32,
+    function_type: FunctionType,
+    generator: bool,
+    /// The enclosing loops, innermost last.
+    loops: Vec<LoopScope>,
+    /// The `try` blocks the current statement is in, not counting their handlers.
+    handlers: usize,
+}
+
+impl FunctionScope {
+    fn new(function_type: FunctionType) -> Self {
+        Self {
+            locals: Vec::with_capacity(MAX_LOCALS),
+            scope_depth: 0,
+            function_type,
+            generator: false,
+            loops: vec![],
+            handlers: 0,
+        }
+    }
+}
+
+/// Assigns the stack slots of the locals and checks the rules the parser can't see.
+pub(crate) struct Resolver<'a> {
+    scanner: &'a Scanner<'a>,
+    function: FunctionScope,
+    /// Globals declared with `const` in this source.
+    global_constants: HashSet<String>,
+    /// Strict mode, globals defined before the source runs, e.g. the natives.
+    /// Every global the source uses must be one of them or declared in the source.
+    known_globals: Option<HashSet<String>>,
+    /// All top level declarations, collected before resolving. Functions can
+    /// use any of them, they are called after the declarations ran.
+    top_level_globals: HashSet<String>,
+    /// The top level declarations resolved so far, which the top level code can use.
+    declared_globals: HashSet<String>,
+    data: ResolverData,
+    errors: Vec<CompileError>,
+}
+
+impl<'a> Resolver<'a> {
+    pub(crate) fn new(scanner: &'a Scanner<'a>) -> Self {
+        Self {
+            scanner,
+            function: FunctionScope::new(FunctionType::Script),
+            global_constants: HashSet::new(),
+            known_globals: None,
+            top_level_globals: HashSet::new(),
+            declared_globals: HashSet::new(),
+            data: ResolverData::default(),
+            errors: vec![],
+        }
+    }
+
+    /// Reports globals which are neither known nor declared in the source.
+    pub(crate) fn strict(scanner: &'a Scanner<'a>, known_globals: HashSet<String>) -> Self {
+        Self {
+            known_globals: Some(known_globals),
+            ..Self::new(scanner)
+        }
+    }
+
+    pub(crate) fn resolve(mut self, statements: &mut [Stmt]) -> Result<ResolverData, Vec<CompileError>> {
+        for statement in statements.iter() {
+            if let Stmt::Var { name, .. } | Stmt::Function(FunctionDecl { name, .. }) = statement
+                && self.top_level_globals.insert(name.to_string())
+            {
+                self.data.globals_declared.push(name.to_string());
+            }
+        }
+
+        for statement in statements {
+            self.statement(statement);
+        }
+
+        if self.errors.is_empty() { Ok(self.data) } else { Err(self.errors) }
+    }
+
+    fn statement(&mut self, statement: &mut Stmt) {
+        match statement {
+            Stmt::Expression(expression) => self.expression(expression),
+            Stmt::Print { expressions, .. } => {
+                for expression in expressions {
+                    self.expression(expression);
+                }
+            }
+            Stmt::Var { name, token, initializer, constant } => {
+                self.var_declaration(name, token, initializer.as_mut(), *constant);
+            }
+            Stmt::Function(declaration) => self.fun_declaration(declaration),
+            Stmt::Block { statements, .. } => {
+                self.begin_scope();
+                self.statements(statements);
+                self.end_scope();
+            }
+            Stmt::If { condition, then_branch, else_branch, .. } => {
+                self.expression(condition);
+                self.statement(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.statement(else_branch);
+                }
+            }
+            Stmt::While { keyword, condition, body, label } => {
+                self.expression(condition);
+                self.loop_body(keyword, label, body);
+            }
+            Stmt::For { keyword, initializer, condition, increment, body, label } => {
+                self.begin_scope();
+                if let Some(initializer) = initializer {
+                    self.statement(initializer);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(increment) = increment {
+                    self.expression(increment);
+                }
+                self.loop_body(keyword, label, body);
+                self.end_scope();
+            }
+            Stmt::ForIn { keyword, name, token, collection, body, collection_slot, label } => {
+                self.begin_scope();
+                self.expression(collection);
+                *collection_slot = self.add_hidden_local(token);
+                self.add_hidden_local(token);
+
+                // the loop variable is popped before the next iteration
+                self.push_loop(keyword, label);
+                self.begin_scope();
+                self.declare_variable(name, token);
+                self.mark_initialized();
+                self.statement(body);
+                self.end_scope();
+                self.function.loops.pop();
+                self.end_scope();
+            }
+            Stmt::Break { keyword, label, target } => {
+                *target = self.loop_target(keyword, label.as_ref(), "break");
+            }
+            Stmt::Continue { keyword, label, target } => {
+                *target = self.loop_target(keyword, label.as_ref(), "continue");
+            }
+            Stmt::Return { keyword, value } => {
+                if self.function.function_type == FunctionType::Script {
+                    self.error(keyword, "Can't return from top-level code.");
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            Stmt::Throw { value, .. } => self.expression(value),
+            Stmt::Match { keyword, value, arms, value_slot } => {
+                self.begin_scope();
+                self.expression(value);
+                *value_slot = self.add_hidden_local(keyword);
+
+                for arm in arms {
+                    self.begin_scope();
+                    for (name, token, _) in arm.pattern.bindings() {
+                        self.declare_variable(name, token);
+                        self.mark_initialized();
+                    }
+                    self.statement(&mut arm.body);
+                    self.end_scope();
+                }
+                self.end_scope();
+            }
+            Stmt::Try { body, name, token, handler, .. } => {
+                self.function.handlers += 1;
+                self.statement(body);
+                self.function.handlers -= 1;
+
+                self.begin_scope();
+                self.declare_variable(name, token);
+                self.mark_initialized();
+                self.statements(handler);
+                self.end_scope();
+            }
+        }
+    }
+
+    fn statements(&mut self, statements: &mut [Stmt]) {
+        for statement in statements {
+            self.statement(statement);
+        }
+    }
+
+    fn loop_body(&mut self, keyword: &Token, label: &Option<Name>, body: &mut Stmt) {
+        self.push_loop(keyword, label);
+        self.statement(body);
+        self.function.loops.pop();
+    }
+
+    fn push_loop(&mut self, keyword: &Token, label: &Option<Name>) {
+        if let Some(label) = label
+            && self.function.loops.iter().any(|outer| outer.label.as_ref() == Some(label))
+        {
+            self.error(keyword, &format!("Label '{label}' is already used by an enclosing loop."));
+        }
+        self.function.loops.push(LoopScope {
+            label: label.clone(),
+            locals: self.function.locals.len(),
+            handlers: self.function.handlers,
+        });
+    }
+
+    /// The loop of a `break` or `continue`, the innermost one without a label.
+    fn loop_target(&mut self, keyword: &Token, label: Option<&(Name, Token)>, kind: &str) -> LoopTarget {
+        let loops = &self.function.loops;
+        let position = match label {
+            Some((name, _)) => loops.iter().rposition(|scope| scope.label.as_ref() == Some(name)),
+            None => loops.len().checked_sub(1),
+        };
+        let Some(position) = position else {
+            match label {
+                Some((name, token)) => self.error(token, &format!("No enclosing loop is labeled '{name}'.")),
+                None => self.error(keyword, &format!("Can't use '{kind}' outside of a loop.")),
+            }
+            return LoopTarget::default();
+        };
+
+        let scope = &loops[position];
+        LoopTarget {
+            depth: loops.len() - 1 - position,
+            locals: self.function.locals.len() - scope.locals,
+            handlers: self.function.handlers - scope.handlers,
+        }
+    }
+
+    fn var_declaration(
+        &mut self,
+        name: &Name,
+        token: &Token,
+        initializer: Option<&mut Expr>,
+        constant: bool,
+    ) {
+        let global = self.function.scope_depth == 0;
+        if global {
+            self.declare_global(name, token);
+            if constant {
+                self.global_constants.insert(name.to_string());
+            }
+        } else {
+            self.declare_variable(name, token);
+            if let Some(local) = self.function.locals.last_mut() {
+                local.constant = constant;
+            }
+        }
+
+        if let Some(initializer) = initializer {
+            self.expression(initializer);
+        }
+
+        if global {
+            self.declared_globals.insert(name.to_string());
+        } else {
+            self.mark_initialized();
+        }
+    }
+
+    fn fun_declaration(&mut self, declaration: &mut FunctionDecl) {
+        if self.function.scope_depth == 0 {
+            self.declare_global(&declaration.name, &declaration.token);
+            self.declared_globals.insert(declaration.name.to_string());
+            self.data.global_functions.push(FunctionDeclaration {
+                name: declaration.name.to_string(),
+                arity: declaration.params.len() - usize::from(declaration.variadic),
+                variadic: declaration.variadic,
+                line: declaration.token.line,
+            });
+        } else {
+            self.declare_variable(&declaration.name, &declaration.token);
+            self.mark_initialized();
+        }
+
+        let enclosing = std::mem::replace(&mut self.function, FunctionScope::new(FunctionType::Function));
+        self.begin_scope();
+        for (name, param) in &declaration.params {
+            self.declare_variable(name, param);
+            self.mark_initialized();
+            // unused parameters are not reported
+            if let Some(local) = self.function.locals.last_mut() {
+                local.read = true;
+            }
+        }
+        self.statements(&mut declaration.body);
+
+        let function = std::mem::replace(&mut self.function, enclosing);
+        self.record_unread(function.locals);
+        declaration.generator = function.generator;
+    }
+
+    fn expression(&mut self, expression: &mut Expr) {
+        match expression {
+            Expr::Literal { .. } => (),
+            Expr::Variable { name, token, slot } => {
+                *slot = match self.resolve_local(name, token) {
+                    Some(pos) => {
+                        self.function.locals[pos].read = true;
+                        Slot::Local(pos)
+                    }
+                    None => {
+                        self.data.global_reads.insert(name.to_string());
+                        self.use_global(name, token);
+                        Slot::Global
+                    }
+                };
+            }
+            Expr::Assign { name, token, value, slot } => {
+                let local_pos = self.resolve_local(name, token);
+                let constant = match local_pos {
+                    Some(pos) => self.function.locals[pos].constant,
+                    None => self.global_constants.contains(&**name),
+                };
+                if constant {
+                    self.error(token, &format!("Can't assign to constant '{name}'."));
+                }
+
+                self.expression(value);
+                if local_pos.is_none() {
+                    self.use_global(name, token);
+                }
+                *slot = local_pos.map_or(Slot::Global, Slot::Local);
+            }
+            Expr::Unary { right, .. } => self.expression(right),
+            Expr::List { elements, .. } => {
+                for element in elements.iter_mut() {
+                    self.expression(element);
+                }
+            }
+            Expr::Get { object, .. } => self.expression(object),
+            Expr::Binary { left, right, .. } | Expr::Logical { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+            Expr::Call { callee, paren, arguments } => {
+                self.expression(callee);
+                for argument in arguments.iter_mut() {
+                    self.expression(argument);
+                }
+
+                if let Expr::Variable { name, slot: Slot::Global, .. } = callee.as_ref() {
+                    self.data.global_calls.push(GlobalCall {
+                        name: name.to_string(),
+                        arg_count: arguments.len(),
+                        line: paren.line,
+                    });
+                }
+            }
+            Expr::Yield { value, .. } => {
+                // a yield in the script suspends the VM, in a function it makes a generator
+                if self.function.function_type == FunctionType::Function {
+                    self.function.generator = true;
+                }
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+        }
+    }
+
+    fn use_global(&mut self, name: &str, token: &Token) {
+        let Some(known_globals) = &self.known_globals else {
+            return;
+        };
+        let declared = match self.function.function_type {
+            FunctionType::Script => &self.declared_globals,
+            FunctionType::Function => &self.top_level_globals,
+        };
+        if !known_globals.contains(name) && !declared.contains(name) {
+            self.error(token, &format!("Undefined variable '{name}'."));
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.function.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        let function = &mut self.function;
+        function.scope_depth -= 1;
+
+        let mut ended = vec![];
+        while let Some(local) = function.locals.last()
+            && local.depth.is_some_and(|depth| depth > function.scope_depth)
+        {
+            ended.extend(function.locals.pop());
+        }
+        self.record_unread(ended);
+    }
+
+    fn record_unread(&mut self, locals: Vec<Local>) {
+        for local in locals.into_iter().filter(|local| !local.read) {
+            self.data.unread_locals.push((local.name.to_string(), local.line));
+        }
+    }
+
+    fn mark_initialized(&mut self) {
+        let function = &mut self.function;
+        if function.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = function.locals.last_mut() {
+            local.depth = Some(function.scope_depth);
+        }
+    }
+
+    /// Globals can be declared again, except for constants.
+    fn declare_global(&mut self, name: &Name, token: &Token) {
+        if self.global_constants.contains(&**name) {
+            self.error(token, "Already a constant with this name.");
+        }
+    }
+
+    fn declare_variable(&mut self, name: &Name, token: &Token) {
+        if self.function.scope_depth == 0 {
+            return;
+        }
+
+        let duplicate = self.function.locals.iter().rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= self.function.scope_depth))
+            .find(|local| Rc::ptr_eq(&local.name, name));
+        match duplicate {
+            Some(local) if local.constant => self.error(token, "Already a constant with this name."),
+            Some(_) => self.error(token, "Already a variable with this name in this scope."),
+            None => (),
+        }
+
+        self.add_local(Local {
+            name: Rc::clone(name),
+            line: token.line,
+            depth: None,
+            read: false,
+            constant: false,
+        }, token);
+    }
+
+    fn add_local(&mut self, local: Local, token: &Token) {
+        if self.function.locals.len() == MAX_LOCALS {
+            self.error(token, "Too many local variables in function.");
+            return;
+        }
+
+        self.function.locals.push(local);
+    }
+
+    /// A local for values only the compiler refers to, the empty name never resolves.
+    fn add_hidden_local(&mut self, token: &Token) -> usize {
+        self.add_local(Local {
+            name: "".into(),
+            line: token.line,
+            depth: Some(self.function.scope_depth),
+            read: true,
+            constant: false,
+        }, token);
+
+        self.function.locals.len().saturating_sub(1)
+    }
+
+    /// Names are interned by the parser, so a local is found by comparing pointers.
+    fn resolve_local(&mut self, name: &Name, token: &Token) -> Option<usize> {
+        let pos = self.function.locals.iter().rposition(|local| Rc::ptr_eq(&local.name, name))?;
+        if self.function.locals[pos].depth.is_none() {
+            self.error(token, "Can't read variable in its own initializer");
+        }
+        Some(pos)
+    }
+
+    fn error(&mut self, token: &Token, message: &str) {
+        self.errors.push(error_at(self.scanner, token, message));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn resolve(source: &str) -> Result<Vec<Stmt>, Vec<CompileError>> {
+        let mut parser = Parser::new(source, false, false);
+        let mut statements = parser.parse()?;
+        Resolver::new(parser.scanner()).resolve(&mut statements)?;
+        Ok(statements)
+    }
+
+    #[test]
+    fn test_slots() {
+        let statements = resolve("var g; { var a; { var b; b = a; } print g; }").unwrap();
+        let Stmt::Block { statements, .. } = &statements[1] else { panic!() };
+        let Stmt::Block { statements: inner, .. } = &statements[1] else { panic!() };
+        let Stmt::Expression(Expr::Assign { slot, value, .. }) = &inner[1] else { panic!() };
+        assert_eq!(*slot, Slot::Local(1));
+        assert!(matches!(value.as_ref(), Expr::Variable { slot: Slot::Local(0), .. }));
+        let Stmt::Print { expressions, .. } = &statements[2] else { panic!() };
+        assert!(matches!(&expressions[..], [Expr::Variable { slot: Slot::Global, .. }]));
+    }
+
+    #[test]
+    fn test_generator_and_errors() {
+        let statements = resolve("fun g() { yield 1; } fun f() { fun h() { yield; } }").unwrap();
+        assert!(matches!(&statements[0], Stmt::Function(FunctionDecl { generator: true, .. })));
+        assert!(matches!(&statements[1], Stmt::Function(FunctionDecl { generator: false, .. })));
+
+        assert!(resolve("{ var a = a; }").is_err());
+        assert!(resolve("{ var a; var a; }").is_err());
+        assert!(resolve("return 1;").is_err());
+        assert!(resolve("match (1) { [a, a] => print a; }").is_err());
+    }
+
+    #[test]
+    fn test_loop_targets() {
+        let statements = resolve("a: while (true) { var x; try { while (true) { var y; break a; } } catch (e) {} }").unwrap();
+        let Stmt::While { body, label: Some(label), .. } = &statements[0] else { panic!() };
+        assert_eq!(&**label, "a");
+        let Stmt::Block { statements, .. } = body.as_ref() else { panic!() };
+        let Stmt::Try { body, .. } = &statements[1] else { panic!() };
+        let Stmt::Block { statements, .. } = body.as_ref() else { panic!() };
+        let Stmt::While { body, .. } = &statements[0] else { panic!() };
+        let Stmt::Block { statements, .. } = body.as_ref() else { panic!() };
+        let Stmt::Break { target, .. } = &statements[1] else { panic!() };
+        assert_eq!(*target, LoopTarget { depth: 1, locals: 2, handlers: 1 });
+
+        assert!(resolve("for (var i in 0..3) { var j; continue; }").is_ok());
+        assert!(resolve("break;").is_err());
+        assert!(resolve("while (true) { fun f() { continue; } }").is_err());
+        assert!(resolve("a: while (true) { break b; }").is_err());
+        assert!(resolve("a: while (true) { a: while (true) {} }").is_err());
+    }
+
+    #[test]
+    fn test_strict_globals() {
+        let strict = |source: &str| {
+            let mut parser = Parser::new(source, false, false);
+            let mut statements = parser.parse()?;
+            let known = HashSet::from(["clock".to_string()]);
+            Resolver::strict(parser.scanner(), known).resolve(&mut statements).map(|_| ())
+        };
+        assert!(strict("fun f() { return g() + clock(); } fun g() { return f(); } var a = 1; a = 2;").is_ok());
+        assert!(strict("print b;").is_err());
+        assert!(strict("b = 1;").is_err());
+        assert!(strict("{ var b; } print b;").is_err());
+        assert!(strict("print b; var b = 1;").is_err());
+        assert!(strict("var b = b;").is_err());
+        assert!(strict("fun f() { return b; } var b = 1;").is_ok());
+        assert!(resolve("print b;").is_ok());
+    }
+}
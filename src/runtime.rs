@@ -0,0 +1,285 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use crate::{value::{NativeFunction, Value}, vm::VM};
+
+/// Function of the host program callable from lox, the arguments are in call order.
+pub type HostFunction = fn(&[Value]) -> Result<Value, String>;
+
+/// Result of an async host function, awaited by the embedder.
+pub type HostFuture = Pin<Box<dyn Future<Output = Result<Value, String>> + Send>>;
+
+/// Host function that suspends the script until the returned future is resolved.
+pub type AsyncHostFunction = fn(&[Value]) -> HostFuture;
+
+/// The natives every VM of a runtime starts with.
+#[derive(Clone)]
+pub struct NativeRegistry {
+    globals: Vec<(String, Value)>,
+    host_functions: Vec<HostFunction>,
+    async_functions: Vec<AsyncHostFunction>,
+}
+
+impl Default for NativeRegistry {
+    fn default() -> Self {
+        let globals = [
+            ("clock", NativeFunction::Clock, 0),
+            ("len", NativeFunction::Len, 1),
+            ("contains", NativeFunction::Contains, 2),
+            ("random", NativeFunction::Random, 0),
+            ("print", NativeFunction::Print, 1),
+            ("println", NativeFunction::Println, 1),
+            ("env", NativeFunction::Env, 1),
+            ("set_env", NativeFunction::SetEnv, 2),
+            ("exec", NativeFunction::Exec, 1),
+            ("shell", NativeFunction::Shell, 1),
+            ("sort", NativeFunction::Sort, 1),
+            ("sort_by", NativeFunction::SortBy, 2),
+            ("inspect", NativeFunction::Inspect, 1),
+            ("dump", NativeFunction::Dump, 1),
+            ("template", NativeFunction::Template, 2),
+            ("globals", NativeFunction::Globals, 0),
+            ("version", NativeFunction::Version, 0),
+            ("is_nan", NativeFunction::IsNan, 1),
+            ("is_finite", NativeFunction::IsFinite, 1),
+        ];
+        #[cfg(feature = "net")]
+        let globals = globals.into_iter().chain([
+            ("tcp_connect", NativeFunction::TcpConnect, 2),
+            ("tcp_send", NativeFunction::TcpSend, 2),
+            ("tcp_recv", NativeFunction::TcpRecv, 1),
+            ("tcp_close", NativeFunction::TcpClose, 1),
+        ]);
+
+        Self {
+            globals: globals
+                .into_iter()
+                .map(|(name, function, arity)| (name.to_string(), Value::Native(function, arity)))
+                .collect(),
+            host_functions: vec![],
+            async_functions: vec![],
+        }
+    }
+}
+
+impl NativeRegistry {
+    pub(crate) fn globals(&self) -> impl Iterator<Item = (&String, &Value)> {
+        self.globals.iter().map(|(name, value)| (name, value))
+    }
+
+    /// The native defined as `name`, also if a script has redefined the global.
+    pub(crate) fn native(&self, name: &str) -> Option<&Value> {
+        self.globals.iter().find(|(global, _)| global == name).map(|(_, value)| value)
+    }
+
+    pub(crate) fn host_function(&self, index: u32) -> Option<HostFunction> {
+        self.host_functions.get(index as usize).copied()
+    }
+
+    pub(crate) fn async_function(&self, index: u32) -> Option<AsyncHostFunction> {
+        self.async_functions.get(index as usize).copied()
+    }
+
+    fn register(&mut self, name: &str, arity: usize, function: HostFunction) {
+        let native = NativeFunction::Host(self.host_functions.len() as u32);
+        self.host_functions.push(function);
+        self.define(name, native, arity);
+    }
+
+    fn register_async(&mut self, name: &str, arity: usize, function: AsyncHostFunction) {
+        let native = NativeFunction::AsyncHost(self.async_functions.len() as u32);
+        self.async_functions.push(function);
+        self.define(name, native, arity);
+    }
+
+    fn define(&mut self, name: &str, native: NativeFunction, arity: usize) {
+        let arity = u8::try_from(arity).expect("a native takes at most 255 arguments");
+        self.globals.retain(|(global, _)| global != name);
+        self.globals.push((name.to_string(), Value::Native(native, arity)));
+    }
+}
+
+/// Creates VMs that share the natives but have their own globals and stacks,
+/// e.g. a fresh interpreter for every request of a server.
+#[derive(Default)]
+pub struct Runtime {
+    natives: Arc<NativeRegistry>,
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Defines `name` in VMs spawned from now on, an existing native with that name is replaced.
+    /// Panics if `arity` is above 255.
+    pub fn register(&mut self, name: &str, arity: usize, function: HostFunction) {
+        Arc::make_mut(&mut self.natives).register(name, arity, function);
+    }
+
+    /// Like `register`, but calls suspend scripts started with `VM::start`, see `Execution::Waiting`.
+    pub fn register_async(&mut self, name: &str, arity: usize, function: AsyncHostFunction) {
+        Arc::make_mut(&mut self.natives).register_async(name, arity, function);
+    }
+
+    pub fn spawn(&self) -> VM {
+        VM::with_natives(Arc::clone(&self.natives))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, task::{Context, Poll, Waker}, thread};
+
+    use crate::{compiler::compile, value::Function, vm::{Execution, InterpretResult}};
+
+    use super::*;
+
+    fn double(args: &[Value]) -> Result<Value, String> {
+        match args {
+            [Value::Number(n)] => Ok(Value::Number(n * 2.0)),
+            _ => Err("double() needs a number.".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_spawned_vms_share_natives() {
+        let mut runtime = Runtime::new();
+        runtime.register("double", 1, double);
+
+        let mut first = runtime.spawn();
+        let mut second = runtime.spawn();
+        assert!(matches!(first.interpret("var a = double(21);".to_string(), false), InterpretResult::Ok));
+        assert!(matches!(second.interpret("var b = double(len(\"ab\"));".to_string(), false), InterpretResult::Ok));
+
+        let global = |vm: &VM, name: &str| {
+            vm.globals().into_iter().find(|(global, _)| *global == name).map(|(_, value)| value.clone())
+        };
+        assert_eq!(global(&first, "a"), Some(Value::Number(42.0)));
+        assert_eq!(global(&first, "b"), None);
+        assert_eq!(global(&second, "b"), Some(Value::Number(4.0)));
+
+        let result = second.interpret("double(nil);".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(error) if error.message == "double() needs a number."));
+    }
+
+    static OUTPUT: Mutex<Vec<Value>> = Mutex::new(vec![]);
+
+    fn capture(args: &[Value]) -> Result<Value, String> {
+        OUTPUT.lock().unwrap().push(args[0].clone());
+        Ok(Value::Nil)
+    }
+
+    #[test]
+    fn test_replace_println() {
+        let mut runtime = Runtime::new();
+        runtime.register("println", 1, capture);
+        let mut vm = runtime.spawn();
+        let result = vm.interpret("print 1; var out = println; out(\"a\");".to_string(), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        // redefining the global doesn't change what `print` calls
+        let result = vm.interpret("var println = 1; print 2;".to_string(), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        assert_eq!(*OUTPUT.lock().unwrap(), vec![Value::Number(1.0), Value::from("a"), Value::Number(2.0)]);
+    }
+
+    fn fetch(args: &[Value]) -> HostFuture {
+        let arg = args[0].clone();
+        Box::pin(async move {
+            match arg {
+                Value::Number(n) => Ok(Value::Number(n + 1.0)),
+                _ => Err("fetch() needs a number.".to_string()),
+            }
+        })
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    #[test]
+    fn test_async_native_suspends() {
+        let mut runtime = Runtime::new();
+        runtime.register_async("fetch", 1, fetch);
+        let mut vm = runtime.spawn();
+        let function = compile("var result = fetch(1) * 10;".to_string(), false).unwrap().main;
+
+        let Ok(Execution::Waiting(handle, future)) = vm.start(function) else { panic!("not waiting") };
+        let value = block_on(future).unwrap();
+        assert_eq!(value, Value::Number(2.0));
+        assert!(matches!(vm.resume(handle, value), Ok(Execution::Complete(Value::Nil))));
+        let (_, result) = vm.globals().into_iter().find(|(name, _)| *name == "result").unwrap();
+        assert_eq!(result, &Value::Number(20.0));
+    }
+
+    #[test]
+    fn test_run_async() {
+        let mut runtime = Runtime::new();
+        runtime.register_async("fetch", 1, fetch);
+        let mut vm = runtime.spawn();
+        let source = "
+            fun twice(n) { return fetch(fetch(n)); }
+            var error;
+            try { fetch(nil); } catch (e) { error = e; }
+            yield 5;
+            var result = twice(1);";
+        let function = compile(source.to_string(), false).unwrap().main;
+        assert_eq!(block_on(vm.run_async(function)), Ok(Value::Nil));
+        let (_, result) = vm.globals().into_iter().find(|(name, _)| *name == "result").unwrap();
+        assert_eq!(result, &Value::Number(3.0));
+        let (_, error) = vm.globals().into_iter().find(|(name, _)| *name == "error").unwrap();
+        assert_eq!(error, &Value::from("fetch() needs a number."));
+
+        let result = vm.interpret("fetch(1);".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(error) if error.message.contains("VM::start")));
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_thread_safe_types() {
+        assert_send_sync::<Value>();
+        assert_send_sync::<Function>();
+        assert_send_sync::<VM>();
+        assert_send_sync::<Runtime>();
+    }
+
+    #[test]
+    fn test_scripts_on_worker_threads() {
+        let mut runtime = Runtime::new();
+        runtime.register("double", 1, double);
+        let jobs = Mutex::new((0..32).collect::<Vec<i32>>());
+        let results = Mutex::new(vec![]);
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| loop {
+                    // release the lock before running the job
+                    let job = jobs.lock().unwrap().pop();
+                    let Some(job) = job else { break };
+
+                    let mut vm = runtime.spawn();
+                    let source = format!(
+                        "fun fib(n) {{ if (n < 2) return n; return fib(n - 1) + fib(n - 2); }}
+                         var result = double(fib({}));", job % 10);
+                    assert!(matches!(vm.interpret(source, false), InterpretResult::Ok));
+                    let (_, result) = vm.globals().into_iter().find(|(name, _)| *name == "result").unwrap();
+                    results.lock().unwrap().push((job, result.clone()));
+                });
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(job, _)| *job);
+        let fib = [0.0, 1.0, 1.0, 2.0, 3.0, 5.0, 8.0, 13.0, 21.0, 34.0];
+        assert_eq!(results.len(), 32);
+        for (job, result) in results {
+            assert_eq!(result, Value::Number(fib[job as usize % 10] * 2.0));
+        }
+    }
+}
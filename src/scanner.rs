@@ -1,26 +1,40 @@
-#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq)]
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Serialize)]
 pub enum TokenType {
     // Single-character tokens.
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    DotDot,
+    /// `...` before a rest parameter.
+    DotDotDot,
     Minus,
     Plus,
     Semicolon,
+    /// `:` after the label of a loop.
+    Colon,
     Slash,
     Star,
-    // One or two character tokens.
+    // One, two or three character tokens.
     Bang,
     BangEqual,
+    /// `!==` and `===` compare the type too, even in strict mode they never fail.
+    BangEqualEqual,
     Equal,
     EqualEqual,
+    EqualEqualEqual,
     Greater,
     GreaterEqual,
     Less,
     LessEqual,
+    /// `=>` between a pattern and its statement.
+    FatArrow,
     // Literals.
     Identifier,
     String,
@@ -33,15 +47,27 @@ pub enum TokenType {
     For,
     Fun,
     If,
+    In,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
+    Try,
     Var,
     While,
+    Catch,
+    Yield,
+    Const,
+    Match,
+    Break,
+    Continue,
+
+    // Only produced when the scanner keeps comments.
+    Comment,
 
     // handled by extra type: Error,
     Eof,
@@ -55,6 +81,16 @@ impl From<TokenType> for usize {
 
 pub type ScanResult = Result<Token, ErrorToken>;
 
+/// The reserved words, for completion.
+pub const KEYWORDS: [&str; 25] = [
+    "and", "break", "catch", "class", "const", "continue", "else", "false", "for", "fun", "if", "in",
+    "match", "nil", "or", "print", "return", "super", "this", "throw", "true", "try", "var", "while",
+    "yield",
+];
+
+/// Opens and closes a raw string, which can contain single quotes.
+const RAW_QUOTES: &str = "\"\"\"";
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
@@ -71,46 +107,52 @@ pub struct ErrorToken {
     pub message: String,
 }
 
-pub struct Scanner {
-    // vec of chars to be similar to the c implementation
-    // but still allow utf-8
-    source: Vec<char>,
+/// Tokens of the source, `start` and `length` of the tokens are byte offsets
+/// into it, so lexemes are slices of the source.
+pub struct Scanner<'a> {
+    source: &'a str,
     line: i32,
     start: usize,
     current: usize,
+    keep_comments: bool,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &'a str) -> Self {
         Self {
-            source: source.chars().collect(),
+            source,
             line: 1,
             start: 0,
             current: 0,
+            keep_comments: false,
+        }
+    }
+
+    /// A scanner which returns `//` comments as tokens, for tooling.
+    pub fn with_comments(source: &'a str) -> Self {
+        Self {
+            keep_comments: true,
+            ..Self::new(source)
         }
     }
 
-    // todo: move the lexeme handling in a separate struct which wraps around the source as Vec<char>
-    pub fn lexeme(&self, token: &Token) -> String {
-        self.source[token.start..token.start + token.length]
-            .iter()
-            .collect()
+    pub fn lexeme(&self, token: &Token) -> &'a str {
+        &self.source[token.start..token.start + token.length]
     }
 
-    pub fn lexeme_string(&self, token: &Token) -> String {
+    pub fn lexeme_string(&self, token: &Token) -> &'a str {
         match token.token_type {
-            TokenType::String => self.source[token.start + 1..token.start + token.length - 1]
-                .iter()
-                .collect(),
+            TokenType::String => {
+                // a string in single quotes can't start with three of them
+                let lexeme = self.lexeme(token);
+                let quotes = if lexeme.starts_with(RAW_QUOTES) { 3 } else { 1 };
+                &lexeme[quotes..lexeme.len() - quotes]
+            }
             _ => panic!("lexeme_string called with {:?}", token.token_type),
         }
     }
 
     pub fn identifiers_equal(&self, token1: &Token, token2: &Token) -> bool {
-        if token1.length != token2.length {
-            return false;
-        }
-
         self.lexeme(token1) == self.lexeme(token2)
     }
 
@@ -123,7 +165,7 @@ impl Scanner {
 
         let c = self.advance();
 
-        if self.is_alpha(c) {
+        if self.is_identifier_start(c) {
             return self.identifier();
         }
 
@@ -136,23 +178,54 @@ impl Scanner {
             ')' => return self.make_token(TokenType::RightParen),
             '{' => return self.make_token(TokenType::LeftBrace),
             '}' => return self.make_token(TokenType::RightBrace),
+            '[' => return self.make_token(TokenType::LeftBracket),
+            ']' => return self.make_token(TokenType::RightBracket),
             ';' => return self.make_token(TokenType::Semicolon),
+            ':' => return self.make_token(TokenType::Colon),
             ',' => return self.make_token(TokenType::Comma),
-            '.' => return self.make_token(TokenType::Dot),
+            '.' => {
+                return if self.match_char('.') {
+                    if self.match_char('.') {
+                        self.make_token(TokenType::DotDotDot)
+                    } else {
+                        self.make_token(TokenType::DotDot)
+                    }
+                } else {
+                    self.make_token(TokenType::Dot)
+                };
+            }
             '-' => return self.make_token(TokenType::Minus),
             '+' => return self.make_token(TokenType::Plus),
-            '/' => return self.make_token(TokenType::Slash),
+            '/' => {
+                if self.keep_comments && self.match_char('/') {
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
+                    }
+                    return self.make_token(TokenType::Comment);
+                }
+                return self.make_token(TokenType::Slash);
+            }
             '*' => return self.make_token(TokenType::Star),
             '!' => {
                 return if self.match_char('=') {
-                    self.make_token(TokenType::BangEqual)
+                    if self.match_char('=') {
+                        self.make_token(TokenType::BangEqualEqual)
+                    } else {
+                        self.make_token(TokenType::BangEqual)
+                    }
                 } else {
                     self.make_token(TokenType::Bang)
                 };
             }
             '=' => {
                 return if self.match_char('=') {
-                    self.make_token(TokenType::EqualEqual)
+                    if self.match_char('=') {
+                        self.make_token(TokenType::EqualEqualEqual)
+                    } else {
+                        self.make_token(TokenType::EqualEqual)
+                    }
+                } else if self.match_char('>') {
+                    self.make_token(TokenType::FatArrow)
                 } else {
                     self.make_token(TokenType::Equal)
                 };
@@ -171,6 +244,7 @@ impl Scanner {
                     self.make_token(TokenType::Greater)
                 };
             }
+            '"' if self.rest().starts_with(&RAW_QUOTES[1..]) => return self.raw_string(),
             '"' => return self.string(),
             _ => (),
         }
@@ -178,6 +252,16 @@ impl Scanner {
         Err(self.error_token("Unexpected character"))
     }
 
+    /// Scan the next token without consuming it.
+    pub fn peek_token(&mut self) -> ScanResult {
+        let (line, start, current) = (self.line, self.start, self.current);
+        let result = self.scan_token();
+        self.line = line;
+        self.start = start;
+        self.current = current;
+        result
+    }
+
     fn skip_whitespace(&mut self) {
         loop {
             let c = self.peek();
@@ -187,7 +271,7 @@ impl Scanner {
                 self.line += 1;
                 self.advance();
             } else if c == '/' {
-                if self.peek_next() == '/' {
+                if self.peek_next() == '/' && !self.keep_comments {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
@@ -217,6 +301,22 @@ impl Scanner {
         self.make_token(TokenType::String)
     }
 
+    /// `"""` up to the next `"""`, the text in between is kept as it is.
+    fn raw_string(&mut self) -> ScanResult {
+        self.current += 2;
+        while !self.rest().starts_with(RAW_QUOTES) {
+            if self.is_at_end() {
+                return Err(self.error_token("Undetermined string"));
+            }
+            if self.advance() == '\n' {
+                self.line += 1;
+            }
+        }
+
+        self.current += RAW_QUOTES.len();
+        self.make_token(TokenType::String)
+    }
+
     fn number(&mut self) -> ScanResult {
         while self.peek().is_ascii_digit() {
             self.advance();
@@ -233,7 +333,7 @@ impl Scanner {
     }
 
     fn identifier(&mut self) -> ScanResult {
-        while self.is_alpha(self.peek()) || self.peek().is_ascii_digit() {
+        while self.is_identifier_continue(self.peek()) {
             self.advance();
         }
 
@@ -254,57 +354,87 @@ impl Scanner {
             message: message.to_string(),
             line: self.line,
             start: self.start,
-            length: self.current,
+            length: self.current - self.start,
         }
     }
 
+    /// Keywords are ascii, so the bytes are compared, the ones of other chars never match.
     fn identifier_type(&self) -> TokenType {
-        match self.source[self.start] {
-            'a' => self.check_keyword(1, "nd", TokenType::And),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, "lse", TokenType::Else),
-            'f' => {
+        let bytes = self.source.as_bytes();
+        match bytes[self.start] {
+            b'a' => self.check_keyword(1, "nd", TokenType::And),
+            b'b' => self.check_keyword(1, "reak", TokenType::Break),
+            b'c' => {
+                if self.current - self.start > 1 {
+                    match bytes[self.start + 1] {
+                        b'a' => self.check_keyword(2, "tch", TokenType::Catch),
+                        b'l' => self.check_keyword(2, "ass", TokenType::Class),
+                        b'o' => match self.check_keyword(2, "nst", TokenType::Const) {
+                            TokenType::Identifier => self.check_keyword(2, "ntinue", TokenType::Continue),
+                            const_type => const_type,
+                        },
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b'e' => self.check_keyword(1, "lse", TokenType::Else),
+            b'f' => {
+                if self.current - self.start > 1 {
+                    match bytes[self.start + 1] {
+                        b'a' => self.check_keyword(2, "lse", TokenType::False),
+                        b'o' => self.check_keyword(2, "r", TokenType::For),
+                        b'u' => self.check_keyword(2, "n", TokenType::Fun),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
+            b'i' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1] {
-                        'a' => self.check_keyword(2, "lse", TokenType::False),
-                        'o' => self.check_keyword(2, "r", TokenType::For),
-                        'u' => self.check_keyword(2, "n", TokenType::Fun),
+                    match bytes[self.start + 1] {
+                        b'f' => self.check_keyword(2, "", TokenType::If),
+                        b'n' => self.check_keyword(2, "", TokenType::In),
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, "f", TokenType::If),
-            'n' => self.check_keyword(1, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, "uper", TokenType::Super),
-            't' => {
+            b'm' => self.check_keyword(1, "atch", TokenType::Match),
+            b'n' => self.check_keyword(1, "il", TokenType::Nil),
+            b'o' => self.check_keyword(1, "r", TokenType::Or),
+            b'p' => self.check_keyword(1, "rint", TokenType::Print),
+            b'r' => self.check_keyword(1, "eturn", TokenType::Return),
+            b's' => self.check_keyword(1, "uper", TokenType::Super),
+            b't' => {
                 if self.current - self.start > 1 {
-                    match self.source[self.start + 1] {
-                        'h' => self.check_keyword(2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, "ue", TokenType::True),
+                    match bytes[self.start + 1] {
+                        b'h' => match self.check_keyword(2, "is", TokenType::This) {
+                            TokenType::Identifier => self.check_keyword(2, "row", TokenType::Throw),
+                            this => this,
+                        },
+                        b'r' => match self.check_keyword(2, "ue", TokenType::True) {
+                            TokenType::Identifier => self.check_keyword(2, "y", TokenType::Try),
+                            true_type => true_type,
+                        },
                         _ => TokenType::Identifier,
                     }
                 } else {
                     TokenType::Identifier
                 }
             }
-            'v' => self.check_keyword(1, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, "hile", TokenType::While),
+            b'v' => self.check_keyword(1, "ar", TokenType::Var),
+            b'w' => self.check_keyword(1, "hile", TokenType::While),
+            b'y' => self.check_keyword(1, "ield", TokenType::Yield),
             _ => TokenType::Identifier,
         }
     }
 
     fn check_keyword(&self, start: usize, rest: &str, token_type: TokenType) -> TokenType {
-        if self.current - self.start == start + rest.len()
-            && self.source[self.start + start..self.current]
-                .iter()
-                .collect::<String>()
-                == rest
-        {
+        if self.source.get(self.start + start..self.current) == Some(rest) {
             token_type
         } else {
             TokenType::Identifier
@@ -315,18 +445,20 @@ impl Scanner {
         self.current == self.source.len()
     }
 
+    /// The source after the current char.
+    fn rest(&self) -> &'a str {
+        &self.source[self.current..]
+    }
+
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source[self.current - 1]
+        let c = self.peek();
+        self.current += c.len_utf8();
+        c
     }
 
     fn match_char(&mut self, c: char) -> bool {
-        if self.is_at_end() {
-            return false;
-        }
-
-        if self.source[self.current] == c {
-            self.current += 1;
+        if self.rest().starts_with(c) {
+            self.current += c.len_utf8();
             true
         } else {
             false
@@ -334,35 +466,25 @@ impl Scanner {
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source[self.current]
-        }
+        self.rest().chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.source[self.current + 1]
-        }
+        self.rest().chars().nth(1).unwrap_or('\0')
     }
 
-    fn is_alpha(&self, c: char) -> bool {
-        c.is_alphabetic() || c == '_'
+    /// Identifiers follow the Unicode rules (UAX #31): XID_Start or '_' first,
+    /// XID_Continue afterwards. Digits are handled before, so they never start one.
+    fn is_identifier_start(&self, c: char) -> bool {
+        c == '_' || unicode_ident::is_xid_start(c)
     }
 
-    pub fn get_lexeme(&self, token: &Token) -> String {
-        self.source[token.start..token.start + token.length]
-            .iter()
-            .collect::<String>()
+    fn is_identifier_continue(&self, c: char) -> bool {
+        unicode_ident::is_xid_continue(c)
     }
 
-    pub fn get_lexeme_error(&self, token: &ErrorToken) -> String {
-        self.source[token.start..token.start + token.length]
-            .iter()
-            .collect::<String>()
+    pub fn get_lexeme_error(&self, token: &ErrorToken) -> &'a str {
+        &self.source[token.start..token.start + token.length]
     }
 }
 
@@ -370,7 +492,7 @@ impl Scanner {
 mod tests {
     use super::*;
 
-    fn create(source: &str) -> Scanner {
+    fn create(source: &str) -> Scanner<'_> {
         Scanner::new(source)
     }
 
@@ -457,4 +579,134 @@ mod tests {
         assert_token_type(&mut target, TokenType::Slash);
         assert_token_type(&mut target, TokenType::Number);
     }
+
+    #[test]
+    fn test_comments() {
+        let mut target = create("a // note\nb");
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::Identifier);
+
+        let mut target = Scanner::with_comments("a // note\nb");
+        assert_token_type(&mut target, TokenType::Identifier);
+        let comment = target.scan_token().unwrap();
+        assert_eq!(comment.token_type, TokenType::Comment);
+        assert_eq!(target.lexeme(&comment), "// note");
+        assert_token_type(&mut target, TokenType::Identifier);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut target = create("0..10.5 ...a");
+        assert_token_type(&mut target, TokenType::Number);
+        assert_token_type(&mut target, TokenType::DotDot);
+        assert_token_type(&mut target, TokenType::Number);
+        assert_token_type(&mut target, TokenType::DotDotDot);
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_truncated_input() {
+        for source in ["1.", "1..", ".", "..", "a.", "/", "=", "\"", "\"\"\"", "\"\"\"a\"\"", "//"] {
+            let mut target = create(source);
+            while !matches!(target.scan_token(), Ok(Token { token_type: TokenType::Eof, .. })) {}
+        }
+    }
+
+    #[test]
+    fn test_in_keyword() {
+        let mut target = create("in if inner i");
+        assert_token_type(&mut target, TokenType::In);
+        assert_token_type(&mut target, TokenType::If);
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::Identifier);
+    }
+
+    #[test]
+    fn test_exception_keywords() {
+        let mut target = create("try catch throw this true class thr yield const");
+        assert_token_type(&mut target, TokenType::Try);
+        assert_token_type(&mut target, TokenType::Catch);
+        assert_token_type(&mut target, TokenType::Throw);
+        assert_token_type(&mut target, TokenType::This);
+        assert_token_type(&mut target, TokenType::True);
+        assert_token_type(&mut target, TokenType::Class);
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::Yield);
+        assert_token_type(&mut target, TokenType::Const);
+    }
+
+    #[test]
+    fn test_match_tokens() {
+        let mut target = create("match matches => == = === !== !=");
+        assert_token_type(&mut target, TokenType::Match);
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::FatArrow);
+        assert_token_type(&mut target, TokenType::EqualEqual);
+        assert_token_type(&mut target, TokenType::Equal);
+        assert_token_type(&mut target, TokenType::EqualEqualEqual);
+        assert_token_type(&mut target, TokenType::BangEqualEqual);
+        assert_token_type(&mut target, TokenType::BangEqual);
+    }
+
+    #[test]
+    fn test_keywords() {
+        for keyword in KEYWORDS {
+            let token = create(keyword).scan_token().unwrap();
+            assert_ne!(token.token_type, TokenType::Identifier, "{keyword}");
+        }
+    }
+
+    #[test]
+    fn test_peek_token() {
+        let mut target = create("a in");
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_eq!(target.peek_token().unwrap().token_type, TokenType::In);
+        assert_token_type(&mut target, TokenType::In);
+    }
+
+    #[test]
+    fn test_unicode_identifier() {
+        let mut target = create("größe _ñ1 π");
+        let res = target.scan_token();
+        let expected = Token {
+            token_type: TokenType::Identifier,
+            line: 1,
+            start: 0,
+            length: 7,
+        };
+        assert_token(res, expected);
+        let token = target.scan_token().unwrap();
+        assert_eq!((token.start, target.lexeme(&token)), (8, "_ñ1"));
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert_token_type(&mut target, TokenType::Eof);
+    }
+
+    #[test]
+    fn test_unicode_not_identifier() {
+        let mut target = create("€ ²");
+        assert!(target.scan_token().is_err());
+        assert!(target.scan_token().is_err());
+    }
+
+    #[test]
+    fn test_raw_string() {
+        let mut target = create("\"\"\"say \"hi\"\n\\n\"\"\" \"\" x \"\"\"open");
+        let token = target.scan_token().unwrap();
+        assert_eq!(token.token_type, TokenType::String);
+        assert_eq!(token.line, 2);
+        assert_eq!(target.lexeme_string(&token), "say \"hi\"\n\\n");
+        let empty = target.scan_token().unwrap();
+        assert_eq!(target.lexeme_string(&empty), "");
+        assert_token_type(&mut target, TokenType::Identifier);
+        assert!(target.scan_token().is_err());
+    }
+
+    #[test]
+    fn test_unicode_string_lexeme() {
+        let mut target = create("\"héllo wörld\"");
+        let token = target.scan_token().unwrap();
+        assert_eq!(token.length, 15);
+        assert_eq!(target.lexeme_string(&token), "héllo wörld");
+    }
 }
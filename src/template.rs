@@ -0,0 +1,61 @@
+use crate::value::Value;
+
+/// Replaces each `{{key}}` with the value of the key, the values are a list
+/// of `[key, value]` pairs until there are maps. Strings are inserted as they
+/// are, other values as `inspect()` writes them.
+pub(crate) fn render(template: &Value, values: &Value) -> Result<Value, String> {
+    let (Value::String(template), Value::List(values)) = (template, values) else {
+        return Err(format!("template() needs a string and a list, not {:?} and {:?}.", template, values));
+    };
+    let pairs = values.values().into_iter()
+        .map(|pair| match &pair {
+            Value::List(entry) => match entry.values().as_slice() {
+                [Value::String(key), value] => Ok((key.to_string(), value.clone())),
+                _ => Err(format!("A template value must be a [key, value] pair, not {:?}.", pair)),
+            },
+            _ => Err(format!("A template value must be a [key, value] pair, not {:?}.", pair)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut output = String::new();
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let Some(length) = rest[start + 2..].find("}}") else {
+            return Err("Unclosed '{{' in template.".to_string());
+        };
+        let key = rest[start + 2..start + 2 + length].trim();
+        match pairs.iter().find(|(name, _)| name == key) {
+            Some((_, Value::String(string))) => output.push_str(string),
+            Some((_, value)) => output.push_str(&value.inspect()),
+            None => return Err(format!("Undefined template key '{key}'.")),
+        }
+        rest = &rest[start + 2 + length + 2..];
+    }
+    output.push_str(rest);
+
+    Ok(output.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::value::List;
+
+    use super::*;
+
+    fn pair(key: &str, value: Value) -> Value {
+        Value::List(List::new(vec![key.into(), value]))
+    }
+
+    #[test]
+    fn test_render() {
+        let values = Value::List(List::new(vec![pair("name", "lox".into()), pair("count", 2.0.into())]));
+        let render_str = |template: &str| render(&template.into(), &values);
+
+        assert_eq!(render_str("{{name}} has {{ count }} items, {{name}}."), Ok("lox has 2 items, lox.".into()));
+        assert_eq!(render_str("no keys"), Ok("no keys".into()));
+        assert_eq!(render_str("{{missing}}"), Err("Undefined template key 'missing'.".to_string()));
+        assert_eq!(render_str("{{name"), Err("Unclosed '{{' in template.".to_string()));
+        assert!(render(&"{{a}}".into(), &Value::List(List::new(vec![1.0.into()]))).is_err());
+    }
+}
@@ -1,26 +1,251 @@
-use serde::{Serialize, Deserialize};
+use std::{fmt, sync::{Arc, Mutex, MutexGuard}};
 
-use crate::{chunk::{Chunk, OpCodeVisitor}, op_code::{Instruction, OpCode}};
+use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
 
-#[derive(Clone, Debug, PartialEq)]
+use crate::{chunk::{Chunk, OpCodeVisitor}, op_code::{Instruction, OpCode, Span}, vm::GeneratorState};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum NativeFunction {
     Clock,
+    Len,
+    Contains,
+    Random,
+    /// Write the value, `Println` adds a newline.
+    Print,
+    Println,
+    /// Variables of the environment, `SetEnv` only changes them for the VM and its commands.
+    Env,
+    SetEnv,
+    /// Run a command, only if the host allows it. `Shell` passes the string to the shell.
+    Exec,
+    Shell,
+    #[cfg(feature = "net")]
+    TcpConnect,
+    #[cfg(feature = "net")]
+    TcpSend,
+    #[cfg(feature = "net")]
+    TcpRecv,
+    #[cfg(feature = "net")]
+    TcpClose,
+    /// A sorted copy of a list, `SortBy` takes a comparator.
+    Sort,
+    SortBy,
+    /// The value as `Value::inspect` writes it, `Dump` prints it.
+    Inspect,
+    Dump,
+    /// Replace the `{{key}}` placeholders of a string.
+    Template,
+    /// `[name, value]` pairs of the globals, sorted by name.
+    Globals,
+    /// `[key, value]` pairs of the build info, see `version::BuildInfo`.
+    Version,
+    /// Whether a number is NaN, `IsFinite` whether it is neither NaN nor infinite.
+    IsNan,
+    IsFinite,
+    /// Index of a function registered at the `Runtime`.
+    Host(u32),
+    /// Index of an async function registered at the `Runtime`.
+    AsyncHost(u32),
+}
+
+/// Up to this all integers are exact as a number, 2^53 - 1.
+pub const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// Whether the number is an integer which is exact, see `MAX_SAFE_INTEGER`.
+pub fn is_safe_integer(number: f64) -> bool {
+    number.fract() == 0.0 && number.abs() <= MAX_SAFE_INTEGER
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Lists longer than this are split into lines by `Value::inspect`.
+const INSPECT_WIDTH: usize = 72;
+const INSPECT_INDENT: usize = 2;
+
+/// Numbers from start (inclusive) to end (exclusive) in steps of one.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Range(pub f64, pub f64);
+
+/// The heap variants are shared handles, copying a value never copies
+/// a string or a function and a value fits in 16 bytes.
+#[derive(Clone, Deserialize, Serialize)]
 pub enum Value {
     Bool(bool),
     Nil,
     Number(f64),
-    String(String),
-    Function(Function),
-    Native(NativeFunction, usize),
+    String(Arc<String>),
+    Function(Arc<Function>),
+    /// The function and its arity.
+    Native(NativeFunction, u8),
+    Range(Arc<Range>),
+    List(List),
+    /// Returned by calling a generator function, iterated with `for in`.
+    #[serde(skip)]
+    Generator(Generator),
+    /// A resource of the VM like a socket, scripts only pass it to natives.
+    #[serde(skip)]
+    Handle(u32),
+}
+
+/// The `==` of Lox, values of different types are never equal. Functions are
+/// compared by identity, two functions with the same code are different.
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::Function(a), Value::Function(b)) => Arc::ptr_eq(a, b),
+            (Value::Native(a, a_arity), Value::Native(b, b_arity)) => a == b && a_arity == b_arity,
+            (Value::Range(a), Value::Range(b)) => a == b,
+            (Value::List(a), Value::List(b)) => a == b,
+            (Value::Generator(a), Value::Generator(b)) => a == b,
+            (Value::Handle(a), Value::Handle(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Like a derived `Debug`, but a function is written as `<fn name/arity>` instead of its chunk.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => f.debug_tuple("Bool").field(b).finish(),
+            Value::Nil => f.write_str("Nil"),
+            Value::Number(n) => f.debug_tuple("Number").field(n).finish(),
+            Value::String(string) => f.debug_tuple("String").field(string).finish(),
+            Value::Function(_) => f.write_str(&self.inspect()),
+            Value::Native(function, arity) => f.debug_tuple("Native").field(function).field(arity).finish(),
+            Value::Range(range) => f.debug_tuple("Range").field(range).finish(),
+            Value::List(list) => f.debug_tuple("List").field(list).finish(),
+            Value::Generator(generator) => f.debug_tuple("Generator").field(generator).finish(),
+            Value::Handle(handle) => f.debug_tuple("Handle").field(handle).finish(),
+        }
+    }
 }
 
 impl Value {
     pub fn is_number(&self) -> bool {
         matches!(self, Value::Number(_))
     }
+
+    /// Element at `index` when iterating over this value and the index of the next one,
+    /// iteration starts at 0. Strings yield their chars, their index is a byte offset.
+    pub fn iter_next(&self, index: usize) -> Result<Option<(Value, usize)>, String> {
+        match self {
+            Value::String(string) => Ok(string
+                .get(index..)
+                .and_then(|rest| rest.chars().next())
+                .map(|c| (c.to_string().into(), index + c.len_utf8()))),
+            Value::List(list) => Ok(list.get(index).map(|element| (element, index + 1))),
+            Value::Range(range) => {
+                let next = range.0 + index as f64;
+                Ok((next < range.1).then_some((next.into(), index + 1)))
+            }
+            other => Err(format!("Can only iterate over strings, ranges and lists, not {:?}.", other)),
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Result<Value, String> {
+        match (self, name) {
+            (Value::String(string), "length") => Ok(Value::Number(string.chars().count() as f64)),
+            (Value::List(list), "length") => Ok(Value::Number(list.len() as f64)),
+            (Value::String(_), name) => Err(format!("Undefined property '{name}' of string.")),
+            (Value::List(_), name) => Err(format!("Undefined property '{name}' of list.")),
+            (other, _) => Err(format!("Only strings and lists have properties, not {:?}.", other)),
+        }
+    }
+
+    /// Checks that the built-in method exists and gets that many arguments.
+    pub fn check_method(&self, name: &str, arg_count: usize) -> Result<(), String> {
+        let arity = match (self, name) {
+            (Value::String(_), "upper" | "lower") => 0,
+            (Value::String(_), "find" | "split") => 1,
+            (Value::List(_), "get" | "push" | "map" | "filter" | "sort") => 1,
+            (Value::List(_), "reduce") => 2,
+            (Value::String(_), name) => return Err(format!("Undefined method '{name}' of string.")),
+            (Value::List(_), name) => return Err(format!("Undefined method '{name}' of list.")),
+            (other, _) => return Err(format!("Only strings and lists have methods, not {:?}.", other)),
+        };
+
+        if arg_count != arity {
+            return Err(format!("Expected {} arguments but got {}.", arity, arg_count));
+        }
+        Ok(())
+    }
+
+    /// Calls a built-in method, the list methods taking a function are run by the VM.
+    pub fn invoke(&self, name: &str, args: &[Value]) -> Result<Value, String> {
+        self.check_method(name, args.len())?;
+
+        match (self, name, args) {
+            (Value::String(string), "upper", _) => Ok(string.to_uppercase().into()),
+            (Value::String(string), "lower", _) => Ok(string.to_lowercase().into()),
+            // the char index like `len` and iteration count chars, nil if not found
+            (Value::String(string), "find", [Value::String(part)]) => Ok(string
+                .find(part.as_str())
+                .map_or(Value::Nil, |byte| Value::Number(string[..byte].chars().count() as f64))),
+            (Value::String(string), "split", [Value::String(separator)]) => {
+                let parts = if separator.is_empty() {
+                    string.chars().map(|c| c.to_string().into()).collect()
+                } else {
+                    string.split(separator.as_str()).map(Value::from).collect()
+                };
+                Ok(Value::List(List::new(parts)))
+            }
+            (Value::String(_), name, [other]) => Err(format!("{name}() needs a string, not {:?}.", other)),
+            (Value::List(list), "get", [Value::Number(index)]) => {
+                let element = (index.fract() == 0.0 && *index >= 0.0)
+                    .then(|| list.get(*index as usize))
+                    .flatten();
+                element.ok_or_else(|| format!("Index {index} is out of range."))
+            }
+            (Value::List(_), "get", [other]) => Err(format!("get() needs a number, not {:?}.", other)),
+            (Value::List(list), "push", [value]) => {
+                list.push(value.clone());
+                Ok(Value::Nil)
+            }
+            (_, name, _) => Err(format!("{name}() calls a function and needs the VM.")),
+        }
+    }
+
+    /// Readable form for `inspect()`, long lists are written one element per line.
+    pub fn inspect(&self) -> String {
+        self.inspect_nested(0, &mut vec![])
+    }
+
+    /// `lists` are the lists being written, a list containing itself is written as `[...]`.
+    fn inspect_nested(&self, depth: usize, lists: &mut Vec<List>) -> String {
+        match self {
+            Value::Bool(b) => b.to_string(),
+            Value::Nil => "nil".to_string(),
+            Value::Number(n) => n.to_string(),
+            Value::String(string) => format!("{:?}", string),
+            Value::Function(function) if function.is_generator() => {
+                format!("<generator fn {}/{}>", function.name(), function.arity())
+            }
+            Value::Function(function) => format!("<fn {}/{}>", function.name(), function.arity()),
+            Value::Native(_, arity) => format!("<native fn/{arity}>"),
+            Value::Range(range) => format!("{}..{}", range.0, range.1),
+            Value::Generator(generator) => format!("{:?}", generator),
+            Value::Handle(handle) => format!("<handle {handle}>"),
+            Value::List(list) if lists.contains(list) => "[...]".to_string(),
+            Value::List(list) => {
+                lists.push(list.clone());
+                let elements: Vec<_> = list.values().iter()
+                    .map(|element| element.inspect_nested(depth + 1, lists))
+                    .collect();
+                lists.pop();
+
+                let inline = format!("[{}]", elements.join(", "));
+                if depth * INSPECT_INDENT + inline.len() <= INSPECT_WIDTH && !inline.contains('\n') {
+                    return inline;
+                }
+                let indent = " ".repeat((depth + 1) * INSPECT_INDENT);
+                let lines: Vec<_> = elements.iter().map(|element| format!("{indent}{element},\n")).collect();
+                format!("[\n{}{}]", lines.concat(), " ".repeat(depth * INSPECT_INDENT))
+            }
+        }
+    }
 }
 
 impl From<bool> for Value {
@@ -37,15 +262,198 @@ impl From<f64> for Value {
 
 impl From<String> for Value {
     fn from(string: String) -> Self {
-        Self::String(string)
+        Self::String(Arc::new(string))
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Self::String(Arc::new(string.to_string()))
+    }
+}
+
+impl From<Function> for Value {
+    fn from(function: Function) -> Self {
+        Self::Function(Arc::new(function))
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(values: Vec<Value>) -> Self {
+        Self::List(List::new(values))
+    }
+}
+
+/// The values usable as keys of maps: nil, booleans, numbers and strings.
+/// Two keys are equal if the values are equal with `==`, so `-0` and `0`
+/// are the same key. NaN is not equal to itself and can't be a key.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Key {
+    Nil,
+    Bool(bool),
+    /// The bits of the number, negative zero is stored as zero.
+    Number(u64),
+    String(Arc<String>),
+}
+
+impl TryFrom<&Value> for Key {
+    type Error = String;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
+        match value {
+            Value::Nil => Ok(Key::Nil),
+            Value::Bool(b) => Ok(Key::Bool(*b)),
+            Value::Number(n) if n.is_nan() => Err("NaN can't be a key.".to_string()),
+            Value::Number(n) => Ok(Key::Number((n + 0.0).to_bits())),
+            Value::String(string) => Ok(Key::String(Arc::clone(string))),
+            other => Err(format!("{:?} can't be a key.", other)),
+        }
+    }
+}
+
+impl From<Key> for Value {
+    fn from(key: Key) -> Self {
+        match key {
+            Key::Nil => Value::Nil,
+            Key::Bool(b) => Value::Bool(b),
+            Key::Number(bits) => Value::Number(f64::from_bits(bits)),
+            Key::String(string) => Value::String(string),
+        }
+    }
+}
+
+/// Copies of a list share the elements, two lists are equal if they are the same list.
+#[derive(Clone)]
+pub struct List(Arc<Mutex<Vec<Value>>>);
+
+impl List {
+    pub fn new(values: Vec<Value>) -> Self {
+        Self(Arc::new(Mutex::new(values)))
+    }
+
+    /// A copy of the elements, e.g. to call functions on them without holding the lock.
+    pub fn values(&self) -> Vec<Value> {
+        self.lock().clone()
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        self.lock().get(index).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    pub fn push(&self, value: Value) {
+        self.lock().push(value);
+    }
+
+    pub fn replace(&self, values: Vec<Value>) {
+        *self.lock() = values;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Value>> {
+        self.0.lock().expect("list lock poisoned")
+    }
+}
+
+impl fmt::Debug for List {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // a list containing itself is locked while its elements are written
+        match self.0.try_lock() {
+            Ok(values) => f.debug_list().entries(values.iter()).finish(),
+            Err(_) => write!(f, "[...]"),
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Serialize for List {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.0.try_lock() {
+            Ok(values) => values.serialize(serializer),
+            Err(_) => Err(ser::Error::custom("a list can't contain itself")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for List {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(List::new)
+    }
+}
+
+/// A call of a generator function, copies of the value continue the same call.
+#[derive(Clone)]
+pub struct Generator(Arc<GeneratorCall>);
+
+struct GeneratorCall {
+    name: String,
+    state: Mutex<GeneratorState>,
+}
+
+impl Generator {
+    pub(crate) fn new(name: String, state: GeneratorState) -> Self {
+        Self(Arc::new(GeneratorCall {
+            name,
+            state: Mutex::new(state),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub(crate) fn state(&self) -> &Mutex<GeneratorState> {
+        &self.0.state
+    }
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<generator {}>", self.name())
+    }
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub struct Function {
+    /// The parameters without the rest parameter of a variadic function.
     arity: usize,
     name: String,
     chunk: Chunk,
+    /// Contains `yield`, calls return a `Generator` instead of running the body.
+    #[serde(default)]
+    generator: bool,
+    /// The extra arguments of a call are passed as a list in one more parameter.
+    #[serde(default)]
+    variadic: bool,
+    /// Names of the parameters and the line of the declaration, for signatures
+    /// and errors. Empty and 0 in bytecode compiled before they were kept.
+    #[serde(default)]
+    params: Vec<String>,
+    #[serde(default)]
+    line: i32,
+    /// Stack slots the function needs, counted from its first argument.
+    #[serde(default)]
+    max_stack: usize,
+    /// The script source, only set on the top level function.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<String>,
 }
 
 impl Function {
@@ -54,6 +462,12 @@ impl Function {
             arity: 0,
             name,
             chunk: Chunk::new(),
+            generator: false,
+            variadic: false,
+            params: vec![],
+            line: 0,
+            max_stack: 0,
+            source: None,
         }
     }
 
@@ -63,47 +477,185 @@ impl Function {
             arity: 0,
             name,
             chunk,
+            generator: false,
+            variadic: false,
+            params: vec![],
+            line: 0,
+            max_stack: 0,
+            source: None,
         }
     }
 
-    pub fn write(&mut self, code: OpCode, line: i32) {
-        self.chunk.write(code, line);
+    pub fn set_chunk(&mut self, chunk: Chunk) {
+        self.chunk = chunk;
+    }
+
+    pub fn codes_from(&self, start: usize) -> Vec<OpCode> {
+        self.chunk.codes_from(start)
     }
 
-    pub fn current_offset(&self) -> usize {
-        self.chunk.current_offset()
+    pub fn optimize(&mut self) {
+        self.chunk.optimize();
     }
-    
-    pub fn emit_jump(&mut self, code: OpCode, line: i32) -> usize {
-        self.chunk.emit_jump(code, line)
+
+    /// Computes the stack depth from the final chunk, so the VM can reserve it on calls.
+    pub fn compute_max_stack(&mut self) {
+        self.max_stack = self.chunk.max_stack_depth(self.parameter_count());
     }
 
-    pub fn emit_loop(&mut self, offset: usize, line: i32) {
-        self.chunk.emit_loop(offset, line);
+    pub fn max_stack(&self) -> usize {
+        self.max_stack
     }
 
-    pub fn patch_jump(&mut self, offset: usize) {
-        self.chunk.patch_jump(offset);
+    pub fn functions(&self) -> &[Arc<Function>] {
+        self.chunk.functions()
     }
 
-    pub fn read_instruction(&mut self, ip: usize) -> &Instruction {
+    pub fn read_instruction(&self, ip: usize) -> &Instruction {
         self.chunk.read_instruction(ip)
     }
 
+    pub fn instruction_count(&self) -> usize {
+        self.chunk.instruction_count()
+    }
+
     pub fn operate_on_codes(&self, op: &mut dyn OpCodeVisitor) {
         self.chunk.operate_on_codes(op);
     }
 
+    /// The code as `Chunk::to_test_string` writes it, for golden files.
+    pub fn to_test_string(&self) -> String {
+        self.chunk.to_test_string()
+    }
+
     pub fn arity(&self) -> usize {
         self.arity
     }
 
-    pub fn increase_arity(&mut self) {
-        self.arity += 1;
+    pub fn set_arity(&mut self, arity: usize) {
+        self.arity = arity;
+    }
+
+    pub fn is_variadic(&self) -> bool {
+        self.variadic
+    }
+
+    pub fn set_variadic(&mut self) {
+        self.variadic = true;
+    }
+
+    pub fn params(&self) -> &[String] {
+        &self.params
+    }
+
+    pub fn set_params(&mut self, params: Vec<String>) {
+        self.params = params;
+    }
+
+    pub fn line(&self) -> i32 {
+        self.line
+    }
+
+    pub fn set_line(&mut self, line: i32) {
+        self.line = line;
+    }
+
+    /// The name and the parameters like in the declaration, `add(a, b)` or `log(format, ...args)`.
+    pub fn signature(&self) -> String {
+        let mut params = self.params.clone();
+        if self.variadic && let Some(rest) = params.last_mut() {
+            rest.insert_str(0, "...");
+        }
+        format!("{}({})", self.name, params.join(", "))
+    }
+
+    /// The stack slots of the arguments when the body starts.
+    pub fn parameter_count(&self) -> usize {
+        self.arity + usize::from(self.variadic)
     }
 
-    #[allow(dead_code)]
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    pub fn is_generator(&self) -> bool {
+        self.generator
+    }
+
+    pub fn set_generator(&mut self) {
+        self.generator = true;
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn set_source(&mut self, source: String) {
+        self.source = Some(source);
+    }
+
+    /// Drop the source text and the instruction spans, only line numbers remain.
+    pub fn strip_source_map(&mut self) {
+        self.source = None;
+        self.chunk.strip_spans();
+    }
+
+    pub fn map_spans(&mut self, map: &dyn Fn(Span) -> Option<Span>) {
+        self.chunk.map_spans(map);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn key(value: Value) -> Key {
+        Key::try_from(&value).unwrap()
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut map = HashMap::new();
+        map.insert(key(0.0.into()), "zero");
+        map.insert(key("a".into()), "a");
+        map.insert(key(Value::Nil), "nil");
+        assert_eq!(map[&key((-0.0).into())], "zero");
+        assert_eq!(map[&key(String::from("a").into())], "a");
+        assert_eq!(map[&key(Value::Nil)], "nil");
+        assert!(!map.contains_key(&key(false.into())));
+
+        assert_eq!(Value::from(key((-0.0).into())), Value::Number(0.0));
+        assert!(Key::try_from(&Value::Number(f64::NAN)).is_err());
+        assert!(Key::try_from(&Value::Range(Arc::new(Range(0.0, 1.0)))).is_err());
+    }
+
+    #[test]
+    fn test_function_identity() {
+        let function = || Value::from(Function::new("f".to_string()));
+        let (first, second) = (function(), function());
+        assert_ne!(first, second);
+        assert_eq!(first, first.clone());
+        assert_eq!(format!("{:?}", Value::List(List::new(vec![first]))), "List([<fn f/0>])");
+        assert_eq!(Value::Native(NativeFunction::Len, 1), Value::Native(NativeFunction::Len, 1));
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut function = Function::new("add".to_string());
+        function.set_arity(2);
+        let list = List::new(vec![1.0.into(), "a\"b".into(), Value::Nil, function.into()]);
+        assert_eq!(Value::List(list.clone()).inspect(), r#"[1, "a\"b", nil, <fn add/2>]"#);
+
+        list.replace(vec![0.5.into(), Value::List(list.clone())]);
+        assert_eq!(Value::List(list.clone()).inspect(), "[0.5, [...]]");
+        // break the cycle so the list is freed
+        list.replace(vec![]);
+
+        let long = Value::List(List::new(vec!["x".repeat(40).into(), "y".repeat(40).into()]));
+        let nested = Value::List(List::new(vec![true.into(), long]));
+        let expected = format!("[\n  true,\n  [\n    \"{}\",\n    \"{}\",\n  ],\n]", "x".repeat(40), "y".repeat(40));
+        assert_eq!(nested.inspect(), expected);
+    }
 }
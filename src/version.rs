@@ -0,0 +1,67 @@
+use std::fmt;
+
+use crate::{bytecode::BYTECODE_VERSION, value::{List, Value}};
+
+/// What this rlox was built as, printed by `rlox --version` and returned by `version()`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    /// The version of the `.loxer` files it writes, older ones can be upgraded.
+    pub bytecode_version: i32,
+    pub features: Vec<&'static str>,
+}
+
+pub fn build_info() -> BuildInfo {
+    let features = [
+        ("net", cfg!(feature = "net")),
+        ("fast-dispatch", cfg!(feature = "fast-dispatch")),
+    ];
+    BuildInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        bytecode_version: BYTECODE_VERSION,
+        features: features.into_iter().filter(|(_, enabled)| *enabled).map(|(name, _)| name).collect(),
+    }
+}
+
+impl BuildInfo {
+    /// `[key, value]` pairs, like the other natives returning several named values.
+    pub fn to_value(&self) -> Value {
+        let features = self.features.iter().map(|feature| Value::from(*feature)).collect();
+        let pairs = [
+            ("version", Value::from(self.version)),
+            ("bytecode", Value::Number(self.bytecode_version.into())),
+            ("features", Value::List(List::new(features))),
+        ];
+        let pairs = pairs.into_iter().map(|(key, value)| Value::List(List::new(vec![key.into(), value])));
+        Value::List(List::new(pairs.collect()))
+    }
+}
+
+impl fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rlox {}, bytecode version {}", self.version, self.bytecode_version)?;
+        if !self.features.is_empty() {
+            write!(f, ", features: {}", self.features.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_info() {
+        let info = build_info();
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert!(info.to_string().starts_with(&format!("rlox {}, bytecode version {BYTECODE_VERSION}", info.version)));
+        assert_eq!(info.features.contains(&"net"), cfg!(feature = "net"));
+        let features: Vec<_> = info.features.iter().map(|feature| format!("String(\"{feature}\")")).collect();
+        assert_eq!(format!("{:?}", info.to_value()), format!(
+            "List([List([String(\"version\"), String(\"{}\")]), List([String(\"bytecode\"), Number({}.0)]), \
+            List([String(\"features\"), List([{}])])])",
+            info.version, BYTECODE_VERSION, features.join(", "),
+        ));
+    }
+}
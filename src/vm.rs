@@ -1,19 +1,46 @@
-use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use std::{any::Any, cmp::Ordering, collections::{BTreeMap, HashMap, HashSet}, env, fmt, io::{self, Write}, mem, process::Command, sync::{Arc, Mutex}, time::{SystemTime, UNIX_EPOCH}};
 
-use crate::{compiler::compile, debug::Debugger, op_code::OpCode, value::{Function, NativeFunction, Value}};
+use serde::{Deserialize, Serialize};
+
+use crate::{bytecode::verify, clock::{Clock, SystemClock, VirtualClock}, compiler::{compile, compile_repl, compile_strict, completions, CompileError}, coverage::Coverage, debug::{snippet, Debugger}, error_codes, op_code::{OpCode, Span}, runtime::{HostFuture, NativeRegistry}, template, value::{is_safe_integer, Function, Generator, List, NativeFunction, Range, Value}, version};
+#[cfg(feature = "net")]
+use crate::net::Sockets;
+#[cfg(feature = "fast-dispatch")]
+use crate::op_code::OPCODE_COUNT;
+
+struct Handler {
+    ip: usize,
+    stack_len: usize,
+}
 
 struct CallFrame {
-    function: Function,
+    function: Arc<Function>,
     ip: usize,
     stack_offset: usize,
+    handlers: Vec<Handler>,
+    /// Set while the frame runs the body of a generator.
+    generator: Option<Generator>,
+    /// Index of the function's `GlobalCache`, set on the first global read.
+    global_cache: Option<usize>,
 }
 
 impl CallFrame {
-    fn new(function: Function, stack_offset: usize) -> Self {
+    fn new(function: Arc<Function>, stack_offset: usize) -> Self {
         Self {
             function,
             ip: 0,
             stack_offset,
+            handlers: vec![],
+            generator: None,
+            global_cache: None,
+        }
+    }
+
+    /// Moves the frame's part of the stack from index `from` to index `to`.
+    fn move_stack(&mut self, from: usize, to: usize) {
+        self.stack_offset = self.stack_offset - from + to;
+        for handler in &mut self.handlers {
+            handler.stack_len = handler.stack_len - from + to;
         }
     }
 
@@ -30,32 +57,202 @@ impl CallFrame {
     }
 }
 
+/// The values read by the `GetGlobal` instructions of one function, by instruction index.
+struct GlobalCache {
+    /// Kept alive, so the address of the function identifies the cache.
+    function: Arc<Function>,
+    values: Vec<Option<Value>>,
+}
+
+/// A generator between two resumptions, the frame is gone once the body returned.
+pub(crate) struct GeneratorState {
+    frame: Option<CallFrame>,
+    /// Callee, arguments and locals of the frame, its stack offset is relative to this.
+    stack: Vec<Value>,
+    started: bool,
+    running: bool,
+}
+
+impl GeneratorState {
+    fn finish(&mut self) {
+        self.frame = None;
+        self.stack.clear();
+        self.running = false;
+    }
+}
+
+/// Notified by the VM while it runs, the callbacks default to doing nothing.
+/// Tools like `Coverage` are observers, added with `VM::add_observer`.
+pub trait VmObserver: Any + Send + Sync {
+    /// Called before the instruction at `ip` of the function runs.
+    fn instruction_executed(&mut self, _function: &Arc<Function>, _ip: usize) {}
+    /// A call or a resumed generator, `depth` counts the frames including this one.
+    fn frame_pushed(&mut self, _function: &Arc<Function>, _depth: usize) {}
+    /// A return, a yielding generator or a frame unwound by an exception.
+    fn frame_popped(&mut self, _function: &Arc<Function>, _depth: usize) {}
+    fn global_defined(&mut self, _name: &str, _value: &Value) {}
+    /// A thrown exception or runtime error, before it is caught or ends the run.
+    fn error_raised(&mut self, _exception: &Value) {}
+}
+
+/// Where a script started with `VM::start` stopped.
+pub enum Execution {
+    /// The script ran to its end and returned the value.
+    Complete(Value),
+    /// The script yielded the value, `VM::resume` continues it.
+    Suspended(ResumeHandle, Value),
+    /// The script called an async native, resume it with the result of the future.
+    Waiting(ResumeHandle, HostFuture),
+}
+
+/// The calls and stack of a suspended script.
+pub struct ResumeHandle {
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    source: Option<String>,
+}
+
+/// Saved globals, written as bson like the compiled programs.
+#[derive(Deserialize, Serialize)]
+struct Snapshot {
+    globals: BTreeMap<String, Value>,
+}
+
+/// Owns all of its values, a VM can be moved to another thread to run scripts there.
 pub struct VM {
     stack: Vec<Value>,
     current_line: i32,
     globals: HashMap<String, Value>,
     frames: Vec<CallFrame>,
+    source: Option<String>,
+    natives: Arc<NativeRegistry>,
+    /// The `println` of the natives, called by the `print` statement.
+    println: Value,
+    /// Future of the async native called by the last instruction,
+    /// in a mutex as futures are only `Send` but the VM is `Sync`.
+    pending: Mutex<Option<HostFuture>>,
+    /// Only scripts run with `start` or `resume` can yield.
+    resumable: bool,
+    /// Frames below a function called by a built-in method, the nested run
+    /// returns when that function returns and exceptions don't unwind below it.
+    base_frames: usize,
+    strict_globals: bool,
+    /// Globals declared with `const` by any script run so far, they can't be changed.
+    constants: HashSet<String>,
+    /// Inline caches of the global reads, so hot loops skip the lookup by name.
+    global_caches: Vec<GlobalCache>,
+    global_cache_indexes: HashMap<usize, usize>,
+    /// The cached reads of each global, cache and instruction index, cleared when it is assigned.
+    global_readers: HashMap<String, Vec<(usize, usize)>>,
+    /// Instructions left before runs stop, for untrusted scripts.
+    instruction_budget: Option<u64>,
+    observers: Vec<Box<dyn VmObserver>>,
+    /// The exception which ended a nested run, rethrown at the method call.
+    uncaught: Option<Value>,
+    /// The time of `clock()`.
+    clock: Box<dyn Clock>,
+    random_state: u64,
+    /// `exec()` and `shell()` fail unless the host allows them.
+    allow_exec: bool,
+    /// Adding, subtracting or multiplying integers fails instead of losing precision.
+    safe_integers: bool,
+    /// `==` of values of different types fails instead of being false.
+    strict_equality: bool,
+    /// Variables set with `set_env()`, the process environment is not changed.
+    env: HashMap<String, String>,
+    #[cfg(feature = "net")]
+    sockets: Sockets,
 }
 
 #[derive(Debug)]
 pub enum InterpretResult {
     Ok,
-    CompileError,
-    RuntimeError,
+    CompileError(Vec<CompileError>),
+    RuntimeError(LoxError),
+}
+
+impl InterpretResult {
+    /// Prints the errors of a failed run to stderr, for the REPL and watch mode.
+    pub fn print_errors(&self) {
+        match self {
+            InterpretResult::Ok => (),
+            InterpretResult::CompileError(errors) => {
+                for error in errors {
+                    eprintln!("{error}");
+                }
+            }
+            InterpretResult::RuntimeError(error) => eprintln!("{error}"),
+        }
+    }
+}
+
+/// An uncaught runtime failure, the stack lists the active calls innermost first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoxError {
+    pub message: String,
+    pub line: i32,
+    /// The source of the failing instruction, when the script carries its source map.
+    pub span: Option<Span>,
+    /// Set if the message is one of the known runtime errors.
+    pub code: Option<&'static str>,
+    pub stack: Vec<String>,
+    /// The failing source line, when the script carries its source map.
+    pub snippet: Option<String>,
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(code) = self.code {
+            write!(f, "Error[{code}]: ")?;
+        }
+        write!(f, "{}", self.message)?;
+        if let Some(snippet) = &self.snippet {
+            write!(f, "\n{snippet}")?;
+        }
+        for entry in &self.stack {
+            write!(f, "\n{entry}")?;
+        }
+
+        Ok(())
+    }
 }
 
+/// An instruction without operands, it fails like the arms of the `run` loop.
+#[cfg(feature = "fast-dispatch")]
+type InstructionFn = fn(&mut VM) -> Result<(), InterpretResult>;
+
+/// The functions by `OpCode::index`, the instructions with operands stay in the `match` of `run`.
+#[cfg(feature = "fast-dispatch")]
+const INSTRUCTION_FNS: [Option<InstructionFn>; OPCODE_COUNT] = {
+    let mut handlers: [Option<InstructionFn>; OPCODE_COUNT] = [None; OPCODE_COUNT];
+    handlers[OpCode::Pop.index() as usize] = Some(VM::pop_value);
+    handlers[OpCode::Equal.index() as usize] = Some(VM::equal);
+    handlers[OpCode::StrictEqual.index() as usize] = Some(VM::strict_equal);
+    handlers[OpCode::Greater.index() as usize] = Some(VM::greater);
+    handlers[OpCode::Less.index() as usize] = Some(VM::less);
+    handlers[OpCode::GreaterEqual.index() as usize] = Some(VM::greater_equal);
+    handlers[OpCode::LessEqual.index() as usize] = Some(VM::less_equal);
+    handlers[OpCode::Nil.index() as usize] = Some(VM::nil);
+    handlers[OpCode::Add.index() as usize] = Some(VM::add);
+    handlers[OpCode::Subtract.index() as usize] = Some(VM::subtract);
+    handlers[OpCode::Multiply.index() as usize] = Some(VM::multiply);
+    handlers[OpCode::Divide.index() as usize] = Some(VM::divide);
+    handlers[OpCode::Not.index() as usize] = Some(VM::not);
+    handlers[OpCode::Negate.index() as usize] = Some(VM::negate);
+    handlers
+};
+
 macro_rules! binary_op {
     ($vm:ident, +) => {{
         let b = $vm.pop();
         let a = $vm.pop();
         match (a,b) {
             (Value::Number(a), Value::Number(b)) => $vm.push((a + b).into()),
-            (Value::String(a), Value::String(b)) => $vm.push((a + &b).into()),
+            (Value::String(a), Value::String(b)) => $vm.push(format!("{a}{b}").into()),
             (a, b) => {
                 $vm.runtime_error(&format!(
                     "Operands must be two numbers or two strings, are {:?} and {:?}",
-                    a, b));
-                return Err(InterpretResult::RuntimeError);
+                    a, b))?;
             }
         }
     }};
@@ -66,20 +263,53 @@ macro_rules! binary_op {
             (Value::Number(a), Value::Number(b)) => $vm.push((a $op b).into()),
             (a, b) => {
                 $vm.runtime_error(&format!("Operands must be numbers, are {:?} and {:?}",
-                a, b));
-                return Err(InterpretResult::RuntimeError);
+                a, b))?;
             }
         }
     }};
 }
 
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VM {
     pub fn new() -> Self {
+        Self::with_natives(Arc::default())
+    }
+
+    pub(crate) fn with_natives(natives: Arc<NativeRegistry>) -> Self {
+        let println = natives.native("println").cloned().unwrap_or(Value::Native(NativeFunction::Println, 1));
         let mut vm = Self {
             stack: vec![],
             current_line: 0,
             globals: HashMap::new(),
             frames: vec![],
+            source: None,
+            natives,
+            println,
+            pending: Mutex::new(None),
+            resumable: false,
+            base_frames: 0,
+            strict_globals: false,
+            constants: HashSet::new(),
+            global_caches: vec![],
+            global_cache_indexes: HashMap::new(),
+            global_readers: HashMap::new(),
+            instruction_budget: None,
+            observers: vec![],
+            uncaught: None,
+            clock: Box::new(SystemClock),
+            random_state: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map_or(0, |t| t.as_nanos() as u64),
+            allow_exec: false,
+            safe_integers: false,
+            strict_equality: false,
+            env: HashMap::new(),
+            #[cfg(feature = "net")]
+            sockets: Sockets::default(),
         };
 
         vm.define_natives();
@@ -92,280 +322,1329 @@ impl VM {
             debugger.disassemble_chunk(&function, "code");
         }
 
-        self.call(function);
+        self.source = function.source().map(str::to_string);
+        self.call(Arc::new(function));
+        self.resumable = false;
+        match self.execute() {
+            Ok(_) => InterpretResult::Ok,
+            Err(error) => InterpretResult::RuntimeError(error),
+        }
+    }
+
+    /// Runs the script until it yields or ends, the globals are shared by all suspended scripts.
+    pub fn start(&mut self, function: Function) -> Result<Execution, LoxError> {
+        self.source = function.source().map(str::to_string);
+        self.call(Arc::new(function));
+        self.resumable = true;
+        self.execute()
+    }
+
+    /// Continues a suspended script, `value` is the result of its `yield` or async native.
+    pub fn resume(&mut self, handle: ResumeHandle, value: Value) -> Result<Execution, LoxError> {
+        self.restore_handle(handle);
+        self.push(value);
+        self.execute()
+    }
+
+    /// Continues a script waiting for an async native with a runtime error at the call.
+    pub fn resume_with_error(&mut self, handle: ResumeHandle, message: &str) -> Result<Execution, LoxError> {
+        self.restore_handle(handle);
+        if let Err(InterpretResult::RuntimeError(error)) = self.runtime_error(message) {
+            self.stack.clear();
+            self.frames.clear();
+            return Err(error);
+        }
+        self.execute()
+    }
+
+    /// Runs the script to its end, awaiting the futures of async natives on the way.
+    /// A `yield` gets nil back.
+    pub async fn run_async(&mut self, function: Function) -> Result<Value, LoxError> {
+        let mut execution = self.start(function)?;
+        loop {
+            execution = match execution {
+                Execution::Complete(value) => return Ok(value),
+                Execution::Suspended(handle, _) => self.resume(handle, Value::Nil)?,
+                Execution::Waiting(handle, future) => match future.await {
+                    Ok(value) => self.resume(handle, value)?,
+                    Err(message) => self.resume_with_error(handle, &message)?,
+                },
+            };
+        }
+    }
+
+    fn restore_handle(&mut self, handle: ResumeHandle) {
+        self.frames = handle.frames;
+        self.stack = handle.stack;
+        self.source = handle.source;
+        self.resumable = true;
+    }
+
+    fn suspend(&mut self) -> ResumeHandle {
+        ResumeHandle {
+            frames: std::mem::take(&mut self.frames),
+            stack: std::mem::take(&mut self.stack),
+            source: self.source.take(),
+        }
+    }
+
+    fn execute(&mut self) -> Result<Execution, LoxError> {
         match self.run() {
-            Ok(()) => InterpretResult::Ok,
-            Err(res) => res,
+            Ok(execution) => {
+                if let Execution::Complete(_) = execution {
+                    self.drop_global_caches();
+                }
+                Ok(execution)
+            }
+            Err(res) => {
+                self.drop_global_caches();
+                // keep the globals usable for the next run, e.g. in the REPL
+                self.stack.clear();
+                self.frames.clear();
+                match res {
+                    InterpretResult::RuntimeError(error) => Err(error),
+                    _ => panic!("only runtime errors happen while running"),
+                }
+            }
         }
     }
 
     pub fn interpret(&mut self, source: String, debug: bool) -> InterpretResult {
-        match compile(source, debug) {
-            Ok(function) => {
-                if debug {
-                    let mut debugger = Debugger::new();
-                    debugger.disassemble_chunk(&function, "code");
-                }
+        let compiled = if self.strict_globals {
+            compile_strict(source, debug, self.globals.keys().cloned().collect())
+        } else {
+            compile(source, debug)
+        };
+        match compiled {
+            Ok(program) => self.run_function(program.main, debug),
+            Err(errors) => InterpretResult::CompileError(errors),
+        }
+    }
 
-                self.call(function);
-                match self.run() {
-                    Ok(()) => InterpretResult::Ok,
-                    Err(res) => res,
-                }
+    pub fn interpret_repl(&mut self, source: String, debug: bool) -> InterpretResult {
+        match compile_repl(source) {
+            Ok(program) => self.run_function(program.main, debug),
+            Err(errors) => InterpretResult::CompileError(errors),
+        }
+    }
+
+    /// Makes runs reproducible, `random()` starts from `seed` and `clock()` returns
+    /// the number of calls so far.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.set_clock(VirtualClock::ticking(1.0));
+        self.random_state = seed;
+    }
+
+    /// Replaces the time source of `clock()`, e.g. with a `VirtualClock` advanced by a test.
+    pub fn set_clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Box::new(clock);
+    }
+
+    /// Makes `interpret` reject scripts using globals which are neither defined
+    /// in the VM nor declared in the script.
+    pub fn set_strict_globals(&mut self, strict: bool) {
+        self.strict_globals = strict;
+    }
+
+    /// Stops with an error which scripts can't catch once this many more instructions ran,
+    /// so a script that loops forever ends.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.instruction_budget = limit;
+    }
+
+    /// Notifies the observer of all following runs, observers are notified in the order they were added.
+    pub fn add_observer(&mut self, observer: impl VmObserver) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// The first added observer of type `T`.
+    pub fn observer<T: VmObserver>(&self) -> Option<&T> {
+        self.observers.iter().find_map(|observer| (&**observer as &dyn Any).downcast_ref())
+    }
+
+    /// Counts the executed instructions of all following runs, see `coverage`.
+    pub fn enable_coverage(&mut self) {
+        if self.coverage().is_none() {
+            self.add_observer(Coverage::default());
+        }
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.observer()
+    }
+
+    /// Lets scripts run commands with `exec()` and `shell()`, they see the variables set with `set_env()`.
+    pub fn set_allow_exec(&mut self, allow: bool) {
+        self.allow_exec = allow;
+    }
+
+    /// Integer arithmetic with a result beyond `MAX_SAFE_INTEGER` is a runtime error,
+    /// so counters and indexes never silently round.
+    pub fn set_safe_integers(&mut self, safe: bool) {
+        self.safe_integers = safe;
+    }
+
+    /// `==` and `!=` of values of different types are a runtime error, except with `nil`,
+    /// `===` and `!==` compare them as not equal.
+    pub fn set_strict_equality(&mut self, strict: bool) {
+        self.strict_equality = strict;
+    }
+
+    /// Drops all globals defined by scripts, only the natives are left.
+    pub fn reset_globals(&mut self) {
+        self.globals.clear();
+        self.constants.clear();
+        self.drop_global_caches();
+        self.define_natives();
+    }
+
+    /// Serializes the globals, natives are only written with `include_natives`.
+    pub fn snapshot(&self, include_natives: bool) -> Vec<u8> {
+        let globals = self.globals
+            .iter()
+            .filter(|(_, value)| include_natives || !matches!(value, Value::Native(..)))
+            // a running call or a resource of the VM cannot be saved
+            .filter(|(_, value)| !matches!(value, Value::Generator(_) | Value::Handle(_)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        bson::to_vec(&Snapshot { globals }).expect("Serialize to bson failed.")
+    }
+
+    /// Defines the globals of a snapshot, existing globals with other names are kept.
+    /// Functions are verified like loaded bytecode and natives are taken from the registry,
+    /// so a crafted snapshot can't run invalid code. Nothing is defined if it is invalid.
+    pub fn restore(&mut self, snapshot: &[u8]) -> Result<(), String> {
+        let snapshot: Snapshot = bson::from_slice(snapshot)
+            .map_err(|err| format!("invalid snapshot: {err}"))?;
+        let globals = snapshot.globals
+            .into_iter()
+            .map(|(name, value)| {
+                let value = match value {
+                    Value::Native(..) if let Some(native) = self.natives.native(&name) => native.clone(),
+                    value => self.restored_value(value)?,
+                };
+                Ok((name, value))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        self.globals.extend(globals);
+        self.invalidate_globals();
+        Ok(())
+    }
+
+    /// The value of a snapshot checked, natives are replaced by the registered ones.
+    fn restored_value(&self, value: Value) -> Result<Value, String> {
+        match value {
+            Value::Function(function) => {
+                verify(&function).map_err(|err| format!("invalid snapshot: {err}"))?;
+                Ok(Value::Function(function))
             }
-            Err(_) => InterpretResult::CompileError,
+            Value::Native(native, _) => self.natives.globals()
+                .map(|(_, value)| value)
+                .find(|value| matches!(value, Value::Native(registered, _) if *registered == native))
+                .cloned()
+                .ok_or_else(|| format!("invalid snapshot: {native:?} is not a native of this VM")),
+            Value::List(list) => list.values()
+                .into_iter()
+                .map(|value| self.restored_value(value))
+                .collect::<Result<_, _>>()
+                .map(|values| Value::List(List::new(values))),
+            value => Ok(value),
         }
     }
 
-    fn run(&mut self) -> Result<(), InterpretResult> {
+    /// The defined globals, natives included, sorted by name.
+    pub fn globals(&self) -> Vec<(&str, &Value)> {
+        self.iter_globals().collect()
+    }
+
+    /// The globals sorted by name, so the order doesn't change between runs.
+    pub fn iter_globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let mut globals: Vec<_> = self.globals
+            .iter()
+            .map(|(name, value)| (name.as_str(), value))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+        globals.into_iter()
+    }
+
+    /// The globals and keywords starting with `prefix`, for tab completion.
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        completions(prefix, self.globals.keys().cloned())
+    }
+
+    pub fn get_function(&self, name: &str) -> Option<&Function> {
+        match self.globals.get(name) {
+            Some(Value::Function(function)) => Some(function),
+            _ => None,
+        }
+    }
+
+    fn run(&mut self) -> Result<Execution, InterpretResult> {
+        // the function of the current frame, holding it keeps the instruction borrowed
+        // while the arms change the VM, it is only replaced by calls and returns
+        let mut function = Arc::clone(&self.frames.last().expect("frames cannot be empty").function);
         loop {
-            let frame = self.current_frame();
+            if let Some(budget) = &mut self.instruction_budget {
+                if *budget == 0 {
+                    let message = "Instruction limit exceeded.";
+                    for observer in &mut self.observers {
+                        observer.error_raised(&message.into());
+                    }
+                    return Err(InterpretResult::RuntimeError(self.lox_error(message)));
+                }
+                *budget -= 1;
+            }
+
+            let frame = self.frames.last_mut().expect("frames cannot be empty");
             let ip = frame.ip;
             frame.increase_ip();
+            for observer in &mut self.observers {
+                observer.instruction_executed(&frame.function, ip);
+            }
+
+            #[cfg(feature = "fast-dispatch")]
+            {
+                let instruction = frame.function.read_instruction(ip);
+                if let Some(instruction_fn) = INSTRUCTION_FNS[instruction.code.index() as usize] {
+                    self.current_line = instruction.line;
+                    instruction_fn(self)?;
+                    continue;
+                }
+            }
 
-            let instr = frame.function.read_instruction(ip).clone();
-            self.current_line = instr.line;
-            match &instr.code {
+            if !Arc::ptr_eq(&function, &frame.function) {
+                function = Arc::clone(&frame.function);
+            }
+            let instruction = function.read_instruction(ip);
+            self.current_line = instruction.line;
+            match &instruction.code {
                 OpCode::Bool(bool_val) => {
                     self.push(Value::Bool(*bool_val));
                 }
                 OpCode::Constant(x) => {
                     self.push_number(*x);
                 }
-                OpCode::Add => {
-                    binary_op!(self, +);
-                }
-                OpCode::Subtract => {
-                    binary_op!(self, -);
-                }
-                OpCode::Multiply => {
-                    binary_op!(self, *);
-                }
-                OpCode::Divide => {
-                    binary_op!(self, /);
-                }
-                OpCode::Nil => {
-                    self.push(Value::Nil);
-                }
-                OpCode::Not => {
-                    let val = self.pop();
-                    self.push(Value::Bool(self.is_falsey(val)));
-                }
-                OpCode::Negate => {
-                    if !self.peek(0).is_number() {
-                        self.runtime_error("Operand must be a number");
-                        return Err(InterpretResult::RuntimeError);
+                OpCode::Add => self.add()?,
+                OpCode::Subtract => self.subtract()?,
+                OpCode::Multiply => self.multiply()?,
+                OpCode::Divide => self.divide()?,
+                OpCode::Range => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => self.push(Value::Range(Arc::new(Range(a, b)))),
+                        (a, b) => {
+                            self.runtime_error(&format!(
+                                "Range bounds must be numbers, are {:?} and {:?}", a, b))?;
+                        }
                     }
-                    let value = self.pop_number();
-                    self.push_number(-value);
                 }
+                OpCode::Nil => self.nil()?,
+                OpCode::Not => self.not()?,
+                OpCode::Negate => self.negate()?,
                 OpCode::Print => println!("{:?}\n", self.pop()),
+                OpCode::PrintLine(arg_count) => {
+                    if let Value::Native(NativeFunction::Println, _) = self.println {
+                        // unlike a call of `println` the statement ends with a blank line
+                        let args = self.stack.split_off(self.stack.len() - arg_count);
+                        println!("{}\n", separated(&args));
+                        self.push(Value::Nil);
+                    } else {
+                        let callee = self.stack.len() - arg_count;
+                        self.stack.insert(callee, self.println.clone());
+                        if let Some(future) = self.call_instruction(*arg_count)? {
+                            return Ok(Execution::Waiting(self.suspend(), future));
+                        }
+                    }
+                }
                 OpCode::Jump(offset) => self.current_frame().jump(*offset),
                 OpCode::JumpIfFalse(offset) => {
                     if self.is_falsey(self.peek(0)) {
                         self.current_frame().jump(*offset);
                     }
                 }
+                OpCode::JumpIfTrue(offset) => {
+                    if !self.is_falsey(self.peek(0)) {
+                        self.current_frame().jump(*offset);
+                    }
+                }
+                OpCode::JumpIfFalsePop(offset) => {
+                    let condition = self.pop();
+                    if self.is_falsey(&condition) {
+                        self.current_frame().jump(*offset);
+                    }
+                }
+                OpCode::JumpIfTruePop(offset) => {
+                    let condition = self.pop();
+                    if !self.is_falsey(&condition) {
+                        self.current_frame().jump(*offset);
+                    }
+                }
                 OpCode::Loop(offset) => self.current_frame().jump_back(*offset),
+                OpCode::IterNext(slot) => {
+                    let slot = *slot + self.current_frame().stack_offset;
+                    if let Value::Generator(generator) = &self.stack[slot] {
+                        if let Err(message) = self.resume_generator(generator.clone()) {
+                            self.runtime_error(&message)?;
+                        }
+                        continue;
+                    }
+
+                    let Value::Number(index) = self.stack[slot + 1] else {
+                        self.runtime_error("The iteration index is not a number.")?;
+                        continue;
+                    };
+                    let index = index as usize;
+
+                    match self.stack[slot].iter_next(index) {
+                        Ok(Some((element, next))) => {
+                            self.stack[slot + 1] = Value::Number(next as f64);
+                            self.push(element);
+                            self.push(Value::Bool(true));
+                        }
+                        Ok(None) => {
+                            self.push(Value::Nil);
+                            self.push(Value::Bool(false));
+                        }
+                        Err(message) => {
+                            self.runtime_error(&message)?;
+                        }
+                    }
+                }
                 OpCode::Call(arg_count) => {
-                    if !self.call_value(self.peek(*arg_count), *arg_count) {
-                        return Err(InterpretResult::RuntimeError);
+                    if let Some(future) = self.call_instruction(*arg_count)? {
+                        return Ok(Execution::Waiting(self.suspend(), future));
+                    }
+                }
+                OpCode::ConstantCall(constant, arg_count) => {
+                    self.push_number(*constant);
+                    if let Some(future) = self.call_instruction(*arg_count)? {
+                        return Ok(Execution::Waiting(self.suspend(), future));
+                    }
+                }
+                OpCode::GetProperty(name) => {
+                    let object = self.pop();
+                    match object.property(name) {
+                        Ok(value) => self.push(value),
+                        Err(message) => self.runtime_error(&message)?,
                     }
                 }
+                OpCode::Invoke(name, arg_count) => {
+                    let args = self.stack.split_off(self.stack.len() - arg_count);
+                    let receiver = self.pop();
+                    match self.invoke(receiver, name, args) {
+                        Ok(value) => self.push(value),
+                        Err(exception) => self.rethrow(exception)?,
+                    }
+                }
+                OpCode::IsList(length) => {
+                    let value = self.pop();
+                    self.push(Value::Bool(matches!(value, Value::List(list) if list.len() == *length)));
+                }
+                OpCode::Index(index) => match self.pop() {
+                    Value::List(list) => match list.get(*index) {
+                        Some(element) => self.push(element),
+                        None => self.runtime_error(&format!("Index {index} is out of range."))?,
+                    },
+                    other => self.runtime_error(&format!("Only lists can be indexed, not {:?}.", other))?,
+                },
+                OpCode::List(count) => {
+                    let elements = self.stack.split_off(self.stack.len() - count);
+                    self.push(elements.into());
+                }
+                OpCode::GetLocalAddConstant(slot, constant) => {
+                    let stack_offset = self.current_frame().stack_offset;
+                    match &self.stack[*slot + stack_offset] {
+                        Value::Number(value) => {
+                            let integers = self.safe_integers && is_safe_integer(*value) && is_safe_integer(*constant);
+                            self.push_number(value + constant);
+                            self.check_integer_result(integers)?;
+                        }
+                        other => {
+                            self.runtime_error(&format!(
+                                "Operands must be two numbers or two strings, are {:?} and {:?}",
+                                other.clone(), Value::Number(*constant)))?;
+                        }
+                    }
+                }
+                OpCode::CompareLessJumpIfFalse(offset) => {
+                    let b = self.pop();
+                    let a = self.pop();
+                    match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => {
+                            let less = a < b;
+                            if !less {
+                                self.current_frame().jump(*offset);
+                            }
+                        }
+                        (a, b) => {
+                            self.runtime_error(&format!("Operands must be numbers, are {:?} and {:?}",
+                            a, b))?;
+                        }
+                    }
+                }
+                OpCode::PushHandler(offset) => {
+                    let stack_len = self.stack.len();
+                    let frame = self.current_frame();
+                    let ip = frame.ip + offset;
+                    frame.handlers.push(Handler { ip, stack_len });
+                }
+                OpCode::PopHandler => {
+                    self.current_frame().handlers.pop();
+                }
+                OpCode::Throw => {
+                    let value = self.pop();
+                    let message = format!("Uncaught exception {:?}.", value);
+                    self.throw(value, &message)?;
+                }
+                OpCode::Yield if self.frames.last().is_some_and(|frame| frame.generator.is_some()) => {
+                    let value = self.pop();
+                    self.suspend_generator(value);
+                }
+                OpCode::Yield => {
+                    if self.resumable {
+                        let value = self.pop();
+                        return Ok(Execution::Suspended(self.suspend(), value));
+                    }
+
+                    self.runtime_error("Can only yield in scripts started with VM::start.")?;
+                }
                 OpCode::Return => {
                     let result = self.pop();
-                    let last_frame = self.frames.pop();
-                    if self.frames.is_empty() {
-                        // self.pop(); no pop as the first frame is not 'empty'
-                        return Ok(());
+                    let last_frame = self.pop_frame();
+                    if self.frames.len() == self.base_frames {
+                        // no pop as the first frame is not 'empty', a nested run removes its callee
+                        if !self.frames.is_empty() {
+                            self.stack.truncate(last_frame.stack_offset - 1);
+                        }
+                        return Ok(Execution::Complete(result));
                     }
 
-                    self.stack.truncate(last_frame.unwrap().stack_offset - 1);
-                    self.push(result);
+                    self.stack.truncate(last_frame.stack_offset - 1);
+                    if let Some(generator) = last_frame.generator {
+                        // ends the loop over the generator
+                        generator.state().lock().expect("generator lock poisoned").finish();
+                        self.push(Value::Nil);
+                        self.push(Value::Bool(false));
+                    } else {
+                        self.push(result);
+                    }
+                }
+                OpCode::Pop => self.pop_value()?,
+                OpCode::PopN(count) => {
+                    let len = self.stack.len() - count;
+                    self.stack.truncate(len);
                 }
-                OpCode::Pop => _ = self.pop(),
-                OpCode::GetLocal(slot) => {
+                OpCode::GetLocal(slot) | OpCode::GetLocalWide(slot) => {
                     let stack_offset = self.current_frame().stack_offset;
                     self.push(self.stack[*slot + stack_offset].clone());
                 }
-                OpCode::SetLocal(slot) => {
+                OpCode::SetLocal(slot) | OpCode::SetLocalWide(slot) => {
                     let stack_offset = self.current_frame().stack_offset;
-                    self.stack[*slot + stack_offset] = self.peek(0);
+                    self.stack[*slot + stack_offset] = self.peek(0).clone();
                 }
-                OpCode::GetGlobal(name) => match self.globals.get(name) {
-                    Some(val) => self.push(val.clone()),
-                    None => {
-                        self.runtime_error(&format!("Undefined variable '{}'.", name));
-                        return Err(InterpretResult::RuntimeError);
+                OpCode::GetGlobal(name) => {
+                    let cache = self.frame_global_cache();
+                    if let Some(value) = &self.global_caches[cache].values[ip] {
+                        self.push(value.clone());
+                    } else if let Some(value) = self.globals.get(name) {
+                        let value = value.clone();
+                        self.global_caches[cache].values[ip] = Some(value.clone());
+                        self.global_readers.entry(name.clone()).or_default().push((cache, ip));
+                        self.push(value);
+                    } else {
+                        self.runtime_error(&format!("Undefined variable '{}'.", name))?;
                     }
-                },
+                }
                 OpCode::DefineGlobal(name) => {
-                    self.globals.insert(name.clone(), self.peek(0));
-                    // todo: check if this is needed:
-                    // pop after insert as gc can resize globals
-                    self.pop();
+                    if self.constants.contains(name) {
+                        self.runtime_error(&format!("Can't redefine constant '{}'.", name))?;
+                        continue;
+                    }
+                    self.define_global(name);
+                }
+                OpCode::DefineConstant(name) => {
+                    // running the declaration again, e.g. in `watch`, keeps it constant
+                    self.constants.insert(name.clone());
+                    self.define_global(name);
                 }
                 OpCode::SetGlobal(name) => {
-                    if self.globals.contains_key(name) {
-                        self.globals.insert(name.clone(), self.peek(0));
+                    if self.constants.contains(name) {
+                        self.runtime_error(&format!("Can't assign to constant '{}'.", name))?;
+                    } else if self.globals.contains_key(name) {
+                        self.invalidate_global(name);
+                        // assigning in place doesn't copy the name
+                        let value = self.peek(0).clone();
+                        *self.globals.get_mut(name).expect("the global exists") = value;
                     } else {
-                        self.runtime_error(&format!("Undefined variable '{}'.", name));
-                        return Err(InterpretResult::RuntimeError);
+                        self.runtime_error(&format!("Undefined variable '{}'.", name))?;
                     }
                 }
-                OpCode::Equal => {
-                    let b = self.pop();
-                    let a = self.pop();
-
-                    self.push(Value::Bool(self.values_equal(a, b)));
-                }
-                OpCode::Greater => {
-                    binary_op!(self, >);
-                }
-                OpCode::Less => {
-                    binary_op!(self, <);
-                }
+                OpCode::GetNative(name) => match self.natives.native(name) {
+                    Some(native) => self.push(native.clone()),
+                    None => self.runtime_error("Unknown native function.")?,
+                },
+                OpCode::Equal => self.equal()?,
+                OpCode::StrictEqual => self.strict_equal()?,
+                OpCode::Greater => self.greater()?,
+                OpCode::Less => self.less()?,
+                OpCode::GreaterEqual => self.greater_equal()?,
+                OpCode::LessEqual => self.less_equal()?,
                 OpCode::String(string) => {
-                    self.push(Value::String(string.clone()));
+                    self.push(string.as_str().into());
+                }
+                OpCode::Function(index) => {
+                    let function = Arc::clone(&self.current_frame().function.functions()[*index]);
+                    self.push(Value::Function(function));
                 }
-                OpCode::Function(fct) => self.push(Value::Function(fct.clone())),
             }
         }
     }
 
-    fn is_falsey(&self, value: Value) -> bool {
-        match value {
-            Value::Nil => true,
-            Value::Bool(val_bool) => !val_bool,
-            _ => false,
-        }
-    }
+    // The instructions without operands, called by `run` and through `INSTRUCTION_FNS`.
 
-    fn values_equal(&self, a: Value, b: Value) -> bool {
-        match (a, b) {
-            (Value::Bool(a), Value::Bool(b)) => a == b,
-            (Value::Nil, Value::Nil) => true,
-            (Value::Number(a), Value::Number(b)) => a == b,
-            (Value::String(a), Value::String(b)) => a == b,
-            _ => false,
-        }
+    fn add(&mut self) -> Result<(), InterpretResult> {
+        let integers = self.integer_operands();
+        binary_op!(self, +);
+        self.check_integer_result(integers)
     }
 
-    fn peek(&self, distance: usize) -> Value {
-        self.stack[self.stack.len() - 1 - distance].clone()
+    fn subtract(&mut self) -> Result<(), InterpretResult> {
+        let integers = self.integer_operands();
+        binary_op!(self, -);
+        self.check_integer_result(integers)
     }
 
-    fn call_value(&mut self, value: Value, arg_count: usize) -> bool {
-        match value {
-            Value::Function(function) => {
-                if arg_count != function.arity() {
-                    self.runtime_error(&format!(
-                        "Expected {} arguments but got {}.",
-                        function.arity(), arg_count)
-                    );
-
-                    return false;
-                }
+    fn multiply(&mut self) -> Result<(), InterpretResult> {
+        let integers = self.integer_operands();
+        binary_op!(self, *);
+        self.check_integer_result(integers)
+    }
 
-                self.call(function)
-            }
-            Value::Native(function, expected_count) => self.call_native(function, expected_count, arg_count),
-            _ => {
-                self.runtime_error("Can only call functions and classes.");
-                false
-            }
-        }
+    fn divide(&mut self) -> Result<(), InterpretResult> {
+        binary_op!(self, /);
+        Ok(())
     }
 
-    fn call(&mut self, function: Function) -> bool {
-        let arg_len = function.arity();
-        let stack_offset = if self.frames.len() > 2 {
-            self.stack.len() - arg_len
-        } else {
-            self.stack.len() - arg_len
-        };
+    fn greater(&mut self) -> Result<(), InterpretResult> {
+        binary_op!(self, >);
+        Ok(())
+    }
 
-        let frame = CallFrame::new(function, stack_offset);
-        self.frames.push(frame);
-        true
+    fn less(&mut self) -> Result<(), InterpretResult> {
+        binary_op!(self, <);
+        Ok(())
     }
 
-    fn call_native(&mut self, function: NativeFunction, expected_count: usize, arg_count: usize) -> bool {
-        if expected_count != arg_count {
-            self.runtime_error(&format!(
-                "Expected {} arguments but got {}.",
-                expected_count, arg_count)
-            );
+    fn greater_equal(&mut self) -> Result<(), InterpretResult> {
+        binary_op!(self, >=);
+        Ok(())
+    }
 
-            return false;
-        }
+    fn less_equal(&mut self) -> Result<(), InterpretResult> {
+        binary_op!(self, <=);
+        Ok(())
+    }
 
-        let mut args = vec![];
-        for _ in 0..expected_count {
-            args.push(self.pop());
+    fn equal(&mut self) -> Result<(), InterpretResult> {
+        let b = self.pop();
+        let a = self.pop();
+        let comparable = matches!(a, Value::Nil) || matches!(b, Value::Nil) || mem::discriminant(&a) == mem::discriminant(&b);
+        if self.strict_equality && !comparable {
+            return self.runtime_error(&format!(
+                "Can't compare values of different types with '==', are {:?} and {:?}, use '===' instead.",
+                a, b));
         }
+        self.push(Value::Bool(a == b));
+        Ok(())
+    }
 
-        let result = match function {
-            NativeFunction::Clock => {
-                let t = SystemTime::now().duration_since(UNIX_EPOCH)
-                    .expect("time before unix?")
-                    .as_secs_f64();
-                Value::Number(t)
-            }
-        };
+    fn strict_equal(&mut self) -> Result<(), InterpretResult> {
+        let b = self.pop();
+        let a = self.pop();
+        self.push(Value::Bool(a == b));
+        Ok(())
+    }
 
-        self.pop();
-        self.push(result);
-        true
+    fn nil(&mut self) -> Result<(), InterpretResult> {
+        self.push(Value::Nil);
+        Ok(())
     }
 
-    fn pop(&mut self) -> Value {
-        self.stack.pop().expect("VM stack was empty")
+    fn not(&mut self) -> Result<(), InterpretResult> {
+        let val = self.pop();
+        self.push(Value::Bool(self.is_falsey(&val)));
+        Ok(())
     }
 
-    fn pop_number(&mut self) -> f64 {
-        if let Value::Number(value) = self.pop() {
-            value
+    fn negate(&mut self) -> Result<(), InterpretResult> {
+        if !self.peek(0).is_number() {
+            self.runtime_error("Operand must be a number")?;
         } else {
-            panic!("pop not a number");
+            let value = self.pop_number();
+            self.push_number(-value);
         }
+        Ok(())
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    fn pop_value(&mut self) -> Result<(), InterpretResult> {
+        self.pop();
+        Ok(())
     }
 
-    fn push_number(&mut self, value: f64) {
-        self.stack.push(Value::Number(value));
+    fn is_falsey(&self, value: &Value) -> bool {
+        match value {
+            Value::Nil => true,
+            Value::Bool(val_bool) => !*val_bool,
+            _ => false,
+        }
     }
 
-    fn current_frame(&mut self) -> &mut CallFrame {
-        self.frames.last_mut().expect("frames cannot be empty")
+    /// Borrows the value, the branches and comparisons only look at it.
+    fn peek(&self, distance: usize) -> &Value {
+        &self.stack[self.stack.len() - 1 - distance]
     }
 
-    fn define_natives(&mut self) {
-        self.globals.insert("clock".to_string(), Value::Native(NativeFunction::Clock, 0));
+    /// Calls the value below the arguments, returns the future of an async native.
+    fn call_instruction(&mut self, arg_count: usize) -> Result<Option<HostFuture>, InterpretResult> {
+        if let Err(exception) = self.call_value(self.peek(arg_count).clone(), arg_count) {
+            self.rethrow(exception)?;
+            return Ok(None);
+        }
+
+        Ok(self.pending.get_mut().expect("pending lock poisoned").take())
     }
 
-    #[allow(dead_code)]
-    fn print_stack(&mut self, info: &str) {
-        println!("stack, offset {}, {info}", self.current_frame().stack_offset);
+    /// Fails with the exception to throw.
+    fn call_value(&mut self, value: Value, arg_count: usize) -> Result<(), Value> {
+        match value {
+            Value::Function(function) => {
+                if function.is_variadic() {
+                    if arg_count < function.arity() {
+                        return Err(arity_error(&function, arg_count));
+                    }
+                    let rest = self.stack.split_off(self.stack.len() - (arg_count - function.arity()));
+                    self.push(rest.into());
+                } else if arg_count != function.arity() {
+                    return Err(arity_error(&function, arg_count));
+                }
+
+                if function.is_generator() {
+                    let generator = self.new_generator(function);
+                    self.push(Value::Generator(generator));
+                } else {
+                    self.call(function);
+                }
+                Ok(())
+            }
+            Value::Native(function, expected_count) => self.call_native(function, expected_count.into(), arg_count),
+            _ => Err("Can only call functions and classes.".into()),
+        }
+    }
+
+    fn call(&mut self, function: Arc<Function>) -> bool {
+        let arg_len = function.parameter_count();
+        let stack_offset = self.stack.len() - arg_len;
+        // the arguments are already on the stack
+        self.stack.reserve(function.max_stack().saturating_sub(arg_len));
+
+        let frame = CallFrame::new(function, stack_offset);
+        self.push_frame(frame);
+        true
+    }
+
+    /// Moves the callee and the arguments into the generator, the body runs when it is iterated.
+    fn new_generator(&mut self, function: Arc<Function>) -> Generator {
+        let stack = self.stack.split_off(self.stack.len() - function.parameter_count() - 1);
+        let name = function.name().to_string();
+        let state = GeneratorState {
+            frame: Some(CallFrame::new(function, 1)),
+            stack,
+            started: false,
+            running: false,
+        };
+        Generator::new(name, state)
+    }
+
+    /// Continues the generator until its next yield, like `IterNext` it pushes
+    /// the element and whether there is one.
+    fn resume_generator(&mut self, generator: Generator) -> Result<(), String> {
+        let mut state = generator.state().lock().expect("generator lock poisoned");
+        if state.running {
+            return Err("Generator is already running.".to_string());
+        }
+
+        let Some(mut frame) = state.frame.take() else {
+            self.push(Value::Nil);
+            self.push(Value::Bool(false));
+            return Ok(());
+        };
+
+        let base = self.stack.len();
+        self.stack.reserve(frame.function.max_stack() + 1);
+        self.stack.append(&mut state.stack);
+        frame.move_stack(0, base);
+        if state.started {
+            // the result of the yield expression
+            self.push(Value::Nil);
+        }
+        state.started = true;
+        state.running = true;
+        drop(state);
+
+        frame.generator = Some(generator);
+        self.push_frame(frame);
+        Ok(())
+    }
+
+    fn suspend_generator(&mut self, value: Value) {
+        let mut frame = self.pop_frame();
+        let generator = frame.generator.take().expect("frame runs a generator");
+        let base = frame.stack_offset - 1;
+        frame.move_stack(base, 0);
+
+        let mut state = generator.state().lock().expect("generator lock poisoned");
+        state.stack = self.stack.split_off(base);
+        state.frame = Some(frame);
+        state.running = false;
+        drop(state);
+
+        self.push(value);
+        self.push(Value::Bool(true));
+    }
+
+    fn call_native(&mut self, function: NativeFunction, expected_count: usize, arg_count: usize) -> Result<(), Value> {
+        // `print` and `println` write any further arguments separated by spaces
+        let variadic = matches!(function, NativeFunction::Print | NativeFunction::Println);
+        if expected_count != arg_count && !(variadic && arg_count > expected_count) {
+            return Err(format!(
+                "Expected {} arguments but got {}.",
+                expected_count, arg_count).into()
+            );
+        }
+
+        let mut args = vec![];
+        for _ in 0..arg_count {
+            args.push(self.pop());
+        }
+        args.reverse();
+
+        let result = match function {
+            NativeFunction::Clock => Ok(Value::Number(self.clock.now())),
+            NativeFunction::Random => Ok(Value::Number(self.next_random())),
+            NativeFunction::Print => {
+                print!("{}", separated(&args));
+                // without a newline stdout is not flushed
+                let _ = io::stdout().flush();
+                Ok(Value::Nil)
+            }
+            NativeFunction::Println => {
+                println!("{}", separated(&args));
+                Ok(Value::Nil)
+            }
+            NativeFunction::Inspect => Ok(args[0].inspect().into()),
+            NativeFunction::Dump => {
+                println!("{}", args[0].inspect());
+                Ok(Value::Nil)
+            }
+            NativeFunction::Template => template::render(&args[0], &args[1]),
+            NativeFunction::Version => Ok(version::build_info().to_value()),
+            NativeFunction::Globals => {
+                let pairs = self.iter_globals()
+                    .map(|(name, value)| Value::List(List::new(vec![name.into(), value.clone()])))
+                    .collect();
+                Ok(Value::List(List::new(pairs)))
+            }
+            NativeFunction::Host(index) => match self.natives.host_function(index) {
+                Some(host_function) => host_function(&args),
+                None => Err("Unknown native function.".to_string()),
+            },
+            NativeFunction::AsyncHost(index) => return self.call_async_native(index, &args).map_err(Value::from),
+            NativeFunction::Env => match &args[0] {
+                Value::String(name) => Ok(self.env.get(name.as_str()).cloned()
+                    .or_else(|| env::var(name.as_str()).ok())
+                    .map_or(Value::Nil, Value::from)),
+                other => Err(format!("env() needs a string, not {:?}.", other)),
+            },
+            NativeFunction::SetEnv => match (&args[0], &args[1]) {
+                (Value::String(name), Value::String(value)) => {
+                    self.env.insert(name.to_string(), value.to_string());
+                    Ok(Value::Nil)
+                }
+                (a, b) => Err(format!("set_env() needs two strings, not {:?} and {:?}.", a, b)),
+            },
+            NativeFunction::Exec | NativeFunction::Shell if !self.allow_exec => {
+                Err("Running commands is not allowed, run with --allow-exec.".to_string())
+            }
+            NativeFunction::Exec => {
+                let words = match &args[0] {
+                    Value::String(command) => command.split_whitespace().map(Value::from).collect(),
+                    Value::List(list) => list.values(),
+                    other => return Err(format!("exec() needs a string or a list, not {:?}.", other).into()),
+                };
+                let words: Option<Vec<String>> = words.iter()
+                    .map(|word| match word { Value::String(word) => Some(word.to_string()), _ => None })
+                    .collect();
+                match words.as_deref() {
+                    Some([program, arguments @ ..]) => {
+                        let mut command = Command::new(program);
+                        command.args(arguments);
+                        self.run_command(command)
+                    }
+                    _ => Err("exec() needs a program and string arguments.".to_string()),
+                }
+            }
+            NativeFunction::Shell => match &args[0] {
+                Value::String(script) => {
+                    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+                    let mut command = Command::new(shell);
+                    command.args([flag, script.as_str()]);
+                    self.run_command(command)
+                }
+                other => Err(format!("shell() needs a string, not {:?}.", other)),
+            },
+            #[cfg(feature = "net")]
+            NativeFunction::TcpConnect => self.sockets.connect(&args[0], &args[1]),
+            #[cfg(feature = "net")]
+            NativeFunction::TcpSend => self.sockets.send(&args[0], &args[1]),
+            #[cfg(feature = "net")]
+            NativeFunction::TcpRecv => self.sockets.receive(&args[0]),
+            #[cfg(feature = "net")]
+            NativeFunction::TcpClose => self.sockets.close(&args[0]),
+            NativeFunction::Sort => Ok(self.sorted("sort", &args[0], None)?),
+            NativeFunction::SortBy => Ok(self.sorted("sort_by", &args[0], Some(&args[1]))?),
+            NativeFunction::Len => match &args[0] {
+                Value::String(string) => Ok(Value::Number(string.chars().count() as f64)),
+                Value::Range(range) => Ok(Value::Number((range.1 - range.0).ceil().max(0.0))),
+                Value::List(list) => Ok(Value::Number(list.len() as f64)),
+                other => Err(format!("len() not supported for {:?}.", other)),
+            },
+            NativeFunction::IsNan => match &args[0] {
+                Value::Number(n) => Ok(Value::Bool(n.is_nan())),
+                other => Err(format!("is_nan() needs a number, not {:?}.", other)),
+            },
+            NativeFunction::IsFinite => match &args[0] {
+                Value::Number(n) => Ok(Value::Bool(n.is_finite())),
+                other => Err(format!("is_finite() needs a number, not {:?}.", other)),
+            },
+            NativeFunction::Contains => match (&args[0], &args[1]) {
+                (Value::Range(range), Value::Number(n)) => {
+                    let Range(start, end) = **range;
+                    Ok(Value::Bool(start <= *n && *n < end && (n - start).fract() == 0.0))
+                }
+                (Value::String(string), Value::String(part)) => {
+                    Ok(Value::Bool(string.contains(part.as_str())))
+                }
+                (Value::List(list), value) => Ok(Value::Bool(
+                    list.values().into_iter().any(|element| element == *value),
+                )),
+                (a, b) => Err(format!("contains() not supported for {:?} and {:?}.", a, b)),
+            },
+        };
+
+        let result = result?;
+        self.pop();
+        self.push(result);
+        Ok(())
+    }
+
+    /// The output of a finished command, fails if it can't be started or exits with an error.
+    fn run_command(&self, mut command: Command) -> Result<Value, String> {
+        let output = command.envs(&self.env).output()
+            .map_err(|error| format!("Can't run command: {error}."))?;
+        if !output.status.success() {
+            return Err(format!("Command failed with {}: {}",
+                output.status, String::from_utf8_lossy(&output.stderr).trim()));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned().into())
+    }
+
+    /// splitmix64, a number in [0, 1).
+    fn next_random(&mut self) -> f64 {
+        self.random_state = self.random_state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.random_state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Only starts the future, `run` suspends the script and the result is pushed on resume.
+    fn call_async_native(&mut self, index: u32, args: &[Value]) -> Result<(), String> {
+        if !self.resumable {
+            return Err("Async natives can only be called in scripts started with VM::start.".to_string());
+        }
+
+        let Some(async_function) = self.natives.async_function(index) else {
+            return Err("Unknown native function.".to_string());
+        };
+        *self.pending.get_mut().expect("pending lock poisoned") = Some(async_function(args));
+        self.pop();
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("VM stack was empty")
+    }
+
+    fn pop_number(&mut self) -> f64 {
+        if let Value::Number(value) = self.pop() {
+            value
+        } else {
+            panic!("pop not a number");
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn push_number(&mut self, value: f64) {
+        self.stack.push(Value::Number(value));
+    }
+
+    fn push_frame(&mut self, frame: CallFrame) {
+        self.frames.push(frame);
+        let function = &self.frames[self.frames.len() - 1].function;
+        for observer in &mut self.observers {
+            observer.frame_pushed(function, self.frames.len());
+        }
+    }
+
+    fn pop_frame(&mut self) -> CallFrame {
+        let depth = self.frames.len();
+        let frame = self.frames.pop().expect("frames cannot be empty");
+        for observer in &mut self.observers {
+            observer.frame_popped(&frame.function, depth);
+        }
+        frame
+    }
+
+    fn current_frame(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("frames cannot be empty")
+    }
+
+    fn define_global(&mut self, name: &str) {
+        self.invalidate_global(name);
+        let value = self.peek(0).clone();
+        for observer in &mut self.observers {
+            observer.global_defined(name, &value);
+        }
+        self.globals.insert(name.to_string(), value);
+        // todo: check if this is needed:
+        // pop after insert as gc can resize globals
+        self.pop();
+    }
+
+    /// The inline cache of the current frame's function, created on its first global read.
+    fn frame_global_cache(&mut self) -> usize {
+        let frame = self.frames.last_mut().expect("frames cannot be empty");
+        // a generator or a resumed script can bring a frame of another VM
+        if let Some(index) = frame.global_cache
+            && self.global_caches.get(index).is_some_and(|cache| Arc::ptr_eq(&cache.function, &frame.function))
+        {
+            return index;
+        }
+
+        let address = Arc::as_ptr(&frame.function) as usize;
+        let index = *self.global_cache_indexes.entry(address).or_insert_with(|| {
+            self.global_caches.push(GlobalCache {
+                function: Arc::clone(&frame.function),
+                values: vec![None; frame.function.instruction_count()],
+            });
+            self.global_caches.len() - 1
+        });
+        frame.global_cache = Some(index);
+        index
+    }
+
+    fn invalidate_global(&mut self, name: &str) {
+        if let Some(readers) = self.global_readers.remove(name) {
+            for (cache, ip) in readers {
+                self.global_caches[cache].values[ip] = None;
+            }
+        }
+    }
+
+    fn invalidate_globals(&mut self) {
+        for (_, readers) in self.global_readers.drain() {
+            for (cache, ip) in readers {
+                self.global_caches[cache].values[ip] = None;
+            }
+        }
+    }
+
+    /// The caches keep their functions alive, they only live as long as the run.
+    /// Frames of generators that outlive it find their index stale and get a new cache.
+    fn drop_global_caches(&mut self) {
+        self.global_caches.clear();
+        self.global_cache_indexes.clear();
+        self.global_readers.clear();
+    }
+
+    fn define_natives(&mut self) {
+        for (name, native) in self.natives.globals() {
+            self.globals.insert(name.clone(), native.clone());
+        }
+    }
+
+    #[allow(dead_code)]
+    fn print_stack(&mut self, info: &str) {
+        println!("stack, offset {}, {info}", self.current_frame().stack_offset);
         for (i, v) in self.stack.iter().enumerate() {
             match v {
                 Value::Function(f) => println!("{i}: Func {}", f.name()),
                 o => println!("{i}: {o:?}"),
             }
         }
-        println!("");
+        println!();
+    }
+
+    fn lox_error(&self, message: &str) -> LoxError {
+        let stack = self.frames.iter().rev().map(|frame| {
+            let line = frame.function.read_instruction(frame.ip.saturating_sub(1)).line;
+            match frame.function.name() {
+                "" => format!("[line {line}] in script"),
+                name => format!("[line {line}] in {name}()"),
+            }
+        }).collect();
+
+        let instruction = self.frames.last()
+            .map(|frame| frame.function.read_instruction(frame.ip.saturating_sub(1)));
+        let span = instruction.and_then(|instruction| instruction.span);
+        let snippet = instruction.and_then(|instruction| {
+            snippet(self.source.as_deref()?, instruction.line, instruction.span?)
+        });
+
+        LoxError {
+            message: message.to_string(),
+            line: self.current_line,
+            span,
+            code: error_codes::runtime_code(message),
+            stack,
+            snippet,
+        }
+    }
+
+    /// Built-in methods, the list methods taking a function call back into lox.
+    fn invoke(&mut self, receiver: Value, name: &str, args: Vec<Value>) -> Result<Value, Value> {
+        let (Value::List(list), "map" | "filter" | "reduce" | "sort") = (&receiver, name) else {
+            return receiver.invoke(name, &args).map_err(Value::from);
+        };
+        receiver.check_method(name, args.len())?;
+
+        let function = &args[0];
+        let values = list.values();
+        match name {
+            "map" => {
+                let mut mapped = Vec::with_capacity(values.len());
+                for value in values {
+                    mapped.push(self.call_function(function, &[value])?);
+                }
+                Ok(mapped.into())
+            }
+            "filter" => {
+                let mut kept = vec![];
+                for value in values {
+                    let keep = self.call_function(function, std::slice::from_ref(&value))?;
+                    if !self.is_falsey(&keep) {
+                        kept.push(value);
+                    }
+                }
+                Ok(kept.into())
+            }
+            "reduce" => {
+                let mut accumulator = args[1].clone();
+                for value in values {
+                    accumulator = self.call_function(function, &[accumulator, value])?;
+                }
+                Ok(accumulator)
+            }
+            _ => {
+                let sorted = self.sort_values(values, Some(function))?;
+                list.replace(sorted);
+                Ok(receiver)
+            }
+        }
+    }
+
+    /// The `sort` and `sort_by` natives, a sorted copy of the list.
+    fn sorted(&mut self, name: &str, list: &Value, comparator: Option<&Value>) -> Result<Value, Value> {
+        let Value::List(list) = list else {
+            return Err(format!("{name}() needs a list, not {:?}.", list).into());
+        };
+        Ok(self.sort_values(list.values(), comparator)?.into())
+    }
+
+    /// Stable sort with the comparator, which returns a negative number, zero or a
+    /// positive number. Without one numbers and strings are sorted ascending.
+    fn sort_values(&mut self, values: Vec<Value>, comparator: Option<&Value>) -> Result<Vec<Value>, Value> {
+        let sorted = self.merge_sort(values, comparator)?;
+        // a comparator without a consistent order can leave neighbours in the wrong order
+        for pair in sorted.windows(2) {
+            if self.compare(comparator, &pair[0], &pair[1])? == Ordering::Greater {
+                return Err(format!(
+                    "Inconsistent comparator, it orders {:?} after {:?} but sorting put it before.",
+                    pair[0], pair[1]).into());
+            }
+        }
+        Ok(sorted)
+    }
+
+    fn merge_sort(&mut self, mut values: Vec<Value>, comparator: Option<&Value>) -> Result<Vec<Value>, Value> {
+        if values.len() < 2 {
+            return Ok(values);
+        }
+
+        let right = values.split_off(values.len() / 2);
+        let mut left = self.merge_sort(values, comparator)?.into_iter().peekable();
+        let mut right = self.merge_sort(right, comparator)?.into_iter().peekable();
+
+        let mut merged = Vec::with_capacity(left.len() + right.len());
+        while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+            // on equal elements the left one goes first, which keeps the sort stable
+            let order = self.compare(comparator, a, b)?;
+            merged.extend(if order == Ordering::Greater { right.next() } else { left.next() });
+        }
+        merged.extend(left);
+        merged.extend(right);
+        Ok(merged)
+    }
+
+    fn compare(&mut self, comparator: Option<&Value>, a: &Value, b: &Value) -> Result<Ordering, Value> {
+        let Some(comparator) = comparator else {
+            return match (a, b) {
+                (Value::Number(x), Value::Number(y)) => x.partial_cmp(y).ok_or_else(|| "Can't sort NaN.".into()),
+                (Value::String(x), Value::String(y)) => Ok(x.cmp(y)),
+                _ => Err(format!("Can only sort numbers or strings, not {:?} and {:?}.", a, b).into()),
+            };
+        };
+
+        match self.call_function(comparator, &[a.clone(), b.clone()])? {
+            Value::Number(order) => order.partial_cmp(&0.0).ok_or_else(|| "The comparator returned NaN.".into()),
+            other => Err(format!("The comparator must return a number, not {:?}.", other).into()),
+        }
+    }
+
+    /// Calls the function and runs it to its return, for natives and methods
+    /// which call back into lox. An exception not caught in the call is returned.
+    fn call_function(&mut self, callee: &Value, args: &[Value]) -> Result<Value, Value> {
+        let frames = self.frames.len();
+        let stack_len = self.stack.len();
+        self.push(callee.clone());
+        self.stack.extend_from_slice(args);
+        if let Err(exception) = self.call_value(callee.clone(), args.len()) {
+            self.stack.truncate(stack_len);
+            return Err(exception);
+        }
+        if self.frames.len() == frames {
+            // a native or a generator function, the result is pushed already
+            return Ok(self.pop());
+        }
+
+        let base_frames = std::mem::replace(&mut self.base_frames, frames);
+        // the caller of the method can't be suspended, so yields and async natives fail
+        let resumable = std::mem::replace(&mut self.resumable, false);
+        let result = self.run();
+        self.base_frames = base_frames;
+        self.resumable = resumable;
+
+        match result {
+            Ok(Execution::Complete(value)) => Ok(value),
+            Ok(_) => unreachable!("a run which is not resumable doesn't suspend"),
+            Err(_) => {
+                self.frames.truncate(frames);
+                self.stack.truncate(stack_len);
+                Err(self.uncaught.take().unwrap_or(Value::Nil))
+            }
+        }
     }
 
-    fn runtime_error(&self, message: &str) {
-        eprintln!("{message}");
+    /// Throws the exception which escaped a call of a built-in method.
+    fn rethrow(&mut self, exception: Value) -> Result<(), InterpretResult> {
+        let message = match &exception {
+            Value::String(message) => message.to_string(),
+            other => format!("Uncaught exception {:?}.", other),
+        };
+        self.throw(exception, &message)
+    }
+
+    /// Runtime errors are thrown as string values, so scripts can catch them.
+    /// With safe integers, whether the two operands on the stack are exact integers.
+    fn integer_operands(&self) -> bool {
+        self.safe_integers && self.stack[self.stack.len() - 2..].iter()
+            .all(|operand| matches!(operand, Value::Number(n) if is_safe_integer(*n)))
+    }
+
+    /// The result of integer operands has to be exact too.
+    fn check_integer_result(&mut self, integers: bool) -> Result<(), InterpretResult> {
+        match self.stack.last() {
+            Some(Value::Number(result)) if integers && !is_safe_integer(*result) => {
+                let message = format!("Integer result {result} is beyond 2^53 and not exact.");
+                self.runtime_error(&message)
+            }
+            _ => Ok(()),
+        }
+    }
 
-        eprintln!("[line {}] in script", self.current_line);
+    fn runtime_error(&mut self, message: &str) -> Result<(), InterpretResult> {
+        self.throw(message.into(), message)
+    }
+
+    /// Unwind to the innermost handler and continue in its catch block,
+    /// if there is none the message is reported and execution stops.
+    fn throw(&mut self, value: Value, uncaught_message: &str) -> Result<(), InterpretResult> {
+        for observer in &mut self.observers {
+            observer.error_raised(&value);
+        }
+
+        if self.frames[self.base_frames..].iter().all(|frame| frame.handlers.is_empty()) {
+            if self.base_frames > 0 {
+                self.uncaught = Some(value);
+            }
+            return Err(InterpretResult::RuntimeError(self.lox_error(uncaught_message)));
+        }
+
+        loop {
+            let frame = self.current_frame();
+            if let Some(handler) = frame.handlers.pop() {
+                frame.ip = handler.ip;
+                self.stack.truncate(handler.stack_len);
+                self.push(value);
+                return Ok(());
+            }
+
+            let frame = self.pop_frame();
+            self.stack.truncate(frame.stack_offset - 1);
+            if let Some(generator) = frame.generator {
+                generator.state().lock().expect("generator lock poisoned").finish();
+            }
+        }
     }
 }
 
+/// The values as `print` writes them.
+fn separated(values: &[Value]) -> String {
+    values.iter().map(|value| format!("{value:?}")).collect::<Vec<_>>().join(" ")
+}
+
+/// The error of a call with the wrong number of arguments, names the missing parameters.
+fn arity_error(function: &Function, arg_count: usize) -> Value {
+    let expected = if function.is_variadic() {
+        format!("at least {}", function.arity())
+    } else {
+        function.arity().to_string()
+    };
+    let mut message = format!("Expected {expected} arguments but got {arg_count}");
+    if let Some(missing) = function.params().get(arg_count..function.arity()) {
+        let missing: Vec<_> = missing.iter().map(|name| format!("'{name}'")).collect();
+        message.push_str(&format!(", missing {}", missing.join(", ")));
+    }
+    message.push('.');
+    message.into()
+}
+
 #[cfg(test)]
 mod tests {
     use crate::chunk::Chunk;
@@ -379,11 +1658,650 @@ mod tests {
             chunk.write(code, 1);
         }
         let function = Function::new_from_chunk("test".to_string(), chunk);
-        vm.frames.push(CallFrame::new(function, 0));
+        vm.frames.push(CallFrame::new(Arc::new(function), 0));
         vm.run().unwrap();
         vm
     }
 
+    /// The natives of the enabled features.
+    fn native_names() -> Vec<String> {
+        NativeRegistry::default().globals().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn interpret_source(source: &str) -> VM {
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret(source.to_string(), false), InterpretResult::Ok));
+        vm
+    }
+
+    #[test]
+    fn test_for_in_string() {
+        let vm = interpret_source(
+            "var s = \"\"; for (var c in \"abc\") { var d = c; s = d + s; }");
+        assert_eq!(vm.globals.get("s").unwrap(), &Value::from("cba"));
+        assert!(vm.stack.is_empty());
+
+        let vm = interpret_source("var s = \"\"; var n = 0; for (var c in \"aé→😀\") { s = c + s; n = n + 1; }");
+        assert_eq!(vm.globals["s"], Value::from("😀→éa"));
+        assert_eq!(vm.globals["n"], Value::Number(4.0));
+    }
+
+    #[test]
+    fn test_for_in_range() {
+        let vm = interpret_source("var sum = 0; for (var i in 1..2+3) sum = sum + i;");
+        assert_eq!(vm.globals.get("sum").unwrap(), &Value::Number(10.0));
+    }
+
+    #[test]
+    fn test_range_natives() {
+        let vm = interpret_source(
+            "var r = 2..6; var l = len(r); var a = contains(r, 5); var b = contains(r, 6);");
+        assert_eq!(vm.globals.get("r").unwrap(), &Value::Range(Arc::new(Range(2.0, 6.0))));
+        assert_eq!(vm.globals.get("l").unwrap(), &Value::Number(4.0));
+        assert_eq!(vm.globals.get("a").unwrap(), &Value::Bool(true));
+        assert_eq!(vm.globals.get("b").unwrap(), &Value::Bool(false));
+    }
+
+    #[test]
+    fn test_nan_and_infinity() {
+        let vm = interpret_source("
+            var inf = 1 / 0; var nan = 0 / 0;
+            var comparisons = [nan == nan, nan != nan, nan < 1, nan <= 1, nan > 1, nan >= 1];
+            var checks = [is_nan(nan), is_nan(inf), is_finite(inf), is_finite(-1 / 3), inf >= inf];
+        ");
+        assert_eq!(vm.globals["inf"], Value::Number(f64::INFINITY));
+        let format = |name| format!("{:?}", vm.globals[name]);
+        assert_eq!(format("comparisons"), format!("{:?}", Value::List(List::new(
+            [false, true, false, false, false, false].map(Value::Bool).to_vec()))));
+        assert_eq!(format("checks"), format!("{:?}", Value::List(List::new(
+            [true, false, false, true, true].map(Value::Bool).to_vec()))));
+
+        let mut vm = VM::new();
+        let result = vm.interpret("var e; try { is_nan(\"a\"); } catch (error) { e = error; }".to_string(), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        assert_eq!(vm.globals["e"], Value::from("is_nan() needs a number, not String(\"a\")."));
+    }
+
+    #[test]
+    fn test_safe_integers() {
+        let source = "var big = 9007199254740991; var counter = big - 1; counter = counter + 1; var half = 0.5 * big;";
+        let vm = interpret_source(&format!("{source} var sum = big + 1;"));
+        assert_eq!(vm.globals["sum"], Value::Number(9007199254740992.0));
+
+        let mut vm = VM::new();
+        vm.set_safe_integers(true);
+        let result = vm.interpret(format!("{source} var e; try {{ big + 1; }} catch (error) {{ e = error; }}"), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        assert_eq!(vm.globals["counter"], Value::Number(9007199254740991.0));
+        assert_eq!(vm.globals["e"], Value::from("Integer result 9007199254740992 is beyond 2^53 and not exact."));
+        assert!(matches!(vm.interpret("var i = 1; i = i * big;".to_string(), false), InterpretResult::Ok));
+        let result = vm.interpret("for (var i = big - 1; i < 9007199254740993; i = i + 1) {}".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn test_strict_equality() {
+        let source = "var a = [1 == \"1\", 1 === \"1\", 1 !== \"1\", nil === false, 2 === 2, \"a\" !== \"a\"];
+            var b; match (\"1\") { 1 => b = 1; \"1\" => b = 2; _ => b = 3; }";
+        let vm = interpret_source(source);
+        let expected = Value::List(List::new([false, false, true, false, true, false].map(Value::Bool).to_vec()));
+        assert_eq!(format!("{:?}", vm.globals["a"]), format!("{expected:?}"));
+
+        let mut vm = VM::new();
+        vm.set_strict_equality(true);
+        let result = vm.interpret(source.replace("1 == \"1\"", "1 == nil"), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        assert_eq!(vm.globals["b"], Value::Number(2.0));
+        let result = vm.interpret("var e; try { 1 != \"1\"; } catch (error) { e = error; }".to_string(), false);
+        assert!(matches!(result, InterpretResult::Ok));
+        assert_eq!(vm.globals["e"], Value::from(
+            "Can't compare values of different types with '==', are Number(1.0) and String(\"1\"), use '===' instead."));
+    }
+
+    #[test]
+    fn test_try_catch_throw() {
+        let vm = interpret_source(
+            "var r; try { var a = 1; throw a + 1; r = 0; } catch (e) { r = e; }");
+        assert_eq!(vm.globals.get("r").unwrap(), &Value::Number(2.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_catch_runtime_error_in_function() {
+        let vm = interpret_source("
+            fun f(x) { return -x; }
+            fun g() { var local = 1; return f(\"a\"); }
+            var r;
+            { var before = 1; try { g(); } catch (e) { r = e; } }");
+        assert_eq!(vm.globals.get("r").unwrap(),
+            &Value::from("Operand must be a number"));
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.frames.len(), 0);
+    }
+
+    #[test]
+    fn test_runtime_error_value() {
+        let mut vm = VM::new();
+        let result = vm.interpret("fun f() {\n  return 1 + nil;\n}\n\nf();".to_string(), false);
+        let InterpretResult::RuntimeError(error) = result else {
+            panic!("expected runtime error, got {:?}", result);
+        };
+        assert_eq!(error.message, "Operands must be two numbers or two strings, are Number(1.0) and Nil");
+        assert_eq!(error.line, 2);
+        assert_eq!(error.stack, vec!["[line 2] in f()", "[line 5] in script"]);
+        assert_eq!(error.snippet.unwrap(), "   2 |   return 1 + nil;\n     |            ^");
+    }
+
+    #[test]
+    fn test_repl_keeps_globals_after_error() {
+        let mut vm = VM::new();
+        assert!(matches!(vm.interpret_repl("var a = 1;".to_string(), false), InterpretResult::Ok));
+        assert!(matches!(vm.interpret_repl("-nil".to_string(), false), InterpretResult::RuntimeError(_)));
+        assert!(matches!(vm.interpret_repl("a = a + 1".to_string(), false), InterpretResult::Ok));
+        assert_eq!(vm.globals.get("a").unwrap(), &Value::Number(2.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_globals_and_functions() {
+        let vm = interpret_source("var b = 1; fun a(x) {}");
+        let names: Vec<_> = vm.globals().into_iter().map(|(name, _)| name).collect();
+        let mut expected = native_names();
+        expected.extend(["a".to_string(), "b".to_string()]);
+        expected.sort();
+        assert_eq!(names, expected);
+        assert_eq!(vm.get_function("a").unwrap().arity(), 1);
+        assert!(vm.get_function("b").is_none());
+        assert!(vm.get_function("clock").is_none());
+    }
+
+    #[test]
+    fn test_globals_native() {
+        let vm = interpret_source("
+            fun on_start() { return 1; }
+            fun on_stop() { return 2; }
+            var handled = [];
+            for (var pair in globals()) {
+                var name = pair.get(0);
+                if (name.find(\"on_\") == 0) handled.push(pair.get(1)());
+            }");
+        let Value::List(handled) = &vm.globals["handled"] else { panic!() };
+        assert_eq!(handled.values(), vec![Value::Number(1.0), Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn test_strict_globals() {
+        let mut vm = VM::new();
+        vm.set_strict_globals(true);
+        assert!(matches!(vm.interpret("print clock() + a;".to_string(), false), InterpretResult::CompileError(_)));
+        assert!(matches!(vm.interpret("var a = 1;".to_string(), false), InterpretResult::Ok));
+        assert!(matches!(vm.interpret("print clock() + a;".to_string(), false), InterpretResult::Ok));
+    }
+
+    #[test]
+    fn test_constants_between_runs() {
+        let mut vm = interpret_source("const a = 1;");
+        let error = |vm: &mut VM, source: &str| match vm.interpret(source.to_string(), false) {
+            InterpretResult::RuntimeError(error) => (error.message, error.code),
+            other => panic!("{source} didn't fail: {other:?}"),
+        };
+        assert_eq!(error(&mut vm, "a = 2;"), ("Can't assign to constant 'a'.".to_string(), Some("E0121")));
+        assert_eq!(error(&mut vm, "var a = 2;"), ("Can't redefine constant 'a'.".to_string(), Some("E0121")));
+        assert_eq!(error(&mut vm, "fun a() {}").0, "Can't redefine constant 'a'.");
+        assert!(matches!(vm.interpret("try { a = 2; } catch (e) { print e; }".to_string(), false), InterpretResult::Ok));
+        assert_eq!(vm.globals["a"], Value::Number(1.0));
+
+        // running the declaration again is allowed
+        assert!(matches!(vm.interpret("const a = 3;".to_string(), false), InterpretResult::Ok));
+        assert_eq!(vm.globals["a"], Value::Number(3.0));
+        vm.reset_globals();
+        assert!(matches!(vm.interpret("var a = 4; a = 5;".to_string(), false), InterpretResult::Ok));
+    }
+
+    #[test]
+    fn test_global_cache() {
+        let source = "var a = 1; var sum = 0; fun read() { return a; }
+            for (var i = 0; i < 4; i = i + 1) { sum = sum + read(); a = a + 1; }
+            var a = 10; sum = sum + read();";
+        let mut vm = interpret_source(source);
+        assert_eq!(vm.globals["sum"], Value::Number(20.0));
+
+        vm.reset_globals();
+        assert!(matches!(vm.interpret("print a;".to_string(), false), InterpretResult::RuntimeError(_)));
+        vm.interpret("var a = 3;".to_string(), false);
+        vm.restore(&interpret_source("var a = 4;").snapshot(false)).unwrap();
+        vm.interpret("var b = a;".to_string(), false);
+        assert_eq!(vm.globals["b"], Value::Number(4.0));
+
+        // generators created by one run read the globals in later runs
+        vm.interpret("fun g() { yield a; yield a; } var it = g(); var c; for (var x in it) { c = x; break; }".to_string(), false);
+        vm.interpret("a = 5; var d; for (var x in it) { d = x; break; }".to_string(), false);
+        assert_eq!(vm.globals["c"], Value::Number(4.0));
+        assert_eq!(vm.globals["d"], Value::Number(5.0));
+        for _ in 0..10 {
+            vm.interpret("fun f() { return a; } f();".to_string(), false);
+        }
+        assert!(vm.global_caches.is_empty());
+        assert!(vm.global_readers.is_empty());
+    }
+
+    #[test]
+    fn test_instruction_limit() {
+        let mut vm = VM::new();
+        vm.set_instruction_limit(Some(1000));
+        let source = "while (true) { try { var a = 1; } catch (e) {} }";
+        let InterpretResult::RuntimeError(error) = vm.interpret(source.to_string(), false) else {
+            panic!("the loop was not stopped");
+        };
+        assert_eq!(error.message, "Instruction limit exceeded.");
+    }
+
+    #[test]
+    fn test_observer() {
+        #[derive(Default)]
+        struct Recorder {
+            instructions: usize,
+            events: Vec<String>,
+        }
+
+        impl VmObserver for Recorder {
+            fn instruction_executed(&mut self, _function: &Arc<Function>, _ip: usize) {
+                self.instructions += 1;
+            }
+
+            fn frame_pushed(&mut self, function: &Arc<Function>, depth: usize) {
+                self.events.push(format!("push {} {depth}", function.name()));
+            }
+
+            fn frame_popped(&mut self, function: &Arc<Function>, depth: usize) {
+                self.events.push(format!("pop {} {depth}", function.name()));
+            }
+
+            fn global_defined(&mut self, name: &str, _value: &Value) {
+                self.events.push(format!("define {name}"));
+            }
+
+            fn error_raised(&mut self, exception: &Value) {
+                self.events.push(format!("error {exception:?}"));
+            }
+        }
+
+        let mut vm = VM::new();
+        vm.add_observer(Recorder::default());
+        vm.interpret("fun f() { throw 1; } try { f(); } catch (e) {}".to_string(), false);
+
+        let recorder = vm.observer::<Recorder>().unwrap();
+        assert!(recorder.instructions > 5);
+        assert_eq!(recorder.events, vec![
+            "push  1", "define f", "push f 2", "error Number(1.0)", "pop f 2", "pop  1",
+        ]);
+        assert!(vm.coverage().is_none());
+    }
+
+    #[test]
+    fn test_complete() {
+        let mut vm = VM::new();
+        vm.interpret("var total = 1; fun twice(x) { return 2 * x; }".to_string(), false);
+        let mut expected: Vec<_> = native_names().into_iter().filter(|name| name.starts_with('t')).collect();
+        expected.extend(["this", "throw", "total", "true", "try", "twice"].map(String::from));
+        expected.sort();
+        assert_eq!(vm.complete("t"), expected);
+        assert_eq!(vm.complete("wh"), vec!["while"]);
+        assert!(vm.complete("zz").is_empty());
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let source = "var a = clock(); var b = clock(); var r = random();";
+        let run = |seed| {
+            let mut vm = VM::new();
+            vm.set_deterministic(seed);
+            vm.interpret(source.to_string(), false);
+            vm
+        };
+
+        let vm = run(7);
+        assert_eq!(vm.globals["a"], Value::Number(1.0));
+        assert_eq!(vm.globals["b"], Value::Number(2.0));
+        let Value::Number(r) = vm.globals["r"] else { panic!("random is not a number") };
+        assert!((0.0..1.0).contains(&r));
+        assert_eq!(run(7).globals["r"], vm.globals["r"]);
+        assert_ne!(run(8).globals["r"], vm.globals["r"]);
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        let vm = interpret_source("var a = 1; var s = \"s\"; var r = 1..3; fun f(x) { return x; }");
+
+        let mut restored = VM::new();
+        restored.globals.clear();
+        restored.restore(&vm.snapshot(false)).unwrap();
+        let names: Vec<_> = restored.globals().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["a", "f", "r", "s"]);
+        assert_eq!(restored.globals["r"], Value::Range(Arc::new(Range(1.0, 3.0))));
+        assert_eq!(restored.get_function("f"), vm.get_function("f"));
+
+        restored.restore(&vm.snapshot(true)).unwrap();
+        // functions are equal by identity, their code is compared
+        let globals = |vm: &VM| vm.globals().into_iter().map(|(name, value)| match value {
+            Value::Function(function) => format!("{name} {function:?}"),
+            value => format!("{name} {value:?}"),
+        }).collect::<Vec<_>>();
+        assert_eq!(globals(&restored), globals(&vm));
+        assert!(restored.restore(b"nope").is_err());
+
+        // natives get the registered arity, functions are verified
+        let crafted = |name: &str, value: Value| bson::to_vec(&Snapshot { globals: BTreeMap::from([(name.to_string(), value)]) }).unwrap();
+        restored.restore(&crafted("len", Value::Native(NativeFunction::Len, 0))).unwrap();
+        assert_eq!(restored.globals["len"], Value::Native(NativeFunction::Len, 1));
+        restored.restore(&crafted("size", Value::List(List::new(vec![Value::Native(NativeFunction::Len, 0)])))).unwrap();
+        assert!(matches!(restored.interpret("print size.get(0)(\"ab\");".to_string(), false), InterpretResult::Ok));
+        assert!(restored.restore(&crafted("double", Value::Native(NativeFunction::Host(7), 1))).unwrap_err().contains("not a native"));
+        let mut chunk = Chunk::new();
+        chunk.write(OpCode::Pop, 1);
+        chunk.write(OpCode::Return, 1);
+        let invalid = Value::Function(Arc::new(Function::new_from_chunk("f".to_string(), chunk)));
+        assert!(restored.restore(&crafted("g", invalid)).unwrap_err().contains("invalid snapshot"));
+        assert!(!restored.globals.contains_key("g"));
+    }
+
+    #[test]
+    fn test_yield_and_resume() {
+        let source = "
+            var total = 0;
+            for (var n = 1; n < 3; n = n + 1) { total = total + (yield n); }";
+        let mut vm = VM::new();
+        let function = compile(source.to_string(), false).unwrap().main;
+
+        let Ok(Execution::Suspended(handle, value)) = vm.start(function) else { panic!("not suspended") };
+        assert_eq!(value, Value::Number(1.0));
+        let Ok(Execution::Suspended(handle, value)) = vm.resume(handle, Value::Number(10.0)) else { panic!("not suspended") };
+        assert_eq!(value, Value::Number(2.0));
+        assert!(matches!(vm.resume(handle, Value::Number(20.0)), Ok(Execution::Complete(Value::Nil))));
+        assert_eq!(vm.globals["total"], Value::Number(30.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_interleaved_scripts() {
+        let mut vm = VM::new();
+        let counter = || compile("var i = yield; i = i + 1; yield i;".to_string(), false).unwrap().main;
+
+        let Ok(Execution::Suspended(first, _)) = vm.start(counter()) else { panic!("not suspended") };
+        let Ok(Execution::Suspended(second, _)) = vm.start(counter()) else { panic!("not suspended") };
+        let Ok(Execution::Suspended(_, value)) = vm.resume(first, Value::Number(1.0)) else { panic!("not suspended") };
+        assert_eq!(value, Value::Number(2.0));
+        let Ok(Execution::Suspended(_, value)) = vm.resume(second, Value::Number(5.0)) else { panic!("not suspended") };
+        assert_eq!(value, Value::Number(6.0));
+    }
+
+    #[test]
+    fn test_call_reserves_stack() {
+        let function = compile("fun f(a, b) { return a + b * 2; } print f(1, 2);".to_string(), false).unwrap().main;
+        let mut vm = VM::new();
+        vm.call(Arc::new(function.clone()));
+        assert!(vm.stack.capacity() >= function.max_stack());
+
+        assert_eq!(function.functions()[0].max_stack(), 5);
+    }
+
+    #[test]
+    fn test_string_methods() {
+        let vm = interpret_source("
+            var s = \"Größe\";
+            var length = s.length;
+            var upper = s.upper();
+            var found = s.lower().find(\"ö\");
+            var missing = s.find(\"x\");
+            var error;
+            try { s.reverse(); } catch (e) { error = e; }");
+        assert_eq!(vm.globals["length"], Value::Number(5.0));
+        assert_eq!(vm.globals["upper"], Value::from("GRÖSSE"));
+        assert_eq!(vm.globals["found"], Value::Number(2.0));
+        assert_eq!(vm.globals["missing"], Value::Nil);
+        assert_eq!(vm.globals["error"], Value::from("Undefined method 'reverse' of string."));
+    }
+
+    #[test]
+    fn test_signatures_and_arity_errors() {
+        let vm = interpret_source("
+            fun add(a, b, c) {}
+            fun log(format, ...args) {}
+            var missing;
+            try { add(1); } catch (e) { missing = e; }
+            var extra;
+            try { add(1, 2, 3, 4); } catch (e) { extra = e; }");
+        let add = vm.get_function("add").unwrap();
+        assert_eq!((add.signature(), add.line()), ("add(a, b, c)".to_string(), 2));
+        assert_eq!(vm.get_function("log").unwrap().signature(), "log(format, ...args)");
+        assert_eq!(vm.globals["missing"], Value::from("Expected 3 arguments but got 1, missing 'b', 'c'."));
+        assert_eq!(vm.globals["extra"], Value::from("Expected 3 arguments but got 4."));
+    }
+
+    #[test]
+    fn test_variadic() {
+        let vm = interpret_source("
+            fun count(first, ...rest) { return first + rest.length; }
+            fun all(...values) { for (var value in values) yield value; }
+            var none = count(10);
+            var two = count(10, \"a\", \"b\");
+            var sum = 0;
+            for (var value in all(1, 2, 3)) sum = sum + value;
+            var error;
+            try { count(); } catch (e) { error = e; }");
+        assert_eq!(vm.globals["none"], Value::Number(10.0));
+        assert_eq!(vm.globals["two"], Value::Number(12.0));
+        assert_eq!(vm.globals["sum"], Value::Number(6.0));
+        assert_eq!(vm.globals["error"], Value::from("Expected at least 1 arguments but got 0, missing 'first'."));
+    }
+
+    #[test]
+    fn test_match() {
+        let vm = interpret_source("
+            fun describe(value) {
+                match (value) {
+                    -1 => return \"minus one\";
+                    [] => return \"empty\";
+                    [\"point\", [x, y]] => return x + y;
+                    [_, b] => return b;
+                    other => return other;
+                }
+            }
+            var results = [describe(-1), describe([]), describe([\"point\", [1, 2]]), describe([1, 2]), describe(\"s\")];
+            var error;
+            try { match (1) { 2 => {} } } catch (e) { error = e; }");
+        let Value::List(results) = &vm.globals["results"] else { panic!() };
+        assert_eq!(results.values(), vec!["minus one".into(), "empty".into(), 3.0.into(), 2.0.into(), "s".into()]);
+        assert_eq!(vm.globals["error"], Value::from("No pattern matches the value."));
+    }
+
+    #[test]
+    fn test_list_methods() {
+        let vm = interpret_source("
+            fun double(x) { return x * 2; }
+            fun add(a, b) { return a + b; }
+            fun odd(x) { return x != 2; }
+            fun by_length(a, b) { return a.length - b.length; }
+            var list = [3, 1, 2];
+            var mapped = list.map(double);
+            var odd = list.filter(odd);
+            var sum = list.reduce(add, 0);
+            var words = \"ccc,a,bb,b\".split(\",\").sort(by_length);
+            list.push(4);");
+        let values = |name: &str| match &vm.globals[name] {
+            Value::List(list) => list.values(),
+            other => panic!("{name} is {other:?}"),
+        };
+        let numbers = |numbers: &[f64]| numbers.iter().map(|n| Value::Number(*n)).collect::<Vec<_>>();
+        assert_eq!(values("mapped"), numbers(&[6.0, 2.0, 4.0]));
+        assert_eq!(values("odd"), numbers(&[3.0, 1.0]));
+        assert_eq!(vm.globals["sum"], Value::Number(6.0));
+        assert_eq!(values("words"), vec!["a".into(), "b".into(), "bb".into(), "ccc".into()]);
+        assert_eq!(values("list"), numbers(&[3.0, 1.0, 2.0, 4.0]));
+    }
+
+    #[test]
+    fn test_sort_natives() {
+        let vm = interpret_source("
+            fun by_first(a, b) { return a.get(0) - b.get(0); }
+            fun always(a, b) { return 1; }
+            fun second(pair) { return pair.get(1); }
+            var numbers = sort([3, -1, 2]);
+            var words = sort([\"b\", \"c\", \"a\"]);
+            var pairs = sort_by([[2, \"x\"], [1, \"y\"], [2, \"z\"], [1, \"w\"]], by_first).map(second);
+            var inconsistent;
+            try { sort_by([1, 2, 3], always); } catch (e) { inconsistent = e; }
+            var mixed;
+            try { sort([1, \"a\"]); } catch (e) { mixed = e; }");
+        let values = |name: &str| match &vm.globals[name] {
+            Value::List(list) => list.values(),
+            other => panic!("{name} is {other:?}"),
+        };
+        assert_eq!(values("numbers"), vec![Value::Number(-1.0), Value::Number(2.0), Value::Number(3.0)]);
+        assert_eq!(values("words"), vec!["a".into(), "b".into(), "c".into()]);
+        assert_eq!(values("pairs"), vec!["y".into(), "w".into(), "x".into(), "z".into()]);
+        assert!(matches!(&vm.globals["inconsistent"], Value::String(e) if e.starts_with("Inconsistent comparator")));
+        assert_eq!(vm.globals["mixed"], Value::from("Can only sort numbers or strings, not Number(1.0) and String(\"a\")."));
+    }
+
+    #[test]
+    fn test_inspect() {
+        let vm = interpret_source("
+            fun add(a, b) { return a + b; }
+            var s = inspect([add, len, \"a\", 2]);
+            var nothing = dump(nil);");
+        assert_eq!(vm.globals["s"], Value::from("[<fn add/2>, <native fn/1>, \"a\", 2]"));
+        assert_eq!(vm.globals["nothing"], Value::Nil);
+    }
+
+    #[test]
+    fn test_env_and_exec() {
+        let mut vm = interpret_source("
+            set_env(\"RLOX_TEST_VAR\", \"set\");
+            var set = env(\"RLOX_TEST_VAR\");
+            var missing = env(\"RLOX_TEST_MISSING\");
+            var error;
+            try { exec(\"echo hi\"); } catch (e) { error = e; }");
+        assert_eq!(vm.globals["set"], Value::from("set"));
+        assert_eq!(vm.globals["missing"], Value::Nil);
+        assert_eq!(vm.globals["error"], Value::from("Running commands is not allowed, run with --allow-exec."));
+        assert!(std::env::var("RLOX_TEST_VAR").is_err());
+
+        if cfg!(unix) {
+            vm.set_allow_exec(true);
+            let source = "var out = exec([\"echo\", \"a  b\"]); var var_out = shell(\"echo $RLOX_TEST_VAR\");";
+            assert!(matches!(vm.interpret(source.to_string(), false), InterpretResult::Ok));
+            assert_eq!(vm.globals["out"], Value::from("a  b\n"));
+            assert_eq!(vm.globals["var_out"], Value::from("set\n"));
+        }
+    }
+
+    #[test]
+    fn test_exceptions_in_callbacks() {
+        let vm = interpret_source("
+            fun fail(x) { if (x == 2) throw x; return x; }
+            fun recover(x) { try { fail(x); } catch (e) { return -e; } return x; }
+            var caught;
+            try { [1, 2].map(fail); } catch (e) { caught = e; }
+            var recovered = [1, 2].map(recover).get(1);
+            var error;
+            try { [1].map(len); } catch (e) { error = e; }");
+        assert_eq!(vm.globals["caught"], Value::Number(2.0));
+        assert_eq!(vm.globals["recovered"], Value::Number(-2.0));
+        assert_eq!(vm.globals["error"], Value::from("len() not supported for Number(1.0)."));
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.base_frames, 0);
+    }
+
+    #[test]
+    fn test_function_in_loop_is_shared() {
+        let vm = interpret_source("
+            var first; var second;
+            for (var i = 0; i < 2; i = i + 1) {
+                fun f() { return i; }
+                if (i == 0) first = f; else second = f;
+            }");
+        let (Value::Function(first), Value::Function(second)) = (&vm.globals["first"], &vm.globals["second"]) else {
+            panic!("both are functions");
+        };
+        assert!(Arc::ptr_eq(first, second));
+    }
+
+    #[test]
+    fn test_value_size() {
+        assert_eq!(std::mem::size_of::<Value>(), 16);
+    }
+
+    #[test]
+    fn test_generator_for_in() {
+        let vm = interpret_source("
+            fun count(from, to) { for (var i = from; i < to; i = i + 1) { yield i * 10; } }
+            var total = 0;
+            var calls = count(1, 3);
+            for (var x in calls) { total = total + x; }
+            for (var x in calls) { total = total + 1000; }
+            for (var c in \"ab\") { for (var x in count(0, 2)) { total = total + x + 1; } }");
+        assert_eq!(vm.globals["total"], Value::Number(54.0));
+        assert!(matches!(&vm.globals["calls"], Value::Generator(generator) if generator.name() == "count"));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn test_generator_throws() {
+        let vm = interpret_source("
+            fun gen() { yield 1; throw \"stop\"; }
+            var seen = 0;
+            var caught;
+            var g = gen();
+            try { for (var x in g) { seen = seen + x; } } catch (e) { caught = e; }
+            for (var x in g) { seen = seen + 100; }");
+        assert_eq!(vm.globals["seen"], Value::Number(1.0));
+        assert_eq!(vm.globals["caught"], Value::from("stop"));
+    }
+
+    #[test]
+    fn test_generator_already_running() {
+        let mut vm = VM::new();
+        let result = vm.interpret("
+            var g;
+            fun gen() { for (var x in g) {} yield 1; }
+            g = gen();
+            for (var x in g) {}".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(error) if error.message == "Generator is already running."));
+    }
+
+    #[test]
+    fn test_yield_without_start() {
+        let mut vm = VM::new();
+        let result = vm.interpret("yield 1;".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(error) if error.message.contains("VM::start")));
+    }
+
+    #[test]
+    fn test_reset_globals() {
+        let mut vm = interpret_source("var a = 1;");
+        vm.reset_globals();
+        assert!(!vm.globals.contains_key("a"));
+        assert!(vm.globals.contains_key("clock"));
+        assert!(matches!(vm.interpret("var b = 2;".to_string(), false), InterpretResult::Ok));
+        assert_eq!(vm.globals.len(), native_names().len() + 1);
+    }
+
+    #[test]
+    fn test_handler_removed_after_try() {
+        let mut vm = VM::new();
+        let result = vm.interpret(
+            "try { } catch (e) { } throw 1;".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
+    #[test]
+    fn test_for_in_not_iterable() {
+        let mut vm = VM::new();
+        let result = vm.interpret("for (var c in 1) print c;".to_string(), false);
+        assert!(matches!(result, InterpretResult::RuntimeError(_)));
+    }
+
     #[test]
     fn test_arithmetic() {
         let vm = fill_and_run_vm(vec![
@@ -414,6 +2332,84 @@ mod tests {
         assert_eq!(vm.stack[0], Value::Bool(true));
     }
 
+    #[test]
+    fn test_nested_control_flow() {
+        let vm = interpret_source("
+            while (false) {}
+            var log = \"\";
+            for (var i = 0; i < 4; i = i + 1) {
+                if (i < 2) {
+                    if (i == 0) log = log + \"a\"; else log = log + \"b\";
+                } else {
+                    var j = 0;
+                    while (j < i) {
+                        if (j == 1) { log = log + \"c\"; } else if (j == 2) log = log + \"d\";
+                        j = j + 1;
+                    }
+                }
+            }
+            try {
+                for (;;) { if (true) { log = log + \"e\"; while (true) { throw nil; } } }
+            } catch (e) {}");
+        assert_eq!(vm.globals["log"], Value::from("abccde"));
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let vm = interpret_source("
+            var found;
+            outer: for (var i = 0; i < 5; i = i + 1) {
+                var j = 0;
+                while (j < 5) {
+                    var p = i * j;
+                    if (p == 6) { found = i * 10 + j; break outer; }
+                    j = j + 1;
+                    if (j > i) continue outer;
+                }
+            }
+            var sum = 0;
+            for (var x in 0..10) {
+                var y = x;
+                if (x == 2) continue;
+                if (x == 6) break;
+                sum = sum + y;
+            }
+            var k = 0;
+            while (true) {
+                k = k + 1;
+                try { var t; if (k == 3) break; } catch (e) {}
+            }
+            var caught;
+            try { throw k; } catch (e) { caught = e; }
+            fun f() {
+                items: for (var a in [1, 2, 3]) {
+                    for (var b in [1, 2, 3]) {
+                        if (b == 2) continue items;
+                        if (a == 3) return a * 10 + b;
+                    }
+                }
+            }
+            var r = f();");
+        assert_eq!(vm.globals["found"], Value::Number(32.0));
+        assert_eq!(vm.globals["sum"], Value::Number(13.0));
+        assert_eq!(vm.globals["caught"], Value::Number(3.0));
+        assert_eq!(vm.globals["r"], Value::Number(31.0));
+    }
+
+    #[test]
+    fn test_wide_locals() {
+        let vm = fill_and_run_vm(vec![
+            OpCode::Constant(1.0),
+            OpCode::Constant(2.0),
+            OpCode::SetLocalWide(0),
+            OpCode::Pop,
+            OpCode::GetLocalWide(0),
+            OpCode::Nil,
+            OpCode::Return,
+        ]);
+        assert_eq!(vm.stack, vec![Value::Number(2.0), Value::Number(2.0)]);
+    }
+
     #[test]
     fn test_string() {
         let vm = fill_and_run_vm(vec![
@@ -423,7 +2419,7 @@ mod tests {
             OpCode::Nil,
             OpCode::Return,
         ]);
-        assert_eq!(vm.stack[0], Value::String("helloworld".to_string()));
+        assert_eq!(vm.stack[0], Value::from("helloworld"));
     }
 
     #[test]
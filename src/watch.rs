@@ -0,0 +1,36 @@
+use std::{fs, thread, time::{Duration, SystemTime}};
+
+use crate::vm::VM;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Runs the file and runs it again every time it is saved, until the process is killed.
+/// With `keep_globals` the globals of the previous run are kept, so state can be built up
+/// while the functions are edited.
+pub fn run(filepath: &str, keep_globals: bool, debug: bool) {
+    let mut vm = VM::new();
+    let mut last_modified = None;
+    loop {
+        let modified = modified(filepath);
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            if !keep_globals {
+                vm.reset_globals();
+            }
+
+            match fs::read_to_string(filepath) {
+                Ok(source) => {
+                    println!("[running {filepath}]");
+                    vm.interpret(source, debug).print_errors();
+                }
+                Err(err) => eprintln!("file '{filepath}' could not be read: {err}"),
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn modified(filepath: &str) -> Option<SystemTime> {
+    fs::metadata(filepath).and_then(|metadata| metadata.modified()).ok()
+}
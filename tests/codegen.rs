@@ -0,0 +1,45 @@
+//! Compiles every `tests/codegen/*.lox` and compares its code with the `.expected`
+//! file next to it. `UPDATE_EXPECTED=1 cargo test --test codegen` rewrites them.
+
+use std::{env, fs, path::Path};
+
+use rlox::compiler::compile;
+
+#[test]
+fn test_codegen() {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/codegen");
+    let update = env::var_os("UPDATE_EXPECTED").is_some();
+
+    let mut sources: Vec<_> = fs::read_dir(&directory)
+        .expect("tests/codegen exists")
+        .map(|entry| entry.expect("directory entry").path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "lox"))
+        .collect();
+    sources.sort();
+    assert!(!sources.is_empty(), "no sources in {}", directory.display());
+
+    let mut failures = vec![];
+    for source in sources {
+        let text = fs::read_to_string(&source).expect("source is readable");
+        let Ok(program) = compile(text, false) else {
+            failures.push(format!("{} does not compile", source.display()));
+            continue;
+        };
+        let actual = program.main.to_test_string();
+
+        let expected_path = source.with_extension("expected");
+        if update {
+            fs::write(&expected_path, &actual).expect("expected file could not be written");
+            continue;
+        }
+        match fs::read_to_string(&expected_path) {
+            Ok(expected) if expected.replace("\r\n", "\n") == actual => (),
+            Ok(expected) => {
+                failures.push(format!("{}\n--- expected\n{expected}--- actual\n{actual}", source.display()));
+            }
+            Err(_) => failures.push(format!("{} is missing", expected_path.display())),
+        }
+    }
+
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
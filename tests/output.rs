@@ -0,0 +1,22 @@
+//! Runs scripts with the `rlox` binary and compares what they print.
+
+use std::{env, fs, process::Command};
+
+fn run(name: &str, source: &str) -> String {
+    let path = env::temp_dir().join(format!("rlox_output_{name}_{}.lox", std::process::id()));
+    fs::write(&path, source).expect("script could not be written");
+    let output = Command::new(env!("CARGO_BIN_EXE_rlox")).arg(&path).output().expect("rlox runs");
+    fs::remove_file(&path).ok();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    String::from_utf8(output.stdout).expect("output is utf-8").replace("\r\n", "\n")
+}
+
+#[test]
+fn test_print_ends_with_blank_line() {
+    assert_eq!(run("print", "print 1; print \"a\", nil;"), "Number(1.0)\n\nString(\"a\") Nil\n\n");
+}
+
+#[test]
+fn test_println_without_blank_line() {
+    assert_eq!(run("println", "println(2); print 3;"), "Number(2.0)\nNumber(3.0)\n\n");
+}